@@ -1,9 +1,11 @@
-use cmake;
-
+//! Builds the `wasm-instrument` C++ library for `common`'s `instrument` feature
 fn main() {
-    let wasm_instrument_dir = "../../wasm-instrument";
-    let dst = cmake::build(wasm_instrument_dir);
-    println!("cargo:rerun-if-changed={}", wasm_instrument_dir);
-    println!("cargo:rustc-link-search=native={}", dst.display());
-    println!("cargo:rustc-link-lib=stdc++");
+    #[cfg(feature = "instrument")]
+    {
+        let wasm_instrument_dir = "../../wasm-instrument";
+        let dst = cmake::build(wasm_instrument_dir);
+        println!("cargo:rerun-if-changed={}", wasm_instrument_dir);
+        println!("cargo:rustc-link-search=native={}", dst.display());
+        println!("cargo:rustc-link-lib=stdc++");
+    }
 }
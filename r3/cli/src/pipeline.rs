@@ -0,0 +1,212 @@
+//! `r3 pipeline`: record → replay → run in one invocation
+//!
+//! Intermediate files are written to a single output directory. Modules are
+//! validated (and optionally dumped to WAT) in-process between stages, and
+//! the pipeline stops at the first failing stage
+use clap::Args;
+use log::info;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use wasmparser::{Validator, WasmFeatures};
+
+use crate::run_tool;
+
+/// Arguments of `r3 pipeline`
+#[derive(Args, Debug)]
+pub struct PipelineArgs {
+    /// Directory for intermediate files (instrumented module, trace, replay
+    /// ops and module, WAT dumps)
+    #[arg(short, long, default_value_t = String::from("r3-out"))]
+    outdir: String,
+
+    /// Instrumentation scheme
+    #[arg(short, long, default_value_t = String::from("r3-record"))]
+    scheme: String,
+
+    /// Backend generating the replay module (see `replay -h`)
+    #[arg(short, long)]
+    backend: Option<String>,
+
+    /// Enable debug calls within the replay module
+    #[arg(short, long)]
+    debug: bool,
+
+    /// Capture output during recording, for verification by the runner
+    #[arg(long)]
+    capture_output: bool,
+
+    /// Dump the instrumented and replay modules as WAT
+    #[arg(long)]
+    wat: bool,
+
+    /// Input Command (Wasm program path + Argv)
+    #[arg(num_args = 1.., trailing_var_arg = true, allow_hyphen_values = true)]
+    input_command: Vec<String>,
+}
+
+impl PipelineArgs {
+    /// Print the CLI configuration
+    pub fn print(&self) {
+        info!("Outdir: {:?}", self.outdir);
+        info!("Scheme: {}", self.scheme);
+        info!("Backend [optional]: {:?}", self.backend);
+        info!("Replay Debug: {:?}", self.debug);
+        info!("Capture Output: {:?}", self.capture_output);
+        info!("WAT Dumps: {:?}", self.wat);
+        info!("Input Command: {:?}", self.input_command);
+    }
+}
+
+/// Outcome of a pipeline stage
+enum Outcome {
+    Pass,
+    Fail(String),
+    /// Not run, since an earlier stage failed
+    Skip,
+}
+
+struct Stage {
+    name: &'static str,
+    outcome: Outcome,
+    elapsed: Duration,
+}
+
+/// Stages of a pipeline run
+#[derive(Default)]
+struct Pipeline {
+    stages: Vec<Stage>,
+    failed: bool,
+}
+
+impl Pipeline {
+    /// Run stage `name` with `f`, unless an earlier stage failed
+    fn stage<F>(&mut self, name: &'static str, f: F)
+    where
+        F: FnOnce() -> Result<(), Box<dyn Error>>,
+    {
+        let start = Instant::now();
+        let outcome = if self.failed {
+            Outcome::Skip
+        } else {
+            info!("Pipeline | Stage: {}", name);
+            match f() {
+                Ok(()) => Outcome::Pass,
+                Err(e) => {
+                    self.failed = true;
+                    Outcome::Fail(e.to_string())
+                }
+            }
+        };
+        self.stages.push(Stage {
+            name,
+            outcome,
+            elapsed: start.elapsed(),
+        });
+    }
+
+    fn print_summary(&self) {
+        println!("\n=== R3 Pipeline Summary ===");
+        for stage in &self.stages {
+            match &stage.outcome {
+                Outcome::Pass => println!("[PASS] {:<20} ({:.2?})", stage.name, stage.elapsed),
+                Outcome::Fail(e) => {
+                    println!("[FAIL] {:<20} ({:.2?}): {}", stage.name, stage.elapsed, e)
+                }
+                Outcome::Skip => println!("[SKIP] {}", stage.name),
+            }
+        }
+        println!("Result: {}", if self.failed { "FAIL" } else { "PASS" });
+    }
+}
+
+/// Validate the Wasm module at `path` (with the threads and multi-memory
+/// proposals that instrumentation relies on), dumping it to `wat` if given
+fn check_module(path: &Path, wat: Option<PathBuf>) -> Result<(), Box<dyn Error>> {
+    let wasm = fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let features = WasmFeatures::default() | WasmFeatures::THREADS | WasmFeatures::MULTI_MEMORY;
+    Validator::new_with_features(features)
+        .validate_all(&wasm)
+        .map_err(|e| format!("{} is invalid: {}", path.display(), e))?;
+    if let Some(wat) = wat {
+        fs::write(&wat, wasmprinter::print_bytes(&wasm)?)?;
+        info!("Dumped {} to {}", path.display(), wat.display());
+    }
+    Ok(())
+}
+
+/// Run the pipeline tool `name`, failing if it exits unsuccessfully
+fn run_stage_tool(name: &str, args: &[&str]) -> Result<(), Box<dyn Error>> {
+    let status = run_tool(name, args)?;
+    if !status.success() {
+        return Err(format!("`{}` exited with {}", name, status).into());
+    }
+    Ok(())
+}
+
+/// Run all pipeline stages for `args`, printing a summary
+///
+/// Returns whether all stages passed
+pub fn run(args: &PipelineArgs) -> Result<bool, Box<dyn Error>> {
+    let outdir = Path::new(&args.outdir);
+    fs::create_dir_all(outdir)?;
+    let out = |name: &str| outdir.join(name);
+    let (instfile, tracefile) = (out("inst.wasm"), out("trace.r3"));
+    let (opsfile, replayfile) = (out("replay.ops"), out("replay.wasm"));
+    let wat = |name: &str| args.wat.then(|| out(name));
+    let path_arg = |path: &Path| path.to_string_lossy().into_owned();
+
+    let wasmfile = Path::new(&args.input_command[0]);
+    let mut pipeline = Pipeline::default();
+    pipeline.stage("validate module", || check_module(wasmfile, None));
+    pipeline.stage("record", || {
+        let (instfile, tracefile) = (path_arg(&instfile), path_arg(&tracefile));
+        let mut record_args = vec!["-s", &args.scheme, "-i", &instfile, "-o", &tracefile];
+        if args.capture_output {
+            record_args.push("--capture-output");
+        }
+        record_args.push("--");
+        record_args.extend(args.input_command.iter().map(String::as_str));
+        run_stage_tool("record", &record_args)
+    });
+    pipeline.stage("check instrumented", || {
+        check_module(&instfile, wat("inst.wat"))
+    });
+    pipeline.stage("replay", || {
+        let (tracefile, opsfile, replayfile) = (
+            path_arg(&tracefile),
+            path_arg(&opsfile),
+            path_arg(&replayfile),
+        );
+        let mut replay_args = vec![
+            "-w",
+            &args.input_command[0],
+            "-t",
+            &tracefile,
+            "-f",
+            &opsfile,
+            "-o",
+            &replayfile,
+            "--scheme",
+            &args.scheme,
+        ];
+        if let Some(ref backend) = args.backend {
+            replay_args.extend(["-b", backend]);
+        }
+        if args.debug {
+            replay_args.push("-d");
+        }
+        run_stage_tool("replay", &replay_args)
+    });
+    pipeline.stage("check replay", || {
+        check_module(&replayfile, wat("replay.wat"))
+    });
+    pipeline.stage("run", || {
+        run_stage_tool("runner", &[&path_arg(&replayfile)])
+    });
+
+    pipeline.print_summary();
+    Ok(!pipeline.failed)
+}
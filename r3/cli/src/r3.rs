@@ -0,0 +1,97 @@
+//! Binary crate providing a single `r3` entrypoint to the pipeline tools
+//! ([`record`](../record/index.html), [`replay`](../replay/index.html),
+//! [`runner`](../runner/index.html) and `deserialize`).
+//!
+//! Subcommands forward their arguments verbatim to the tool of the same
+//! stage, which is expected next to the `r3` binary (as built by
+//! `cargo build`). `r3 pipeline` runs all stages in one invocation
+use clap::{Args, Parser, Subcommand};
+use log::info;
+use std::env;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::path::PathBuf;
+use std::process::{self, Command as Process, ExitStatus};
+
+mod pipeline;
+use pipeline::PipelineArgs;
+
+/// Command-Line Arguments
+#[derive(Parser, Debug)]
+#[command(version, about, long_about=None)]
+struct CLI {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Record a Wasm program (see `r3 record -h`)
+    #[command(disable_help_flag = true)]
+    Record(ToolArgs),
+    /// Generate a replay module from a trace (see `r3 replay -h`)
+    #[command(disable_help_flag = true)]
+    Replay(ToolArgs),
+    /// Run a replay module (see `r3 run -h`)
+    #[command(disable_help_flag = true)]
+    Run(ToolArgs),
+    /// Inspect a trace (see `r3 inspect -h`)
+    #[command(disable_help_flag = true)]
+    Inspect(ToolArgs),
+    /// Record, replay and run a Wasm program, reporting a pass/fail summary
+    Pipeline(PipelineArgs),
+}
+
+/// Arguments forwarded verbatim to a pipeline tool
+#[derive(Args, Debug)]
+struct ToolArgs {
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
+    args: Vec<String>,
+}
+
+/// Path of the pipeline tool `name`, which is built alongside `r3`
+fn tool_path(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let exe = env::current_exe()?;
+    let path = exe.with_file_name(name);
+    if !path.is_file() {
+        return Err(format!(
+            "Tool `{}` not found next to {} (build the whole workspace)",
+            name,
+            exe.display()
+        )
+        .into());
+    }
+    Ok(path)
+}
+
+/// Run the pipeline tool `name` with `args` to completion, inheriting stdio
+fn run_tool<I, S>(name: &str, args: I) -> Result<ExitStatus, Box<dyn Error>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let path = tool_path(name)?;
+    let mut tool = Process::new(&path);
+    tool.args(args);
+    info!("Running {:?}", tool);
+    Ok(tool.status()?)
+}
+
+/// Entrypoint for `r3`
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::builder().format_timestamp_millis().init();
+    let cli = CLI::parse();
+
+    let status = match &cli.command {
+        Command::Record(tool) => run_tool("record", &tool.args)?,
+        Command::Replay(tool) => run_tool("replay", &tool.args)?,
+        Command::Run(tool) => run_tool("runner", &tool.args)?,
+        Command::Inspect(tool) => run_tool("deserialize", &tool.args)?,
+        Command::Pipeline(args) => {
+            args.print();
+            let passed = pipeline::run(args)?;
+            process::exit(if passed { 0 } else { 1 });
+        }
+    };
+    process::exit(status.code().unwrap_or(1));
+}
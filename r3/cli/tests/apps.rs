@@ -0,0 +1,189 @@
+//! End-to-end regression tests over the `apps/` corpus
+//!
+//! Each app is recorded, replayed and run through the pipeline libraries,
+//! checking that the replay exits and writes output (stdout included) like the
+//! recorded run.
+//!
+//! Deserialized traces of deterministic apps are kept as goldens in
+//! `tests/golden`, so that changes in recording or replay generation show up
+//! as diffs. A missing golden fails the test; set `R3_BLESS=1` to write
+//! them, e.g. for a new app or after an intended change
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use common::trace::{Termination, TraceData, TraceOp};
+use common::R3Error;
+use record::{RecordOptions, Recorder};
+use replay::{generate_replay_from_trace, ReplayOptions};
+use runner::{run, RunOptions};
+
+/// Environment variable to rewrite goldens with the current traces
+const BLESS_VAR: &str = "R3_BLESS";
+
+/// Path of `name` in the `apps/` corpus
+fn app_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../../apps")
+        .join(name)
+}
+
+/// Path of the golden trace of app `name`
+fn golden_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/golden")
+        .join(format!("{}.trace.txt", name))
+}
+
+/// Compare the textual form of `trace` (without provenance, which differs
+/// across runs) against the golden trace of app `name`
+fn check_golden(name: &str, mut trace: TraceData) {
+    trace.header.provenance = None;
+    let text = trace.to_text();
+    let path = golden_path(name);
+    if env::var_os(BLESS_VAR).is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &text).unwrap();
+        eprintln!("{}: wrote golden trace {}", name, path.display());
+        return;
+    }
+    let golden = fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "{}: cannot read golden trace {}: {}\n(rerun with {}=1 to write it)",
+            name,
+            path.display(),
+            e,
+            BLESS_VAR
+        )
+    });
+    let (golden, traced): (Vec<&str>, Vec<&str>) =
+        (golden.lines().collect(), text.lines().collect());
+    fn line<'a>(lines: &[&'a str], idx: usize) -> &'a str {
+        lines.get(idx).copied().unwrap_or("<end of trace>")
+    }
+    if let Some(idx) =
+        (0..golden.len().max(traced.len())).find(|&idx| golden.get(idx) != traced.get(idx))
+    {
+        panic!(
+            "{}: trace differs from {} at line {}\n  Golden: {}\n  Traced: {}\n\
+             (rerun with {}=1 if the change is intended)",
+            name,
+            path.display(),
+            idx + 1,
+            line(&golden, idx),
+            line(&traced, idx),
+            BLESS_VAR
+        );
+    }
+}
+
+/// Record app `name` with `args`, generate its replay and run it, checking
+/// the replay against the recording (and the golden trace, if `golden`)
+fn e2e(name: &str, args: &[PathBuf], golden: bool) {
+    let wasm_path = app_path(&format!("{}.wasm", name));
+    let wasm = fs::read(&wasm_path).unwrap();
+    let argv: Vec<String> = std::iter::once(&wasm_path)
+        .chain(args)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+
+    let failed = |stage: &str, e: R3Error| -> ! { panic!("{}: {} failed: {}", name, stage, e) };
+
+    let recorder = Recorder::new(
+        &wasm,
+        RecordOptions {
+            capture_output: true,
+            ..Default::default()
+        },
+    )
+    .unwrap_or_else(|e| failed("instrumentation", e));
+    let trace = recorder
+        .record(&argv)
+        .unwrap_or_else(|e| failed("recording", e));
+    assert!(!trace.truncated, "{}: recorded trace is truncated", name);
+    let recorded: Option<Termination> = trace.trace.iter().rev().find_map(|op| match op {
+        TraceOp::Terminate { kind } => Some(kind.clone()),
+        _ => None,
+    });
+    assert!(recorded.is_some(), "{}: recording did not terminate", name);
+
+    let replay_module = generate_replay_from_trace(&wasm, &trace, &ReplayOptions::default())
+        .unwrap_or_else(|e| failed("replay generation", e));
+    let result = run(&replay_module, &argv, &RunOptions::default())
+        .unwrap_or_else(|e| failed("replay run", e));
+    assert_eq!(
+        result.expected_termination, recorded,
+        "{}: replay module does not embed the recorded termination",
+        name
+    );
+    assert_eq!(
+        result.termination_matches(),
+        Some(true),
+        "{}: replay terminated with {:?}, but recording with {:?}",
+        name,
+        result.termination,
+        recorded
+    );
+    let recorded_output = trace
+        .trace
+        .iter()
+        .any(|op| matches!(op, TraceOp::Output { .. }));
+    match result.output_check {
+        Some(Ok(())) => {}
+        Some(Err(diff)) => panic!("{}: replay output differs | {}", name, diff),
+        None if recorded_output => panic!("{}: replay module does not embed recorded output", name),
+        None => {}
+    }
+
+    if golden {
+        check_golden(name, trace);
+    }
+}
+
+#[test]
+fn hello_world() {
+    e2e("hello_world", &[], true);
+}
+
+#[test]
+fn malloc() {
+    e2e("malloc", &[], true);
+}
+
+#[test]
+fn malloc_single() {
+    e2e("malloc_single", &[], true);
+}
+
+#[test]
+fn read() {
+    e2e("read", &[], true);
+}
+
+#[test]
+fn write() {
+    e2e("write", &[], true);
+}
+
+#[test]
+fn indirect_wali_basic() {
+    e2e("indirect_wali_basic", &[], true);
+}
+
+#[test]
+fn lua() {
+    e2e("lua", &[app_path("my.lua")], true);
+}
+
+/// Thread interleavings differ across runs, so there is no golden trace
+#[test]
+fn thread() {
+    e2e("thread", &[], false);
+}
+
+/// Network behaviour differs across runs, so there is no golden trace
+#[test]
+#[ignore = "needs an external MQTT broker"]
+fn mqtt() {
+    e2e("mqtt", &[], false);
+}
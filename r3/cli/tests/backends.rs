@@ -0,0 +1,219 @@
+//! Equivalence of the C++ and Rust replay generators over the `apps/` corpus
+//!
+//! Each app is recorded once, and both backends generate a replay from the
+//! same plan. Both replays must be valid, keep the exports of the original
+//! module, only import functions of the module or of the replay interface
+//! (with the same types across backends), and run to the same, verified,
+//! termination and output.
+//!
+//! Requires the `rust-generator` feature (enabled by default)
+#![cfg(feature = "rust-generator")]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use common::R3Error;
+use record::{RecordOptions, Recorder};
+use replay::generator::Backend;
+use replay::plan::ReplayPlan;
+use replay::{generate_replay_from_plan, ReplayOptions};
+use runner::{run, RunOptions, RunResult};
+use wasmparser::{Parser, Payload, TypeRef, Validator, WasmFeatures};
+
+/// Module name of the replay interface registered by `runner`
+const REPLAY_MODULE: &str = "r3-replay";
+
+/// Replay interface functions registered by `runner`
+const REPLAY_INTERFACE: [&str; 6] = [
+    "SC_proc_exit",
+    "SC_thread_exit",
+    "SC_writev",
+    "SC_futex_log",
+    "SC_gettid",
+    "SC_log_call",
+];
+
+/// Path of `name` in the `apps/` corpus
+fn app_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../../apps")
+        .join(name)
+}
+
+/// Imports (with their resolved types) and exports of a module
+#[derive(Debug, PartialEq)]
+struct Interface {
+    imports: BTreeMap<(String, String), String>,
+    exports: Vec<String>,
+}
+
+/// [Interface] of `wasm`, after validating it
+fn interface(wasm: &[u8]) -> Interface {
+    let features = WasmFeatures::default() | WasmFeatures::THREADS | WasmFeatures::MULTI_MEMORY;
+    Validator::new_with_features(features)
+        .validate_all(wasm)
+        .unwrap();
+    let mut types = Vec::new();
+    let mut interface = Interface {
+        imports: BTreeMap::new(),
+        exports: Vec::new(),
+    };
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload.unwrap() {
+            Payload::TypeSection(reader) => {
+                for rec_group in reader {
+                    for ty in rec_group.unwrap().into_types() {
+                        types.push(format!("{}", ty.composite_type));
+                    }
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.unwrap();
+                    let ty = match import.ty {
+                        TypeRef::Func(idx) => types[idx as usize].clone(),
+                        ty => format!("{:?}", ty),
+                    };
+                    interface
+                        .imports
+                        .insert((import.module.into(), import.name.into()), ty);
+                }
+            }
+            Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.unwrap();
+                    interface
+                        .exports
+                        .push(format!("{} {:?}", export.name, export.kind));
+                }
+            }
+            _ => {}
+        }
+    }
+    interface
+}
+
+/// Record app `name` with `args`, then generate and run its replay with both
+/// backends, checking that they behave identically
+fn compare(name: &str, args: &[PathBuf]) {
+    let wasm_path = app_path(&format!("{}.wasm", name));
+    let wasm = fs::read(&wasm_path).unwrap();
+    let argv: Vec<String> = std::iter::once(&wasm_path)
+        .chain(args)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+
+    let failed = |stage: &str, e: R3Error| -> ! { panic!("{}: {} failed: {}", name, stage, e) };
+
+    let recorder = Recorder::new(
+        &wasm,
+        RecordOptions {
+            capture_output: true,
+            ..Default::default()
+        },
+    )
+    .unwrap_or_else(|e| failed("instrumentation", e));
+    let trace = recorder
+        .record(&argv)
+        .unwrap_or_else(|e| failed("recording", e));
+    let plan = ReplayPlan::from_trace(&trace.header, trace.trace.iter().cloned().map(Ok))
+        .unwrap_or_else(|e| failed("planning", e));
+
+    let original = interface(&wasm);
+    let (interfaces, results): (Vec<Interface>, Vec<RunResult>) = [Backend::Cpp, Backend::Rust]
+        .into_iter()
+        .map(|backend| {
+            let options = ReplayOptions {
+                backend: Some(backend),
+                ..Default::default()
+            };
+            let replay_module = generate_replay_from_plan(&wasm, plan.clone(), &options)
+                .unwrap_or_else(|e| failed(&format!("{:?} replay generation", backend), e));
+            let replay_interface = interface(&replay_module);
+            assert_eq!(
+                replay_interface.exports, original.exports,
+                "{}: {:?} replay changes the module's exports",
+                name, backend
+            );
+            for (import, ty) in &replay_interface.imports {
+                let known = match original.imports.get(import) {
+                    Some(original_ty) => original_ty == ty,
+                    None => import.0 == REPLAY_MODULE && REPLAY_INTERFACE.contains(&&*import.1),
+                };
+                assert!(
+                    known,
+                    "{}: {:?} replay imports {}::{} {}, which is neither an import of the \
+                     module nor of the replay interface",
+                    name, backend, import.0, import.1, ty
+                );
+            }
+
+            let result = run(&replay_module, &argv, &RunOptions::default())
+                .unwrap_or_else(|e| failed(&format!("{:?} replay run", backend), e));
+            assert_eq!(
+                result.termination_matches(),
+                Some(true),
+                "{}: {:?} replay terminated with {:?}, but recording with {:?}",
+                name,
+                backend,
+                result.termination,
+                result.expected_termination
+            );
+            if let Some(Err(diff)) = result.output_check {
+                panic!("{}: {:?} replay output differs | {}", name, backend, diff);
+            }
+            (replay_interface, result)
+        })
+        .unzip();
+
+    for (import, ty) in &interfaces[0].imports {
+        if let Some(rust_ty) = interfaces[1].imports.get(import) {
+            assert_eq!(
+                ty, rust_ty,
+                "{}: backends import {}::{} with different types",
+                name, import.0, import.1
+            );
+        }
+    }
+    assert_eq!(
+        results[0].termination, results[1].termination,
+        "{}: replays of the C++ and Rust backends terminate differently",
+        name
+    );
+}
+
+#[test]
+fn hello_world() {
+    compare("hello_world", &[]);
+}
+
+#[test]
+fn malloc() {
+    compare("malloc", &[]);
+}
+
+#[test]
+fn malloc_single() {
+    compare("malloc_single", &[]);
+}
+
+#[test]
+fn read() {
+    compare("read", &[]);
+}
+
+#[test]
+fn write() {
+    compare("write", &[]);
+}
+
+#[test]
+fn indirect_wali_basic() {
+    compare("indirect_wali_basic", &[]);
+}
+
+#[test]
+fn lua() {
+    compare("lua", &[app_path("my.lua")]);
+}
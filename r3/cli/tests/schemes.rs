@@ -0,0 +1,147 @@
+//! Equivalence of the C++ `r3-record` and native `r3-record-rs` record passes
+//! over the `apps/` corpus
+//!
+//! Each deterministic app is recorded with both passes. Access sites (the
+//! access index of every traced load, store and import call) must be numbered
+//! identically, so that traces of either scheme replay alike: every access
+//! index traced by both passes must have the same opcode, and both passes
+//! must trace the same import calls.
+//!
+//! Requires `--features rust-instrument`
+#![cfg(feature = "rust-instrument")]
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use common::instrument::r3_record::SCHEME;
+use common::trace::TraceOp;
+use record::{RecordOptions, Recorder};
+
+/// Path of `name` in the `apps/` corpus
+fn app_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../../apps")
+        .join(name)
+}
+
+/// Access sites traced by a recording
+#[derive(Debug, Default)]
+struct AccessSites {
+    /// Opcode of every traced access index
+    opcodes: BTreeMap<u32, i32>,
+    /// Access index and function index of every traced import call, in order
+    calls: Vec<(u32, u32)>,
+}
+
+/// Record app `name` with `args` using `scheme`, returning its access sites
+fn access_sites(name: &str, args: &[PathBuf], scheme: &str) -> AccessSites {
+    let wasm_path = app_path(&format!("{}.wasm", name));
+    let wasm = fs::read(&wasm_path).unwrap();
+    let argv: Vec<String> = std::iter::once(&wasm_path)
+        .chain(args)
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let options = RecordOptions {
+        scheme: scheme.to_string(),
+        ..Default::default()
+    };
+    let trace = Recorder::new(&wasm, options)
+        .and_then(|recorder| recorder.record(&argv))
+        .unwrap_or_else(|e| panic!("{}: recording with {} failed: {}", name, scheme, e));
+    assert!(!trace.truncated, "{}: {} trace is truncated", name, scheme);
+
+    let mut sites = AccessSites::default();
+    for op in trace.trace {
+        let (access_idx, opcode) = match op {
+            TraceOp::Access {
+                access_idx, opcode, ..
+            }
+            | TraceOp::SyncAccess {
+                access_idx, opcode, ..
+            } => (access_idx, opcode),
+            TraceOp::Call {
+                access_idx,
+                opcode,
+                func_idx,
+                ..
+            } => {
+                sites.calls.push((access_idx, func_idx));
+                (access_idx, opcode)
+            }
+            TraceOp::Output { .. } | TraceOp::Terminate { .. } => continue,
+        };
+        if let Some(prev) = sites.opcodes.insert(access_idx, opcode) {
+            assert_eq!(
+                prev, opcode,
+                "{}: {} traces access index {} with different opcodes",
+                name, scheme, access_idx
+            );
+        }
+    }
+    sites
+}
+
+/// Record app `name` with `args` using both passes, checking that they number
+/// access sites identically
+fn compare(name: &str, args: &[PathBuf]) {
+    let cpp = access_sites(name, args, "r3-record");
+    let rust = access_sites(name, args, SCHEME);
+    for (access_idx, opcode) in &cpp.opcodes {
+        if let Some(rust_opcode) = rust.opcodes.get(access_idx) {
+            assert_eq!(
+                opcode, rust_opcode,
+                "{}: access index {} has opcode {:#X} with r3-record, but {:#X} with {}",
+                name, access_idx, opcode, rust_opcode, SCHEME
+            );
+        }
+    }
+    if let Some(idx) = (0..cpp.calls.len().max(rust.calls.len()))
+        .find(|&idx| cpp.calls.get(idx) != rust.calls.get(idx))
+    {
+        panic!(
+            "{}: import call {} is traced at (access index, function) {:?} with r3-record, but \
+             {:?} with {}",
+            name,
+            idx,
+            cpp.calls.get(idx),
+            rust.calls.get(idx),
+            SCHEME
+        );
+    }
+}
+
+#[test]
+fn hello_world() {
+    compare("hello_world", &[]);
+}
+
+#[test]
+fn malloc() {
+    compare("malloc", &[]);
+}
+
+#[test]
+fn malloc_single() {
+    compare("malloc_single", &[]);
+}
+
+#[test]
+fn read() {
+    compare("read", &[]);
+}
+
+#[test]
+fn write() {
+    compare("write", &[]);
+}
+
+#[test]
+fn indirect_wali_basic() {
+    compare("indirect_wali_basic", &[]);
+}
+
+#[test]
+fn lua() {
+    compare("lua", &[app_path("my.lua")]);
+}
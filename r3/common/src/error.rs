@@ -0,0 +1,76 @@
+//! Error type shared across the R3 pipeline
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::trace::TraceError;
+
+/// Errors raised while recording, processing or replaying traces
+#[derive(Debug)]
+pub enum R3Error {
+    /// Trace could not be decoded
+    Trace(TraceError),
+    /// Trace was recorded from a different module than the one provided
+    Sha256Mismatch { expected: String, found: String },
+    /// Instrumentation reported a call ID that is not a known
+    /// [CallID](crate::trace::CallID)
+    UnknownCallId(u32),
+    /// A differing access of thread `tid` was traced before any call it could
+    /// be attributed to
+    UnmappedAccess { tid: u64, access_idx: u32 },
+    /// Instrumentation of a module failed
+    Instrument(String),
+    /// Trace did not round-trip after being written
+    Verification(String),
+    /// The Wasm engine could not be set up, or did not report its outcome
+    Engine(String),
+    /// Replay plan could not be decoded
+    Plan(String),
+    /// A replay plan could not be reduced
+    Reduce(String),
+    /// Underlying I/O failed
+    Io(io::Error),
+}
+impl fmt::Display for R3Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            R3Error::Trace(e) => write!(f, "{}", e),
+            R3Error::Sha256Mismatch { expected, found } => write!(
+                f,
+                "SHA256 mismatch between trace ({}) and module ({})",
+                found, expected
+            ),
+            R3Error::UnknownCallId(call_id) => write!(f, "Unknown call ID {:#X}", call_id),
+            R3Error::UnmappedAccess { tid, access_idx } => write!(
+                f,
+                "No previous call to map access [{}::{}] to in trace",
+                tid, access_idx
+            ),
+            R3Error::Instrument(msg) => write!(f, "Instrumentation failed: {}", msg),
+            R3Error::Verification(msg) => write!(f, "Trace verification failed: {}", msg),
+            R3Error::Engine(msg) => write!(f, "Wasm engine failed: {}", msg),
+            R3Error::Plan(msg) => write!(f, "Malformed replay plan: {}", msg),
+            R3Error::Reduce(msg) => write!(f, "Reduction failed: {}", msg),
+            R3Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for R3Error {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            R3Error::Trace(e) => Some(e),
+            R3Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+impl From<TraceError> for R3Error {
+    fn from(e: TraceError) -> Self {
+        R3Error::Trace(e)
+    }
+}
+impl From<io::Error> for R3Error {
+    fn from(e: io::Error) -> Self {
+        R3Error::Io(e)
+    }
+}
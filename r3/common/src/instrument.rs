@@ -1,12 +1,24 @@
 //! FFI utilities for accessing the [`wasm-instrument`](https://github.com/arjunr2/wasm-instrument)
-//! C++ instrumentation API
-use libc::{c_char, c_void};
-
+//! C++ instrumentation API (feature `instrument`)
+//!
+//! With feature `rust-instrument`, the [`r3-record-rs`](r3_record::SCHEME)
+//! scheme is instrumented natively instead
 use log::info;
-use std::error::Error;
-use std::ffi::CString;
+#[cfg(feature = "rust-instrument")]
+use log::warn;
+use std::ffi::c_void;
+#[cfg(feature = "instrument")]
+use std::ffi::{c_char, CString};
+use std::ops::Deref;
+#[cfg(feature = "instrument")]
 use std::slice;
 
+use crate::R3Error;
+
+#[cfg(feature = "rust-instrument")]
+pub mod r3_record;
+
+#[cfg(feature = "instrument")]
 #[link(name = "wasminstrument", kind = "static")]
 extern "C" {
     /// API to instrument a module
@@ -21,7 +33,7 @@ extern "C" {
     ) -> *mut c_char;
 
     /// API to cleanup allocations from [instrument_module_buffer]
-    fn destroy_file_buf(buf: *const c_char) -> ();
+    fn destroy_file_buf(buf: *const c_char);
 }
 
 /// Arguments for the instrumentation routine
@@ -30,64 +42,151 @@ pub enum InstrumentArgs<'a> {
     AnonArr(*const c_void, u32, i64),
 }
 
-/// Convenient Rust wrapper method to instrument a module
+/// An instrumented module, owning the output buffer of the C++
+/// instrumentation library (or of a native Rust pass)
 ///
-/// Instrumentation is performed in-place.
-///
-/// **NOTE**: Remember to call [destroy_instrument_module] to prevent memory
-/// leaks after you are done with the output buffer
+/// The buffer is released on [Drop]; derefs to the module bytes
 ///
 /// ### Usage
-/// ```rust
+/// ```rust,no_run
+/// # use common::instrument::{InstrumentArgs, InstrumentedModule};
+/// # use std::fs;
+/// # fn main() -> Result<(), common::R3Error> {
+/// # let (file, outfile) = ("app.wasm", "app.instr.wasm");
 /// let contents = fs::read(file)?;
 /// let routine = "r3-record";
 /// let args = InstrumentArgs::Generic(&[]);
-/// let outbuf = instrument_module(&contents, routine, args)?;
-/// // ...
-/// // ...
-/// destroy_instrument_module(outbuf);
+/// let module = InstrumentedModule::new(&contents, routine, args)?;
+/// fs::write(outfile, &*module)?;
+/// # Ok(())
+/// # }
 /// ```
-pub fn instrument_module(
-    contents: &Vec<u8>,
-    routine: &str,
-    args: InstrumentArgs,
-) -> Result<&'static [u8], Box<dyn Error>> {
-    let c_routine = CString::new(routine)?;
-    let (c_args_ptr, c_args_len, c_args_flags) = match args {
-        InstrumentArgs::Generic(ax) => {
-            let args_cstr: Vec<CString> = ax.iter().map(|s| CString::new(*s).unwrap()).collect();
-            let c_args: Vec<*const i8> = args_cstr.iter().map(|s| s.as_ptr()).collect();
-            (c_args.as_ptr() as *const c_void, c_args.len() as u32, 0)
+pub struct InstrumentedModule {
+    inner: ModuleBuf,
+}
+
+/// Storage of an [InstrumentedModule]
+enum ModuleBuf {
+    /// Buffer allocated by the C++ instrumentation library
+    #[cfg(feature = "instrument")]
+    Ffi { buf: *mut c_char, len: usize },
+    /// Module produced by a native Rust pass
+    #[cfg(feature = "rust-instrument")]
+    Owned(Vec<u8>),
+}
+
+impl InstrumentedModule {
+    /// Instrument the module `contents` with the instrumentation `routine`
+    ///
+    /// Fails if the arguments cannot be passed over FFI, or if
+    /// instrumentation fails
+    pub fn new(contents: &[u8], routine: &str, args: InstrumentArgs) -> Result<Self, R3Error> {
+        let inner = match routine {
+            #[cfg(feature = "rust-instrument")]
+            r3_record::SCHEME => {
+                if !matches!(args, InstrumentArgs::Generic([])) {
+                    warn!("Routine \"{}\" takes no arguments; ignoring them", routine);
+                }
+                ModuleBuf::Owned(r3_record::instrument_module(contents)?)
+            }
+            #[cfg(feature = "instrument")]
+            _ => Self::instrument_ffi(contents, routine, args)?,
+            #[cfg(not(feature = "instrument"))]
+            _ => {
+                return Err(R3Error::Instrument(format!(
+                    "Routine \"{}\" requires feature `instrument`",
+                    routine
+                )))
+            }
+        };
+        let module = InstrumentedModule { inner };
+        if module.is_empty() {
+            return Err(R3Error::Instrument(format!(
+                "Routine \"{}\" produced an empty module",
+                routine
+            )));
         }
-        InstrumentArgs::AnonArr(ax, num_ax, flags) => (ax, num_ax, flags),
-    };
-    let mut outsize: u32 = 0;
-    let outsize_ptr: *mut u32 = &mut outsize;
-    let outslice: &[u8];
-    unsafe {
-        let outbuf: *mut c_char = instrument_module_buffer(
-            contents.as_ptr() as *const c_char,
-            contents.len() as u32,
-            outsize_ptr,
-            c_routine.as_ptr() as *const c_char,
-            c_args_ptr,
-            c_args_len,
-            c_args_flags,
+        info!(
+            "Instrument | Insize: {}, Outsize: {}",
+            contents.len(),
+            module.len()
         );
-        outslice = slice::from_raw_parts(outbuf as *const u8, outsize as usize);
-    };
-    info!(
-        "Instrument | Insize: {}, Outsize: {}",
-        contents.len(),
-        outsize
-    );
-    return Ok(outslice);
+        Ok(module)
+    }
+
+    /// Instrument `contents` with the C++ instrumentation library
+    #[cfg(feature = "instrument")]
+    fn instrument_ffi(
+        contents: &[u8],
+        routine: &str,
+        args: InstrumentArgs,
+    ) -> Result<ModuleBuf, R3Error> {
+        let c_routine = CString::new(routine)
+            .map_err(|_| R3Error::Instrument(format!("Invalid routine name {:?}", routine)))?;
+        // Generic arguments must outlive the FFI call below
+        let args_cstr: Vec<CString>;
+        let c_args: Vec<*const c_char>;
+        let (c_args_ptr, c_args_len, c_args_flags) = match args {
+            InstrumentArgs::Generic(ax) => {
+                args_cstr = ax
+                    .iter()
+                    .map(|s| CString::new(*s))
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| {
+                        R3Error::Instrument(format!("Invalid instrumentation arguments {:?}", ax))
+                    })?;
+                c_args = args_cstr.iter().map(|s| s.as_ptr()).collect();
+                (c_args.as_ptr() as *const c_void, c_args.len() as u32, 0)
+            }
+            InstrumentArgs::AnonArr(ax, num_ax, flags) => (ax, num_ax, flags),
+        };
+        let mut outsize: u32 = 0;
+        let outbuf: *mut c_char = unsafe {
+            instrument_module_buffer(
+                contents.as_ptr() as *const c_char,
+                contents.len() as u32,
+                &mut outsize,
+                c_routine.as_ptr(),
+                c_args_ptr,
+                c_args_len,
+                c_args_flags,
+            )
+        };
+        if outbuf.is_null() {
+            return Err(R3Error::Instrument(format!(
+                "Routine \"{}\" failed to instrument module",
+                routine
+            )));
+        }
+        Ok(ModuleBuf::Ffi {
+            buf: outbuf,
+            len: outsize as usize,
+        })
+    }
+}
+
+impl Deref for InstrumentedModule {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        match &self.inner {
+            #[cfg(feature = "instrument")]
+            ModuleBuf::Ffi { buf, len } => unsafe {
+                slice::from_raw_parts(*buf as *const u8, *len)
+            },
+            #[cfg(feature = "rust-instrument")]
+            ModuleBuf::Owned(module) => module,
+        }
+    }
 }
 
-/// Convenient Rust wrapper to destroy the output buffer from
-/// [instrument_module]
-pub fn destroy_instrument_module(contents: &[u8]) -> () {
-    unsafe {
-        destroy_file_buf(contents.as_ptr() as *const c_char);
+impl Drop for InstrumentedModule {
+    fn drop(&mut self) {
+        #[cfg(feature = "instrument")]
+        #[allow(irrefutable_let_patterns)]
+        if let ModuleBuf::Ffi { buf, .. } = self.inner {
+            unsafe {
+                destroy_file_buf(buf);
+            }
+        }
     }
 }
@@ -0,0 +1,915 @@
+//! Native Rust `r3-record` instrumentation (scheme [SCHEME], feature
+//! `rust-instrument`)
+//!
+//! Equivalent to the C++ `r3-record` routine, with the same
+//! [access indices](crate::rewrite), so traces of either scheme replay alike:
+//! * A *shadow memory* mirrors memory 0 as last observed by the module. Every
+//!   load compares memory against the shadow; a difference was written by the
+//!   host (or another thread), so the access is traced to
+//!   `instrument.memop_tracedump` and the shadow is updated. Atomic accesses
+//!   are always traced, as synchronization points
+//! * Stores (and bulk memory operations) are mirrored to the shadow
+//! * Every call of an import is traced to `instrument.call_tracedump`, with the
+//!   [CallID] derived from the import name
+//!
+//! ### Design Notes
+//! The shadow memory is a new memory of the same type as memory 0. It is
+//! initialized from memory 0 by a generated function that runs before the
+//! original start function, and is grown to the size of memory 0 after every
+//! `memory.grow` and import call. Operands of instrumented instructions are
+//! saved to scratch locals appended to each function, which are reused across
+//! instructions
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+
+use wasm_encoder::reencode::{utils, Error as ReencodeError, Reencode};
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, Function, FunctionSection, ImportSection, Instruction,
+    MemArg, MemorySection, SectionId, StartSection, TypeSection, ValType,
+};
+use wasmparser::{
+    CompositeInnerType, FuncType, KnownCustom, MemoryType, NameSectionReader, Operator, Parser,
+    Payload, TypeRef,
+};
+
+use crate::rewrite;
+use crate::trace::{CallID, FutexOp};
+use crate::{R3Error, WasmOpcode};
+
+/// Instrumentation scheme name selecting this pass
+pub const SCHEME: &str = "r3-record-rs";
+
+/// Module name of the record interface registered by `record`
+const RECORD_MODULE: &str = "instrument";
+
+/// Record interface functions imported by the instrumented module, following
+/// the original function imports
+const TRACE_IMPORTS: [(&str, &[ValType]); 2] = [
+    (
+        "memop_tracedump",
+        &[
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I64,
+            ValType::I64,
+            ValType::I32,
+        ],
+    ),
+    (
+        "call_tracedump",
+        &[
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+        ],
+    ),
+];
+const MEMOP_TRACEDUMP: u32 = 0;
+const CALL_TRACEDUMP: u32 = 1;
+const NUM_TRACE_IMPORTS: u32 = TRACE_IMPORTS.len() as u32;
+
+/// Name of the generated shadow memory initializer
+const SHADOW_INIT_NAME: &str = "r3_record_shadow_init";
+
+/// [CallID] of calls to the import `name` (a WALI or WASI function), with
+/// its parameters filled in at runtime
+fn call_id_of(name: &str) -> CallID {
+    match name {
+        "SYS_mmap" => CallID::ScMmap { grow: 0 },
+        "SYS_writev" => CallID::ScWritev {
+            fd: 0,
+            iov: 0,
+            iovcnt: 0,
+        },
+        "__wasm_thread_spawn" | "thread-spawn" => CallID::ScThreadSpawn {
+            fn_ptr: 0,
+            args_ptr: 0,
+        },
+        "SYS_futex" => CallID::ScFutex {
+            addr: 0,
+            op: FutexOp::Unknown,
+            val: 0,
+        },
+        "SYS_exit" => CallID::ScThreadExit { status: 0 },
+        "SYS_exit_group" | "proc_exit" => CallID::ScProcExit { status: 0 },
+        _ => CallID::ScGeneric,
+    }
+}
+
+/// Memory access performed by an access site
+struct Access {
+    /// Bytes accessed
+    size: u32,
+    /// Types of the operands following the address
+    operands: Vec<ValType>,
+    /// Whether the access observes memory, and is compared to the shadow
+    reads: bool,
+    /// Whether memory is written, and mirrored to the shadow
+    writes: bool,
+    /// Whether the access is atomic, and always traced
+    sync: bool,
+}
+
+impl Access {
+    /// Access performed by `opcode` (see [rewrite::accesses_memory])
+    fn of(opcode: u32) -> Self {
+        const LOAD_SIZES: [u32; 14] = [4, 8, 4, 8, 1, 1, 2, 2, 1, 1, 2, 2, 4, 4];
+        const STORES: [(ValType, u32); 9] = [
+            (ValType::I32, 4),
+            (ValType::I64, 8),
+            (ValType::F32, 4),
+            (ValType::F64, 8),
+            (ValType::I32, 1),
+            (ValType::I32, 2),
+            (ValType::I64, 1),
+            (ValType::I64, 2),
+            (ValType::I64, 4),
+        ];
+        // Repeats for each group of atomic loads, stores and read-modify-writes
+        const ATOMICS: [(ValType, u32); 7] = [
+            (ValType::I32, 4),
+            (ValType::I64, 8),
+            (ValType::I32, 1),
+            (ValType::I32, 2),
+            (ValType::I64, 1),
+            (ValType::I64, 2),
+            (ValType::I64, 4),
+        ];
+        let plain = |size, operands, reads| Access {
+            size,
+            operands,
+            reads,
+            writes: !reads,
+            sync: false,
+        };
+        let atomic = |size, operands, writes| Access {
+            size,
+            operands,
+            reads: true,
+            writes,
+            sync: true,
+        };
+        match opcode {
+            o if o < WasmOpcode::I32Store as u32 => plain(
+                LOAD_SIZES[(o - WasmOpcode::I32Load as u32) as usize],
+                vec![],
+                true,
+            ),
+            o if o <= WasmOpcode::I64Store32 as u32 => {
+                let (ty, size) = STORES[(o - WasmOpcode::I32Store as u32) as usize];
+                plain(size, vec![ty], false)
+            }
+            o if o == WasmOpcode::MemoryAtomicNotify as u32 => atomic(4, vec![ValType::I32], false),
+            o if o == WasmOpcode::MemoryAtomicWait32 as u32 => {
+                atomic(4, vec![ValType::I32, ValType::I64], false)
+            }
+            o if o == WasmOpcode::MemoryAtomicWait64 as u32 => {
+                atomic(8, vec![ValType::I64, ValType::I64], false)
+            }
+            o => {
+                let k = (o - WasmOpcode::I32AtomicLoad as u32) as usize;
+                let (ty, size) = ATOMICS[k % ATOMICS.len()];
+                match k / ATOMICS.len() {
+                    0 => atomic(size, vec![], false),
+                    // cmpxchg
+                    8 => atomic(size, vec![ty, ty], true),
+                    _ => atomic(size, vec![ty], true),
+                }
+            }
+        }
+    }
+}
+
+/// Load of `size` bytes as an `i64`
+fn raw_load(size: u32, offset: u32, memory_index: u32) -> Instruction<'static> {
+    let memarg = MemArg {
+        offset: offset as u64,
+        align: 0,
+        memory_index,
+    };
+    match size {
+        1 => Instruction::I64Load8U(memarg),
+        2 => Instruction::I64Load16U(memarg),
+        4 => Instruction::I64Load32U(memarg),
+        _ => Instruction::I64Load(memarg),
+    }
+}
+
+/// Store of the low `size` bytes of an `i64`
+fn raw_store(size: u32, offset: u32, memory_index: u32) -> Instruction<'static> {
+    let memarg = MemArg {
+        offset: offset as u64,
+        align: 0,
+        memory_index,
+    };
+    match size {
+        1 => Instruction::I64Store8(memarg),
+        2 => Instruction::I64Store16(memarg),
+        4 => Instruction::I64Store32(memarg),
+        _ => Instruction::I64Store(memarg),
+    }
+}
+
+/// Push the value of `local` as an `i64` trace argument (`0` if absent or
+/// not numeric)
+fn push_as_i64(body: &mut Vec<Instruction>, local: Option<(u32, ValType)>) {
+    match local {
+        Some((local, ValType::I32)) => {
+            body.extend([Instruction::LocalGet(local), Instruction::I64ExtendI32S])
+        }
+        Some((local, ValType::I64)) => body.push(Instruction::LocalGet(local)),
+        Some((local, ValType::F32)) => body.extend([
+            Instruction::LocalGet(local),
+            Instruction::I32ReinterpretF32,
+            Instruction::I64ExtendI32U,
+        ]),
+        Some((local, ValType::F64)) => {
+            body.extend([Instruction::LocalGet(local), Instruction::I64ReinterpretF64])
+        }
+        _ => body.push(Instruction::I64Const(0)),
+    }
+}
+
+/// Scratch locals of an instrumented function, following its original locals
+struct Scratch {
+    /// Index of the first scratch local
+    base: u32,
+    types: Vec<ValType>,
+    in_use: Vec<bool>,
+}
+
+impl Scratch {
+    fn new(base: u32) -> Self {
+        Scratch {
+            base,
+            types: Vec::new(),
+            in_use: Vec::new(),
+        }
+    }
+
+    /// Index of an unused scratch local of type `ty`
+    fn get(&mut self, ty: ValType) -> u32 {
+        let idx = match (0..self.types.len()).find(|i| !self.in_use[*i] && self.types[*i] == ty) {
+            Some(idx) => idx,
+            None => {
+                self.types.push(ty);
+                self.in_use.push(false);
+                self.types.len() - 1
+            }
+        };
+        self.in_use[idx] = true;
+        self.base + idx as u32
+    }
+
+    /// Release all scratch locals for reuse by the next instruction
+    fn release(&mut self) {
+        self.in_use.fill(false);
+    }
+}
+
+/// Function import of the original module
+struct FuncImport {
+    name: String,
+    ty: u32,
+}
+
+/// Index spaces of the original module that are extended by the
+/// instrumentation
+#[derive(Default)]
+struct ModuleLayout {
+    /// Function types by type index (`None` for non-function types)
+    types: Vec<Option<FuncType>>,
+    func_imports: Vec<FuncImport>,
+    /// Type of each defined function
+    defined_funcs: Vec<u32>,
+    /// Imported and defined memories
+    memories: Vec<MemoryType>,
+    start: Option<u32>,
+}
+
+impl ModuleLayout {
+    fn func_type(&self, ty: u32) -> &FuncType {
+        self.types[ty as usize].as_ref().unwrap()
+    }
+}
+
+/// [`Reencode`]r instrumenting the original module for recording
+struct RecordInstrumenter<'a> {
+    wasm: &'a [u8],
+    layout: ModuleLayout,
+    num_bodies: u32,
+    next_access_idx: u32,
+    imports_done: bool,
+    memories_done: bool,
+    start_done: bool,
+}
+
+impl<'a> RecordInstrumenter<'a> {
+    /// Scan the original module, checking that it can be instrumented
+    fn new(wasm: &'a [u8]) -> Result<Self, R3Error> {
+        let parse_err = |e: wasmparser::BinaryReaderError| R3Error::Instrument(e.to_string());
+
+        let mut layout = ModuleLayout::default();
+        let mut num_sites = 0;
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.map_err(parse_err)? {
+                Payload::TypeSection(reader) => {
+                    for rec_group in reader {
+                        for sub_type in rec_group.map_err(parse_err)?.into_types() {
+                            layout.types.push(match sub_type.composite_type.inner {
+                                CompositeInnerType::Func(ty) => Some(ty),
+                                _ => None,
+                            });
+                        }
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.map_err(parse_err)?;
+                        match import.ty {
+                            TypeRef::Func(ty) => layout.func_imports.push(FuncImport {
+                                name: import.name.to_string(),
+                                ty,
+                            }),
+                            TypeRef::Memory(ty) => layout.memories.push(ty),
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        layout.defined_funcs.push(ty.map_err(parse_err)?);
+                    }
+                }
+                Payload::MemorySection(reader) => {
+                    for ty in reader {
+                        layout.memories.push(ty.map_err(parse_err)?);
+                    }
+                }
+                Payload::StartSection { func, .. } => layout.start = Some(func),
+                Payload::CodeSectionEntry(body) => {
+                    let mut reader = body.get_operators_reader().map_err(parse_err)?;
+                    while !reader.eof() {
+                        let pos = reader.original_position();
+                        let op = reader.read().map_err(parse_err)?;
+                        if rewrite::is_access_site(
+                            &wasm[pos..],
+                            &op,
+                            layout.func_imports.len() as u32,
+                        ) {
+                            num_sites += 1;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let unsupported = |msg: &str| Err(R3Error::Instrument(String::from(msg)));
+        if layout.defined_funcs.is_empty() {
+            return unsupported("Module has no functions to instrument");
+        }
+        if layout.memories.is_empty() {
+            return unsupported("Module has no memory to record");
+        }
+        if layout.memories.iter().any(|mem| mem.memory64) {
+            return unsupported("64-bit memories are not supported");
+        }
+        if layout
+            .start
+            .is_some_and(|start| (start as usize) < layout.func_imports.len())
+        {
+            return unsupported("Imported start functions are not supported");
+        }
+        if layout.memories.len() > 1 {
+            warn!("Module has multiple memories; only memory 0 is recorded");
+        }
+        info!(
+            "Module has {} access sites ({} imports)",
+            num_sites,
+            layout.func_imports.len()
+        );
+
+        let start_done = layout.start.is_some();
+        Ok(RecordInstrumenter {
+            wasm,
+            layout,
+            num_bodies: 0,
+            next_access_idx: 0,
+            imports_done: false,
+            memories_done: false,
+            start_done,
+        })
+    }
+
+    fn num_func_imports(&self) -> u32 {
+        self.layout.func_imports.len() as u32
+    }
+
+    /// Function index of the record interface import `trace_import`
+    fn trace_func(&self, trace_import: u32) -> u32 {
+        self.num_func_imports() + trace_import
+    }
+
+    /// Function index of the shadow memory initializer, following the
+    /// defined functions
+    fn shadow_init_func(&self) -> u32 {
+        self.num_func_imports() + NUM_TRACE_IMPORTS + self.layout.defined_funcs.len() as u32
+    }
+
+    /// Memory index of the shadow memory, following all original memories
+    fn shadow_memory(&self) -> u32 {
+        self.layout.memories.len() as u32
+    }
+
+    /// Add the record interface imports
+    fn push_trace_imports(&mut self, imports: &mut ImportSection) {
+        let num_types = self.layout.types.len() as u32;
+        for (i, (name, _)) in TRACE_IMPORTS.iter().enumerate() {
+            imports.import(
+                RECORD_MODULE,
+                name,
+                EntityType::Function(num_types + i as u32),
+            );
+        }
+        self.imports_done = true;
+    }
+
+    /// Add the shadow memory
+    fn push_shadow_memory(&mut self, memories: &mut MemorySection) {
+        memories.memory(self.memory_type(self.layout.memories[0]));
+        self.memories_done = true;
+    }
+
+    /// Grow the shadow memory to the size of memory 0, using the i32 `delta`
+    /// local
+    fn sync_shadow_size(&self, body: &mut Vec<Instruction>, delta: u32) {
+        let shadow = self.shadow_memory();
+        body.extend([
+            Instruction::MemorySize(0),
+            Instruction::MemorySize(shadow),
+            Instruction::I32Sub,
+            Instruction::LocalTee(delta),
+            Instruction::I32Const(0),
+            Instruction::I32GtS,
+            Instruction::If(BlockType::Empty),
+            Instruction::LocalGet(delta),
+            Instruction::MemoryGrow(shadow),
+            Instruction::Drop,
+            Instruction::End,
+        ]);
+    }
+
+    /// Function copying memory 0 into the shadow memory
+    fn shadow_init_function(&self) -> Function {
+        let mut body = Vec::new();
+        self.sync_shadow_size(&mut body, 0);
+        body.extend([
+            Instruction::I32Const(0),
+            Instruction::I32Const(0),
+            Instruction::MemorySize(0),
+            Instruction::I32Const(16),
+            Instruction::I32Shl,
+            Instruction::MemoryCopy {
+                src_mem: 0,
+                dst_mem: self.shadow_memory(),
+            },
+            Instruction::End,
+        ]);
+        let mut f = Function::new([(1, ValType::I32)]);
+        for instr in &body {
+            f.instruction(instr);
+        }
+        f
+    }
+
+    /// Trace the access to memory 0 by `instr` (with `opcode` and static
+    /// `offset`) at `access_idx`
+    fn instrument_access<'b>(
+        &self,
+        body: &mut Vec<Instruction<'b>>,
+        scratch: &mut Scratch,
+        access_idx: u32,
+        opcode: u32,
+        offset: u32,
+        instr: Instruction<'b>,
+    ) {
+        let shadow = self.shadow_memory();
+        let access = Access::of(opcode);
+        let operands: Vec<u32> = access.operands.iter().map(|ty| scratch.get(*ty)).collect();
+        for local in operands.iter().rev() {
+            body.push(Instruction::LocalSet(*local));
+        }
+        let addr = scratch.get(ValType::I32);
+        body.push(Instruction::LocalSet(addr));
+
+        if access.reads {
+            let raw = scratch.get(ValType::I64);
+            body.extend([
+                Instruction::LocalGet(addr),
+                raw_load(access.size, offset, 0),
+                Instruction::LocalTee(raw),
+                Instruction::LocalGet(addr),
+                raw_load(access.size, offset, shadow),
+                Instruction::I64Ne,
+            ]);
+            let differ = if access.sync {
+                let differ = scratch.get(ValType::I32);
+                body.push(Instruction::LocalSet(differ));
+                Instruction::LocalGet(differ)
+            } else {
+                Instruction::I32Const(1)
+            };
+            let trace_args = [
+                Instruction::I32Const(access_idx as i32),
+                Instruction::I32Const(opcode as i32),
+                Instruction::LocalGet(addr),
+                Instruction::I32Const(offset as i32),
+                Instruction::I32Add,
+                Instruction::I32Const(access.size as i32),
+                Instruction::LocalGet(raw),
+                Instruction::LocalGet(addr),
+                raw_load(access.size, offset, shadow),
+                Instruction::I32Const(access.sync as i32),
+                Instruction::Call(self.trace_func(MEMOP_TRACEDUMP)),
+            ];
+            if access.sync {
+                body.push(differ.clone());
+                body.extend(trace_args);
+                body.extend([differ, Instruction::If(BlockType::Empty)]);
+            } else {
+                body.extend([Instruction::If(BlockType::Empty), differ]);
+                body.extend(trace_args);
+            }
+            body.extend([
+                Instruction::LocalGet(addr),
+                Instruction::LocalGet(raw),
+                raw_store(access.size, offset, shadow),
+                Instruction::End,
+            ]);
+        }
+
+        body.push(Instruction::LocalGet(addr));
+        body.extend(operands.iter().map(|local| Instruction::LocalGet(*local)));
+        body.push(instr);
+
+        if access.writes {
+            body.extend([
+                Instruction::LocalGet(addr),
+                Instruction::LocalGet(addr),
+                raw_load(access.size, offset, 0),
+                raw_store(access.size, offset, shadow),
+            ]);
+        }
+    }
+
+    /// Trace the call of import `func` at `access_idx`
+    fn instrument_call(
+        &mut self,
+        body: &mut Vec<Instruction>,
+        scratch: &mut Scratch,
+        access_idx: u32,
+        func: u32,
+    ) -> Result<(), ReencodeError<Infallible>> {
+        let import = &self.layout.func_imports[func as usize];
+        let call_id = call_id_of(&import.name);
+        let ty = self.layout.func_type(import.ty).clone();
+        let args = ty
+            .params()
+            .iter()
+            .map(|ty| {
+                let ty = self.val_type(*ty)?;
+                Ok((scratch.get(ty), ty))
+            })
+            .collect::<Result<Vec<_>, ReencodeError<Infallible>>>()?;
+        let result = ty
+            .results()
+            .first()
+            .map(|ty| self.val_type(*ty))
+            .transpose()?;
+
+        for (local, _) in args.iter().rev() {
+            body.push(Instruction::LocalSet(*local));
+        }
+        let pages = matches!(call_id, CallID::ScMmap { .. }).then(|| {
+            let pages = scratch.get(ValType::I32);
+            body.extend([Instruction::MemorySize(0), Instruction::LocalSet(pages)]);
+            pages
+        });
+        // Exits do not return, so they are traced before the call
+        let exits = matches!(
+            call_id,
+            CallID::ScThreadExit { .. } | CallID::ScProcExit { .. }
+        );
+        if exits {
+            self.trace_call(body, access_idx, func, &call_id, None, &args, pages);
+        }
+        body.extend(args.iter().map(|(local, _)| Instruction::LocalGet(*local)));
+        body.push(Instruction::Call(func));
+        let ret = result.map(|ty| {
+            let ret = scratch.get(ty);
+            body.push(Instruction::LocalTee(ret));
+            (ret, ty)
+        });
+        self.sync_shadow_size(body, scratch.get(ValType::I32));
+        if !exits {
+            self.trace_call(body, access_idx, func, &call_id, ret, &args, pages);
+        }
+        Ok(())
+    }
+
+    /// Emit the `call_tracedump` of a call of import `func`
+    ///
+    /// `pages` holds the size of memory 0 before an `mmap`
+    #[allow(clippy::too_many_arguments)]
+    fn trace_call(
+        &self,
+        body: &mut Vec<Instruction>,
+        access_idx: u32,
+        func: u32,
+        call_id: &CallID,
+        ret: Option<(u32, ValType)>,
+        args: &[(u32, ValType)],
+        pages: Option<u32>,
+    ) {
+        let (id, _) = call_id.to_parts();
+        body.extend([
+            Instruction::I32Const(access_idx as i32),
+            Instruction::I32Const(WasmOpcode::Call as i32),
+            Instruction::I32Const(func as i32),
+            Instruction::I32Const(id as i32),
+        ]);
+        push_as_i64(body, ret);
+        match (call_id, pages) {
+            (CallID::ScMmap { .. }, Some(pages)) => body.extend([
+                Instruction::MemorySize(0),
+                Instruction::LocalGet(pages),
+                Instruction::I32Sub,
+                Instruction::I64ExtendI32U,
+                Instruction::I64Const(0),
+                Instruction::I64Const(0),
+            ]),
+            (CallID::ScGeneric, _) => body.extend((0..3).map(|_| Instruction::I64Const(0))),
+            _ => {
+                for i in 0..3 {
+                    push_as_i64(body, args.get(i).copied());
+                }
+            }
+        }
+        body.push(Instruction::Call(self.trace_func(CALL_TRACEDUMP)));
+    }
+
+    /// Mirror a bulk write to memory 0 by `instr` (`memory.fill`,
+    /// `memory.copy` or `memory.init`) to the shadow memory
+    fn instrument_bulk<'b>(
+        &self,
+        body: &mut Vec<Instruction<'b>>,
+        scratch: &mut Scratch,
+        instr: Instruction<'b>,
+    ) {
+        let [dst, src, len] = [(); 3].map(|_| scratch.get(ValType::I32));
+        body.extend([
+            Instruction::LocalSet(len),
+            Instruction::LocalSet(src),
+            Instruction::LocalSet(dst),
+            Instruction::LocalGet(dst),
+            Instruction::LocalGet(src),
+            Instruction::LocalGet(len),
+            instr,
+            Instruction::LocalGet(dst),
+            Instruction::LocalGet(dst),
+            Instruction::LocalGet(len),
+            Instruction::MemoryCopy {
+                src_mem: 0,
+                dst_mem: self.shadow_memory(),
+            },
+        ]);
+    }
+
+    /// Remap function names to the instrumented module, naming generated
+    /// functions. Other name subsections are dropped
+    fn parse_name_section(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        reader: NameSectionReader,
+    ) -> Result<(), ReencodeError<Infallible>> {
+        let mut extra: BTreeMap<u32, String> = BTreeMap::new();
+        for (i, (name, _)) in TRACE_IMPORTS.iter().enumerate() {
+            extra.insert(self.trace_func(i as u32), name.to_string());
+        }
+        extra.insert(self.shadow_init_func(), String::from(SHADOW_INIT_NAME));
+        let names = rewrite::remap_function_names(reader, |idx| self.function_index(idx), extra)?;
+        module.section(&names);
+        Ok(())
+    }
+}
+
+impl Reencode for RecordInstrumenter<'_> {
+    type Error = Infallible;
+
+    /// Defined functions follow the record interface imports
+    fn function_index(&mut self, func: u32) -> u32 {
+        if func < self.num_func_imports() {
+            func
+        } else {
+            func + NUM_TRACE_IMPORTS
+        }
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_type_section(self, types, section)?;
+        for (_, params) in TRACE_IMPORTS {
+            types.ty().function(params.iter().copied(), []);
+        }
+        // Shadow memory initializer
+        types.ty().function([], []);
+        Ok(())
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_import_section(self, imports, section)?;
+        self.push_trace_imports(imports);
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_function_section(self, functions, section)?;
+        functions.function(self.layout.types.len() as u32 + NUM_TRACE_IMPORTS);
+        Ok(())
+    }
+
+    fn parse_memory_section(
+        &mut self,
+        memories: &mut MemorySection,
+        section: wasmparser::MemorySectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_memory_section(self, memories, section)?;
+        self.push_shadow_memory(memories);
+        Ok(())
+    }
+
+    fn parse_function_body(
+        &mut self,
+        code: &mut CodeSection,
+        func: wasmparser::FunctionBody<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        let wasm = self.wasm;
+        let ty = self.layout.defined_funcs[self.num_bodies as usize];
+        let mut num_locals = self.layout.func_type(ty).params().len() as u32;
+        let mut locals = Vec::new();
+        for local in func.get_locals_reader()? {
+            let (count, ty) = local?;
+            num_locals += count;
+            locals.push((count, self.val_type(ty)?));
+        }
+        let mut scratch = Scratch::new(num_locals);
+
+        let mut body = Vec::new();
+        if self.layout.start == Some(self.num_func_imports() + self.num_bodies) {
+            body.push(Instruction::Call(self.shadow_init_func()));
+        }
+        let mut reader = func.get_operators_reader()?;
+        while !reader.eof() {
+            let pos = reader.original_position();
+            let op = reader.read()?;
+            let code = &wasm[pos..];
+            if rewrite::is_access_site(code, &op, self.num_func_imports()) {
+                let access_idx = self.next_access_idx;
+                self.next_access_idx += 1;
+                match (op, rewrite::memarg(code)) {
+                    (Operator::Call { function_index }, _) => {
+                        self.instrument_call(&mut body, &mut scratch, access_idx, function_index)?
+                    }
+                    (op, Some((0, offset))) => {
+                        let opcode = rewrite::opcode(code).unwrap();
+                        let instr = self.instruction(op)?;
+                        self.instrument_access(
+                            &mut body,
+                            &mut scratch,
+                            access_idx,
+                            opcode,
+                            offset,
+                            instr,
+                        );
+                    }
+                    (op, _) => body.push(self.instruction(op)?),
+                }
+            } else {
+                match op {
+                    Operator::MemoryGrow { mem: 0 } => {
+                        body.push(self.instruction(op)?);
+                        self.sync_shadow_size(&mut body, scratch.get(ValType::I32));
+                    }
+                    Operator::MemoryFill { mem: 0 }
+                    | Operator::MemoryCopy { dst_mem: 0, .. }
+                    | Operator::MemoryInit { mem: 0, .. } => {
+                        let instr = self.instruction(op)?;
+                        self.instrument_bulk(&mut body, &mut scratch, instr);
+                    }
+                    op => body.push(self.instruction(op)?),
+                }
+            }
+            scratch.release();
+        }
+
+        locals.extend(scratch.types.iter().map(|ty| (1, *ty)));
+        let mut f = Function::new(locals);
+        for instr in &body {
+            f.instruction(instr);
+        }
+        code.function(&f);
+
+        self.num_bodies += 1;
+        if self.num_bodies as usize == self.layout.defined_funcs.len() {
+            code.function(&self.shadow_init_function());
+        }
+        Ok(())
+    }
+
+    fn parse_custom_section(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        section: wasmparser::CustomSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        match section.as_known() {
+            KnownCustom::Name(reader) => self.parse_name_section(module, reader),
+            _ => utils::parse_custom_section(self, module, section),
+        }
+    }
+
+    /// Insert the import, memory and start sections if the original module
+    /// has none
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        if !self.imports_done && !matches!(before, Some(SectionId::Type | SectionId::Import)) {
+            let mut imports = ImportSection::new();
+            self.push_trace_imports(&mut imports);
+            module.section(&imports);
+        }
+        if !self.memories_done
+            && !matches!(
+                before,
+                Some(
+                    SectionId::Type
+                        | SectionId::Import
+                        | SectionId::Function
+                        | SectionId::Table
+                        | SectionId::Memory
+                )
+            )
+        {
+            let mut memories = MemorySection::new();
+            self.push_shadow_memory(&mut memories);
+            module.section(&memories);
+        }
+        if !self.start_done
+            && matches!(
+                before,
+                None | Some(
+                    SectionId::Element | SectionId::DataCount | SectionId::Code | SectionId::Data
+                )
+            )
+        {
+            debug!("Adding start section for shadow memory initialization");
+            module.section(&StartSection {
+                function_index: self.shadow_init_func(),
+            });
+            self.start_done = true;
+        }
+        Ok(())
+    }
+}
+
+/// Instrument the wasm binary `wasmbin` for recording
+pub fn instrument_module(wasmbin: &[u8]) -> Result<Vec<u8>, R3Error> {
+    let mut instrumenter = RecordInstrumenter::new(wasmbin)?;
+    let mut module = wasm_encoder::Module::new();
+    instrumenter
+        .parse_core_module(&mut module, Parser::new(0), wasmbin)
+        .map_err(|e| R3Error::Instrument(e.to_string()))?;
+    Ok(module.finish())
+}
@@ -1,10 +1,25 @@
 //! Common library utilities used by [`record`](../record/index.html),
 //! [`replay`](../replay/index.html), and [`runner`](../runner/index.html).
-#![feature(iter_advance_by)]
+//!
+//! Traces ([trace]), [WasmOpcode] and [sections] build without a native
+//! toolchain. Opt-in features add:
+//! * `instrument`: the C++ instrumentation library (`instrument`), built with
+//!   cmake
+//! * `rust-instrument`: native Rust instrumentation and module rewriting
+//!   (`instrument::r3_record`, `rewrite`)
+//! * `wamr`: glue for the WAMR engine (`wasm2native`)
+#![cfg_attr(feature = "wamr", feature(iter_advance_by))]
 
+pub mod error;
+#[cfg(any(feature = "instrument", feature = "rust-instrument"))]
 pub mod instrument;
+#[cfg(feature = "rust-instrument")]
+pub mod rewrite;
+pub mod sections;
 pub mod trace;
+#[cfg(feature = "wamr")]
 pub mod wasm2native;
+pub use error::R3Error;
 pub use opcodes::WasmOpcode;
 
 mod opcodes;
@@ -0,0 +1,113 @@
+//! Shared utilities for native Rust module rewriting (feature
+//! `rust-instrument`)
+//!
+//! Recording and replay generation must agree on the *access index* of every
+//! instruction: instructions take indices in code section order, where each
+//! memory access (load, store or atomic operation) and each direct call of an
+//! import takes the next index. Both the `r3-record-rs`
+//! [instrumentation](crate::instrument::r3_record) and the native replay
+//! generator number sites through [is_access_site]
+use log::debug;
+use std::collections::BTreeMap;
+
+use wasm_encoder::{NameMap, NameSection};
+use wasmparser::{BinaryReaderError, Name, NameSectionReader, Operator};
+
+use crate::sections::read_u32;
+use crate::WasmOpcode;
+
+// Re-exported so rewriting passes outside this crate parse and encode with the
+// same versions
+pub use {wasm_encoder, wasmparser};
+
+/// Bit of the memarg alignment flags indicating an explicit memory index
+/// (multi-memory)
+const MEMARG_HAS_MEMORY: u32 = 0x40;
+
+/// Prefixes of multi-byte opcodes
+const OPCODE_PREFIXES: [u8; 3] = [0xFC, 0xFD, 0xFE];
+
+/// Read the opcode at `*pos` in `code` as a [WasmOpcode] value, advancing
+/// past it
+fn read_opcode(code: &[u8], pos: &mut usize) -> Option<u32> {
+    let byte = *code.get(*pos)?;
+    *pos += 1;
+    if OPCODE_PREFIXES.contains(&byte) {
+        Some(((byte as u32) << 8) | read_u32(code, pos)?)
+    } else {
+        Some(byte as u32)
+    }
+}
+
+/// Opcode of the instruction encoded at the start of `code`, as a
+/// [WasmOpcode] value
+pub fn opcode(code: &[u8]) -> Option<u32> {
+    read_opcode(code, &mut 0)
+}
+
+/// Memory index and static offset of the memory access encoded at the start
+/// of `code`
+pub fn memarg(code: &[u8]) -> Option<(u32, u32)> {
+    let mut pos = 0;
+    read_opcode(code, &mut pos)?;
+    let flags = read_u32(code, &mut pos)?;
+    let memory = if flags & MEMARG_HAS_MEMORY != 0 {
+        read_u32(code, &mut pos)?
+    } else {
+        0
+    };
+    Some((memory, read_u32(code, &mut pos)?))
+}
+
+/// Whether `opcode` accesses linear memory, i.e., is a load, store or atomic
+/// memory operation
+pub fn accesses_memory(opcode: u32) -> bool {
+    (WasmOpcode::I32Load as u32..=WasmOpcode::I64Store32 as u32).contains(&opcode)
+        || (opcode != WasmOpcode::AtomicFence as u32
+            && (WasmOpcode::MemoryAtomicNotify as u32..=WasmOpcode::I64AtomicRmw32CmpxchgU as u32)
+                .contains(&opcode))
+}
+
+/// Whether `op`, encoded at the start of `code`, takes an access index in a
+/// module with `num_func_imports` function imports
+pub fn is_access_site(code: &[u8], op: &Operator, num_func_imports: u32) -> bool {
+    match op {
+        Operator::Call { function_index } => *function_index < num_func_imports,
+        _ => opcode(code).is_some_and(accesses_memory),
+    }
+}
+
+/// Rebuild the name section of a rewritten module
+///
+/// The module name is kept and function names are moved to their index in
+/// the rewritten module (`remap`), alongside the `extra` names of added
+/// functions. Other name subsections are dropped
+pub fn remap_function_names<F>(
+    reader: NameSectionReader,
+    mut remap: F,
+    extra: BTreeMap<u32, String>,
+) -> Result<NameSection, BinaryReaderError>
+where
+    F: FnMut(u32) -> u32,
+{
+    let mut names = NameSection::new();
+    let mut func_names = extra;
+    for subsection in reader {
+        match subsection? {
+            Name::Module { name, .. } => names.module(name),
+            Name::Function(map) => {
+                for naming in map {
+                    let naming = naming?;
+                    func_names.insert(remap(naming.index), naming.name.into());
+                }
+            }
+            _ => debug!("Dropping name subsection of original module"),
+        }
+    }
+    let mut map = NameMap::new();
+    for (idx, name) in &func_names {
+        map.append(*idx, name);
+    }
+    names.functions(&map);
+    Ok(names)
+}
@@ -0,0 +1,72 @@
+//! Utilities for embedding R3 metadata in Wasm modules as custom sections
+/// Custom section holding the recorded
+/// [`Termination`](crate::trace::Termination) of a replay module
+/// (postcard-encoded)
+pub const TERMINATION_SECTION: &str = "r3-termination";
+
+/// Custom section holding the output recorded for each fd of a replay module
+/// (postcard-encoded `BTreeMap<i32, Vec<u8>>`)
+pub const OUTPUT_SECTION: &str = "r3-output";
+
+/// Size of the Wasm magic number + version preamble
+const PREAMBLE_SIZE: usize = 8;
+
+/// Custom section ID
+const CUSTOM_SECTION_ID: u8 = 0;
+
+/// Append `v` as an unsigned LEB128 to `buf`
+fn write_u32(buf: &mut Vec<u8>, mut v: u32) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Read an unsigned LEB128 from `buf` at `*pos`, advancing past it
+pub(crate) fn read_u32(buf: &[u8], pos: &mut usize) -> Option<u32> {
+    let mut v: u32 = 0;
+    for shift in (0..35).step_by(7) {
+        let b = *buf.get(*pos)?;
+        *pos += 1;
+        v |= ((b & 0x7f) as u32) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+/// Append a custom section `name` with contents `payload` to a Wasm `module`
+pub fn append_custom_section(module: &mut Vec<u8>, name: &str, payload: &[u8]) {
+    let mut contents: Vec<u8> = Vec::with_capacity(name.len() + payload.len() + 5);
+    write_u32(&mut contents, name.len() as u32);
+    contents.extend_from_slice(name.as_bytes());
+    contents.extend_from_slice(payload);
+    module.push(CUSTOM_SECTION_ID);
+    write_u32(module, contents.len() as u32);
+    module.extend_from_slice(&contents);
+}
+
+/// Find the contents of the first custom section `name` in a Wasm `module`
+///
+/// Returns `None` if absent, or if the module is malformed
+pub fn find_custom_section<'a>(module: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    let mut pos = PREAMBLE_SIZE;
+    while pos < module.len() {
+        let id = module[pos];
+        pos += 1;
+        let size = read_u32(module, &mut pos)? as usize;
+        let end = pos.checked_add(size).filter(|end| *end <= module.len())?;
+        if id == CUSTOM_SECTION_ID {
+            let mut name_pos = pos;
+            let name_len = read_u32(module, &mut name_pos)? as usize;
+            let name_end = name_pos.checked_add(name_len).filter(|e| *e <= end)?;
+            if &module[name_pos..name_end] == name.as_bytes() {
+                return Some(&module[name_end..end]);
+            }
+        }
+        pos = end;
+    }
+    None
+}
@@ -1,8 +1,37 @@
 //! Utilities for generating a Trace of program execution
-use crate::wasm2native::FutexOp;
+use crate::R3Error;
 use postcard;
 use serde::{Deserialize, Serialize};
+use std::error::Error;
 use std::fmt;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+mod codec;
+pub mod slice;
+pub mod stream;
+pub mod text;
+pub use slice::TraceSlice;
+pub use stream::{TraceReader, TraceWriter};
+
+/// Futex operations supported for record/replay
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum FutexOp {
+    Wait = 0,
+    Wake = 1,
+    Unknown = -1,
+}
+impl FutexOp {
+    /// Compose [FutexOp] variant from its [i32] representation
+    pub fn from_i32(op: i32) -> Self {
+        // Mask out FUTEX_PRIVATE (bit 7)
+        match op & 0x7f {
+            0 => FutexOp::Wait,
+            1 => FutexOp::Wake,
+            _ => FutexOp::Unknown,
+        }
+    }
+}
 
 /// Import Call Personality
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
@@ -21,34 +50,48 @@ impl CallID {
     /// Compose [CallID] variant from its parameters
     ///
     /// Required for parsing the ID from the Wasm record interface
-    pub fn from_parts(call_id: u32, args: [i64; 3]) -> Option<Self> {
+    pub fn from_parts(call_id: u32, args: [i64; 3]) -> Result<Self, R3Error> {
         match call_id {
-            0 => Some(CallID::ScUnknown),
-            1 => Some(CallID::ScMmap {
+            0 => Ok(CallID::ScUnknown),
+            1 => Ok(CallID::ScMmap {
                 grow: args[0] as u32,
             }),
-            2 => Some(CallID::ScWritev {
+            2 => Ok(CallID::ScWritev {
                 fd: args[0] as i32,
                 iov: args[1] as i32,
                 iovcnt: args[2] as u32,
             }),
-            3 => Some(CallID::ScThreadSpawn {
+            3 => Ok(CallID::ScThreadSpawn {
                 fn_ptr: args[0] as i32,
                 args_ptr: args[1] as i32,
             }),
-            4 => Some(CallID::ScFutex {
+            4 => Ok(CallID::ScFutex {
                 addr: args[0] as i32,
                 op: FutexOp::from_i32(args[1] as i32),
                 val: args[2] as u32,
             }),
-            5 => Some(CallID::ScThreadExit {
+            5 => Ok(CallID::ScThreadExit {
                 status: args[0] as i32,
             }),
-            6 => Some(CallID::ScProcExit {
+            6 => Ok(CallID::ScProcExit {
                 status: args[0] as i32,
             }),
-            0xFFFFFFFF => Some(CallID::ScGeneric),
-            _ => None,
+            0xFFFFFFFF => Ok(CallID::ScGeneric),
+            _ => Err(R3Error::UnknownCallId(call_id)),
+        }
+    }
+
+    /// Name of the [CallID] variant, without its parameters
+    pub fn name(&self) -> &'static str {
+        match self {
+            CallID::ScUnknown => "ScUnknown",
+            CallID::ScMmap { .. } => "ScMmap",
+            CallID::ScWritev { .. } => "ScWritev",
+            CallID::ScThreadSpawn { .. } => "ScThreadSpawn",
+            CallID::ScFutex { .. } => "ScFutex",
+            CallID::ScThreadExit { .. } => "ScThreadExit",
+            CallID::ScProcExit { .. } => "ScProcExit",
+            CallID::ScGeneric => "ScGeneric",
         }
     }
 
@@ -72,7 +115,7 @@ impl CallID {
 }
 
 /// Valid Trace operations during module recording
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub enum TraceOp {
     Access {
         tid: u64,
@@ -102,6 +145,34 @@ pub enum TraceOp {
         return_val: i64,
         call_id: CallID,
     },
+    /// Bytes passed by thread `tid` to the preceding `writev` call on `fd`
+    ///
+    /// Only recorded when output capture is enabled
+    Output { tid: u64, fd: i32, data: Vec<u8> },
+    /// Terminal record describing how the recorded program ended
+    Terminate { kind: Termination },
+}
+impl TraceOp {
+    /// Thread that performed the op, if any
+    pub fn tid(&self) -> Option<u64> {
+        match self {
+            TraceOp::Access { tid, .. }
+            | TraceOp::SyncAccess { tid, .. }
+            | TraceOp::Call { tid, .. }
+            | TraceOp::Output { tid, .. } => Some(*tid),
+            TraceOp::Terminate { .. } => None,
+        }
+    }
+
+    /// Static code location of the op, if any
+    pub fn access_idx(&self) -> Option<u32> {
+        match self {
+            TraceOp::Access { access_idx, .. }
+            | TraceOp::SyncAccess { access_idx, .. }
+            | TraceOp::Call { access_idx, .. } => Some(*access_idx),
+            TraceOp::Output { .. } | TraceOp::Terminate { .. } => None,
+        }
+    }
 }
 impl fmt::Display for TraceOp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -155,38 +226,352 @@ impl fmt::Display for TraceOp {
                     "Call", tid, access_idx, opcode, call_id, func_idx, return_val
                 )
             }
+            TraceOp::Output { tid, fd, data } => write!(
+                f,
+                "{:>10} [{:>6}::{:>6}] with {} bytes \"{}\"",
+                "Output",
+                tid,
+                fd,
+                data.len(),
+                data.escape_ascii()
+            ),
+            TraceOp::Terminate { kind } => write!(f, "{:>10} [{}]", "Terminate", kind),
         }
     }
 }
 
+/// How a recorded (or replayed) program terminated
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub enum Termination {
+    /// Returned from main or called `proc_exit` with `code`
+    Exit { code: i32 },
+    /// Trapped with `message`
+    ///
+    /// `access_idx` is the last location traced by thread `tid` that observed
+    /// the trap, if known
+    Trap {
+        tid: u64,
+        message: String,
+        access_idx: Option<u32>,
+    },
+    /// Engine was killed by signal `signo`
+    Signal { signo: i32 },
+}
+impl Termination {
+    /// Whether `other` ended the same way, ignoring trap locations (which are
+    /// not observable during replay)
+    pub fn same_outcome(&self, other: &Termination) -> bool {
+        match (self, other) {
+            (Termination::Exit { code: a }, Termination::Exit { code: b }) => a == b,
+            (Termination::Trap { message: a, .. }, Termination::Trap { message: b, .. }) => a == b,
+            (Termination::Signal { signo: a }, Termination::Signal { signo: b }) => a == b,
+            _ => false,
+        }
+    }
+}
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Termination::Exit { code } => write!(f, "Exit with code {}", code),
+            Termination::Trap {
+                tid,
+                message,
+                access_idx: Some(access_idx),
+            } => write!(
+                f,
+                "Trap \"{}\" in thread {} near {}",
+                message, tid, access_idx
+            ),
+            Termination::Trap { tid, message, .. } => {
+                write!(f, "Trap \"{}\" in thread {}", message, tid)
+            }
+            Termination::Signal { signo } => write!(f, "Killed by signal {}", signo),
+        }
+    }
+}
+
+/// Magic number at the start of every versioned `.r3` trace file
+///
+/// The leading NUL byte cannot begin a headerless (version 0) trace, which
+/// always starts with the varint length of its sha256 string
+pub const TRACE_MAGIC: [u8; 4] = [0x00, b'R', b'3', b'T'];
+
+/// Current version of the `.r3` trace format
+///
+/// Bump this whenever the serialized form of [TraceHeader], [TraceOp] or
+/// [CallID] changes, and add an upgrade path for the previous version in
+/// [TraceReader::new]
+pub const TRACE_VERSION: u32 = 7;
+
+/// Errors encountered while decoding a `.r3` trace
+#[derive(Debug)]
+pub enum TraceError {
+    /// Trace was written by a format version this build does not understand
+    UnsupportedVersion(u32),
+    /// Trace ends before its header or final chunk
+    Truncated,
+    /// A header or chunk is larger than [MAX_CHUNK_LEN](stream::MAX_CHUNK_LEN)
+    /// bytes, so the trace is corrupt
+    Oversized(u64),
+    /// Trace header or body could not be decoded
+    Decode(postcard::Error),
+    /// Underlying reader failed
+    Io(io::Error),
+}
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TraceError::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported trace format version {} (supported: 0..={})",
+                v, TRACE_VERSION
+            ),
+            TraceError::Truncated => write!(f, "Trace ends unexpectedly"),
+            TraceError::Oversized(len) => write!(
+                f,
+                "Trace section of {} bytes exceeds the maximum of {} bytes",
+                len,
+                stream::MAX_CHUNK_LEN
+            ),
+            TraceError::Decode(e) => write!(f, "Malformed trace: {}", e),
+            TraceError::Io(e) => write!(f, "Failed to read trace: {}", e),
+        }
+    }
+}
+impl Error for TraceError {}
+impl From<postcard::Error> for TraceError {
+    fn from(e: postcard::Error) -> Self {
+        TraceError::Decode(e)
+    }
+}
+impl From<io::Error> for TraceError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::UnexpectedEof => TraceError::Truncated,
+            _ => TraceError::Io(e),
+        }
+    }
+}
+
+/// Encoding of the op chunks in a `.r3` trace
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy, Default)]
+pub enum TraceEncoding {
+    /// Plain postcard-encoded ops
+    #[default]
+    Plain,
+    /// Delta-coded ops in zstd-compressed chunks
+    Compressed,
+}
+
+/// Self-describing metadata stored ahead of the ops in a `.r3` trace
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct TraceHeader {
+    /// SHA256 digest of the recorded (uninstrumented) Wasm module
+    pub sha256: String,
+    /// Encoding used for op chunks
+    pub encoding: TraceEncoding,
+    /// How the trace was recorded, if known
+    pub provenance: Option<Provenance>,
+}
+impl TraceHeader {
+    /// Create a [TraceEncoding::Plain] header without provenance for a module
+    /// with the given `sha256`
+    pub fn new(sha256: &str) -> Self {
+        TraceHeader {
+            sha256: sha256.to_string(),
+            encoding: TraceEncoding::Plain,
+            provenance: None,
+        }
+    }
+
+    /// Verify the trace was recorded from a module with digest `sha256`, if
+    /// provided
+    pub fn check_sha256(&self, sha256: Option<&str>) -> Result<(), R3Error> {
+        match sha256 {
+            Some(digest) if digest != self.sha256 => Err(R3Error::Sha256Mismatch {
+                expected: digest.to_string(),
+                found: self.sha256.clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Description of how and where a trace was recorded
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct Provenance {
+    /// Input command (Wasm program path + Argv) of the recorded module
+    pub argv: Vec<String>,
+    /// Environment (`KEY=VALUE`) of the recording, if it was captured
+    pub env: Vec<String>,
+    /// Instrumentation scheme used for recording
+    pub scheme: String,
+    /// Instrumentation arguments used for recording
+    pub instargs: Vec<String>,
+    /// Log-level within the Wasm engine
+    pub log_level: u32,
+    /// Name and version of the recording tool
+    pub tool_version: String,
+    /// Hostname of the recording machine
+    pub host: String,
+    /// Target (`<arch>-<os>`) of the recording machine
+    pub target: String,
+    /// Start of the recording, in milliseconds since the Unix epoch
+    pub started_ms: u64,
+    /// End of the recording, in milliseconds since the Unix epoch
+    pub finished_ms: u64,
+}
+impl Provenance {
+    /// Milliseconds since the Unix epoch, for timestamping recordings
+    pub fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64)
+    }
+}
+
+/// Format `ms` since the Unix epoch as an ISO 8601 UTC timestamp
+fn fmt_utc_ms(ms: u64) -> String {
+    let (days, day_ms) = ((ms / 86_400_000) as i64, ms % 86_400_000);
+    // Civil-from-days conversion (proleptic Gregorian calendar)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_ms / 3_600_000,
+        day_ms / 60_000 % 60,
+        day_ms / 1000 % 60,
+        day_ms % 1000
+    )
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Command: {:?}", self.argv)?;
+        writeln!(f, "Scheme: {} {:?}", self.scheme, self.instargs)?;
+        writeln!(f, "Engine Log-level: {}", self.log_level)?;
+        writeln!(f, "Recorded by: {}", self.tool_version)?;
+        writeln!(f, "Host: {} ({})", self.host, self.target)?;
+        writeln!(
+            f,
+            "Recorded: {} -- {}",
+            fmt_utc_ms(self.started_ms),
+            fmt_utc_ms(self.finished_ms)
+        )?;
+        write!(f, "Environment: {:?}", self.env)
+    }
+}
+
 /// A Serializable-Deserializable container for a Trace
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
-pub struct TraceData<'a> {
-    pub sha256: &'a str,
+///
+/// Holds the entire trace in memory; use [TraceReader]/[TraceWriter] to
+/// process large traces op-by-op. See [stream](self::stream) for the on-disk
+/// format
+#[derive(Debug, PartialEq)]
+pub struct TraceData {
+    pub header: TraceHeader,
     pub trace: Vec<TraceOp>,
+    /// Recording ended before all ops could be saved
+    pub truncated: bool,
 }
-impl<'a> TraceData<'a> {
-    /// Deserialize a Trace from vector `ser` into [TraceData][Self].
-    ///
-    /// Optionally provide a SHA256 digest to verify integrity. Panics if
-    /// provided digest does not match the expected value
-    pub fn deserialize(ser: &'a Vec<u8>, sha256: Option<&str>) -> Self {
-        let deser: Self = postcard::from_bytes(ser).unwrap();
-        if let Some(digest) = sha256 {
-            assert_eq!(
-                digest, deser.sha256,
-                "SHA256 mismatch between trace and expected"
-            );
+impl TraceData {
+    /// Create a new [TraceData][Self] for a module with the given `sha256`
+    pub fn new(sha256: &str, trace: Vec<TraceOp>) -> Self {
+        TraceData {
+            header: TraceHeader::new(sha256),
+            trace,
+            truncated: false,
         }
-        deser
     }
 
-    /// Serialize a Trace from vector `ser`.
+    /// Deserialize a Trace from buffer `ser` into [TraceData][Self],
+    /// upgrading traces written by older format versions.
     ///
-    /// Optionally provide a SHA256 digest to verify integrity. Panics if
-    /// provided digest does not match the expected value
-    pub fn serialize(&self) -> Vec<u8> {
-        postcard::to_stdvec(&self).unwrap()
+    /// Optionally provide a SHA256 digest to verify integrity
+    pub fn deserialize(ser: &[u8], sha256: Option<&str>) -> Result<Self, R3Error> {
+        let mut reader = TraceReader::new(ser)?;
+        reader.header().check_sha256(sha256)?;
+        let header = reader.header().clone();
+        let trace = reader.by_ref().collect::<Result<_, _>>()?;
+        Ok(TraceData {
+            header,
+            trace,
+            truncated: reader.is_truncated(),
+        })
+    }
+
+    /// Serialize a Trace into the current versioned format
+    pub fn serialize(&self) -> Result<Vec<u8>, R3Error> {
+        let mut writer = TraceWriter::new(Vec::new(), &self.header)?;
+        for op in &self.trace {
+            writer.push(op)?;
+        }
+        if self.truncated {
+            writer.set_truncated();
+        }
+        Ok(writer.finish()?)
+    }
+}
+
+/// Version 0 (headerless) trace layout
+mod v0 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct TraceData<'a> {
+        pub sha256: &'a str,
+        pub trace: Vec<TraceOp>,
+    }
+
+    /// Version 0 ops decode as [TraceOp]s and [CallID]s: the variants they
+    /// use are unchanged, and later versions only append variants
+    /// ([TraceOp::Output], [TraceOp::Terminate])
+    pub fn upgrade(old: TraceData) -> (TraceHeader, Vec<TraceOp>) {
+        (TraceHeader::new(old.sha256), old.trace)
+    }
+}
+
+/// Version 1 and 2 trace header layout
+mod v2 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct TraceHeader {
+        pub sha256: String,
+    }
+
+    /// Traces before version 3 were always [TraceEncoding::Plain]
+    pub fn upgrade(old: TraceHeader) -> super::TraceHeader {
+        super::TraceHeader::new(&old.sha256)
+    }
+}
+
+/// Version 3 to 6 trace header layout
+mod v6 {
+    use super::*;
+
+    #[derive(Deserialize)]
+    pub struct TraceHeader {
+        pub sha256: String,
+        pub encoding: TraceEncoding,
+    }
+
+    /// Provenance was not recorded before version 7
+    pub fn upgrade(old: TraceHeader) -> super::TraceHeader {
+        super::TraceHeader {
+            encoding: old.encoding,
+            ..super::TraceHeader::new(&old.sha256)
+        }
     }
 }
 
@@ -246,3 +631,100 @@ impl PartialEq for ReplayPropLogInfo {
     }
 }
 impl Eq for ReplayPropLogInfo {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn provenance() -> Provenance {
+        Provenance {
+            argv: vec!["app.wasm".into(), "--flag".into()],
+            env: vec!["HOME=/root".into()],
+            scheme: "r3-record".into(),
+            instargs: vec![],
+            log_level: 2,
+            tool_version: "record 0.1.0".into(),
+            host: "builder".into(),
+            target: "x86_64-linux".into(),
+            started_ms: 1_709_210_096_789,
+            finished_ms: 1_709_210_097_000,
+        }
+    }
+
+    #[test]
+    fn utc_timestamps() {
+        assert_eq!(fmt_utc_ms(0), "1970-01-01T00:00:00.000Z");
+        // Leap day
+        assert_eq!(fmt_utc_ms(1_709_210_096_789), "2024-02-29T12:34:56.789Z");
+        // Year (and century) rollover
+        assert_eq!(fmt_utc_ms(946_684_799_999), "1999-12-31T23:59:59.999Z");
+        assert_eq!(fmt_utc_ms(946_684_800_000), "2000-01-01T00:00:00.000Z");
+        // 2100 is not a leap year
+        assert_eq!(fmt_utc_ms(4_107_542_399_999), "2100-02-28T23:59:59.999Z");
+        assert_eq!(fmt_utc_ms(4_107_542_400_000), "2100-03-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn provenance_display() {
+        let display = provenance().to_string();
+        assert!(display.starts_with("Command: [\"app.wasm\", \"--flag\"]\nScheme: r3-record []\n"));
+        assert!(display.contains("Host: builder (x86_64-linux)\n"));
+        assert!(
+            display.contains("Recorded: 2024-02-29T12:34:56.789Z -- 2024-02-29T12:34:57.000Z\n")
+        );
+    }
+
+    #[test]
+    fn header_roundtrip() {
+        for encoding in [TraceEncoding::Plain, TraceEncoding::Compressed] {
+            let mut data = TraceData::new("0123abcd", vec![]);
+            data.header.encoding = encoding;
+            data.header.provenance = Some(provenance());
+            let ser = data.serialize().unwrap();
+            assert_eq!(
+                TraceData::deserialize(&ser, Some("0123abcd")).unwrap(),
+                data
+            );
+        }
+        let data = TraceData::new("0123abcd", vec![]);
+        assert_eq!(
+            TraceData::deserialize(&data.serialize().unwrap(), None)
+                .unwrap()
+                .header
+                .provenance,
+            None
+        );
+    }
+
+    #[test]
+    fn unknown_call_id() {
+        assert_eq!(
+            CallID::from_parts(0xFFFFFFFF, [0; 3]).unwrap(),
+            CallID::ScGeneric
+        );
+        for call_id in [7, 0x100] {
+            assert!(matches!(
+                CallID::from_parts(call_id, [0; 3]),
+                Err(R3Error::UnknownCallId(id)) if id == call_id
+            ));
+        }
+    }
+
+    #[test]
+    fn deserialize_errors() {
+        let data = TraceData::new("0123abcd", vec![]);
+        let ser = data.serialize().unwrap();
+        assert!(matches!(
+            TraceData::deserialize(&ser, Some("4567ef")),
+            Err(R3Error::Sha256Mismatch { expected, found }) if expected == "4567ef" && found == "0123abcd"
+        ));
+        assert!(matches!(
+            TraceData::deserialize(&ser[..ser.len() - 1], None),
+            Err(R3Error::Trace(TraceError::Truncated))
+        ));
+        assert!(matches!(
+            TraceData::deserialize(b"\0garbage", None),
+            Err(R3Error::Trace(_))
+        ));
+    }
+}
@@ -0,0 +1,545 @@
+//! Compact encoding of [TraceOp]s for [TraceEncoding::Compressed] traces
+//!
+//! Each op is written as a tag byte followed by its fields as LEB128 varints:
+//! * `tid` is omitted when it repeats the previous op's (flagged in the tag),
+//!   and is otherwise delta-coded
+//! * `access_idx` and `addr` are zigzag delta-coded against the previous op
+//! * all other signed fields are zigzag-coded
+//! * [TraceOp::Output] and [TraceOp::Terminate] records are postcard-encoded
+//!   after their tag
+//!
+//! The encoded chunk is then block-compressed with zstd. Delta state is reset
+//! at every chunk so chunks can be decoded independently.
+use std::io::Read;
+
+use super::*;
+
+/// zstd compression level used for chunks
+const ZSTD_LEVEL: i32 = 9;
+
+/// Tag bits
+const TAG_KIND_MASK: u8 = 0b11;
+const TAG_ACCESS: u8 = 0;
+const TAG_SYNC_ACCESS: u8 = 1;
+const TAG_CALL: u8 = 2;
+const TAG_TERMINATE: u8 = 3;
+const TAG_OUTPUT: u8 = 1 << 4;
+const TAG_DIFFER: u8 = 1 << 2;
+const TAG_SAME_TID: u8 = 1 << 3;
+
+#[inline]
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+#[inline]
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// Cursor over an encoded chunk
+struct ChunkCursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+impl ChunkCursor<'_> {
+    fn byte(&mut self) -> Result<u8, TraceError> {
+        let b = *self.buf.get(self.pos).ok_or(TraceError::Truncated)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64, TraceError> {
+        let mut v: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.byte()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(TraceError::Decode(postcard::Error::DeserializeBadVarint))
+    }
+
+    fn signed(&mut self) -> Result<i64, TraceError> {
+        Ok(unzigzag(self.varint()?))
+    }
+}
+
+/// Running delta state shared by [DeltaEncoder] and [DeltaDecoder]
+#[derive(Default)]
+struct DeltaState {
+    tid: u64,
+    access_idx: u32,
+    addr: i32,
+}
+
+/// Incrementally encodes [TraceOp]s into a chunk
+#[derive(Default)]
+pub(super) struct DeltaEncoder {
+    state: DeltaState,
+}
+impl DeltaEncoder {
+    /// Append the encoding of `op` to `buf`
+    pub fn encode(&mut self, op: &TraceOp, buf: &mut Vec<u8>) {
+        let (kind, tid, access_idx, differ) = match op {
+            TraceOp::Terminate { kind } => {
+                buf.push(TAG_TERMINATE);
+                postcard::to_io(kind, &mut *buf).unwrap();
+                return;
+            }
+            TraceOp::Output { .. } => {
+                buf.push(TAG_OUTPUT);
+                postcard::to_io(op, &mut *buf).unwrap();
+                return;
+            }
+            TraceOp::Access {
+                tid,
+                access_idx,
+                differ,
+                ..
+            } => (TAG_ACCESS, *tid, *access_idx, *differ),
+            TraceOp::SyncAccess {
+                tid,
+                access_idx,
+                differ,
+                ..
+            } => (TAG_SYNC_ACCESS, *tid, *access_idx, *differ),
+            TraceOp::Call {
+                tid, access_idx, ..
+            } => (TAG_CALL, *tid, *access_idx, false),
+        };
+        let mut tag = kind;
+        if differ {
+            tag |= TAG_DIFFER;
+        }
+        if tid == self.state.tid {
+            tag |= TAG_SAME_TID;
+        }
+        buf.push(tag);
+        if tid != self.state.tid {
+            put_varint(buf, zigzag(tid.wrapping_sub(self.state.tid) as i64));
+            self.state.tid = tid;
+        }
+        put_varint(
+            buf,
+            zigzag(access_idx.wrapping_sub(self.state.access_idx) as i32 as i64),
+        );
+        self.state.access_idx = access_idx;
+
+        match op {
+            TraceOp::Access {
+                opcode,
+                addr,
+                size,
+                load_value,
+                expected_value,
+                ..
+            }
+            | TraceOp::SyncAccess {
+                opcode,
+                addr,
+                size,
+                load_value,
+                expected_value,
+                ..
+            } => {
+                put_varint(buf, zigzag(*opcode as i64));
+                put_varint(buf, zigzag(addr.wrapping_sub(self.state.addr) as i64));
+                self.state.addr = *addr;
+                put_varint(buf, *size as u64);
+                put_varint(buf, zigzag(*load_value));
+                put_varint(buf, zigzag(*expected_value));
+            }
+            TraceOp::Call {
+                opcode,
+                func_idx,
+                return_val,
+                call_id,
+                ..
+            } => {
+                put_varint(buf, zigzag(*opcode as i64));
+                put_varint(buf, *func_idx as u64);
+                put_varint(buf, zigzag(*return_val));
+                postcard::to_io(call_id, &mut *buf).unwrap();
+            }
+            TraceOp::Output { .. } | TraceOp::Terminate { .. } => unreachable!(),
+        }
+    }
+}
+
+/// Decodes [TraceOp]s from a chunk written by [DeltaEncoder]
+#[derive(Default)]
+pub(super) struct DeltaDecoder {
+    state: DeltaState,
+}
+impl DeltaDecoder {
+    /// Decode the op at `*pos` in `buf`, advancing `pos` past it
+    pub fn decode(&mut self, buf: &[u8], pos: &mut usize) -> Result<TraceOp, TraceError> {
+        let mut cur = ChunkCursor { buf, pos: *pos };
+        let tag = cur.byte()?;
+        if tag == TAG_TERMINATE {
+            let (kind, rest) = postcard::take_from_bytes(&buf[cur.pos..])?;
+            *pos = buf.len() - rest.len();
+            return Ok(TraceOp::Terminate { kind });
+        }
+        if tag == TAG_OUTPUT {
+            let (op, rest) = postcard::take_from_bytes(&buf[cur.pos..])?;
+            *pos = buf.len() - rest.len();
+            return Ok(op);
+        }
+        if tag & TAG_SAME_TID == 0 {
+            self.state.tid = self.state.tid.wrapping_add(cur.signed()? as u64);
+        }
+        self.state.access_idx = self
+            .state
+            .access_idx
+            .wrapping_add(cur.signed()? as i32 as u32);
+        let (tid, access_idx) = (self.state.tid, self.state.access_idx);
+        let op = match tag & TAG_KIND_MASK {
+            TAG_ACCESS | TAG_SYNC_ACCESS => {
+                let opcode = cur.signed()? as i32;
+                self.state.addr = self.state.addr.wrapping_add(cur.signed()? as i32);
+                let addr = self.state.addr;
+                let size = cur.varint()? as u32;
+                let load_value = cur.signed()?;
+                let expected_value = cur.signed()?;
+                let differ = tag & TAG_DIFFER != 0;
+                if tag & TAG_KIND_MASK == TAG_ACCESS {
+                    TraceOp::Access {
+                        tid,
+                        access_idx,
+                        opcode,
+                        addr,
+                        size,
+                        load_value,
+                        expected_value,
+                        differ,
+                    }
+                } else {
+                    TraceOp::SyncAccess {
+                        tid,
+                        access_idx,
+                        opcode,
+                        addr,
+                        size,
+                        load_value,
+                        expected_value,
+                        differ,
+                    }
+                }
+            }
+            TAG_CALL => {
+                let opcode = cur.signed()? as i32;
+                let func_idx = cur.varint()? as u32;
+                let return_val = cur.signed()?;
+                let (call_id, rest) = postcard::take_from_bytes(&buf[cur.pos..])?;
+                cur.pos = buf.len() - rest.len();
+                TraceOp::Call {
+                    tid,
+                    access_idx,
+                    opcode,
+                    func_idx,
+                    return_val,
+                    call_id,
+                }
+            }
+            _ => return Err(TraceError::Decode(postcard::Error::DeserializeBadEnum)),
+        };
+        *pos = cur.pos;
+        Ok(op)
+    }
+}
+
+/// Block-compress an encoded chunk
+pub(super) fn compress(chunk: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::bulk::compress(chunk, ZSTD_LEVEL)
+}
+
+/// Decompress a chunk produced by [compress]
+///
+/// Fails if the chunk decompresses to more than
+/// [MAX_CHUNK_LEN](super::stream::MAX_CHUNK_LEN) bytes
+pub(super) fn decompress(chunk: &[u8]) -> Result<Vec<u8>, TraceError> {
+    decompress_bounded(chunk, stream::MAX_CHUNK_LEN)
+}
+
+/// Decompress `chunk` into at most `max_len` bytes
+fn decompress_bounded(chunk: &[u8], max_len: usize) -> Result<Vec<u8>, TraceError> {
+    let mut decompressed = Vec::new();
+    zstd::Decoder::new(chunk)
+        .map_err(TraceError::Io)?
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut decompressed)
+        .map_err(TraceError::Io)?;
+    if decompressed.len() > max_len {
+        return Err(TraceError::Oversized(decompressed.len() as u64));
+    }
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode `ops` into a single chunk and decode them back
+    fn roundtrip(ops: &[TraceOp]) -> Vec<TraceOp> {
+        let mut encoder = DeltaEncoder::default();
+        let mut buf = Vec::new();
+        for op in ops {
+            encoder.encode(op, &mut buf);
+        }
+        let mut decoder = DeltaDecoder::default();
+        let mut pos = 0;
+        let decoded = ops
+            .iter()
+            .map(|_| decoder.decode(&buf, &mut pos).unwrap())
+            .collect();
+        assert_eq!(pos, buf.len());
+        decoded
+    }
+
+    fn access(tid: u64, access_idx: u32, addr: i32, value: i64, differ: bool) -> TraceOp {
+        TraceOp::Access {
+            tid,
+            access_idx,
+            opcode: 0x29,
+            addr,
+            size: 8,
+            load_value: value,
+            expected_value: value.wrapping_neg(),
+            differ,
+        }
+    }
+
+    fn call(tid: u64, access_idx: u32, return_val: i64, call_id: CallID) -> TraceOp {
+        TraceOp::Call {
+            tid,
+            access_idx,
+            opcode: 0x10,
+            func_idx: u32::MAX,
+            return_val,
+            call_id,
+        }
+    }
+
+    #[test]
+    fn zigzag_roundtrip() {
+        for (v, zz) in [(0, 0), (-1, 1), (1, 2), (-2, 3), (i64::MAX, u64::MAX - 1)] {
+            assert_eq!(zigzag(v), zz);
+            assert_eq!(unzigzag(zz), v);
+        }
+        assert_eq!(zigzag(i64::MIN), u64::MAX);
+        assert_eq!(unzigzag(u64::MAX), i64::MIN);
+    }
+
+    #[test]
+    fn varint_roundtrip() {
+        let values = [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX];
+        let mut buf = Vec::new();
+        for v in values {
+            put_varint(&mut buf, v);
+        }
+        let mut cur = ChunkCursor { buf: &buf, pos: 0 };
+        for v in values {
+            assert_eq!(cur.varint().unwrap(), v);
+        }
+        assert_eq!(cur.pos, buf.len());
+        assert!(matches!(cur.varint(), Err(TraceError::Truncated)));
+    }
+
+    #[test]
+    fn varint_sizes() {
+        for (v, len) in [(0, 1), (0x7f, 1), (0x80, 2), (u64::MAX, 10)] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, v);
+            assert_eq!(buf.len(), len, "varint {:#x}", v);
+        }
+    }
+
+    #[test]
+    fn varint_overlong() {
+        let buf = [0xff; 11];
+        let mut cur = ChunkCursor { buf: &buf, pos: 0 };
+        assert!(matches!(
+            cur.varint(),
+            Err(TraceError::Decode(postcard::Error::DeserializeBadVarint))
+        ));
+    }
+
+    #[test]
+    fn signed_roundtrip() {
+        let values = [0, -1, 1, -64, 64, i64::MIN, i64::MAX];
+        let mut buf = Vec::new();
+        for v in values {
+            put_varint(&mut buf, zigzag(v));
+        }
+        let mut cur = ChunkCursor { buf: &buf, pos: 0 };
+        for v in values {
+            assert_eq!(cur.signed().unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn roundtrip_all_tags() {
+        let ops = vec![
+            access(0, 10, 1024, 5, true),
+            access(0, 11, 1032, 6, false),
+            TraceOp::SyncAccess {
+                tid: 1,
+                access_idx: 12,
+                opcode: 0xfe48,
+                addr: 64,
+                size: 4,
+                load_value: 1,
+                expected_value: 0,
+                differ: true,
+            },
+            TraceOp::SyncAccess {
+                tid: 1,
+                access_idx: 12,
+                opcode: 0xfe48,
+                addr: 64,
+                size: 4,
+                load_value: 0,
+                expected_value: 0,
+                differ: false,
+            },
+            call(1, 13, -22, CallID::ScUnknown),
+            call(0, 14, 0, CallID::ScMmap { grow: 3 }),
+            call(
+                0,
+                15,
+                12,
+                CallID::ScWritev {
+                    fd: 1,
+                    iov: 2048,
+                    iovcnt: 2,
+                },
+            ),
+            call(
+                0,
+                16,
+                2,
+                CallID::ScThreadSpawn {
+                    fn_ptr: 3,
+                    args_ptr: 4096,
+                },
+            ),
+            call(
+                2,
+                17,
+                0,
+                CallID::ScFutex {
+                    addr: 64,
+                    op: FutexOp::Wait,
+                    val: 1,
+                },
+            ),
+            call(2, 18, 0, CallID::ScThreadExit { status: 0 }),
+            TraceOp::Output {
+                tid: 0,
+                fd: 1,
+                data: b"hello\n".to_vec(),
+            },
+            call(0, 19, 0, CallID::ScProcExit { status: -1 }),
+            call(0, 20, i64::MAX, CallID::ScGeneric),
+            TraceOp::Terminate {
+                kind: Termination::Trap {
+                    tid: 3,
+                    message: String::from("out of bounds memory access"),
+                    access_idx: Some(7),
+                },
+            },
+            TraceOp::Terminate {
+                kind: Termination::Exit { code: 0 },
+            },
+            TraceOp::Terminate {
+                kind: Termination::Signal { signo: 11 },
+            },
+        ];
+        assert_eq!(roundtrip(&ops), ops);
+    }
+
+    #[test]
+    fn roundtrip_boundary_deltas() {
+        let ops = vec![
+            // Negative deltas of every delta-coded field
+            access(u64::MAX, u32::MAX, i32::MAX, i64::MAX, true),
+            access(0, 0, i32::MIN, i64::MIN, true),
+            access(u64::MAX, u32::MAX, i32::MAX, -1, false),
+            access(5, 3, -8, 0, true),
+            access(2, 1, -16, 1, true),
+            call(1, 0, i64::MIN, CallID::ScGeneric),
+            call(u64::MAX / 2, u32::MAX / 2, i64::MAX, CallID::ScGeneric),
+        ];
+        assert_eq!(roundtrip(&ops), ops);
+    }
+
+    #[test]
+    fn repeated_tid_is_omitted() {
+        let mut encoder = DeltaEncoder::default();
+        let (mut first, mut second) = (Vec::new(), Vec::new());
+        encoder.encode(&access(7, 1, 8, 0, true), &mut first);
+        encoder.encode(&access(7, 2, 16, 0, true), &mut second);
+        assert_eq!(first[0] & TAG_SAME_TID, 0);
+        assert_ne!(second[0] & TAG_SAME_TID, 0);
+        assert_eq!(first.len(), second.len() + 1);
+    }
+
+    #[test]
+    fn decode_bad_tag() {
+        // Only a bare TAG_TERMINATE is a terminate record; flags make it invalid
+        let buf = [TAG_SAME_TID | TAG_TERMINATE | TAG_DIFFER, 0];
+        assert!(DeltaDecoder::default().decode(&buf, &mut 0).is_err());
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let mut buf = Vec::new();
+        DeltaEncoder::default().encode(&access(1, 1000, 4096, i64::MAX, true), &mut buf);
+        for len in 0..buf.len() {
+            assert!(
+                DeltaDecoder::default().decode(&buf[..len], &mut 0).is_err(),
+                "decoded from {} of {} bytes",
+                len,
+                buf.len()
+            );
+        }
+    }
+
+    #[test]
+    fn compress_roundtrip() {
+        let mut encoder = DeltaEncoder::default();
+        let mut chunk = Vec::new();
+        for i in 0..1000 {
+            encoder.encode(
+                &access(i % 3, i as u32, i as i32 * 4, i as i64, true),
+                &mut chunk,
+            );
+        }
+        let compressed = compress(&chunk).unwrap();
+        assert!(compressed.len() < chunk.len());
+        assert_eq!(decompress(&compressed).unwrap(), chunk);
+        assert!(decompress(&chunk).is_err());
+    }
+
+    #[test]
+    fn decompress_bound() {
+        let chunk = vec![0u8; 4096];
+        let compressed = compress(&chunk).unwrap();
+        assert_eq!(decompress_bounded(&compressed, 4096).unwrap(), chunk);
+        assert!(matches!(
+            decompress_bounded(&compressed, 4095),
+            Err(TraceError::Oversized(4096))
+        ));
+    }
+}
@@ -0,0 +1,251 @@
+//! Extraction of sub-traces, e.g. to produce prefix replays
+//!
+//! A slice no longer describes a complete execution, so [TraceOp::Output] and
+//! [TraceOp::Terminate] records are never part of it
+use std::collections::BTreeSet;
+use std::ops::Range;
+
+use super::*;
+
+/// Selection of ops to extract from a trace
+///
+/// All given criteria must hold for an op to be selected
+#[derive(Debug, Clone, Default)]
+pub struct TraceSlice {
+    /// Indices of ops (in the original trace) to select
+    pub range: Option<Range<usize>>,
+    /// Threads whose ops are selected
+    pub tids: Option<BTreeSet<u64>>,
+    /// End the slice at the Nth (1-based) selected call with the given
+    /// [CallID::name], inclusive
+    pub until_call: Option<(String, usize)>,
+}
+impl TraceSlice {
+    /// Lazily select ops from `trace`, stopping as early as possible
+    pub fn apply<I>(&self, trace: I) -> SliceIter<'_, I::IntoIter>
+    where
+        I: IntoIterator<Item = Result<TraceOp, TraceError>>,
+    {
+        SliceIter {
+            slice: self,
+            inner: trace.into_iter(),
+            pos: 0,
+            calls_seen: 0,
+            done: false,
+        }
+    }
+
+    /// Whether `op` (at index `pos` of the original trace) is selected,
+    /// ignoring [until_call](Self::until_call)
+    fn selects(&self, pos: usize, op: &TraceOp) -> bool {
+        self.range.as_ref().is_none_or(|range| range.contains(&pos))
+            && match op.tid() {
+                Some(tid) => self.tids.as_ref().is_none_or(|tids| tids.contains(&tid)),
+                None => false,
+            }
+            && !matches!(op, TraceOp::Output { .. })
+    }
+}
+
+/// Iterator over the ops selected by a [TraceSlice]
+pub struct SliceIter<'a, I> {
+    slice: &'a TraceSlice,
+    inner: I,
+    pos: usize,
+    calls_seen: usize,
+    done: bool,
+}
+impl<I> Iterator for SliceIter<'_, I>
+where
+    I: Iterator<Item = Result<TraceOp, TraceError>>,
+{
+    type Item = Result<TraceOp, TraceError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if self
+                .slice
+                .range
+                .as_ref()
+                .is_some_and(|range| self.pos >= range.end)
+            {
+                self.done = true;
+                break;
+            }
+            let op = match self.inner.next()? {
+                Ok(op) => op,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            let pos = self.pos;
+            self.pos += 1;
+            if !self.slice.selects(pos, &op) {
+                continue;
+            }
+            if let (Some((name, n)), TraceOp::Call { call_id, .. }) = (&self.slice.until_call, &op)
+            {
+                if call_id.name() == name {
+                    self.calls_seen += 1;
+                    self.done = self.calls_seen >= *n;
+                }
+            }
+            return Some(Ok(op));
+        }
+        None
+    }
+}
+
+impl TraceData {
+    /// Extract the ops selected by `slice` into a new trace for the same
+    /// module
+    pub fn slice(&self, slice: &TraceSlice) -> Result<TraceData, R3Error> {
+        Ok(TraceData {
+            header: self.header.clone(),
+            trace: slice
+                .apply(self.trace.iter().cloned().map(Ok))
+                .collect::<Result<_, _>>()?,
+            truncated: self.truncated,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(tid: u64, access_idx: u32, call_id: CallID) -> TraceOp {
+        TraceOp::Call {
+            tid,
+            access_idx,
+            opcode: 0x10,
+            func_idx: 0,
+            return_val: 0,
+            call_id,
+        }
+    }
+
+    fn access(tid: u64, access_idx: u32) -> TraceOp {
+        TraceOp::Access {
+            tid,
+            access_idx,
+            opcode: 0x28,
+            addr: 0,
+            size: 4,
+            load_value: 0,
+            expected_value: 0,
+            differ: false,
+        }
+    }
+
+    fn trace() -> TraceData {
+        let mut data = TraceData::new(
+            "sha",
+            vec![
+                access(1, 0),
+                call(1, 10, CallID::ScGeneric),
+                access(2, 1),
+                call(2, 11, CallID::ScGeneric),
+                TraceOp::Output {
+                    tid: 1,
+                    fd: 1,
+                    data: b"hi".to_vec(),
+                },
+                call(1, 12, CallID::ScGeneric),
+                access(1, 2),
+                TraceOp::Terminate {
+                    kind: Termination::Exit { code: 0 },
+                },
+            ],
+        );
+        data.truncated = true;
+        data
+    }
+
+    /// Indices into [trace] (whose ops are distinct) of the ops selected by
+    /// `slice`
+    fn selected(slice: TraceSlice) -> Vec<usize> {
+        let (data, sliced) = (trace(), trace().slice(&slice).unwrap());
+        assert_eq!(sliced.header, data.header);
+        assert!(sliced.truncated);
+        let mut ops = data.trace.iter().enumerate();
+        sliced
+            .trace
+            .iter()
+            .map(|op| ops.find(|(_, orig)| *orig == op).unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn whole_trace() {
+        // Outputs and the termination are dropped
+        assert_eq!(selected(TraceSlice::default()), [0, 1, 2, 3, 5, 6]);
+    }
+
+    #[test]
+    fn range_and_tids() {
+        let range = |range: Range<usize>| TraceSlice {
+            range: Some(range),
+            ..Default::default()
+        };
+        assert_eq!(selected(range(1..4)), [1, 2, 3]);
+        assert_eq!(selected(range(4..usize::MAX)), [5, 6]);
+        assert_eq!(selected(range(8..9)), []);
+
+        let tids = TraceSlice {
+            tids: Some(BTreeSet::from([2])),
+            ..Default::default()
+        };
+        assert_eq!(selected(tids.clone()), [2, 3]);
+        assert_eq!(
+            selected(TraceSlice {
+                range: Some(3..7),
+                ..tids
+            }),
+            [3]
+        );
+    }
+
+    #[test]
+    fn until_call() {
+        let until = |name: &str, n: usize| TraceSlice {
+            until_call: Some((name.to_string(), n)),
+            ..Default::default()
+        };
+        assert_eq!(selected(until("ScGeneric", 1)), [0, 1]);
+        assert_eq!(selected(until("ScGeneric", 2)), [0, 1, 2, 3]);
+        // Fewer occurrences than requested keep the rest of the trace
+        assert_eq!(selected(until("ScGeneric", 4)), [0, 1, 2, 3, 5, 6]);
+        assert_eq!(selected(until("ScFutex", 1)), [0, 1, 2, 3, 5, 6]);
+        // Only calls of selected threads count
+        let slice = TraceSlice {
+            tids: Some(BTreeSet::from([1])),
+            ..until("ScGeneric", 2)
+        };
+        assert_eq!(selected(slice), [0, 1, 5]);
+    }
+
+    #[test]
+    fn stops_early() {
+        let ops = trace().trace;
+        let pulled = std::cell::Cell::new(0);
+        let trace = ops.into_iter().map(|op| {
+            pulled.set(pulled.get() + 1);
+            Ok(op)
+        });
+        let slice = TraceSlice {
+            range: Some(0..3),
+            ..Default::default()
+        };
+        assert_eq!(slice.apply(trace).count(), 3);
+        assert_eq!(pulled.get(), 3);
+
+        let trace = [
+            Ok(access(1, 0)),
+            Err(TraceError::Truncated),
+            Ok(access(1, 1)),
+        ];
+        let sliced: Vec<_> = TraceSlice::default().apply(trace).collect();
+        assert!(matches!(sliced[..], [Ok(_), Err(TraceError::Truncated)]));
+    }
+}
@@ -0,0 +1,620 @@
+//! Chunked on-disk trace format with streaming reader/writer
+//!
+//! ### Format (version 7)
+//! ```text
+//!  | TRACE_MAGIC (4B) | version (u32 LE) | header_len (u32 LE) | TraceHeader |
+//!  | chunk | chunk | ... | end-of-trace |
+//! ```
+//! where each chunk is
+//! ```text
+//!  | len (u32 LE) | count (u32 LE) | `count` encoded TraceOps (len B) |
+//! ```
+//! and the end-of-trace marker is an empty chunk whose `count` holds
+//! [end flags](END_TRUNCATED)
+//! Ops are postcard-encoded for [TraceEncoding::Plain] traces, or delta-coded
+//! and zstd-compressed per chunk for [TraceEncoding::Compressed] traces (see
+//! `codec`). Only a single chunk is held in memory at a time, so traces can be
+//! processed in memory bounded by [TRACE_CHUNK_SIZE] rather than the size of
+//! the trace. Headers and (decompressed) chunks larger than [MAX_CHUNK_LEN]
+//! are rejected as corrupt, rather than allocated.
+//!
+//! Older formats are still readable:
+//! * Version 0: bare postcard `(sha256, Vec<TraceOp>)` without magic
+//! * Version 1: `| TRACE_MAGIC | 1 (u32 LE) | TraceHeader | Vec<TraceOp> |`
+//! * Version 2: Same as version 3, but the header has no encoding (always
+//!   plain)
+//! * Version 3: Same as version 4, but end flags are always 0
+//! * Version 4: Same as version 5, but without a [TraceOp::Terminate] record
+//! * Version 5: Same as version 6, but without [TraceOp::Output] records
+//! * Version 6: Same as version 7, but the header has no provenance
+//!
+//! Versions 0 and 1 are whole-blob formats, and are decoded in full when
+//! opened.
+use log::info;
+use postcard;
+use std::io::{self, Read, Write};
+use std::vec;
+
+use super::codec::{self, DeltaDecoder, DeltaEncoder};
+use super::*;
+
+/// Size (in bytes, before compression) after which a chunk of ops is flushed
+/// by [TraceWriter]
+pub const TRACE_CHUNK_SIZE: usize = 1 << 16;
+
+/// Maximum size (in bytes) of a header or chunk, before or after
+/// decompression
+///
+/// A chunk exceeds [TRACE_CHUNK_SIZE] by at most its last op, which only
+/// [TraceOp::Output] records can make large
+pub const MAX_CHUNK_LEN: usize = 1 << 28;
+
+/// End flag: recording ended before all ops could be saved
+pub const END_TRUNCATED: u32 = 1 << 0;
+
+/// Read a little-endian [u32] from `reader`
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Read a little-endian [u32] length from `reader`, bounded by
+/// [MAX_CHUNK_LEN]
+fn read_len<R: Read>(reader: &mut R) -> Result<usize, TraceError> {
+    let len = read_u32(reader)? as usize;
+    if len > MAX_CHUNK_LEN {
+        return Err(TraceError::Oversized(len as u64));
+    }
+    Ok(len)
+}
+
+/// Streaming writer that emits [TraceOp]s in chunks to `W`
+///
+/// **NOTE**: Remember to call [finish](Self::finish); traces without an
+/// end-of-trace marker are considered truncated
+///
+/// ### Usage
+/// ```rust,no_run
+/// # use common::trace::{TraceHeader, TraceOp, TraceWriter};
+/// # use std::fs::File;
+/// # use std::io::BufWriter;
+/// # fn main() -> std::io::Result<()> {
+/// # let (file, header, ops) = (File::create("trace.r3")?, TraceHeader::new(""), Vec::<TraceOp>::new());
+/// let mut writer = TraceWriter::new(BufWriter::new(file), &header)?;
+/// for op in ops {
+///     writer.push(&op)?;
+/// }
+/// writer.finish()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct TraceWriter<W: Write> {
+    inner: W,
+    chunk: Vec<u8>,
+    count: u32,
+    /// Present only for [TraceEncoding::Compressed] traces
+    encoder: Option<DeltaEncoder>,
+    end_flags: u32,
+}
+impl<W: Write> TraceWriter<W> {
+    /// Create a writer, emitting the magic number, version and `header`
+    pub fn new(mut inner: W, header: &TraceHeader) -> io::Result<Self> {
+        let header_ser = postcard::to_stdvec(header)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if header_ser.len() > MAX_CHUNK_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                TraceError::Oversized(header_ser.len() as u64),
+            ));
+        }
+        inner.write_all(&TRACE_MAGIC)?;
+        inner.write_all(&TRACE_VERSION.to_le_bytes())?;
+        inner.write_all(&(header_ser.len() as u32).to_le_bytes())?;
+        inner.write_all(&header_ser)?;
+        Ok(TraceWriter {
+            inner,
+            chunk: Vec::with_capacity(TRACE_CHUNK_SIZE),
+            count: 0,
+            encoder: match header.encoding {
+                TraceEncoding::Plain => None,
+                TraceEncoding::Compressed => Some(DeltaEncoder::default()),
+            },
+            end_flags: 0,
+        })
+    }
+
+    /// Append a single [TraceOp] to the trace
+    pub fn push(&mut self, op: &TraceOp) -> io::Result<()> {
+        match self.encoder {
+            Some(ref mut encoder) => encoder.encode(op, &mut self.chunk),
+            None => {
+                postcard::to_io(op, &mut self.chunk)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+        }
+        self.count += 1;
+        if self.chunk.len() > MAX_CHUNK_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                TraceError::Oversized(self.chunk.len() as u64),
+            ));
+        }
+        if self.chunk.len() >= TRACE_CHUNK_SIZE {
+            self.flush_chunk()?;
+        }
+        Ok(())
+    }
+
+    /// Write out the currently buffered chunk, if any
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if self.count == 0 {
+            return Ok(());
+        }
+        let compressed;
+        let chunk = match self.encoder {
+            Some(ref mut encoder) => {
+                // Delta state restarts with every chunk
+                *encoder = DeltaEncoder::default();
+                compressed = codec::compress(&self.chunk)?;
+                &compressed
+            }
+            None => &self.chunk,
+        };
+        self.inner.write_all(&(chunk.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&self.count.to_le_bytes())?;
+        self.inner.write_all(chunk)?;
+        self.chunk.clear();
+        self.count = 0;
+        Ok(())
+    }
+
+    /// Mark the trace as truncated, i.e. missing ops that occurred during
+    /// recording
+    pub fn set_truncated(&mut self) {
+        self.end_flags |= END_TRUNCATED;
+    }
+
+    /// Flush remaining ops and the end-of-trace marker, returning the
+    /// underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_chunk()?;
+        self.inner.write_all(&0u32.to_le_bytes())?;
+        self.inner.write_all(&self.end_flags.to_le_bytes())?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+/// Chunk-by-chunk decoding state for chunked (version 2 and later) traces
+struct ChunkedSource<R: Read> {
+    inner: R,
+    chunk: Vec<u8>,
+    pos: usize,
+    remaining: u32,
+    done: bool,
+    end_flags: u32,
+    /// Present only for [TraceEncoding::Compressed] traces
+    decoder: Option<DeltaDecoder>,
+}
+impl<R: Read> ChunkedSource<R> {
+    /// Load the next chunk from the underlying reader
+    fn read_chunk(&mut self) -> Result<(), TraceError> {
+        let len = read_len(&mut self.inner)?;
+        let count = read_u32(&mut self.inner)?;
+        if len == 0 {
+            self.done = true;
+            self.end_flags = count;
+            return Ok(());
+        }
+        self.chunk.resize(len, 0);
+        self.inner.read_exact(&mut self.chunk)?;
+        if let Some(ref mut decoder) = self.decoder {
+            *decoder = DeltaDecoder::default();
+            self.chunk = codec::decompress(&self.chunk)?;
+        }
+        self.pos = 0;
+        self.remaining = count;
+        Ok(())
+    }
+
+    fn next_op(&mut self) -> Result<Option<TraceOp>, TraceError> {
+        while self.remaining == 0 {
+            if self.done {
+                return Ok(None);
+            }
+            self.read_chunk()?;
+        }
+        let op = match self.decoder {
+            Some(ref mut decoder) => decoder.decode(&self.chunk, &mut self.pos)?,
+            None => {
+                let (op, rest) = postcard::take_from_bytes(&self.chunk[self.pos..])?;
+                self.pos = self.chunk.len() - rest.len();
+                op
+            }
+        };
+        self.remaining -= 1;
+        Ok(Some(op))
+    }
+}
+
+enum Source<R: Read> {
+    /// Whole-trace formats (versions 0 and 1), decoded upfront
+    Buffered(vec::IntoIter<TraceOp>),
+    Chunked(ChunkedSource<R>),
+}
+
+/// Streaming iterator over the [TraceOp]s of a trace read from `R`
+///
+/// Yields an error and stops if the trace is malformed or truncated
+///
+/// ### Usage
+/// ```rust,no_run
+/// # use common::trace::TraceReader;
+/// # use std::fs::File;
+/// # use std::io::BufReader;
+/// # fn main() -> Result<(), common::R3Error> {
+/// # let (tracefile, digest) = ("trace.r3", "");
+/// let reader = TraceReader::new(BufReader::new(File::open(tracefile)?))?;
+/// reader.header().check_sha256(Some(digest))?;
+/// for op in reader {
+///     let op = op?;
+///     // ...
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct TraceReader<R: Read> {
+    header: TraceHeader,
+    version: u32,
+    source: Source<R>,
+}
+impl<R: Read> TraceReader<R> {
+    /// Open a trace, reading its header and upgrading older format versions
+    pub fn new(mut inner: R) -> Result<Self, TraceError> {
+        let mut magic = [0u8; 4];
+        inner.read_exact(&mut magic)?;
+        if magic != TRACE_MAGIC {
+            let mut ser = magic.to_vec();
+            inner.read_to_end(&mut ser)?;
+            info!(
+                "Upgrading headerless (version 0) trace to version {}",
+                TRACE_VERSION
+            );
+            let (header, trace) = v0::upgrade(postcard::from_bytes(&ser)?);
+            return Ok(TraceReader {
+                header,
+                version: 0,
+                source: Source::Buffered(trace.into_iter()),
+            });
+        }
+        let version = read_u32(&mut inner)?;
+        let (header, source) = match version {
+            1 => {
+                let mut ser = Vec::new();
+                inner.read_to_end(&mut ser)?;
+                let (header, body) = postcard::take_from_bytes(&ser)?;
+                let trace: Vec<TraceOp> = postcard::from_bytes(body)?;
+                (v2::upgrade(header), Source::Buffered(trace.into_iter()))
+            }
+            2..=TRACE_VERSION => {
+                let mut header_ser = vec![0u8; read_len(&mut inner)?];
+                inner.read_exact(&mut header_ser)?;
+                let header: TraceHeader = match version {
+                    2 => v2::upgrade(postcard::from_bytes(&header_ser)?),
+                    3..=6 => v6::upgrade(postcard::from_bytes(&header_ser)?),
+                    _ => postcard::from_bytes(&header_ser)?,
+                };
+                let decoder = match header.encoding {
+                    TraceEncoding::Plain => None,
+                    TraceEncoding::Compressed => Some(DeltaDecoder::default()),
+                };
+                (
+                    header,
+                    Source::Chunked(ChunkedSource {
+                        inner,
+                        chunk: Vec::new(),
+                        pos: 0,
+                        remaining: 0,
+                        done: false,
+                        end_flags: 0,
+                        decoder,
+                    }),
+                )
+            }
+            v => return Err(TraceError::UnsupportedVersion(v)),
+        };
+        Ok(TraceReader {
+            header,
+            version,
+            source,
+        })
+    }
+
+    /// Header of the trace being read
+    pub fn header(&self) -> &TraceHeader {
+        &self.header
+    }
+
+    /// Format version the trace was written with
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Whether the trace is marked as truncated during recording
+    ///
+    /// Only meaningful once all ops have been read
+    pub fn is_truncated(&self) -> bool {
+        match &self.source {
+            Source::Buffered(_) => false,
+            Source::Chunked(chunked) => chunked.end_flags & END_TRUNCATED != 0,
+        }
+    }
+}
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = Result<TraceOp, TraceError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.source {
+            Source::Buffered(ops) => ops.next().map(Ok),
+            Source::Chunked(chunked) => {
+                let op = chunked.next_op();
+                if op.is_err() {
+                    // Stop iterating after the first error
+                    chunked.remaining = 0;
+                    chunked.done = true;
+                }
+                op.transpose()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256: &str = "0123abcd";
+
+    fn ops(n: usize) -> Vec<TraceOp> {
+        (0..n)
+            .map(|i| match i % 3 {
+                0 => TraceOp::Access {
+                    tid: (i % 4) as u64,
+                    access_idx: i as u32,
+                    opcode: 0x28,
+                    addr: (i * 8) as i32,
+                    size: 4,
+                    load_value: i as i64,
+                    expected_value: -(i as i64),
+                    differ: i % 2 == 0,
+                },
+                1 => TraceOp::Call {
+                    tid: (i % 4) as u64,
+                    access_idx: i as u32,
+                    opcode: 0x10,
+                    func_idx: 3,
+                    return_val: i as i64 - 100,
+                    call_id: CallID::ScWritev {
+                        fd: 1,
+                        iov: 64,
+                        iovcnt: 2,
+                    },
+                },
+                _ => TraceOp::Output {
+                    tid: 0,
+                    fd: 1,
+                    data: vec![i as u8; i % 7],
+                },
+            })
+            .chain([TraceOp::Terminate {
+                kind: Termination::Exit { code: 3 },
+            }])
+            .collect()
+    }
+
+    fn write(header: &TraceHeader, ops: &[TraceOp], truncated: bool) -> Vec<u8> {
+        let mut writer = TraceWriter::new(Vec::new(), header).unwrap();
+        for op in ops {
+            writer.push(op).unwrap();
+        }
+        if truncated {
+            writer.set_truncated();
+        }
+        writer.finish().unwrap()
+    }
+
+    /// Read all ops, returning them with the reader
+    fn read(ser: &[u8]) -> (TraceReader<&[u8]>, Vec<TraceOp>) {
+        let mut reader = TraceReader::new(ser).unwrap();
+        let ops = reader.by_ref().collect::<Result<_, _>>().unwrap();
+        (reader, ops)
+    }
+
+    /// Chunked trace of `version` with a raw `header`, holding `ops` in a
+    /// single plain chunk
+    fn chunked<H: Serialize>(version: u32, header: &H, ops: &[TraceOp]) -> Vec<u8> {
+        let header_ser = postcard::to_stdvec(header).unwrap();
+        let mut chunk = Vec::new();
+        for op in ops {
+            postcard::to_io(op, &mut chunk).unwrap();
+        }
+        let mut ser = TRACE_MAGIC.to_vec();
+        ser.extend_from_slice(&version.to_le_bytes());
+        ser.extend_from_slice(&(header_ser.len() as u32).to_le_bytes());
+        ser.extend_from_slice(&header_ser);
+        ser.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        ser.extend_from_slice(&(ops.len() as u32).to_le_bytes());
+        ser.extend_from_slice(&chunk);
+        ser.extend_from_slice(&[0; 8]);
+        ser
+    }
+
+    #[derive(Serialize)]
+    struct OldHeader {
+        sha256: String,
+    }
+
+    #[derive(Serialize)]
+    struct EncodedHeader {
+        sha256: String,
+        encoding: TraceEncoding,
+    }
+
+    #[test]
+    fn roundtrip_multiple_chunks() {
+        let ops = ops(20_000);
+        for encoding in [TraceEncoding::Plain, TraceEncoding::Compressed] {
+            let header = TraceHeader {
+                encoding,
+                ..TraceHeader::new(SHA256)
+            };
+            let ser = write(&header, &ops, false);
+            let (reader, read_ops) = read(&ser);
+            assert_eq!(reader.version(), TRACE_VERSION);
+            assert_eq!(reader.header(), &header);
+            assert_eq!(read_ops, ops);
+            assert!(!reader.is_truncated());
+        }
+        let ser = write(&TraceHeader::new(SHA256), &ops, false);
+        assert!(ser.len() > 2 * TRACE_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn roundtrip_empty() {
+        let ser = write(&TraceHeader::new(SHA256), &[], false);
+        let (reader, read_ops) = read(&ser);
+        assert!(read_ops.is_empty());
+        assert!(!reader.is_truncated());
+    }
+
+    #[test]
+    fn end_marker_flags() {
+        let ser = write(&TraceHeader::new(SHA256), &ops(10), true);
+        let (reader, _) = read(&ser);
+        assert!(reader.is_truncated());
+        assert_eq!(
+            u32::from_le_bytes(ser[ser.len() - 4..].try_into().unwrap()),
+            END_TRUNCATED
+        );
+    }
+
+    #[test]
+    fn missing_end_marker() {
+        let ops = ops(10);
+        let ser = write(&TraceHeader::new(SHA256), &ops, false);
+        let mut reader = TraceReader::new(&ser[..ser.len() - 8]).unwrap();
+        for op in &ops {
+            assert_eq!(&reader.next().unwrap().unwrap(), op);
+        }
+        assert!(matches!(reader.next(), Some(Err(TraceError::Truncated))));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn truncated_chunk() {
+        let ser = write(&TraceHeader::new(SHA256), &ops(10), false);
+        let mut reader = TraceReader::new(&ser[..ser.len() - 20]).unwrap();
+        assert!(matches!(reader.next(), Some(Err(TraceError::Truncated))));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn oversized_chunk() {
+        let mut ser = write(&TraceHeader::new(SHA256), &[], false);
+        let end = ser.len() - 8;
+        ser[end..end + 4].copy_from_slice(&(MAX_CHUNK_LEN as u32 + 1).to_le_bytes());
+        let mut reader = TraceReader::new(&ser[..]).unwrap();
+        assert!(matches!(
+            reader.next(),
+            Some(Err(TraceError::Oversized(len))) if len == MAX_CHUNK_LEN as u64 + 1
+        ));
+    }
+
+    #[test]
+    fn oversized_header() {
+        let mut ser = TRACE_MAGIC.to_vec();
+        ser.extend_from_slice(&TRACE_VERSION.to_le_bytes());
+        ser.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert!(matches!(
+            TraceReader::new(&ser[..]),
+            Err(TraceError::Oversized(_))
+        ));
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let ser = chunked(TRACE_VERSION + 1, &TraceHeader::new(SHA256), &[]);
+        assert!(matches!(
+            TraceReader::new(&ser[..]),
+            Err(TraceError::UnsupportedVersion(v)) if v == TRACE_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn upgrade_v0() {
+        #[derive(Serialize)]
+        struct V0 {
+            sha256: String,
+            trace: Vec<TraceOp>,
+        }
+        let ops = ops(30);
+        let ser = postcard::to_stdvec(&V0 {
+            sha256: SHA256.to_string(),
+            trace: ops.clone(),
+        })
+        .unwrap();
+        let (reader, read_ops) = read(&ser);
+        assert_eq!(reader.version(), 0);
+        assert_eq!(reader.header(), &TraceHeader::new(SHA256));
+        assert_eq!(read_ops, ops);
+        assert!(!reader.is_truncated());
+    }
+
+    #[test]
+    fn upgrade_v1() {
+        let ops = ops(30);
+        let mut ser = TRACE_MAGIC.to_vec();
+        ser.extend_from_slice(&1u32.to_le_bytes());
+        postcard::to_io(
+            &OldHeader {
+                sha256: SHA256.to_string(),
+            },
+            &mut ser,
+        )
+        .unwrap();
+        postcard::to_io(&ops, &mut ser).unwrap();
+        let (reader, read_ops) = read(&ser);
+        assert_eq!(reader.version(), 1);
+        assert_eq!(reader.header(), &TraceHeader::new(SHA256));
+        assert_eq!(read_ops, ops);
+    }
+
+    #[test]
+    fn upgrade_v2() {
+        let ops = ops(30);
+        let header = OldHeader {
+            sha256: SHA256.to_string(),
+        };
+        let ser = chunked(2, &header, &ops);
+        let (reader, read_ops) = read(&ser);
+        assert_eq!(reader.version(), 2);
+        assert_eq!(reader.header(), &TraceHeader::new(SHA256));
+        assert_eq!(read_ops, ops);
+    }
+
+    #[test]
+    fn upgrade_v3_to_v6() {
+        let ops = ops(30);
+        for version in 3..=6 {
+            let header = EncodedHeader {
+                sha256: SHA256.to_string(),
+                encoding: TraceEncoding::Plain,
+            };
+            let ser = chunked(version, &header, &ops);
+            let (reader, read_ops) = read(&ser);
+            assert_eq!(reader.version(), version);
+            assert_eq!(reader.header(), &TraceHeader::new(SHA256));
+            assert_eq!(read_ops, ops);
+        }
+    }
+}
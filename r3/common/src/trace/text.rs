@@ -0,0 +1,618 @@
+//! Textual trace syntax, as emitted by `deserialize`
+//!
+//! Each line holds either a [TraceOp] in its [Display](fmt::Display) form, a
+//! directive, a `#` comment, or nothing. Directives describe the header:
+//! ```text
+//! @sha256 <digest>
+//! @encoding <Plain|Compressed>
+//! @truncated
+//! ```
+//! Whitespace is insignificant, and all numbers may be written either in
+//! decimal or in hex (`0x` prefixed), so traces can also be written by hand:
+//! ```text
+//! @sha256 1f2e...
+//! Call [1::10 | 0x10] for [ScMmap { grow: 1 } | 3] with Return [0x1]
+//! Access [1::12 | 0x28] for Addr [1024::4] with Read [0x7] ==/== [0x0]
+//! Terminate [Exit with code 0]
+//! ```
+//! Provenance is not part of the syntax, so assembled traces have none.
+//! `UCSyAccess` ops omit their expected value, which is the loaded value for
+//! accesses that do not differ
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use super::*;
+
+/// Error in a textual trace
+#[derive(Debug)]
+pub struct TextError {
+    /// 1-based line number of the error
+    pub line: usize,
+    pub msg: String,
+}
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.msg)
+    }
+}
+impl Error for TextError {}
+
+/// Cursor over the remainder of a line being parsed
+struct Cursor<'a>(&'a str);
+impl<'a> Cursor<'a> {
+    /// Consume the literal `lit`, ignoring leading whitespace
+    fn expect(&mut self, lit: &str) -> Result<(), String> {
+        self.0 = self
+            .0
+            .trim_start()
+            .strip_prefix(lit)
+            .ok_or_else(|| format!("Expected \"{}\" at \"{}\"", lit, self.0.trim()))?;
+        Ok(())
+    }
+
+    /// Consume and return everything up to `delim`, as well as `delim`
+    fn until(&mut self, delim: &str) -> Result<&'a str, String> {
+        let (head, rest) = self
+            .0
+            .split_once(delim)
+            .ok_or_else(|| format!("Expected \"{}\" in \"{}\"", delim, self.0.trim()))?;
+        self.0 = rest;
+        Ok(head.trim())
+    }
+
+    /// Consume a number up to `delim`
+    fn num_until(&mut self, delim: &str) -> Result<u64, String> {
+        parse_num(self.until(delim)?)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.trim().is_empty()
+    }
+
+    fn finish(&self) -> Result<(), String> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Unexpected \"{}\"", self.0.trim()))
+        }
+    }
+}
+
+/// Parse a decimal or `0x`-prefixed hex number into its 64-bit pattern, to
+/// be cast into the field's type
+fn parse_num(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let res = if let Some(hex) = s.strip_prefix("0x").or(s.strip_prefix("0X")) {
+        u64::from_str_radix(hex, 16).ok()
+    } else if s.starts_with('-') {
+        s.parse::<i64>().ok().map(|v| v as u64)
+    } else {
+        s.parse::<u64>().ok()
+    };
+    res.ok_or_else(|| format!("Invalid number \"{}\"", s))
+}
+
+/// Reverse of [escape_ascii](<[u8]>::escape_ascii)
+fn unescape_ascii(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+        bytes.push(match iter.next() {
+            Some(b't') => b'\t',
+            Some(b'r') => b'\r',
+            Some(b'n') => b'\n',
+            Some(b'x') => {
+                let hex = [iter.next(), iter.next()]
+                    .into_iter()
+                    .collect::<Option<Vec<u8>>>()
+                    .and_then(|hex| String::from_utf8(hex).ok())
+                    .ok_or("Incomplete \\x escape")?;
+                u8::from_str_radix(&hex, 16).map_err(|_| format!("Invalid escape \\x{}", hex))?
+            }
+            Some(c @ (b'\\' | b'\'' | b'"')) => c,
+            c => return Err(format!("Invalid escape {:?}", c.map(char::from))),
+        });
+    }
+    Ok(bytes)
+}
+
+impl FromStr for FutexOp {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "Wait" => Ok(FutexOp::Wait),
+            "Wake" => Ok(FutexOp::Wake),
+            "Unknown" => Ok(FutexOp::Unknown),
+            _ => Err(format!("Invalid futex op \"{}\"", s)),
+        }
+    }
+}
+
+/// Parses the [Debug] form of a [CallID], e.g. `ScWritev { fd: 1, iov: 1024,
+/// iovcnt: 2 }`
+impl FromStr for CallID {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let (name, fields) = match s.trim().split_once('{') {
+            Some((name, fields)) => (
+                name.trim(),
+                fields
+                    .trim()
+                    .strip_suffix('}')
+                    .ok_or_else(|| format!("Unterminated call ID \"{}\"", s))?,
+            ),
+            None => (s.trim(), ""),
+        };
+        let mut args: BTreeMap<&str, &str> = BTreeMap::new();
+        for field in fields.split(',').filter(|f| !f.trim().is_empty()) {
+            let (key, val) = field
+                .split_once(':')
+                .ok_or_else(|| format!("Invalid call ID field \"{}\"", field))?;
+            args.insert(key.trim(), val.trim());
+        }
+        let mut arg = |key: &str| -> Result<&str, String> {
+            args.remove(key)
+                .ok_or_else(|| format!("Missing field \"{}\" for {}", key, name))
+        };
+        let call_id = match name {
+            "ScUnknown" => CallID::ScUnknown,
+            "ScMmap" => CallID::ScMmap {
+                grow: parse_num(arg("grow")?)? as u32,
+            },
+            "ScWritev" => CallID::ScWritev {
+                fd: parse_num(arg("fd")?)? as i32,
+                iov: parse_num(arg("iov")?)? as i32,
+                iovcnt: parse_num(arg("iovcnt")?)? as u32,
+            },
+            "ScThreadSpawn" => CallID::ScThreadSpawn {
+                fn_ptr: parse_num(arg("fn_ptr")?)? as i32,
+                args_ptr: parse_num(arg("args_ptr")?)? as i32,
+            },
+            "ScFutex" => CallID::ScFutex {
+                addr: parse_num(arg("addr")?)? as i32,
+                op: arg("op")?.parse()?,
+                val: parse_num(arg("val")?)? as u32,
+            },
+            "ScThreadExit" => CallID::ScThreadExit {
+                status: parse_num(arg("status")?)? as i32,
+            },
+            "ScProcExit" => CallID::ScProcExit {
+                status: parse_num(arg("status")?)? as i32,
+            },
+            "ScGeneric" => CallID::ScGeneric,
+            _ => return Err(format!("Unknown call ID \"{}\"", name)),
+        };
+        match args.keys().next() {
+            Some(key) => Err(format!("Unexpected field \"{}\" for {}", key, name)),
+            None => Ok(call_id),
+        }
+    }
+}
+
+/// Parses the [Display](fmt::Display) form of a [Termination]
+impl FromStr for Termination {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if let Some(code) = s.strip_prefix("Exit with code") {
+            Ok(Termination::Exit {
+                code: parse_num(code)? as i32,
+            })
+        } else if let Some(signo) = s.strip_prefix("Killed by signal") {
+            Ok(Termination::Signal {
+                signo: parse_num(signo)? as i32,
+            })
+        } else if let Some(trap) = s.strip_prefix("Trap \"") {
+            // The message is unescaped, so split at its last closing quote
+            let (message, rest) = trap
+                .rsplit_once("\" in thread")
+                .ok_or_else(|| format!("Invalid trap \"{}\"", s))?;
+            let (tid, access_idx) = match rest.split_once("near") {
+                Some((tid, access_idx)) => (tid, Some(parse_num(access_idx)? as u32)),
+                None => (rest, None),
+            };
+            Ok(Termination::Trap {
+                tid: parse_num(tid)?,
+                message: message.to_string(),
+                access_idx,
+            })
+        } else {
+            Err(format!("Invalid termination \"{}\"", s))
+        }
+    }
+}
+
+/// Parses the [Display](fmt::Display) form of a [TraceOp]
+impl FromStr for TraceOp {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        let (name, rest) = s.split_once(char::is_whitespace).unwrap_or((s, ""));
+        let mut cur = Cursor(rest);
+        let op = match name {
+            "Access" | "UCAccess" | "SyAccess" | "UCSyAccess" => {
+                cur.expect("[")?;
+                let tid = cur.num_until("::")?;
+                let access_idx = cur.num_until("|")? as u32;
+                let opcode = cur.num_until("]")? as i32;
+                cur.expect("for")?;
+                cur.expect("Addr")?;
+                cur.expect("[")?;
+                let addr = cur.num_until("::")? as i32;
+                let size = cur.num_until("]")? as u32;
+                cur.expect("with")?;
+                cur.expect("Read")?;
+                cur.expect("[")?;
+                let load_value = cur.num_until("]")? as i64;
+                let expected_value = match name {
+                    "UCSyAccess" if cur.is_empty() => load_value,
+                    _ => {
+                        cur.expect("==/==")?;
+                        cur.expect("[")?;
+                        cur.num_until("]")? as i64
+                    }
+                };
+                let differ = !name.starts_with("UC");
+                match name {
+                    "Access" | "UCAccess" => TraceOp::Access {
+                        tid,
+                        access_idx,
+                        opcode,
+                        addr,
+                        size,
+                        load_value,
+                        expected_value,
+                        differ,
+                    },
+                    _ => TraceOp::SyncAccess {
+                        tid,
+                        access_idx,
+                        opcode,
+                        addr,
+                        size,
+                        load_value,
+                        expected_value,
+                        differ,
+                    },
+                }
+            }
+            "Call" => {
+                cur.expect("[")?;
+                let tid = cur.num_until("::")?;
+                let access_idx = cur.num_until("|")? as u32;
+                let opcode = cur.num_until("]")? as i32;
+                cur.expect("for")?;
+                cur.expect("[")?;
+                let call_id = cur.until("|")?.parse()?;
+                let func_idx = cur.num_until("]")? as u32;
+                cur.expect("with")?;
+                cur.expect("Return")?;
+                cur.expect("[")?;
+                let return_val = cur.num_until("]")? as i64;
+                TraceOp::Call {
+                    tid,
+                    access_idx,
+                    opcode,
+                    func_idx,
+                    return_val,
+                    call_id,
+                }
+            }
+            "Output" => {
+                cur.expect("[")?;
+                let tid = cur.num_until("::")?;
+                let fd = cur.num_until("]")? as i32;
+                cur.expect("with")?;
+                let len = cur.num_until("bytes")? as usize;
+                cur.expect("\"")?;
+                let data = cur
+                    .0
+                    .trim_end()
+                    .strip_suffix('"')
+                    .ok_or("Unterminated output data")?;
+                let data = unescape_ascii(data)?;
+                if data.len() != len {
+                    return Err(format!(
+                        "Output has {} bytes, but {} were declared",
+                        data.len(),
+                        len
+                    ));
+                }
+                cur.0 = "";
+                TraceOp::Output { tid, fd, data }
+            }
+            "Terminate" => {
+                cur.expect("[")?;
+                let kind = cur
+                    .0
+                    .trim_end()
+                    .strip_suffix(']')
+                    .ok_or("Unterminated termination")?
+                    .parse()?;
+                cur.0 = "";
+                TraceOp::Terminate { kind }
+            }
+            _ => return Err(format!("Unknown op \"{}\"", name)),
+        };
+        cur.finish()?;
+        Ok(op)
+    }
+}
+
+/// Header directive lines for a trace with `header`
+///
+/// Provenance is informational only, and is emitted as comments
+pub fn header_directives(header: &TraceHeader) -> String {
+    let mut text = format!("@sha256 {}\n@encoding {:?}", header.sha256, header.encoding);
+    if let Some(provenance) = &header.provenance {
+        for line in provenance.to_string().lines() {
+            text.push_str("\n# ");
+            text.push_str(line);
+        }
+    }
+    text
+}
+
+/// Directive line marking a truncated trace
+pub const TRUNCATED_DIRECTIVE: &str = "@truncated";
+
+impl TraceData {
+    /// Parse a trace from its textual form
+    ///
+    /// The digest is left empty if there is no `@sha256` directive
+    pub fn from_text(text: &str) -> Result<Self, TextError> {
+        let mut data = TraceData::new("", vec![]);
+        for (idx, line) in text.lines().enumerate() {
+            let err = |msg: String| TextError { line: idx + 1, msg };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(directive) = line.strip_prefix('@') {
+                let (key, val) = directive
+                    .split_once(char::is_whitespace)
+                    .map_or((directive, ""), |(k, v)| (k, v.trim()));
+                match key {
+                    "sha256" => data.header.sha256 = val.to_string(),
+                    "encoding" => {
+                        data.header.encoding = match val {
+                            "Plain" => TraceEncoding::Plain,
+                            "Compressed" => TraceEncoding::Compressed,
+                            _ => return Err(err(format!("Invalid encoding \"{}\"", val))),
+                        }
+                    }
+                    "truncated" => data.truncated = true,
+                    _ => return Err(err(format!("Invalid directive \"{}\"", line))),
+                }
+                continue;
+            }
+            data.trace.push(line.parse().map_err(err)?);
+        }
+        Ok(data)
+    }
+
+    /// Render the trace in its textual form
+    pub fn to_text(&self) -> String {
+        let mut text = header_directives(&self.header);
+        for op in self.trace.iter() {
+            text.push('\n');
+            text.push_str(&op.to_string());
+        }
+        if self.truncated {
+            text.push('\n');
+            text.push_str(TRUNCATED_DIRECTIVE);
+        }
+        text.push('\n');
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access(differ: bool, sync: bool) -> TraceOp {
+        let (tid, access_idx, opcode, addr, size) = (3, 12, 0x28, 1024, 4);
+        let (load_value, expected_value) = match differ {
+            true => (-1, 7),
+            false => (7, 7),
+        };
+        match sync {
+            false => TraceOp::Access {
+                tid,
+                access_idx,
+                opcode,
+                addr,
+                size,
+                load_value,
+                expected_value,
+                differ,
+            },
+            true => TraceOp::SyncAccess {
+                tid,
+                access_idx,
+                opcode,
+                addr,
+                size,
+                load_value,
+                expected_value,
+                differ,
+            },
+        }
+    }
+
+    fn call(call_id: CallID, return_val: i64) -> TraceOp {
+        TraceOp::Call {
+            tid: 1,
+            access_idx: 10,
+            opcode: 0x10,
+            func_idx: 3,
+            return_val,
+            call_id,
+        }
+    }
+
+    /// Ops covering every op kind, call ID and termination
+    fn ops() -> Vec<TraceOp> {
+        vec![
+            access(true, false),
+            access(false, false),
+            access(true, true),
+            access(false, true),
+            call(CallID::ScUnknown, 0),
+            call(CallID::ScMmap { grow: 1 }, 0x10000),
+            call(
+                CallID::ScWritev {
+                    fd: 1,
+                    iov: 1024,
+                    iovcnt: 2,
+                },
+                13,
+            ),
+            call(
+                CallID::ScThreadSpawn {
+                    fn_ptr: 5,
+                    args_ptr: 2048,
+                },
+                2,
+            ),
+            call(
+                CallID::ScFutex {
+                    addr: 4096,
+                    op: FutexOp::Wait,
+                    val: 1,
+                },
+                0,
+            ),
+            call(CallID::ScThreadExit { status: 0 }, 0),
+            call(CallID::ScProcExit { status: -2 }, 0),
+            call(CallID::ScGeneric, i64::MIN),
+            TraceOp::Output {
+                tid: 1,
+                fd: 2,
+                data: b"say \"hi\"\\\n\t\x00\xff".to_vec(),
+            },
+            TraceOp::Terminate {
+                kind: Termination::Exit { code: -1 },
+            },
+            TraceOp::Terminate {
+                kind: Termination::Signal { signo: 9 },
+            },
+            TraceOp::Terminate {
+                kind: Termination::Trap {
+                    tid: 2,
+                    message: String::from("bad \" in thread 1"),
+                    access_idx: Some(40),
+                },
+            },
+            TraceOp::Terminate {
+                kind: Termination::Trap {
+                    tid: 0,
+                    message: String::from("unreachable"),
+                    access_idx: None,
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn roundtrip() {
+        let mut data = TraceData::new("1f2e", ops());
+        assert_eq!(TraceData::from_text(&data.to_text()).unwrap(), data);
+
+        data.header.encoding = TraceEncoding::Compressed;
+        data.truncated = true;
+        let text = data.to_text();
+        assert!(text.trim_end().ends_with(TRUNCATED_DIRECTIVE));
+        assert_eq!(TraceData::from_text(&text).unwrap(), data);
+    }
+
+    #[test]
+    fn handwritten() {
+        let text = "
+            # comment
+            @sha256 1f2e
+            Call [1::10 | 0x10] for [ScMmap { grow: 1 } | 3] with Return [0x1]
+            Access [0x1::12 | 0x28] for Addr [1024::4] with Read [0x7] ==/== [0]
+
+            UCSyAccess [3::12 | 0x28] for Addr [1024::4] with Read [7]
+            Terminate [Exit with code 0]
+        ";
+        let data = TraceData::from_text(text).unwrap();
+        assert_eq!(data.header.sha256, "1f2e");
+        assert!(!data.truncated);
+        assert_eq!(
+            data.trace,
+            [
+                call(CallID::ScMmap { grow: 1 }, 1),
+                TraceOp::Access {
+                    tid: 1,
+                    access_idx: 12,
+                    opcode: 0x28,
+                    addr: 1024,
+                    size: 4,
+                    load_value: 7,
+                    expected_value: 0,
+                    differ: true,
+                },
+                access(false, true),
+                TraceOp::Terminate {
+                    kind: Termination::Exit { code: 0 }
+                },
+            ]
+        );
+    }
+
+    /// Line of the error parsing `text`
+    fn error_line(text: &str) -> usize {
+        TraceData::from_text(text).unwrap_err().line
+    }
+
+    #[test]
+    fn malformed_ops() {
+        let valid = "Call [1::10 | 0x10] for [ScGeneric | 3] with Return [0x1]";
+        assert!(TraceData::from_text(valid).is_ok());
+        for line in [
+            "Jump [1::10 | 0x10]",
+            "Call [1::10 | 0x10] for [ScGeneric | 3] with Return [0x1] extra",
+            "Call [1::10 | 0x10] for [ScGeneric | 3] with Return [0xZ]",
+            "Call [1::10 | 0x10] for [ScGeneric | 3] with Return [1",
+            "Call [1::10 | 0x10] for [ScFork | 3] with Return [0x1]",
+            "Call [1::10 | 0x10] for [ScMmap { grow: 1, fd: 2 } | 3] with Return [0]",
+            "Call [1::10 | 0x10] for [ScWritev { fd: 1 } | 3] with Return [0]",
+            "Call [1::10 | 0x10] for [ScFutex { addr: 1, op: Swap, val: 0 } | 3] with Return [0]",
+            "Access [1::12 | 0x28] for Addr [1024::4] with Read [0x7]",
+            "Output [1::1] with 3 bytes \"ab\"",
+            "Output [1::1] with 2 bytes \"ab",
+            "Output [1::1] with 1 bytes \"\\q\"",
+            "Terminate [Exit with code 0",
+            "Terminate [Exited]",
+            "Terminate [Trap \"oops\"]",
+        ] {
+            let text = format!("{}\n{}", valid, line);
+            assert_eq!(error_line(&text), 2, "{}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_directives() {
+        assert_eq!(error_line("@sha256 1f2e\n@encoding Zstd"), 2);
+        assert_eq!(error_line("@sha256 1f2e\n@compressed"), 2);
+        assert_eq!(error_line("@truncated\n@"), 2);
+    }
+
+    #[test]
+    fn truncated_directive() {
+        let data = TraceData::from_text(TRUNCATED_DIRECTIVE).unwrap();
+        assert!(data.truncated);
+        assert!(data.trace.is_empty());
+
+        let data = TraceData::from_text("@sha256 1f2e").unwrap();
+        assert!(!data.truncated);
+        assert!(!data.to_text().contains(TRUNCATED_DIRECTIVE));
+    }
+}
@@ -1,13 +1,15 @@
 //! Utilities for transforming data from Wasm to native contexts and vice versa
 use libc::{self, c_void};
 use log::{trace, warn};
-use serde::{Deserialize, Serialize};
 use std::mem::{size_of, MaybeUninit};
 use std::ptr;
+use std::slice;
+
+pub use crate::trace::FutexOp;
 
 use wamr_rust_sdk::{
     wasm_exec_env_t, wasm_runtime_addr_app_to_native, wasm_runtime_get_exec_env_uid,
-    wasm_runtime_get_module_inst,
+    wasm_runtime_get_module_inst, wasm_runtime_validate_app_addr,
 };
 
 /// Types for Wasm to Native conversion
@@ -22,25 +24,6 @@ impl WasmPrimitiveType for i64 {}
 impl WasmPrimitiveType for u32 {}
 impl WasmPrimitiveType for u64 {}
 
-/// Futex operations supported for record/replay
-#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
-pub enum FutexOp {
-    Wait = 0,
-    Wake = 1,
-    Unknown = -1,
-}
-impl FutexOp {
-    /// Compose [FutexOp] variant from its [i32] representation
-    pub fn from_i32(op: i32) -> Self {
-        // Mask out FUTEX_PRIVATE (bit 7)
-        match op & 0x7f {
-            0 => FutexOp::Wait,
-            1 => FutexOp::Wake,
-            _ => FutexOp::Unknown,
-        }
-    }
-}
-
 /// Returns the native address corresponding to a Wasm address
 pub unsafe fn maddr(exec_env: wasm_exec_env_t, wasm_addr: WasmAddr) -> Addr {
     let native_addr: *mut c_void = unsafe {
@@ -130,6 +113,55 @@ pub unsafe fn get_native_iovec_from_wali(
     native_iovs
 }
 
+/// Gather the first `len` bytes of the buffers referenced by the WALI iovec
+/// array of `iovcnt` entries at `wasm_iov`, e.g. the bytes written by a
+/// `writev` returning `len`
+///
+/// `memory(addr, size)` returns `size` bytes of linear memory at `addr`, or
+/// `None` if any of them is out of bounds, in which case so is the result
+pub fn gather_wali_iovec<'a>(
+    memory: impl Fn(WasmAddr, usize) -> Option<&'a [u8]>,
+    wasm_iov: WasmAddr,
+    iovcnt: i32,
+    len: usize,
+) -> Option<Vec<u8>> {
+    let read = |addr: WasmAddr, size: usize| match size {
+        0 => Some(&[][..]),
+        _ => memory(addr, size),
+    };
+    let iovs = read(wasm_iov, 8 * usize::try_from(iovcnt).ok()?)?;
+    let mut bytes = Vec::new();
+    for iov in iovs.chunks_exact(8) {
+        let remaining = len - bytes.len();
+        if remaining == 0 {
+            break;
+        }
+        let iov_base = u32::from_le_bytes(iov[..4].try_into().unwrap());
+        let iov_len = u32::from_le_bytes(iov[4..].try_into().unwrap()) as usize;
+        bytes.extend_from_slice(read(iov_base, iov_len.min(remaining))?);
+    }
+    Some(bytes)
+}
+
+/// [gather_wali_iovec] from the linear memory of `exec_env`, bounds-checked
+/// by the engine
+pub unsafe fn read_wali_iovec_bytes(
+    exec_env: wasm_exec_env_t,
+    wasm_iov: WasmAddr,
+    iovcnt: i32,
+    len: usize,
+) -> Option<Vec<u8>> {
+    let module_inst = wasm_runtime_get_module_inst(exec_env);
+    let memory = |addr: WasmAddr, size: usize| {
+        if !wasm_runtime_validate_app_addr(module_inst, addr as u64, size as u64) {
+            return None;
+        }
+        let native_addr = wasm_runtime_addr_app_to_native(module_inst, addr as u64);
+        Some(slice::from_raw_parts(native_addr as *const u8, size))
+    };
+    gather_wali_iovec(memory, wasm_iov, iovcnt, len)
+}
+
 /// Get the TID of the Wasm executing environment
 ///
 /// TIDs start with 0 and sequentially increment in order of creation
@@ -141,3 +173,52 @@ pub fn get_wasmtid(exec_env: wasm_exec_env_t) -> u64 {
     // the main thread thereafter, so offset the wasm runtime's internal TID by 1
     unsafe { wasm_runtime_get_exec_env_uid(exec_env) - 1 }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Linear memory of 64 bytes, with iovecs `(base, len)` at address 0 and
+    /// the bytes `0..32` at address 32
+    fn linear_memory(iovs: &[(u32, u32)]) -> Vec<u8> {
+        let mut memory = vec![0u8; 64];
+        for (idx, (base, len)) in iovs.iter().enumerate() {
+            memory[idx * 8..idx * 8 + 4].copy_from_slice(&base.to_le_bytes());
+            memory[idx * 8 + 4..idx * 8 + 8].copy_from_slice(&len.to_le_bytes());
+        }
+        for (idx, byte) in memory[32..].iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+        memory
+    }
+
+    fn gather(memory: &[u8], iovcnt: i32, len: usize) -> Option<Vec<u8>> {
+        let read = |addr: WasmAddr, size: usize| memory.get(addr as usize..addr as usize + size);
+        gather_wali_iovec(read, 0, iovcnt, len)
+    }
+
+    #[test]
+    fn gather_written_bytes() {
+        let memory = linear_memory(&[(32, 3), (40, 0), (48, 4)]);
+        assert_eq!(gather(&memory, 3, 7), Some(vec![0, 1, 2, 16, 17, 18, 19]));
+        // Partial writes only cover the bytes written
+        assert_eq!(gather(&memory, 3, 5), Some(vec![0, 1, 2, 16, 17]));
+        assert_eq!(gather(&memory, 3, 0), Some(vec![]));
+        assert_eq!(gather(&memory, 0, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn gather_out_of_bounds() {
+        // Buffer beyond linear memory
+        let memory = linear_memory(&[(32, 3), (60, 8)]);
+        assert_eq!(gather(&memory, 2, 11), None);
+        // ...unless the write ends before it
+        assert_eq!(gather(&memory, 2, 7), Some(vec![0, 1, 2, 28, 29, 30, 31]));
+        // Iovec array beyond linear memory
+        assert_eq!(gather(&memory, 9, 0), None);
+        assert_eq!(gather(&memory, -1, 0), None);
+        // Buffer wrapping the address space
+        let memory = linear_memory(&[(u32::MAX, 2)]);
+        assert_eq!(gather(&memory, 1, 2), None);
+    }
+}
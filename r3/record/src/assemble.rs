@@ -0,0 +1,71 @@
+//! Binary to assemble a trace file from its textual form (see
+//! [common::trace::text]), e.g. as written by hand or emitted by
+//! [`deserialize`](../deserialize/index.html)
+use clap::Parser;
+use log::info;
+use sha256::digest;
+use std::error::Error;
+use std::fs;
+
+use common::trace::{TraceData, TraceEncoding};
+
+/// Command-Line Arguments
+#[derive(Parser, Debug)]
+#[command(version, about, long_about=None)]
+struct CLI {
+    /// Output trace file
+    #[arg(short, long, default_value_t = String::from("trace.r3"))]
+    outfile: String,
+
+    /// Compute the trace's SHA256 from this Wasm module, overriding any
+    /// `@sha256` directive
+    #[arg(short, long)]
+    module: Option<String>,
+
+    /// Encode trace ops compactly (delta-coded + zstd compressed), overriding
+    /// any `@encoding` directive
+    #[arg(short = 'z', long)]
+    compress: bool,
+
+    /// Textual trace file
+    infile: String,
+}
+
+impl CLI {
+    /// Print the CLI configuration
+    fn print(&self) {
+        info!("Infile: {:?}", self.infile);
+        info!("Outfile: {:?}", self.outfile);
+        info!("Module: {:?}", self.module);
+    }
+}
+
+/// Entrypoint for `trace-assemble`
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::builder().format_timestamp_millis().init();
+
+    let cli = CLI::parse();
+    cli.print();
+
+    let mut trace = TraceData::from_text(&fs::read_to_string(cli.infile.as_str())?)?;
+    if let Some(module) = cli.module.as_ref() {
+        trace.header.sha256 = digest(&fs::read(module)?);
+    }
+    if trace.header.sha256.is_empty() {
+        return Err(
+            "Trace has no @sha256 directive; provide the recorded module with --module".into(),
+        );
+    }
+    if cli.compress {
+        trace.header.encoding = TraceEncoding::Compressed;
+    }
+    fs::write(cli.outfile.as_str(), trace.serialize()?)?;
+    info!(
+        "Assembled trace with {} ops for module with SHA256 {} written to \"{}\"",
+        trace.trace.len(),
+        trace.header.sha256,
+        cli.outfile
+    );
+
+    Ok(())
+}
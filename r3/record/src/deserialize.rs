@@ -1,12 +1,19 @@
 //! Binary to deserialize a trace file generated by
 //! [`record`](../record/index.html)
 use clap::Parser;
-use log::info;
+use log::{info, warn};
 use std::error::Error;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 
-use common::trace::TraceData;
+use common::trace::text::{header_directives, TRUNCATED_DIRECTIVE};
+use common::trace::TraceReader;
+
+mod export;
+use export::{write_jsonl, CsvWriter, Format};
+
+mod stats;
+use stats::TraceStats;
 
 /// Command-Line Arguments
 #[derive(Parser, Debug)]
@@ -16,6 +23,18 @@ struct CLI {
     #[arg(short, long, default_value_t = String::from("trace.ds"))]
     outfile: String,
 
+    /// Format of the deserialized output
+    #[arg(short, long, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Print summary statistics to stdout instead of dumping every op
+    #[arg(short, long)]
+    summary: bool,
+
+    /// Number of access sites to show in the summary
+    #[arg(long, default_value_t = 10, requires = "summary")]
+    top: usize,
+
     /// Trace output file generated by `record`
     #[arg(default_value_t = String::from("trace.r3"))]
     tracefile: String,
@@ -25,20 +44,68 @@ impl CLI {
     /// Print the CLI configuration
     fn print(&self) {
         info!("Tracefile: {:?}", self.tracefile);
-        info!("Outfile: {:?}", self.outfile);
+        if !self.summary {
+            info!("Outfile: {:?}", self.outfile);
+            info!("Format: {:?}", self.format);
+        }
     }
 }
 
-/// Dump [TraceData] deserialized trace to `deserfile`
-fn dump_deserialized(deserialized: &TraceData, deserfile: &str) -> Result<(), io::Error> {
-    let mut file = fs::File::create(deserfile)?;
-    for traceop in deserialized.trace.iter() {
-        writeln!(file, "{}", traceop)?;
+/// Dump the ops streamed by `reader` to `deserfile` in the given `format`
+fn dump_deserialized<R: Read>(
+    mut reader: TraceReader<R>,
+    deserfile: &str,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new(fs::File::create(deserfile)?);
+    match format {
+        Format::Text => {
+            // Emitted in the syntax accepted by `trace-assemble`
+            writeln!(file, "{}", header_directives(reader.header()))?;
+            for traceop in reader.by_ref() {
+                writeln!(file, "{}", traceop?)?;
+            }
+            if reader.is_truncated() {
+                writeln!(file, "{}", TRUNCATED_DIRECTIVE)?;
+            }
+        }
+        Format::Jsonl => {
+            for traceop in reader.by_ref() {
+                write_jsonl(&mut file, &traceop?)?;
+            }
+        }
+        Format::Csv => {
+            let mut csv = CsvWriter::new(&mut file);
+            for traceop in reader.by_ref() {
+                csv.write(&traceop?)?;
+            }
+            csv.finish()?;
+        }
+    }
+    file.flush()?;
+    if reader.is_truncated() {
+        warn!("Trace is truncated: recording ended before all ops were saved");
     }
     info!("Deserialized output written to \"{}\"", deserfile);
     Ok(())
 }
 
+/// Summarize the ops streamed by `reader` to stdout
+fn dump_summary<R: Read>(mut reader: TraceReader<R>, top: usize) -> Result<(), Box<dyn Error>> {
+    if let Some(provenance) = &reader.header().provenance {
+        println!("{}\n", provenance);
+    }
+    let mut stats = TraceStats::default();
+    for traceop in reader.by_ref() {
+        stats.add(&traceop?);
+    }
+    if reader.is_truncated() {
+        warn!("Trace is truncated: recording ended before all ops were saved");
+    }
+    stats.report(&mut io::stdout().lock(), top)?;
+    Ok(())
+}
+
 /// Entrypoint for `deserialize`
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::builder().format_timestamp_millis().init();
@@ -46,13 +113,28 @@ fn main() -> Result<(), Box<dyn Error>> {
     let cli = CLI::parse();
     cli.print();
 
-    // Read trace file
-    let tracebin = fs::read(cli.tracefile.as_str())?;
-
+    // Stream trace file
+    let reader = TraceReader::new(BufReader::new(fs::File::open(cli.tracefile.as_str())?))?;
     // Don't check for sha256 match; this executable purely deserializes
-    let deserialized = TraceData::deserialize(&tracebin, None);
+    info!(
+        "Trace (format version {}) for module with SHA256: {}",
+        reader.version(),
+        reader.header().sha256
+    );
+    match &reader.header().provenance {
+        Some(provenance) => {
+            for line in provenance.to_string().lines() {
+                info!("{}", line);
+            }
+        }
+        None => info!("Trace has no provenance"),
+    }
 
-    dump_deserialized(&deserialized, cli.outfile.as_str())?;
+    if cli.summary {
+        dump_summary(reader, cli.top)?;
+    } else {
+        dump_deserialized(reader, cli.outfile.as_str(), cli.format)?;
+    }
 
     Ok(())
 }
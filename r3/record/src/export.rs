@@ -0,0 +1,286 @@
+//! Structured (JSON Lines/CSV) export of traces, for `deserialize --format`
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::io::Write;
+
+use common::trace::TraceOp;
+
+/// Output format of `deserialize`
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Human-readable text, one op per line
+    Text,
+    /// JSON Lines, one object per op
+    Jsonl,
+    /// CSV with a header row, one row per op
+    Csv,
+}
+
+/// Name of the [TraceOp] variant
+fn op_name(op: &TraceOp) -> &'static str {
+    match op {
+        TraceOp::Access { .. } => "Access",
+        TraceOp::SyncAccess { .. } => "SyncAccess",
+        TraceOp::Call { .. } => "Call",
+        TraceOp::Output { .. } => "Output",
+        TraceOp::Terminate { .. } => "Terminate",
+    }
+}
+
+/// Flatten a serialized enum into an object with its variant under `name`,
+/// alongside the variant's fields
+fn flatten_variant<T: Serialize>(value: &T) -> Value {
+    match serde_json::to_value(value).unwrap() {
+        Value::String(name) => json!({ "name": name }),
+        Value::Object(variant) => {
+            let (name, fields) = variant.into_iter().next().unwrap();
+            let mut obj = Map::new();
+            obj.insert(String::from("name"), Value::String(name));
+            if let Value::Object(fields) = fields {
+                obj.extend(fields);
+            }
+            Value::Object(obj)
+        }
+        value => value,
+    }
+}
+
+/// Write `op` as a single JSON object line
+///
+/// Fields are those of the [TraceOp] variant, plus its name under `op`.
+/// [CallID](common::trace::CallID)s and
+/// [Termination](common::trace::Termination)s are objects with their
+/// variant under `name`, and output data is an array of bytes
+pub fn write_jsonl<W: Write>(out: &mut W, op: &TraceOp) -> Result<(), Box<dyn Error>> {
+    let mut obj = Map::new();
+    obj.insert(String::from("op"), Value::from(op_name(op)));
+    if let Value::Object(fields) = flatten_variant(op) {
+        for (key, value) in fields.into_iter().filter(|(key, _)| key != "name") {
+            let value = match (op, key.as_str()) {
+                (TraceOp::Call { call_id, .. }, "call_id") => flatten_variant(call_id),
+                (TraceOp::Terminate { kind }, "kind") => flatten_variant(kind),
+                _ => value,
+            };
+            obj.insert(key, value);
+        }
+    }
+    serde_json::to_writer(&mut *out, &Value::Object(obj))?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Flat CSV row covering the fields of all [TraceOp] variants
+///
+/// Fields not present in an op are left empty. [CallID](common::trace::CallID)
+/// parameters are given in the order of
+/// [to_parts](common::trace::CallID::to_parts)
+#[derive(Serialize, Default)]
+struct CsvRow {
+    op: &'static str,
+    tid: Option<u64>,
+    access_idx: Option<u32>,
+    opcode: Option<i32>,
+    addr: Option<i32>,
+    size: Option<u32>,
+    load_value: Option<i64>,
+    expected_value: Option<i64>,
+    differ: Option<bool>,
+    func_idx: Option<u32>,
+    return_val: Option<i64>,
+    call_id: Option<&'static str>,
+    call_arg0: Option<i64>,
+    call_arg1: Option<i64>,
+    call_arg2: Option<i64>,
+    fd: Option<i32>,
+    /// Hex-encoded output bytes
+    data: Option<String>,
+    termination: Option<String>,
+}
+
+impl CsvRow {
+    fn from_op(op: &TraceOp) -> Self {
+        let mut row = CsvRow {
+            op: op_name(op),
+            tid: op.tid(),
+            access_idx: op.access_idx(),
+            ..Default::default()
+        };
+        match op {
+            TraceOp::Access {
+                opcode,
+                addr,
+                size,
+                load_value,
+                expected_value,
+                differ,
+                ..
+            }
+            | TraceOp::SyncAccess {
+                opcode,
+                addr,
+                size,
+                load_value,
+                expected_value,
+                differ,
+                ..
+            } => {
+                row.opcode = Some(*opcode);
+                row.addr = Some(*addr);
+                row.size = Some(*size);
+                row.load_value = Some(*load_value);
+                row.expected_value = Some(*expected_value);
+                row.differ = Some(*differ);
+            }
+            TraceOp::Call {
+                opcode,
+                func_idx,
+                return_val,
+                call_id,
+                ..
+            } => {
+                let (_, args) = call_id.to_parts();
+                row.opcode = Some(*opcode);
+                row.func_idx = Some(*func_idx);
+                row.return_val = Some(*return_val);
+                row.call_id = Some(call_id.name());
+                row.call_arg0 = Some(args[0]);
+                row.call_arg1 = Some(args[1]);
+                row.call_arg2 = Some(args[2]);
+            }
+            TraceOp::Output { fd, data, .. } => {
+                row.fd = Some(*fd);
+                row.data = Some(data.iter().map(|b| format!("{:02x}", b)).collect());
+            }
+            TraceOp::Terminate { kind } => {
+                row.termination = Some(kind.to_string());
+            }
+        }
+        row
+    }
+}
+
+/// Writer of [TraceOp]s as CSV rows
+pub struct CsvWriter<W: Write> {
+    inner: csv::Writer<W>,
+}
+
+impl<W: Write> CsvWriter<W> {
+    pub fn new(out: W) -> Self {
+        CsvWriter {
+            inner: csv::Writer::from_writer(out),
+        }
+    }
+
+    pub fn write(&mut self, op: &TraceOp) -> Result<(), Box<dyn Error>> {
+        self.inner.serialize(CsvRow::from_op(op))?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+        self.inner.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::trace::{CallID, Termination};
+
+    fn ops() -> Vec<TraceOp> {
+        vec![
+            TraceOp::Access {
+                tid: 1,
+                access_idx: 2,
+                opcode: 0x28,
+                addr: 0x100,
+                size: 4,
+                load_value: -1,
+                expected_value: 0,
+                differ: true,
+            },
+            TraceOp::Call {
+                tid: 1,
+                access_idx: 3,
+                opcode: 0x10,
+                func_idx: 4,
+                return_val: 3,
+                call_id: CallID::ScWritev {
+                    fd: 1,
+                    iov: 0x200,
+                    iovcnt: 2,
+                },
+            },
+            TraceOp::Call {
+                tid: 2,
+                access_idx: 5,
+                opcode: 0x10,
+                func_idx: 6,
+                return_val: 0,
+                call_id: CallID::ScGeneric,
+            },
+            TraceOp::Output {
+                tid: 1,
+                fd: 1,
+                data: b"hi\n".to_vec(),
+            },
+            TraceOp::Terminate {
+                kind: Termination::Trap {
+                    tid: 1,
+                    message: "oops, \"unreachable\"".to_string(),
+                    access_idx: Some(3),
+                },
+            },
+        ]
+    }
+
+    #[test]
+    fn jsonl() {
+        let mut out = Vec::new();
+        for op in ops() {
+            write_jsonl(&mut out, &op).unwrap();
+        }
+        let lines: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                json!({"op": "Access", "tid": 1, "access_idx": 2, "opcode": 0x28, "addr": 0x100,
+                       "size": 4, "load_value": -1, "expected_value": 0, "differ": true}),
+                json!({"op": "Call", "tid": 1, "access_idx": 3, "opcode": 0x10, "func_idx": 4,
+                       "return_val": 3,
+                       "call_id": {"name": "ScWritev", "fd": 1, "iov": 0x200, "iovcnt": 2}}),
+                json!({"op": "Call", "tid": 2, "access_idx": 5, "opcode": 0x10, "func_idx": 6,
+                       "return_val": 0, "call_id": {"name": "ScGeneric"}}),
+                json!({"op": "Output", "tid": 1, "fd": 1, "data": [0x68, 0x69, 0x0A]}),
+                json!({"op": "Terminate",
+                       "kind": {"name": "Trap", "tid": 1, "message": "oops, \"unreachable\"",
+                                "access_idx": 3}}),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv() {
+        let mut out = Vec::new();
+        let mut writer = CsvWriter::new(&mut out);
+        for op in ops() {
+            writer.write(&op).unwrap();
+        }
+        writer.finish().unwrap();
+        let expected = "\
+op,tid,access_idx,opcode,addr,size,load_value,expected_value,differ,func_idx,return_val,call_id,call_arg0,call_arg1,call_arg2,fd,data,termination
+Access,1,2,40,256,4,-1,0,true,,,,,,,,,
+Call,1,3,16,,,,,,4,3,ScWritev,1,512,2,,,
+Call,2,5,16,,,,,,6,0,ScGeneric,0,0,0,,,
+Output,1,,,,,,,,,,,,,,1,68690a,
+Terminate,,,,,,,,,,,,,,,,,\"Trap \"\"oops, \"\"unreachable\"\"\"\" in thread 1 near 3\"
+";
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,25 @@
+//! Library crate for recording a Wasm module's execution into a Trace, for
+//! embedding recording in other tools (feature `engine`).
+//!
+//! The `record` binary is a thin CLI over a [Recorder]:
+//! ```rust,no_run
+//! # #[cfg(feature = "engine")]
+//! # fn main() -> Result<(), common::R3Error> {
+//! # use common::trace::TraceData;
+//! # use record::{RecordOptions, Recorder};
+//! # let wasm = std::fs::read("app.wasm")?;
+//! # let argv = vec![String::from("app.wasm")];
+//! let recorder = Recorder::new(&wasm, RecordOptions::default())?;
+//! let trace: TraceData = recorder.record(&argv)?;
+//! # Ok(())
+//! # }
+//! # #[cfg(not(feature = "engine"))]
+//! # fn main() {}
+//! ```
+#[cfg(feature = "engine")]
+pub mod record_interface;
+
+#[cfg(feature = "engine")]
+mod recorder;
+#[cfg(feature = "engine")]
+pub use recorder::{RecordOptions, Recorder};
@@ -1,30 +1,20 @@
 //! Binary crate for recording a Wasm modules execution and generate a Trace
 use clap::Parser;
-use libc::c_void;
-use log::{info, warn};
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{fork, ForkResult};
-use sha256::digest;
+use log::info;
 use std::error::Error;
 use std::fs;
-use std::process;
-
-use wamr_rust_sdk::{instance::Instance, module::Module, runtime::Runtime};
+use std::path::Path;
 
 use wamr_rust_sdk::{log_level_t, LOG_LEVEL_WARNING};
 
-use common::instrument::{destroy_instrument_module, instrument_module, InstrumentArgs};
-
-pub mod record_interface;
-use record_interface::{
-    dump_global_trace, initialize_tmpfile_name, wasm_call_tracedump, wasm_memop_tracedump,
-};
+use record::{RecordOptions, Recorder};
 
 /// Command-Line Arguments
 #[derive(Parser, Debug)]
 #[command(version, about, long_about=None)]
 struct CLI {
-    /// Instrumentation Scheme
+    /// Instrumentation Scheme (`r3-record-rs` for native instrumentation, with
+    /// feature `rust-instrument`)
     #[arg(short, long, default_value_t = String::from("r3-record"))]
     scheme: String,
 
@@ -40,6 +30,19 @@ struct CLI {
     #[arg(short, long, default_value_t = String::from("trace.r3"))]
     outfile: String,
 
+    /// Compress the output trace
+    #[arg(short = 'z', long)]
+    compress: bool,
+
+    /// Capture bytes written through `writev` for output verification by
+    /// `runner`
+    #[arg(long)]
+    capture_output: bool,
+
+    /// Store the recording's environment variables in the trace provenance
+    #[arg(long)]
+    record_env: bool,
+
     /// Instrumented program path
     #[arg(short, long)]
     instfile: Option<String>,
@@ -57,6 +60,9 @@ impl CLI {
         info!("Input Command: {:?}", self.input_command);
         info!("Instfile [optional]: {:?}", self.instfile);
         info!("Outfile: {:?}", self.outfile);
+        info!("Compress: {}", self.compress);
+        info!("Capture Output: {}", self.capture_output);
+        info!("Record Environment: {}", self.record_env);
     }
 }
 
@@ -67,58 +73,27 @@ pub fn main() -> Result<(), Box<dyn Error>> {
     let cli = CLI::parse();
     cli.print();
 
-    // Read wasm file, compute its digest
+    // Read and instrument wasm file
     let infile = cli.input_command[0].as_str();
     let contents = fs::read(infile)?;
-    let sha256_infile = digest(&contents);
-
-    let args: Vec<&str> = cli.instargs.iter().map(|s| s.as_str()).collect();
-    let inst_module: &[u8] = instrument_module(
+    let recorder = Recorder::new(
         &contents,
-        cli.scheme.as_str(),
-        InstrumentArgs::Generic(&args[..]),
+        RecordOptions {
+            scheme: cli.scheme,
+            instargs: cli.instargs,
+            log_level: cli.verbose,
+            compress: cli.compress,
+            capture_output: cli.capture_output,
+            record_env: cli.record_env,
+        },
     )?;
     if let Some(instfile) = cli.instfile {
         info!("Writing module to {}", instfile);
-        fs::write(instfile, inst_module)?;
+        fs::write(instfile, recorder.instrumented())?;
     }
 
-    // This needs to be done before fork to prevent double initialization of
-    // Lazy
-    initialize_tmpfile_name();
-    match unsafe { fork() }? {
-        ForkResult::Child => {
-            info!("Wasm engine executing with PID: {}", process::id());
-            // WAMR Instantiate and Run
-            let runtime = Runtime::builder()
-                .use_system_allocator()
-                .set_host_function_module_name("instrument")
-                .register_host_function("memop_tracedump", wasm_memop_tracedump as *mut c_void)
-                .register_host_function("call_tracedump", wasm_call_tracedump as *mut c_void)
-                .set_max_thread_num(100)
-                .build()?;
-            runtime.set_log_level(cli.verbose);
-            let module = Module::from_buf(&runtime, inst_module, infile)?;
-            let instance = Instance::new(&runtime, &module, 1024 * 256)?;
-
-            let _ = instance.execute_main(&cli.input_command)?;
-            info!("Wasm module safely exited from child process");
-            process::exit(0);
-        }
-        ForkResult::Parent { child } => match waitpid(child, None)? {
-            WaitStatus::Exited(pid, status) => {
-                info!("Wasm engine (PID: {}) exited with status: {}", pid, status);
-            }
-            status => {
-                warn!("Wasm engine exited with bad status: {:?}", status);
-            }
-        },
-    }
-
-    dump_global_trace(&cli.outfile, sha256_infile.as_str())?;
+    recorder.record_to_file(&cli.input_command, Path::new(&cli.outfile))?;
     info!("Dumped trace to {}", cli.outfile);
 
-    destroy_instrument_module(inst_module);
-
     return Ok(());
 }
@@ -1,76 +1,490 @@
 //! Utilities to implement foreign function interface for trace recording
 use log::Level::Trace;
-use log::{debug, info, log_enabled, warn};
+use log::{debug, error, info, log_enabled, warn};
 use once_cell::sync::Lazy;
 use postcard;
-use std::fs::{remove_file, File};
-use std::io::{self, BufWriter, Seek, Write};
-use std::path::PathBuf;
-use std::sync::{LazyLock, Mutex};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{self, create_dir_all, read_dir, remove_dir_all, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, LazyLock, Mutex, Once};
+use std::thread::{self, JoinHandle};
 use tempfile::env;
 use uuid::Uuid;
 use wamr_rust_sdk::wasm_exec_env_t;
 
 use common::trace::*;
 use common::wasm2native::*;
-use common::WasmOpcode;
+use common::{R3Error, WasmOpcode};
 
-/// A Lazy-initialized temporary disk-backed filepath
-static TMP_FILEPATH: Lazy<PathBuf> = Lazy::new(|| {
+/// Number of ops buffered per thread before handing off to the trace writer
+const THREAD_BUFFER_OPS: usize = 4096;
+
+/// Number of full thread buffers that may be in flight to the trace writer
+/// before recording threads block
+const WRITER_QUEUE_DEPTH: usize = 64;
+
+/// A Lazy-initialized temporary disk-backed directory, holding one
+/// intermediate traceop file per thread
+static TMP_DIRPATH: Lazy<PathBuf> = Lazy::new(|| {
     let mut temppath = env::temp_dir();
     temppath.push(Uuid::new_v4().to_string());
-    info!("Intermediate tracefile: {:?}", temppath);
+    info!("Intermediate trace directory: {:?}", temppath);
     temppath
 });
 
-/// A Lazy-initialized writer to a temporary disk-backed file for storing
-/// intermediate traceops
-pub static TRACEOP_FILE: LazyLock<Mutex<BufWriter<File>>> =
-    LazyLock::new(|| Mutex::new(BufWriter::new(File::create(&*TMP_FILEPATH).unwrap())));
+/// Global sequence number establishing the observed order of ops across
+/// threads
+static TRACE_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Whether `writev` payloads are captured as [TraceOp::Output] records
+static CAPTURE_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Errors encountered by recording callbacks, which must not abort the engine
+static RECORD_ERRORS: Mutex<Vec<R3Error>> = Mutex::new(Vec::new());
+
+/// Name of the file (in [TMP_DIRPATH]) that the engine process reports
+/// [RECORD_ERRORS] to
+const ERRORS_FILENAME: &str = "errors.log";
+
+/// A [TraceOp] tagged with its global sequence number
+type SeqTraceOp = (u64, TraceOp);
+
+/// Messages to the background trace writer
+enum WriterMsg {
+    /// Sequence-ordered ops from the thread with the given TID
+    Batch(u64, Vec<SeqTraceOp>),
+    /// Flush all intermediate files and stop
+    Finish,
+}
+
+/// Handle to the background trace writer thread
+type WriterHandle = (SyncSender<WriterMsg>, Mutex<Option<JoinHandle<()>>>);
+
+/// A Lazy-initialized background writer that persists thread buffers to
+/// per-thread intermediate files
+///
+/// ### Design Notes
+/// Spawned on first use, so that it lives in the forked engine process. The
+/// [JoinHandle] is only locked when recording finishes
+static TRACE_WRITER: LazyLock<WriterHandle> = LazyLock::new(|| {
+    let (tx, rx) = sync_channel(WRITER_QUEUE_DEPTH);
+    let handle = thread::Builder::new()
+        .name("r3-trace-writer".into())
+        .spawn(move || trace_writer_loop(rx, &TMP_DIRPATH))
+        .unwrap();
+    (tx, Mutex::new(Some(handle)))
+});
+
+/// Per-thread buffer of [SeqTraceOp]s, flushed to [TRACE_WRITER] when full,
+/// when the thread exits, or when the engine process exits
+struct ThreadTraceBuffer {
+    tid: u64,
+    ops: Vec<SeqTraceOp>,
+    /// Location of the most recent op traced by this thread
+    last_access_idx: Option<u32>,
+}
+impl ThreadTraceBuffer {
+    fn new() -> Self {
+        ThreadTraceBuffer {
+            tid: 0,
+            ops: Vec::with_capacity(THREAD_BUFFER_OPS),
+            last_access_idx: None,
+        }
+    }
+
+    /// Append `op` of thread `tid` with sequence number `seq`, handing the
+    /// buffer to `writer` once full
+    fn push(&mut self, tid: u64, seq: u64, op: TraceOp, writer: &SyncSender<WriterMsg>) {
+        // Native threads may be reused across Wasm threads
+        if self.tid != tid {
+            self.flush(writer);
+            self.tid = tid;
+            self.last_access_idx = None;
+        }
+        // Outputs have no location, and directly follow their `writev` call
+        if let Some(access_idx) = op.access_idx() {
+            self.last_access_idx = Some(access_idx);
+        }
+        self.ops.push((seq, op));
+        if self.ops.len() >= THREAD_BUFFER_OPS {
+            self.flush(writer);
+        }
+    }
+
+    /// Hand the buffered ops to `writer`
+    fn flush(&mut self, writer: &SyncSender<WriterMsg>) {
+        if self.ops.is_empty() {
+            return;
+        }
+        let ops = std::mem::replace(&mut self.ops, Vec::with_capacity(THREAD_BUFFER_OPS));
+        let num_ops = ops.len();
+        if writer.send(WriterMsg::Batch(self.tid, ops)).is_err() {
+            warn!(
+                "[{:>18}] Trace writer already finished; dropping {} traceops",
+                self.tid, num_ops
+            );
+        }
+    }
+}
+
+/// Shared handle to a [ThreadTraceBuffer]
+///
+/// ### Design Notes
+/// The buffer's lock is only ever contended when the engine process exits and
+/// [finish_trace] flushes every registered buffer; on the recording path it is
+/// only taken by its owning thread
+type SharedTraceBuffer = Arc<Mutex<ThreadTraceBuffer>>;
+
+/// Registry of all live thread buffers, so that exit paths can flush buffers
+/// of threads other than the exiting one
+static THREAD_BUFFERS: Mutex<Vec<SharedTraceBuffer>> = Mutex::new(Vec::new());
+
+/// Thread-local owner of a registered [ThreadTraceBuffer]; flushes and
+/// unregisters the buffer on thread exit
+struct ThreadBufferHandle(SharedTraceBuffer);
+impl ThreadBufferHandle {
+    fn new() -> Self {
+        let buf = Arc::new(Mutex::new(ThreadTraceBuffer::new()));
+        THREAD_BUFFERS.lock().unwrap().push(buf.clone());
+        ThreadBufferHandle(buf)
+    }
+}
+impl Drop for ThreadBufferHandle {
+    fn drop(&mut self) {
+        self.0.lock().unwrap().flush(&TRACE_WRITER.0);
+        THREAD_BUFFERS
+            .lock()
+            .unwrap()
+            .retain(|buf| !Arc::ptr_eq(buf, &self.0));
+    }
+}
+
+thread_local! {
+    static THREAD_BUFFER: ThreadBufferHandle = ThreadBufferHandle::new();
+}
+
+/// Path of the intermediate traceop file for thread `tid` in `dir`
+fn thread_filepath(dir: &Path, tid: u64) -> PathBuf {
+    dir.join(format!("{}.ops", tid))
+}
+
+/// Body of the background trace writer thread, appending the ops of each
+/// thread to its intermediate file in `dir`
+///
+/// Failures are recorded, and lose the affected ops (marking the trace
+/// truncated) rather than aborting the engine
+fn trace_writer_loop(rx: Receiver<WriterMsg>, dir: &Path) {
+    let mut files: HashMap<u64, BufWriter<File>> = HashMap::new();
+    for msg in rx {
+        match msg {
+            WriterMsg::Batch(tid, ops) => {
+                let file = match files.get_mut(&tid) {
+                    Some(file) => file,
+                    None => match File::create(thread_filepath(dir, tid)) {
+                        Ok(file) => files.entry(tid).or_insert(BufWriter::new(file)),
+                        Err(e) => {
+                            record_error(e.into());
+                            continue;
+                        }
+                    },
+                };
+                if let Err(e) = ops.iter().try_for_each(|op| {
+                    postcard::to_io(op, &mut *file)
+                        .map(|_| ())
+                        .map_err(io::Error::other)
+                }) {
+                    record_error(e.into());
+                }
+            }
+            WriterMsg::Finish => break,
+        }
+    }
+    for (_, mut file) in files {
+        if let Err(e) = file.flush() {
+            record_error(e.into());
+        }
+    }
+}
+
+/// Record an error from a recording callback, without interrupting the
+/// recorded program
+fn record_error(e: R3Error) {
+    error!("Recording error: {}", e);
+    RECORD_ERRORS.lock().unwrap().push(e);
+}
 
-/// Initialize the temporary file name
-pub fn initialize_tmpfile_name() {
-    let _ = &*TMP_FILEPATH;
+/// Set whether the bytes passed to every `writev` call are captured into the
+/// trace
+pub fn set_output_capture(enabled: bool) {
+    CAPTURE_OUTPUT.store(enabled, Ordering::Relaxed);
+}
+
+/// Initialize an empty temporary directory for intermediate traceop files,
+/// discarding any left by an earlier recording in this process
+pub fn initialize_tmpdir() -> Result<(), R3Error> {
+    match remove_dir_all(&*TMP_DIRPATH) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    Ok(create_dir_all(&*TMP_DIRPATH)?)
+}
+
+/// Add a [TraceOp] from thread `tid` to recorded trace
+///
+/// Only the sequence number is shared across threads; the op itself is
+/// appended to a thread-local buffer
+fn append_traceop(tid: u64, op: TraceOp) {
+    let seq = TRACE_SEQ.fetch_add(1, Ordering::SeqCst);
+    THREAD_BUFFER.with(|handle| handle.0.lock().unwrap().push(tid, seq, op, &TRACE_WRITER.0));
+}
+
+/// Compose the [Termination] for a trap with `message` observed by the calling
+/// thread, located at the last op it traced
+pub fn trap_termination(message: String) -> Termination {
+    THREAD_BUFFER.with(|handle| {
+        let buf = handle.0.lock().unwrap();
+        Termination::Trap {
+            tid: buf.tid,
+            message,
+            access_idx: buf.last_access_idx,
+        }
+    })
+}
+
+/// Flush every thread's buffer, then wait for the background writer to
+/// persist all intermediate traceops.
+///
+/// Only the first call has any effect; it is run at process exit if
+/// [install_exit_flush] was called
+pub fn finish_trace() {
+    static FINISHED: Once = Once::new();
+    FINISHED.call_once(|| {
+        for buf in THREAD_BUFFERS.lock().unwrap().iter() {
+            buf.lock().unwrap().flush(&TRACE_WRITER.0);
+        }
+        let _ = TRACE_WRITER.0.send(WriterMsg::Finish);
+        if let Some(handle) = TRACE_WRITER.1.lock().unwrap().take() {
+            handle.join().unwrap();
+        }
+        debug!("Intermediate trace flushed");
+        // Hand recorded errors to the parent process
+        let errors = RECORD_ERRORS.lock().unwrap();
+        if !errors.is_empty() {
+            let report: String = errors.iter().map(|e| format!("{}\n", e)).collect();
+            if let Err(e) = fs::write(TMP_DIRPATH.join(ERRORS_FILENAME), report) {
+                error!("Failed to report {} recording errors: {}", errors.len(), e);
+            }
+        }
+    });
+}
+
+/// Guarantee [finish_trace] runs when the engine process exits, including
+/// through guest `proc_exit` or [process::exit](std::process::exit)
+pub fn install_exit_flush() {
+    extern "C" fn flush_at_exit() {
+        finish_trace();
+    }
+    unsafe {
+        libc::atexit(flush_at_exit);
+    }
+}
+
+/// Reader over a single thread's intermediate traceop file
+///
+/// Stops at the first incomplete record, e.g. from a crashed engine
+struct ThreadOpsReader {
+    path: PathBuf,
+    file: BufReader<File>,
+    truncated: bool,
+}
+impl ThreadOpsReader {
+    fn open(path: PathBuf) -> io::Result<Self> {
+        Ok(ThreadOpsReader {
+            file: BufReader::new(File::open(&path)?),
+            path,
+            truncated: false,
+        })
+    }
+}
+impl Iterator for ThreadOpsReader {
+    type Item = SeqTraceOp;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.truncated || self.file.fill_buf().ok()?.is_empty() {
+            return None;
+        }
+        match postcard::from_io((&mut self.file, &mut [0; 0])) {
+            Ok((op, _)) => Some(op),
+            Err(e) => {
+                warn!("Intermediate trace {:?} is truncated: {}", self.path, e);
+                self.truncated = true;
+                None
+            }
+        }
+    }
 }
 
-/// Add a [TraceOp] to recorded trace
-fn append_traceop(op: TraceOp) {
-    let file = &mut *(TRACEOP_FILE.lock().unwrap());
-    postcard::to_io(&op, file).unwrap();
+/// Merge of per-thread, sequence-ordered [SeqTraceOp]s into the global
+/// sequence order
+///
+/// ### Design Notes
+/// A min-heap holds the sequence number of the next op of each thread, so
+/// only one op per thread is held in memory at a time
+struct SeqMerge<I> {
+    threads: Vec<I>,
+    heads: BinaryHeap<Reverse<(u64, usize)>>,
+    pending: Vec<Option<TraceOp>>,
 }
+impl<I: Iterator<Item = SeqTraceOp>> SeqMerge<I> {
+    fn new(mut threads: Vec<I>) -> Self {
+        let mut heads = BinaryHeap::new();
+        let mut pending = Vec::with_capacity(threads.len());
+        for (idx, thread) in threads.iter_mut().enumerate() {
+            let head = thread.next();
+            if let Some((seq, _)) = head {
+                heads.push(Reverse((seq, idx)));
+            }
+            pending.push(head.map(|(_, op)| op));
+        }
+        SeqMerge {
+            threads,
+            heads,
+            pending,
+        }
+    }
 
-/// Check if file is at EOF
-fn is_at_eof(mut file: &File) -> io::Result<bool> {
-    let current_pos = file.stream_position()?;
-    let file_len = file.metadata()?.len();
-    Ok(current_pos == file_len)
+    /// Per-thread sources, e.g. to check them once merged
+    fn threads(&self) -> &[I] {
+        &self.threads
+    }
+}
+impl<I: Iterator<Item = SeqTraceOp>> Iterator for SeqMerge<I> {
+    type Item = SeqTraceOp;
+    fn next(&mut self) -> Option<Self::Item> {
+        let Reverse((seq, idx)) = self.heads.pop()?;
+        let head = self.threads[idx].next();
+        if let Some((next_seq, _)) = head {
+            self.heads.push(Reverse((next_seq, idx)));
+        }
+        let op = std::mem::replace(&mut self.pending[idx], head.map(|(_, op)| op));
+        Some((seq, op.unwrap()))
+    }
 }
 
 /// Generates the finalized trace to `tracefile` with the `sha256` digest by
-/// aggregating intermediate generated traceops
-pub fn dump_global_trace(tracefile: &String, sha256: &str) -> io::Result<()> {
-    let mut dumpfile = File::create(tracefile)?;
-    let traceop_file = File::open(&*TMP_FILEPATH)?;
-    let mut trace_data = TraceData {
-        sha256: sha256,
-        trace: vec![],
-    };
+/// aggregating intermediate generated traceops, encoded with `encoding`, and
+/// ending with a [TraceOp::Terminate] record for `termination` (if known)
+///
+/// Every complete op is salvaged from the intermediate files. The trace is
+/// marked truncated if any op was lost, or if the engine did not terminate
+/// through an exit or trap (e.g. killed by a signal)
+///
+/// Errors recorded by the engine process during recording are reported, but
+/// do not fail trace generation
+///
+/// ### Design Notes
+/// Each per-thread intermediate file is already in sequence order, so ops are
+/// k-way merged by sequence number ([SeqMerge]) to recover the observed global
+/// order. Ops are streamed into the final trace, so memory use stays bounded
+/// regardless of trace length
+pub fn dump_global_trace(
+    tracefile: &Path,
+    header: &TraceHeader,
+    termination: Option<Termination>,
+) -> Result<(), R3Error> {
+    merge_intermediate_traces(&TMP_DIRPATH, tracefile, header, termination)
+}
+
+/// [dump_global_trace] from the intermediate files in `dir`, which is removed
+/// once merged
+fn merge_intermediate_traces(
+    dir: &Path,
+    tracefile: &Path,
+    header: &TraceHeader,
+    termination: Option<Termination>,
+) -> Result<(), R3Error> {
+    let dumpfile = BufWriter::new(File::create(tracefile)?);
+    let mut writer = TraceWriter::new(dumpfile, header)?;
 
-    // Read each traceop from the intermediate file and convert to final trace
-    // format
-    while !is_at_eof(&traceop_file)? {
-        let top = postcard::from_io((&traceop_file, &mut [0; 0])).unwrap();
-        trace_data.trace.push(top.0);
+    let mut readers: Vec<ThreadOpsReader> = Vec::new();
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "ops") {
+            continue;
+        }
+        readers.push(ThreadOpsReader::open(path)?);
     }
-    let ser = trace_data.serialize();
-    dumpfile.write_all(&ser)?;
+    debug!("Merging intermediate traces from {} threads", readers.len());
 
-    // Cleanup the temporary file
-    remove_file(&*TMP_FILEPATH)?;
+    let mut merged = SeqMerge::new(readers);
+    let mut num_ops: usize = 0;
+    let mut expected_seq: u64 = 0;
+    let mut truncated = !matches!(
+        termination,
+        Some(Termination::Exit { .. } | Termination::Trap { .. })
+    );
+    for (seq, op) in merged.by_ref() {
+        if seq != expected_seq {
+            warn!(
+                "Missing traceops between sequence numbers {} and {}",
+                expected_seq, seq
+            );
+            truncated = true;
+        }
+        expected_seq = seq + 1;
+        writer.push(&op)?;
+        num_ops += 1;
+    }
+    if let Some(kind) = termination {
+        info!("Recorded termination: {}", kind);
+        writer.push(&TraceOp::Terminate { kind })?;
+        num_ops += 1;
+    }
+    truncated |= merged.threads().iter().any(|r| r.truncated);
+    if truncated {
+        warn!("Trace is truncated; salvaged {} traceops", num_ops);
+        writer.set_truncated();
+    }
+    writer.finish()?;
+
+    match fs::read_to_string(dir.join(ERRORS_FILENAME)) {
+        Ok(report) => {
+            for line in report.lines() {
+                error!("Recording error: {}", line);
+            }
+            warn!(
+                "{} errors occurred during recording; the trace may not replay faithfully",
+                report.lines().count()
+            );
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+
+    // Cleanup the temporary files
+    remove_dir_all(dir)?;
 
     // Verify serialization can be effectively deserialized
-    let deserialized = TraceData::deserialize(&ser, None);
-    assert_eq!(*trace_data.trace, deserialized.trace);
+    let mut reader = TraceReader::new(BufReader::new(File::open(tracefile)?))?;
+    let mut num_deser: usize = 0;
+    for op in reader.by_ref() {
+        op?;
+        num_deser += 1;
+    }
+    if num_ops != num_deser {
+        return Err(R3Error::Verification(format!(
+            "wrote {} traceops, but read back {}",
+            num_ops, num_deser
+        )));
+    }
+    if truncated != reader.is_truncated() {
+        return Err(R3Error::Verification(String::from(
+            "truncation flag was not preserved",
+        )));
+    }
     Ok(())
 }
 
@@ -106,7 +520,7 @@ pub extern "C" fn wasm_memop_tracedump(
             differ: differ != 0,
         };
         debug!("[{:>18}] [Trace SYNCACCESS] {}", tid, sync_access);
-        append_traceop(sync_access);
+        append_traceop(tid, sync_access);
     }
     // Non-Synchronized operations are only traced when diff
     else if differ != 0 {
@@ -121,7 +535,7 @@ pub extern "C" fn wasm_memop_tracedump(
             differ: differ != 0,
         };
         debug!("[{:>18}] [Trace ACCESS] {} | Diff? {}", tid, access, differ);
-        append_traceop(access);
+        append_traceop(tid, access);
     }
 }
 
@@ -143,7 +557,10 @@ pub extern "C" fn wasm_call_tracedump(
     if opcode != WasmOpcode::Call as i32 {
         warn!("[{} | {:#04X}] Unexpected opcode", access_idx, opcode);
     }
-    let call_id = CallID::from_parts(call_id, [a1, a2, a3]).unwrap();
+    let call_id = CallID::from_parts(call_id, [a1, a2, a3]).unwrap_or_else(|e| {
+        record_error(e);
+        CallID::ScUnknown
+    });
     let call_trace = TraceOp::Call {
         tid,
         access_idx,
@@ -160,5 +577,242 @@ pub extern "C" fn wasm_call_tracedump(
             };
         }
     }
-    append_traceop(call_trace);
+    append_traceop(tid, call_trace);
+    // Payload immediately follows its call in the observed order. Only the
+    // bytes written are captured, and failed writes output nothing
+    if CAPTURE_OUTPUT.load(Ordering::Relaxed) {
+        if let (CallID::ScWritev { fd, iov, iovcnt }, Ok(len)) =
+            (call_id, usize::try_from(return_val))
+        {
+            match unsafe { read_wali_iovec_bytes(exec_env, iov as WasmAddr, iovcnt as i32, len) } {
+                Some(data) => append_traceop(tid, TraceOp::Output { tid, fd, data }),
+                None => record_error(R3Error::Engine(format!(
+                    "[{}] writev iovec at {:#X} is out of bounds",
+                    access_idx, iov
+                ))),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(tid: u64, access_idx: u32) -> TraceOp {
+        TraceOp::Call {
+            tid,
+            access_idx,
+            opcode: WasmOpcode::Call as i32,
+            func_idx: 0,
+            return_val: 0,
+            call_id: CallID::ScGeneric,
+        }
+    }
+
+    /// Ops of thread `tid` with the given sequence numbers
+    fn thread(tid: u64, seqs: &[u64]) -> Vec<SeqTraceOp> {
+        seqs.iter()
+            .map(|seq| (*seq, call(tid, *seq as u32)))
+            .collect()
+    }
+
+    #[test]
+    fn merge_interleaves_threads() {
+        let threads = vec![
+            thread(1, &[0, 3, 4]),
+            thread(2, &[1, 5]),
+            thread(3, &[]),
+            thread(4, &[2, 6]),
+        ];
+        let merged: Vec<SeqTraceOp> =
+            SeqMerge::new(threads.into_iter().map(Vec::into_iter).collect()).collect();
+        let order: Vec<(u64, Option<u64>)> =
+            merged.iter().map(|(seq, op)| (*seq, op.tid())).collect();
+        assert_eq!(
+            order,
+            [
+                (0, Some(1)),
+                (1, Some(2)),
+                (2, Some(4)),
+                (3, Some(1)),
+                (4, Some(1)),
+                (5, Some(2)),
+                (6, Some(4))
+            ]
+        );
+        assert_eq!(merged[3].1, call(1, 3));
+
+        let none: Vec<std::vec::IntoIter<SeqTraceOp>> = Vec::new();
+        assert_eq!(SeqMerge::new(none).count(), 0);
+    }
+
+    #[test]
+    fn buffer_hands_off_when_full() {
+        let (tx, rx) = sync_channel(WRITER_QUEUE_DEPTH);
+        let mut buf = ThreadTraceBuffer::new();
+        for seq in 0..THREAD_BUFFER_OPS as u64 + 1 {
+            buf.push(1, seq, call(1, 0), &tx);
+        }
+        let full: Vec<SeqTraceOp> = (0..THREAD_BUFFER_OPS as u64)
+            .map(|seq| (seq, call(1, 0)))
+            .collect();
+        assert!(matches!(rx.try_recv(), Ok(WriterMsg::Batch(1, ops)) if ops == full));
+        assert!(rx.try_recv().is_err());
+        buf.flush(&tx);
+        assert!(matches!(rx.try_recv(), Ok(WriterMsg::Batch(1, ops)) if ops.len() == 1));
+    }
+
+    #[test]
+    fn last_access_idx_skips_outputs() {
+        let (tx, _rx) = sync_channel(WRITER_QUEUE_DEPTH);
+        let mut buf = ThreadTraceBuffer::new();
+        buf.push(1, 0, call(1, 7), &tx);
+        let output = |tid: u64| TraceOp::Output {
+            tid,
+            fd: 1,
+            data: b"hi".to_vec(),
+        };
+        buf.push(1, 1, output(1), &tx);
+        assert_eq!(buf.last_access_idx, Some(7));
+        // Not carried over to another Wasm thread on the same native thread
+        buf.push(2, 2, output(2), &tx);
+        assert_eq!(buf.last_access_idx, None);
+    }
+
+    #[test]
+    fn reused_thread_writes_per_tid() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, rx) = sync_channel(WRITER_QUEUE_DEPTH);
+        let writer = {
+            let dir = dir.path().to_path_buf();
+            thread::spawn(move || trace_writer_loop(rx, &dir))
+        };
+        // A native thread running Wasm thread 1, then thread 2, then 1 again
+        let mut buf = ThreadTraceBuffer::new();
+        for (tid, seq) in [(1, 0), (1, 2), (2, 3), (2, 4), (1, 6)] {
+            buf.push(tid, seq, call(tid, seq as u32), &tx);
+        }
+        buf.flush(&tx);
+        // Another native thread
+        let mut other = ThreadTraceBuffer::new();
+        for (tid, seq) in [(3, 1), (3, 5)] {
+            other.push(tid, seq, call(tid, seq as u32), &tx);
+        }
+        other.flush(&tx);
+        tx.send(WriterMsg::Finish).unwrap();
+        writer.join().unwrap();
+
+        let read = |tid: u64| -> Vec<SeqTraceOp> {
+            ThreadOpsReader::open(thread_filepath(dir.path(), tid))
+                .unwrap()
+                .collect()
+        };
+        assert_eq!(read(1), thread(1, &[0, 2, 6]));
+        assert_eq!(read(2), thread(2, &[3, 4]));
+        assert_eq!(read(3), thread(3, &[1, 5]));
+
+        let readers = (1..=3)
+            .map(|tid| ThreadOpsReader::open(thread_filepath(dir.path(), tid)).unwrap())
+            .collect();
+        let merged: Vec<u64> = SeqMerge::new(readers).map(|(seq, _)| seq).collect();
+        assert_eq!(merged, (0..7).collect::<Vec<_>>());
+    }
+
+    /// Write intermediate files of `threads` to a new directory, the last
+    /// thread's cut short by `cut` bytes
+    fn intermediate(threads: &[Vec<SeqTraceOp>], cut: usize) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (idx, ops) in threads.iter().enumerate() {
+            let tid = ops.first().and_then(|(_, op)| op.tid()).unwrap();
+            let mut ser = Vec::new();
+            for op in ops {
+                ser.extend(postcard::to_stdvec(op).unwrap());
+            }
+            if idx == threads.len() - 1 {
+                ser.truncate(ser.len() - cut);
+            }
+            fs::write(thread_filepath(dir.path(), tid), ser).unwrap();
+        }
+        dir
+    }
+
+    /// Merge the intermediate files in `dir` into a trace, returning its ops
+    /// and whether it is truncated
+    fn merge(dir: tempfile::TempDir, termination: Option<Termination>) -> (Vec<TraceOp>, bool) {
+        let out = tempfile::tempdir().unwrap();
+        let tracefile = out.path().join("trace.r3");
+        merge_intermediate_traces(
+            dir.path(),
+            &tracefile,
+            &TraceHeader::new("sha"),
+            termination,
+        )
+        .unwrap();
+        assert!(!dir.path().exists());
+        let mut reader = TraceReader::new(BufReader::new(File::open(&tracefile).unwrap())).unwrap();
+        let ops = reader.by_ref().collect::<Result<_, _>>().unwrap();
+        (ops, reader.is_truncated())
+    }
+
+    const EXIT: Termination = Termination::Exit { code: 0 };
+
+    #[test]
+    fn merge_complete() {
+        let dir = intermediate(&[thread(1, &[0, 2]), thread(2, &[1, 3])], 0);
+        let (ops, truncated) = merge(dir, Some(EXIT));
+        let expected = vec![
+            call(1, 0),
+            call(2, 1),
+            call(1, 2),
+            call(2, 3),
+            TraceOp::Terminate { kind: EXIT },
+        ];
+        assert_eq!(ops, expected);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn salvage_partial_record() {
+        // The last op of thread 2 was only partially written
+        let dir = intermediate(&[thread(1, &[0, 2]), thread(2, &[1, 3])], 1);
+        fs::write(dir.path().join("notes.txt"), "not an intermediate file").unwrap();
+        let (ops, truncated) = merge(dir, Some(EXIT));
+        let salvaged = [call(1, 0), call(2, 1), call(1, 2)];
+        assert_eq!(ops[..3], salvaged);
+        assert_eq!(ops[3..], [TraceOp::Terminate { kind: EXIT }]);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn salvage_sequence_gap() {
+        // Ops 2 and 3 were lost, e.g. in a batch the writer failed to persist
+        let dir = intermediate(&[thread(1, &[0, 4]), thread(2, &[1, 5])], 0);
+        let (ops, truncated) = merge(dir, Some(EXIT));
+        assert_eq!(ops.len(), 5);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn salvage_without_termination() {
+        let dir = intermediate(&[thread(1, &[0, 1])], 0);
+        let (ops, truncated) = merge(dir, None);
+        assert_eq!(ops, [call(1, 0), call(1, 1)]);
+        assert!(truncated);
+
+        // Killed by a signal: the termination is kept, but the trace is incomplete
+        let signal = Termination::Signal { signo: 9 };
+        let (ops, truncated) = merge(intermediate(&[thread(1, &[0])], 0), Some(signal.clone()));
+        assert_eq!(ops, [call(1, 0), TraceOp::Terminate { kind: signal }]);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn salvage_with_recording_errors() {
+        let dir = intermediate(&[thread(1, &[0])], 0);
+        fs::write(dir.path().join(ERRORS_FILENAME), "Failed to write\n").unwrap();
+        let (ops, truncated) = merge(dir, Some(EXIT));
+        assert_eq!(ops.len(), 2);
+        assert!(!truncated);
+    }
 }
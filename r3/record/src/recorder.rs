@@ -0,0 +1,267 @@
+//! Recording a Wasm module's execution in a forked WAMR engine
+use libc::c_void;
+use log::{error, info, warn};
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{fork, gethostname, ForkResult};
+use serde::{Deserialize, Serialize};
+use sha256::digest;
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::process;
+use std::sync::Mutex;
+use tempfile::NamedTempFile;
+
+use wamr_rust_sdk::{instance::Instance, module::Module, runtime::Runtime};
+
+use wamr_rust_sdk::{log_level_t, LOG_LEVEL_WARNING};
+
+use common::instrument::{InstrumentArgs, InstrumentedModule};
+use common::trace::{Provenance, Termination, TraceData, TraceEncoding, TraceError, TraceHeader};
+use common::R3Error;
+
+use crate::record_interface::{
+    dump_global_trace, finish_trace, initialize_tmpdir, install_exit_flush, set_output_capture,
+    trap_termination, wasm_call_tracedump, wasm_memop_tracedump,
+};
+
+/// Serializes recordings within a process
+///
+/// ### Design Notes
+/// Intermediate traceops and output capture are process-wide state in
+/// [record_interface](crate::record_interface), so only one recording may be
+/// in flight at a time
+static RECORD_LOCK: Mutex<()> = Mutex::new(());
+
+/// Options of a recording; see `record -h` for their CLI counterparts
+#[derive(Debug, Clone)]
+pub struct RecordOptions {
+    /// Instrumentation scheme
+    pub scheme: String,
+    /// Instrumentation arguments
+    pub instargs: Vec<String>,
+    /// Log-level within the Wasm engine
+    pub log_level: log_level_t,
+    /// Compress the trace
+    pub compress: bool,
+    /// Capture bytes written through `writev` for output verification
+    pub capture_output: bool,
+    /// Store the recording's environment variables in the trace provenance
+    pub record_env: bool,
+}
+impl Default for RecordOptions {
+    fn default() -> Self {
+        RecordOptions {
+            scheme: String::from("r3-record"),
+            instargs: vec![],
+            log_level: LOG_LEVEL_WARNING,
+            compress: false,
+            capture_output: false,
+            record_env: false,
+        }
+    }
+}
+
+/// Outcome reported by the engine process, when it did not exit normally
+#[derive(Serialize, Deserialize)]
+enum EngineReport {
+    /// The module trapped
+    Trap(Termination),
+    /// The engine could not instantiate the module
+    Failed(String),
+}
+
+/// A Wasm module instrumented for recording, which can be executed (and
+/// recorded) any number of times
+pub struct Recorder {
+    options: RecordOptions,
+    /// Digest of the original module
+    sha256: String,
+    instrumented: Vec<u8>,
+}
+
+impl Recorder {
+    /// Instrument module `wasm` with `options.scheme`
+    pub fn new(wasm: &[u8], options: RecordOptions) -> Result<Self, R3Error> {
+        let args: Vec<&str> = options.instargs.iter().map(|s| s.as_str()).collect();
+        let instrumented = InstrumentedModule::new(
+            wasm,
+            options.scheme.as_str(),
+            InstrumentArgs::Generic(&args[..]),
+        )?
+        .to_vec();
+        Ok(Recorder {
+            options,
+            sha256: digest(wasm),
+            instrumented,
+        })
+    }
+
+    /// The instrumented module
+    pub fn instrumented(&self) -> &[u8] {
+        &self.instrumented
+    }
+
+    /// Record an execution with `argv` (program name + arguments), returning
+    /// the trace
+    pub fn record(&self, argv: &[String]) -> Result<TraceData, R3Error> {
+        let tracefile = NamedTempFile::new()?;
+        self.record_to_file(argv, tracefile.path())?;
+        TraceData::deserialize(&fs::read(tracefile.path())?, Some(self.sha256.as_str()))
+    }
+
+    /// Record an execution with `argv` (program name + arguments), streaming
+    /// the trace to `tracefile`
+    ///
+    /// Returns how the engine terminated, if known. A trapping module is a
+    /// successful recording; only failures to record are errors
+    ///
+    /// ### Design Notes
+    /// The engine runs in a forked process, so guest `proc_exit`, traps and
+    /// crashes cannot take down the caller, and WAMR's process-wide state
+    /// stays isolated from any runtime the caller hosts. As with any `fork`
+    /// of a multithreaded process, only the calling thread is duplicated
+    pub fn record_to_file(
+        &self,
+        argv: &[String],
+        tracefile: &Path,
+    ) -> Result<Option<Termination>, R3Error> {
+        if argv.is_empty() {
+            return Err(R3Error::Engine(String::from("argv has no program name")));
+        }
+        let _guard = RECORD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let started_ms = Provenance::now_ms();
+
+        // This needs to be done before fork to prevent double initialization
+        // of Lazy
+        initialize_tmpdir()?;
+        set_output_capture(self.options.capture_output);
+        // Channel for the engine to report traps back to the parent
+        let (mut trap_rx, trap_tx) = UnixStream::pair()?;
+        let termination = match unsafe { fork() }.map_err(io::Error::from)? {
+            ForkResult::Child => {
+                drop(trap_rx);
+                self.engine_process(argv, trap_tx)
+            }
+            ForkResult::Parent { child } => {
+                drop(trap_tx);
+                let status = waitpid(child, None).map_err(io::Error::from)?;
+                let mut report_ser = Vec::new();
+                trap_rx.read_to_end(&mut report_ser)?;
+                let report: Option<EngineReport> = (!report_ser.is_empty())
+                    .then(|| postcard::from_bytes(&report_ser))
+                    .transpose()
+                    .map_err(TraceError::from)?;
+                match status {
+                    WaitStatus::Exited(pid, code) => {
+                        info!("Wasm engine (PID: {}) exited with status: {}", pid, code);
+                        match report {
+                            None => Some(Termination::Exit { code }),
+                            Some(EngineReport::Trap(trap)) => Some(trap),
+                            Some(EngineReport::Failed(msg)) => return Err(R3Error::Engine(msg)),
+                        }
+                    }
+                    WaitStatus::Signaled(pid, signal, _) => {
+                        warn!("Wasm engine (PID: {}) killed by signal: {:?}", pid, signal);
+                        Some(Termination::Signal {
+                            signo: signal as i32,
+                        })
+                    }
+                    status => {
+                        warn!("Wasm engine exited with bad status: {:?}", status);
+                        None
+                    }
+                }
+            }
+        };
+
+        let header = TraceHeader {
+            encoding: if self.options.compress {
+                TraceEncoding::Compressed
+            } else {
+                TraceEncoding::Plain
+            },
+            provenance: Some(self.provenance(argv, started_ms)),
+            ..TraceHeader::new(self.sha256.as_str())
+        };
+        dump_global_trace(tracefile, &header, termination.clone())?;
+        Ok(termination)
+    }
+
+    /// Body of the forked engine process, reporting to `trap_tx` unless the
+    /// module exits normally
+    ///
+    /// Never returns to the caller of [record_to_file](Self::record_to_file)
+    fn engine_process(&self, argv: &[String], mut trap_tx: UnixStream) -> ! {
+        info!("Wasm engine executing with PID: {}", process::id());
+        // Flush the trace on every exit path, including guest `proc_exit`
+        install_exit_flush();
+        let report = match panic::catch_unwind(AssertUnwindSafe(|| self.execute(argv))) {
+            Ok(Ok(())) => {
+                info!("Wasm module safely exited from child process");
+                None
+            }
+            Ok(Err(report)) => Some(report),
+            Err(_) => Some(EngineReport::Failed(String::from("engine panicked"))),
+        };
+        finish_trace();
+        let exit_code = match report {
+            None => 0,
+            Some(report) => {
+                if let Err(e) = postcard::to_io(&report, &mut trap_tx) {
+                    error!("Failed to report engine outcome: {}", e);
+                }
+                1
+            }
+        };
+        process::exit(exit_code);
+    }
+
+    /// Instantiate and run the instrumented module with `argv` in WAMR
+    fn execute(&self, argv: &[String]) -> Result<(), EngineReport> {
+        let runtime = Runtime::builder()
+            .use_system_allocator()
+            .set_host_function_module_name("instrument")
+            .register_host_function("memop_tracedump", wasm_memop_tracedump as *mut c_void)
+            .register_host_function("call_tracedump", wasm_call_tracedump as *mut c_void)
+            .set_max_thread_num(100)
+            .build()
+            .map_err(|e| EngineReport::Failed(e.to_string()))?;
+        runtime.set_log_level(self.options.log_level);
+        let module = Module::from_buf(&runtime, &self.instrumented, &argv[0])
+            .map_err(|e| EngineReport::Failed(e.to_string()))?;
+        let instance = Instance::new(&runtime, &module, 1024 * 256)
+            .map_err(|e| EngineReport::Failed(e.to_string()))?;
+
+        if let Err(e) = instance.execute_main(&argv.to_vec()) {
+            warn!("Wasm module terminated with error: {}", e);
+            return Err(EngineReport::Trap(trap_termination(e.to_string())));
+        }
+        Ok(())
+    }
+
+    /// Provenance of a recording of `argv` started at `started_ms`
+    fn provenance(&self, argv: &[String], started_ms: u64) -> Provenance {
+        Provenance {
+            argv: argv.to_vec(),
+            env: if self.options.record_env {
+                env::vars().map(|(k, v)| format!("{}={}", k, v)).collect()
+            } else {
+                vec![]
+            },
+            scheme: self.options.scheme.clone(),
+            instargs: self.options.instargs.clone(),
+            log_level: self.options.log_level,
+            tool_version: format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+            host: gethostname()
+                .map(|h| h.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            target: format!("{}-{}", env::consts::ARCH, env::consts::OS),
+            started_ms,
+            finished_ms: Provenance::now_ms(),
+        }
+    }
+}
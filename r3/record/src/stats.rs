@@ -0,0 +1,334 @@
+//! Summary statistics over a trace, for `deserialize --summary`
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use common::trace::{CallID, TraceOp};
+
+/// Number of most frequent return values shown per call kind
+const TOP_RETURN_VALS: usize = 5;
+
+/// Op counts of a single thread
+#[derive(Default)]
+struct ThreadStats {
+    accesses: u64,
+    sync_accesses: u64,
+    calls: u64,
+    outputs: u64,
+}
+
+/// Call counts and return values of a single call kind
+#[derive(Default)]
+struct CallStats {
+    count: u64,
+    return_vals: HashMap<i64, u64>,
+}
+
+/// Differing (i.e. replayed) stores attributed to a call kind
+#[derive(Default)]
+struct StoreStats {
+    count: u64,
+    bytes: u64,
+}
+
+/// Statistics accumulated op-by-op over a trace
+#[derive(Default)]
+pub struct TraceStats {
+    ops: u64,
+    threads: BTreeMap<u64, ThreadStats>,
+    calls: BTreeMap<&'static str, CallStats>,
+    /// Differing accesses per `access_idx`
+    differ_sites: HashMap<u32, u64>,
+    stores: BTreeMap<&'static str, StoreStats>,
+    /// Call kind that differing accesses are currently attributed to
+    store_target: Option<&'static str>,
+    output_bytes: u64,
+    terminated: bool,
+}
+
+impl TraceStats {
+    /// Account for the next op in the trace
+    pub fn add(&mut self, op: &TraceOp) {
+        self.ops += 1;
+        if let Some(tid) = op.tid() {
+            let thread = self.threads.entry(tid).or_default();
+            match op {
+                TraceOp::Access { .. } => thread.accesses += 1,
+                TraceOp::SyncAccess { .. } => thread.sync_accesses += 1,
+                TraceOp::Call { .. } => thread.calls += 1,
+                TraceOp::Output { .. } => thread.outputs += 1,
+                TraceOp::Terminate { .. } => {}
+            }
+        }
+        match op {
+            TraceOp::Call {
+                return_val,
+                call_id,
+                ..
+            } => {
+                let call = self.calls.entry(call_id.name()).or_default();
+                call.count += 1;
+                *call.return_vals.entry(*return_val).or_default() += 1;
+                // Mirrors `replay`: stores are mapped to the last call that
+                // may cause them, or the first op queued before any such call
+                match call_id {
+                    CallID::ScGeneric | CallID::ScMmap { .. } => {
+                        self.store_target = Some(call_id.name())
+                    }
+                    _ => {
+                        self.store_target.get_or_insert(call_id.name());
+                    }
+                }
+            }
+            TraceOp::Access {
+                access_idx,
+                size,
+                differ,
+                ..
+            }
+            | TraceOp::SyncAccess {
+                access_idx,
+                size,
+                differ,
+                ..
+            } => {
+                if *differ {
+                    *self.differ_sites.entry(*access_idx).or_default() += 1;
+                    let stores = self
+                        .stores
+                        .entry(self.store_target.unwrap_or("<none>"))
+                        .or_default();
+                    stores.count += 1;
+                    stores.bytes += *size as u64;
+                }
+                if let TraceOp::SyncAccess { .. } = op {
+                    self.store_target.get_or_insert("SyncAccess");
+                }
+            }
+            TraceOp::Output { data, .. } => self.output_bytes += data.len() as u64,
+            TraceOp::Terminate { .. } => self.terminated = true,
+        }
+    }
+
+    /// Write the summary report, listing the `top` most differing access sites
+    pub fn report<W: Write>(&self, out: &mut W, top: usize) -> io::Result<()> {
+        writeln!(out, "Total ops: {}", self.ops)?;
+        if self.output_bytes > 0 {
+            writeln!(out, "Captured output: {} bytes", self.output_bytes)?;
+        }
+        if !self.terminated {
+            writeln!(out, "Termination: not recorded")?;
+        }
+
+        writeln!(out, "\n== Ops per thread ==")?;
+        writeln!(
+            out,
+            "{:>18} {:>12} {:>12} {:>12} {:>12}",
+            "TID", "Access", "SyncAccess", "Call", "Output"
+        )?;
+        for (tid, thread) in self.threads.iter() {
+            writeln!(
+                out,
+                "{:>18} {:>12} {:>12} {:>12} {:>12}",
+                tid, thread.accesses, thread.sync_accesses, thread.calls, thread.outputs
+            )?;
+        }
+
+        writeln!(out, "\n== Calls ==")?;
+        for (name, call) in self.calls.iter() {
+            let mut return_vals: Vec<(&i64, &u64)> = call.return_vals.iter().collect();
+            return_vals.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            let (min, max) = (
+                call.return_vals.keys().min().unwrap(),
+                call.return_vals.keys().max().unwrap(),
+            );
+            writeln!(
+                out,
+                "{:>14}: {} calls, {} distinct return values in [{}, {}]",
+                name,
+                call.count,
+                call.return_vals.len(),
+                min,
+                max
+            )?;
+            for (val, count) in return_vals.iter().take(TOP_RETURN_VALS) {
+                writeln!(out, "{:>16}{:#X} x {}", "", val, count)?;
+            }
+        }
+
+        writeln!(out, "\n== Store data per call kind ==")?;
+        for (name, stores) in self.stores.iter() {
+            writeln!(
+                out,
+                "{:>14}: {} stores, {} bytes",
+                name, stores.count, stores.bytes
+            )?;
+        }
+
+        writeln!(out, "\n== Top {} differing access sites ==", top)?;
+        let mut sites: Vec<(&u32, &u64)> = self.differ_sites.iter().collect();
+        sites.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (access_idx, count) in sites.iter().take(top) {
+            writeln!(out, "{:>14}: {}", access_idx, count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::trace::{FutexOp, Termination};
+
+    fn call(tid: u64, call_id: CallID, return_val: i64) -> TraceOp {
+        TraceOp::Call {
+            tid,
+            access_idx: 0,
+            opcode: 0x10,
+            func_idx: 0,
+            return_val,
+            call_id,
+        }
+    }
+
+    fn access(access_idx: u32, size: u32, differ: bool) -> TraceOp {
+        TraceOp::Access {
+            tid: 1,
+            access_idx,
+            opcode: 0x28,
+            addr: 0,
+            size,
+            load_value: 0,
+            expected_value: 0,
+            differ,
+        }
+    }
+
+    fn collect(ops: &[TraceOp]) -> TraceStats {
+        let mut stats = TraceStats::default();
+        for op in ops {
+            stats.add(op);
+        }
+        stats
+    }
+
+    fn report(stats: &TraceStats, top: usize) -> String {
+        let mut out = Vec::new();
+        stats.report(&mut out, top).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn op_counts() {
+        let writev = CallID::ScWritev {
+            fd: 1,
+            iov: 0,
+            iovcnt: 1,
+        };
+        let stats = collect(&[
+            access(0, 4, false),
+            call(1, writev, 3),
+            TraceOp::Output {
+                tid: 1,
+                fd: 1,
+                data: b"hi\n".to_vec(),
+            },
+            call(2, CallID::ScGeneric, 0),
+            TraceOp::Terminate {
+                kind: Termination::Exit { code: 0 },
+            },
+        ]);
+        assert_eq!(stats.ops, 5);
+        let counts = |tid: u64| {
+            let thread = &stats.threads[&tid];
+            (
+                thread.accesses,
+                thread.sync_accesses,
+                thread.calls,
+                thread.outputs,
+            )
+        };
+        assert_eq!(counts(1), (1, 0, 1, 1));
+        assert_eq!(counts(2), (0, 0, 1, 0));
+        assert_eq!(stats.output_bytes, 3);
+        assert!(stats.terminated);
+
+        let report = report(&stats, 5);
+        assert!(
+            report.starts_with("Total ops: 5\nCaptured output: 3 bytes\n"),
+            "{}",
+            report
+        );
+        assert!(!report.contains("Termination"), "{}", report);
+    }
+
+    #[test]
+    fn store_attribution() {
+        let futex = CallID::ScFutex {
+            addr: 0,
+            op: FutexOp::Wait,
+            val: 0,
+        };
+        let stats = collect(&[
+            access(0, 4, true),
+            call(1, CallID::ScUnknown, 0),
+            access(0, 8, true),
+            // Only generic and mmap calls take over later stores
+            call(1, CallID::ScMmap { grow: 1 }, 0),
+            access(1, 2, true),
+            access(1, 2, false),
+            call(1, futex, 0),
+            access(1, 1, true),
+        ]);
+        let stores = |name: &str| {
+            let stores = &stats.stores[name];
+            (stores.count, stores.bytes)
+        };
+        assert_eq!(stores("<none>"), (1, 4));
+        assert_eq!(stores("ScUnknown"), (1, 8));
+        assert_eq!(stores("ScMmap"), (2, 3));
+        assert_eq!(stats.stores.len(), 3);
+        assert_eq!(stats.differ_sites, HashMap::from([(0, 2), (1, 2)]));
+
+        let stats = collect(&[
+            TraceOp::SyncAccess {
+                tid: 1,
+                access_idx: 0,
+                opcode: 0xFE,
+                addr: 0,
+                size: 4,
+                load_value: 0,
+                expected_value: 0,
+                differ: false,
+            },
+            access(1, 4, true),
+        ]);
+        assert_eq!(stats.stores["SyncAccess"].count, 1);
+    }
+
+    #[test]
+    fn report_rankings() {
+        let mut ops: Vec<TraceOp> = [3, 1, 1, 2, 2, 7, 0, -1]
+            .into_iter()
+            .map(|return_val| call(1, CallID::ScGeneric, return_val))
+            .collect();
+        ops.extend([access(5, 4, true), access(9, 4, true), access(9, 4, true)]);
+        ops.push(access(2, 4, true));
+        let report = report(&collect(&ops), 2);
+        assert!(report.contains("Termination: not recorded\n"), "{}", report);
+        // Ties are broken by value, and only the top values are listed
+        let calls = "     ScGeneric: 8 calls, 6 distinct return values in [-1, 7]\n\
+                     \x20               0x1 x 2\n\
+                     \x20               0x2 x 2\n\
+                     \x20               0xFFFFFFFFFFFFFFFF x 1\n\
+                     \x20               0x0 x 1\n\
+                     \x20               0x3 x 1\n";
+        assert!(report.contains(calls), "{}", report);
+        assert!(
+            report.contains("     ScGeneric: 4 stores, 16 bytes\n"),
+            "{}",
+            report
+        );
+        let sites = "== Top 2 differing access sites ==\n             9: 2\n             2: 1\n";
+        assert!(report.ends_with(sites), "{}", report);
+    }
+}
@@ -0,0 +1,620 @@
+//! Binary to compare two trace files generated by
+//! [`record`](../record/index.html)
+//!
+//! Ops are aligned per thread by their `access_idx` and [CallID] kind. Since
+//! thread IDs are generally not stable across recordings, threads are paired
+//! up in order of their first appearance in each trace. When the aligned ops
+//! stop matching, the diff attempts to resynchronize within a bounded window;
+//! ops skipped on either side are reported as missing/inserted.
+use clap::Parser;
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::io::BufReader;
+use std::mem::{self, Discriminant};
+use std::process;
+
+use common::trace::{CallID, Termination, TraceOp, TraceReader};
+
+/// Command-Line Arguments
+#[derive(Parser, Debug)]
+#[command(version, about, long_about=None)]
+struct CLI {
+    /// Number of ops to look ahead when resynchronizing diverged threads
+    #[arg(short, long, default_value_t = 256)]
+    window: usize,
+
+    /// Maximum number of differing values to print per category
+    #[arg(short, long, default_value_t = 20)]
+    max_report: usize,
+
+    /// Pair threads by their recorded thread ID instead of order of appearance
+    #[arg(long)]
+    match_tids: bool,
+
+    /// Baseline trace file generated by `record`
+    old_tracefile: String,
+
+    /// Trace file generated by `record` to compare against the baseline
+    new_tracefile: String,
+}
+
+impl CLI {
+    /// Print the CLI configuration
+    fn print(&self) {
+        info!("Old Tracefile: {:?}", self.old_tracefile);
+        info!("New Tracefile: {:?}", self.new_tracefile);
+        info!("Resync Window: {}", self.window);
+    }
+}
+
+/// A recorded trace, split by thread
+struct ThreadedTrace {
+    sha256: String,
+    /// Ops of each thread in order, with the position of each op in the trace
+    threads: BTreeMap<u64, Vec<(usize, TraceOp)>>,
+    /// Threads in order of first appearance
+    thread_order: Vec<u64>,
+    termination: Option<Termination>,
+    truncated: bool,
+}
+
+impl ThreadedTrace {
+    fn load(tracefile: &str) -> Result<Self, Box<dyn Error>> {
+        let mut reader = TraceReader::new(BufReader::new(fs::File::open(tracefile)?))?;
+        let sha256 = reader.header().sha256.clone();
+        let mut threads: BTreeMap<u64, Vec<(usize, TraceOp)>> = BTreeMap::new();
+        let mut thread_order = Vec::new();
+        let mut termination = None;
+        for (pos, op) in reader.by_ref().enumerate() {
+            let op = op?;
+            match op.tid() {
+                Some(tid) => threads
+                    .entry(tid)
+                    .or_insert_with(|| {
+                        thread_order.push(tid);
+                        Vec::new()
+                    })
+                    .push((pos, op)),
+                None => {
+                    if let TraceOp::Terminate { kind } = op {
+                        termination = Some(kind);
+                    }
+                }
+            }
+        }
+        let truncated = reader.is_truncated();
+        if truncated {
+            warn!(
+                "Trace \"{}\" is truncated; differences may be spurious",
+                tracefile
+            );
+        }
+        Ok(ThreadedTrace {
+            sha256,
+            threads,
+            thread_order,
+            termination,
+            truncated,
+        })
+    }
+}
+
+/// Identity of an op used for alignment
+#[derive(PartialEq, Eq)]
+enum AlignKey {
+    Access(u32),
+    SyncAccess(u32),
+    Call(u32, Discriminant<CallID>),
+    Output(i32),
+    Terminate,
+}
+
+impl AlignKey {
+    fn of(op: &TraceOp) -> Self {
+        match op {
+            TraceOp::Access { access_idx, .. } => AlignKey::Access(*access_idx),
+            TraceOp::SyncAccess { access_idx, .. } => AlignKey::SyncAccess(*access_idx),
+            TraceOp::Call {
+                access_idx,
+                call_id,
+                ..
+            } => AlignKey::Call(*access_idx, mem::discriminant(call_id)),
+            TraceOp::Output { fd, .. } => AlignKey::Output(*fd),
+            TraceOp::Terminate { .. } => AlignKey::Terminate,
+        }
+    }
+}
+
+/// Name of a call for summarizing inserted/missing calls
+fn call_name(call_id: &CallID, func_idx: u32) -> String {
+    match call_id {
+        CallID::ScGeneric | CallID::ScUnknown => format!("{}[func {}]", call_id.name(), func_idx),
+        _ => call_id.name().to_string(),
+    }
+}
+
+/// A single difference between aligned threads
+#[derive(Clone, Copy)]
+struct Divergence<'a> {
+    /// Position in the old trace where the divergence occurs
+    pos: usize,
+    tid: (u64, u64),
+    old: Option<&'a TraceOp>,
+    new: Option<&'a TraceOp>,
+}
+
+/// Differences found between two traces
+#[derive(Default)]
+struct DiffReport<'a> {
+    first: Option<Divergence<'a>>,
+    return_values: Vec<Divergence<'a>>,
+    store_values: Vec<Divergence<'a>>,
+    other_values: Vec<Divergence<'a>>,
+    /// Calls present only in the old trace, by name
+    missing_calls: BTreeMap<String, usize>,
+    /// Calls present only in the new trace, by name
+    inserted_calls: BTreeMap<String, usize>,
+    missing_ops: usize,
+    inserted_ops: usize,
+    /// Thread pairs that could not be resynchronized
+    unaligned_threads: Vec<(u64, u64)>,
+    unpaired_threads: (Vec<u64>, Vec<u64>),
+}
+
+impl<'a> DiffReport<'a> {
+    fn diverge(&mut self, div: &Divergence<'a>) {
+        if self.first.as_ref().is_none_or(|first| div.pos < first.pos) {
+            self.first = Some(*div);
+        }
+    }
+
+    /// Compare two aligned ops
+    fn compare(&mut self, pos: usize, tid: (u64, u64), old: &'a TraceOp, new: &'a TraceOp) {
+        let div = Divergence {
+            pos,
+            tid,
+            old: Some(old),
+            new: Some(new),
+        };
+        match (old, new) {
+            (
+                TraceOp::Call {
+                    return_val: a,
+                    call_id: ca,
+                    ..
+                },
+                TraceOp::Call {
+                    return_val: b,
+                    call_id: cb,
+                    ..
+                },
+            ) => {
+                if a != b {
+                    self.diverge(&div);
+                    self.return_values.push(div);
+                } else if ca != cb {
+                    self.diverge(&div);
+                    self.other_values.push(div);
+                }
+            }
+            // Differing loads are replayed as stores of the loaded value
+            (
+                TraceOp::Access {
+                    addr: aa,
+                    load_value: a,
+                    ..
+                },
+                TraceOp::Access {
+                    addr: ab,
+                    load_value: b,
+                    ..
+                },
+            )
+            | (
+                TraceOp::SyncAccess {
+                    addr: aa,
+                    load_value: a,
+                    ..
+                },
+                TraceOp::SyncAccess {
+                    addr: ab,
+                    load_value: b,
+                    ..
+                },
+            ) => {
+                if a != b {
+                    self.diverge(&div);
+                    self.store_values.push(div);
+                } else if aa != ab {
+                    self.diverge(&div);
+                    self.other_values.push(div);
+                }
+            }
+            (old, new) => {
+                if old != new {
+                    self.diverge(&div);
+                    self.other_values.push(div);
+                }
+            }
+        }
+    }
+
+    /// Record ops present in only one of the traces
+    fn skip(
+        &mut self,
+        pos: usize,
+        tid: (u64, u64),
+        old: &'a [(usize, TraceOp)],
+        new: &'a [(usize, TraceOp)],
+    ) {
+        if old.is_empty() && new.is_empty() {
+            return;
+        }
+        self.diverge(&Divergence {
+            pos: old.first().map_or(pos, |(p, _)| *p),
+            tid,
+            old: old.first().map(|(_, op)| op),
+            new: new.first().map(|(_, op)| op),
+        });
+        for (ops, calls, count) in [
+            (old, &mut self.missing_calls, &mut self.missing_ops),
+            (new, &mut self.inserted_calls, &mut self.inserted_ops),
+        ] {
+            *count += ops.len();
+            for (_, op) in ops {
+                if let TraceOp::Call {
+                    func_idx, call_id, ..
+                } = op
+                {
+                    *calls.entry(call_name(call_id, *func_idx)).or_default() += 1;
+                }
+            }
+        }
+    }
+
+    /// Align and compare the ops of one thread in each trace
+    fn diff_thread(
+        &mut self,
+        tid: (u64, u64),
+        old: &'a [(usize, TraceOp)],
+        new: &'a [(usize, TraceOp)],
+        window: usize,
+    ) {
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() && j < new.len() {
+            if AlignKey::of(&old[i].1) == AlignKey::of(&new[j].1) {
+                self.compare(old[i].0, tid, &old[i].1, &new[j].1);
+                i += 1;
+                j += 1;
+                continue;
+            }
+            // Find the closest resynchronization point within the window
+            let resync = (1..=window).find_map(|dist| {
+                (0..=dist).find_map(|di| {
+                    let (a, b) = (old.get(i + di)?, new.get(j + dist - di)?);
+                    (AlignKey::of(&a.1) == AlignKey::of(&b.1)).then_some((di, dist - di))
+                })
+            });
+            match resync {
+                Some((di, dj)) => {
+                    self.skip(old[i].0, tid, &old[i..i + di], &new[j..j + dj]);
+                    i += di;
+                    j += dj;
+                }
+                None => {
+                    self.unaligned_threads.push(tid);
+                    break;
+                }
+            }
+        }
+        let pos = old.get(i).or(old.last()).map_or(0, |(p, _)| *p);
+        self.skip(pos, tid, &old[i..], &new[j..]);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.first.is_none()
+            && self.unpaired_threads.0.is_empty()
+            && self.unpaired_threads.1.is_empty()
+    }
+}
+
+fn print_divergence(div: &Divergence) {
+    println!("  Thread {} <-> {}", div.tid.0, div.tid.1);
+    let show = |op: Option<&TraceOp>| op.map_or(String::from("<none>"), |op| op.to_string());
+    println!("    - {}", show(div.old));
+    println!("    + {}", show(div.new));
+}
+
+fn print_divergences(title: &str, divs: &[Divergence], max_report: usize) {
+    println!("{}: {}", title, divs.len());
+    for div in divs.iter().take(max_report) {
+        print_divergence(div);
+    }
+    if divs.len() > max_report {
+        println!("  ... {} more", divs.len() - max_report);
+    }
+}
+
+fn print_calls(title: &str, calls: &BTreeMap<String, usize>) {
+    println!("{}: {}", title, calls.values().sum::<usize>());
+    for (name, count) in calls {
+        println!("  {:>8} x {}", count, name);
+    }
+}
+
+/// Entrypoint for `trace-diff`
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::builder().format_timestamp_millis().init();
+
+    let cli = CLI::parse();
+    cli.print();
+
+    let old = ThreadedTrace::load(cli.old_tracefile.as_str())?;
+    let new = ThreadedTrace::load(cli.new_tracefile.as_str())?;
+    if old.sha256 != new.sha256 {
+        warn!(
+            "Traces were recorded on different modules | Old: {}, New: {}",
+            old.sha256, new.sha256
+        );
+    }
+
+    // Pair up threads
+    let pairs: Vec<(u64, u64)> = if cli.match_tids {
+        old.thread_order
+            .iter()
+            .filter(|tid| new.threads.contains_key(tid))
+            .map(|tid| (*tid, *tid))
+            .collect()
+    } else {
+        old.thread_order
+            .iter()
+            .copied()
+            .zip(new.thread_order.iter().copied())
+            .collect()
+    };
+    for (a, b) in pairs.iter() {
+        info!("Pairing thread {} <-> {}", a, b);
+    }
+
+    let mut report = DiffReport::default();
+    for &(a, b) in pairs.iter() {
+        report.diff_thread((a, b), &old.threads[&a], &new.threads[&b], cli.window);
+    }
+    report.unpaired_threads = (
+        old.thread_order
+            .iter()
+            .filter(|tid| !pairs.iter().any(|(a, _)| a == *tid))
+            .copied()
+            .collect(),
+        new.thread_order
+            .iter()
+            .filter(|tid| !pairs.iter().any(|(_, b)| b == *tid))
+            .copied()
+            .collect(),
+    );
+
+    let termination_differs = match (&old.termination, &new.termination) {
+        (Some(a), Some(b)) => !a.same_outcome(b),
+        (a, b) => a.is_some() != b.is_some(),
+    };
+
+    if report.is_empty() && !termination_differs {
+        println!("Traces are equivalent");
+        return Ok(());
+    }
+
+    if let Some(ref first) = report.first {
+        println!("First divergence at op {} of old trace:", first.pos);
+        print_divergence(first);
+    }
+    print_divergences(
+        "Differing return values",
+        &report.return_values,
+        cli.max_report,
+    );
+    print_divergences(
+        "Differing store values",
+        &report.store_values,
+        cli.max_report,
+    );
+    print_divergences("Other differing ops", &report.other_values, cli.max_report);
+    println!(
+        "Ops only in old trace: {}, only in new trace: {}",
+        report.missing_ops, report.inserted_ops
+    );
+    print_calls("Missing calls", &report.missing_calls);
+    print_calls("Inserted calls", &report.inserted_calls);
+    for (a, b) in report.unaligned_threads.iter() {
+        println!(
+            "Thread {} <-> {} could not be resynchronized within {} ops",
+            a, b, cli.window
+        );
+    }
+    for tid in report.unpaired_threads.0.iter() {
+        println!("Thread {} only in old trace", tid);
+    }
+    for tid in report.unpaired_threads.1.iter() {
+        println!("Thread {} only in new trace", tid);
+    }
+    if termination_differs {
+        let show = |t: &Option<Termination>| {
+            t.as_ref()
+                .map_or(String::from("Unknown"), |t| t.to_string())
+        };
+        println!(
+            "Termination differs | Old: {}, New: {}",
+            show(&old.termination),
+            show(&new.termination)
+        );
+    }
+    if old.truncated || new.truncated {
+        println!("NOTE: At least one trace is truncated");
+    }
+
+    process::exit(1);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(access_idx: u32, call_id: CallID, return_val: i64) -> TraceOp {
+        TraceOp::Call {
+            tid: 1,
+            access_idx,
+            opcode: 0x10,
+            func_idx: 3,
+            return_val,
+            call_id,
+        }
+    }
+
+    fn load(access_idx: u32, addr: i32, load_value: i64) -> TraceOp {
+        TraceOp::Access {
+            tid: 1,
+            access_idx,
+            opcode: 0x28,
+            addr,
+            size: 4,
+            load_value,
+            expected_value: 0,
+            differ: true,
+        }
+    }
+
+    /// Ops of a thread, at consecutive positions from `from`
+    fn thread(from: usize, ops: Vec<TraceOp>) -> Vec<(usize, TraceOp)> {
+        (from..).zip(ops).collect()
+    }
+
+    fn ops() -> Vec<TraceOp> {
+        vec![
+            call(1, CallID::ScGeneric, 0),
+            load(2, 0x10, 5),
+            call(3, CallID::ScMmap { grow: 1 }, 7),
+            TraceOp::Output {
+                tid: 1,
+                fd: 1,
+                data: b"hi".to_vec(),
+            },
+        ]
+    }
+
+    #[test]
+    fn equivalent() {
+        let (old, new) = (thread(0, ops()), thread(0, ops()));
+        let mut report = DiffReport::default();
+        report.diff_thread((1, 2), &old, &new, 4);
+        assert!(report.is_empty());
+        assert_eq!((report.missing_ops, report.inserted_ops), (0, 0));
+    }
+
+    #[test]
+    fn differing_values() {
+        let old = thread(0, ops());
+        let mut new = ops();
+        new[0] = call(1, CallID::ScGeneric, -1);
+        new[1] = load(2, 0x10, 6);
+        new[2] = call(3, CallID::ScMmap { grow: 2 }, 7);
+        new[3] = TraceOp::Output {
+            tid: 1,
+            fd: 1,
+            data: b"ho".to_vec(),
+        };
+        let new = thread(0, new);
+        let mut report = DiffReport::default();
+        report.diff_thread((1, 1), &old, &new, 4);
+        assert_eq!(report.first.unwrap().pos, 0);
+        let positions = |divs: &[Divergence]| divs.iter().map(|div| div.pos).collect::<Vec<_>>();
+        assert_eq!(positions(&report.return_values), [0]);
+        assert_eq!(positions(&report.store_values), [1]);
+        assert_eq!(positions(&report.other_values), [2, 3]);
+        assert_eq!((report.missing_ops, report.inserted_ops), (0, 0));
+
+        // Loads of the same value from another address
+        let new = thread(0, vec![ops()[0].clone(), load(2, 0x20, 5)]);
+        let mut report = DiffReport::default();
+        report.diff_thread((1, 1), &old[..2], &new, 4);
+        assert!(report.store_values.is_empty());
+        assert_eq!(positions(&report.other_values), [1]);
+    }
+
+    #[test]
+    fn inserted_and_missing_ops() {
+        let old = thread(0, ops());
+        let mut new = ops();
+        new.insert(1, call(9, CallID::ScGeneric, 0));
+        new.insert(
+            2,
+            call(
+                9,
+                CallID::ScWritev {
+                    fd: 1,
+                    iov: 0,
+                    iovcnt: 1,
+                },
+                2,
+            ),
+        );
+        // The last op of the old trace is missing
+        new.pop();
+        let new = thread(0, new);
+        let mut report = DiffReport::default();
+        report.diff_thread((1, 1), &old, &new, 4);
+        let first = report.first.unwrap();
+        assert_eq!(first.pos, 1);
+        assert_eq!(first.old, None);
+        assert_eq!(first.new, Some(&new[1].1));
+        assert!(report.return_values.is_empty() && report.other_values.is_empty());
+        assert_eq!((report.missing_ops, report.inserted_ops), (1, 2));
+        assert!(report.missing_calls.is_empty());
+        assert_eq!(
+            report.inserted_calls,
+            BTreeMap::from([
+                ("ScGeneric[func 3]".to_string(), 1),
+                ("ScWritev".to_string(), 1)
+            ])
+        );
+        assert!(report.unaligned_threads.is_empty());
+    }
+
+    #[test]
+    fn resync_window() {
+        let old = thread(0, ops());
+        let mut new = ops();
+        new.splice(
+            1..1,
+            (10..13).map(|access_idx| call(access_idx, CallID::ScGeneric, 0)),
+        );
+        let new = thread(0, new);
+
+        let mut report = DiffReport::default();
+        report.diff_thread((1, 1), &old, &new, 3);
+        assert!(report.unaligned_threads.is_empty());
+        assert_eq!((report.missing_ops, report.inserted_ops), (0, 3));
+
+        // Too far apart to resynchronize: the rest of both threads differ
+        let mut report = DiffReport::default();
+        report.diff_thread((1, 1), &old, &new, 2);
+        assert_eq!(report.unaligned_threads, [(1, 1)]);
+        assert_eq!((report.missing_ops, report.inserted_ops), (3, 6));
+    }
+
+    #[test]
+    fn first_divergence_across_threads() {
+        let old = thread(5, ops());
+        let mut new = ops();
+        new[1] = load(2, 0x10, 6);
+        let new = thread(5, new);
+        let mut report = DiffReport::default();
+        report.diff_thread((1, 1), &old, &new, 4);
+        assert_eq!(report.first.unwrap().pos, 6);
+
+        let (old, new) = (thread(0, ops()), thread(0, ops()[..3].to_vec()));
+        report.diff_thread((2, 2), &old, &new, 4);
+        assert_eq!(report.first.unwrap().pos, 3);
+        assert_eq!(report.first.unwrap().tid, (2, 2));
+    }
+}
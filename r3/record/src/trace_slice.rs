@@ -0,0 +1,148 @@
+//! Binary to extract a sub-trace from a trace file generated by
+//! [`record`](../record/index.html)
+//!
+//! The sliced trace keeps the header (and hence sha256) of the original, so
+//! it can be fed to `replay` to generate prefix replays
+use clap::Parser;
+use log::{info, warn};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::ops::Range;
+
+use common::trace::{TraceReader, TraceSlice, TraceWriter};
+
+/// Parse an op index range of the form `start..end`, `start..` or `..end`
+fn parse_range(s: &str) -> Result<Range<usize>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or(format!("Expected range `start..end`, got \"{}\"", s))?;
+    let parse = |v: &str, default: usize| match v {
+        "" => Ok(default),
+        v => v.parse::<usize>().map_err(|e| format!("{}: \"{}\"", e, v)),
+    };
+    Ok(parse(start, 0)?..parse(end, usize::MAX)?)
+}
+
+/// Parse a call occurrence of the form `<CallID name>:<N>`, e.g. `ScFutex:3`
+fn parse_until_call(s: &str) -> Result<(String, usize), String> {
+    let (name, n) = s
+        .rsplit_once(':')
+        .ok_or(format!("Expected `<CallID>:<N>`, got \"{}\"", s))?;
+    match n.parse::<usize>() {
+        Ok(n) if n > 0 => Ok((name.to_string(), n)),
+        _ => Err(format!(
+            "Expected a positive occurrence count, got \"{}\"",
+            n
+        )),
+    }
+}
+
+/// Command-Line Arguments
+#[derive(Parser, Debug)]
+#[command(version, about, long_about=None)]
+struct CLI {
+    /// Output sliced trace file
+    #[arg(short, long, default_value_t = String::from("slice.r3"))]
+    outfile: String,
+
+    /// Range of op indices to keep (`start..end`, end exclusive)
+    #[arg(short, long, value_parser = parse_range)]
+    range: Option<Range<usize>>,
+
+    /// Comma-separated thread IDs whose ops are kept
+    #[arg(short, long, value_delimiter = ',')]
+    tids: Option<Vec<u64>>,
+
+    /// End the slice at the Nth occurrence of a call (`<CallID>:<N>`, e.g.
+    /// `ScWritev:2`), inclusive
+    #[arg(short, long, value_parser = parse_until_call)]
+    until_call: Option<(String, usize)>,
+
+    /// Trace output file generated by `record`
+    #[arg(default_value_t = String::from("trace.r3"))]
+    tracefile: String,
+}
+
+impl CLI {
+    /// Print the CLI configuration
+    fn print(&self) {
+        info!("Tracefile: {:?}", self.tracefile);
+        info!("Outfile: {:?}", self.outfile);
+        info!("Range: {:?}", self.range);
+        info!("TIDs: {:?}", self.tids);
+        info!("Until Call: {:?}", self.until_call);
+    }
+}
+
+/// Entrypoint for `trace-slice`
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::builder().format_timestamp_millis().init();
+
+    let cli = CLI::parse();
+    cli.print();
+
+    let slice = TraceSlice {
+        range: cli.range.clone(),
+        tids: cli
+            .tids
+            .as_ref()
+            .map(|tids| tids.iter().copied().collect::<BTreeSet<_>>()),
+        until_call: cli.until_call.clone(),
+    };
+
+    let mut reader = TraceReader::new(BufReader::new(fs::File::open(cli.tracefile.as_str())?))?;
+    let mut writer = TraceWriter::new(
+        BufWriter::new(fs::File::create(cli.outfile.as_str())?),
+        reader.header(),
+    )?;
+    let mut num_ops = 0;
+    for op in slice.apply(reader.by_ref()) {
+        writer.push(&op?)?;
+        num_ops += 1;
+    }
+    // Slicing may stop early; drain the rest to learn whether the recording
+    // was complete
+    for op in reader.by_ref() {
+        op?;
+    }
+    if reader.is_truncated() {
+        warn!("Source trace is truncated: recording ended before all ops were saved");
+        writer.set_truncated();
+    }
+    writer.finish()?;
+    info!(
+        "Sliced trace with {} ops written to \"{}\"",
+        num_ops, cli.outfile
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges() {
+        assert_eq!(parse_range("2..5"), Ok(2..5));
+        assert_eq!(parse_range("2.."), Ok(2..usize::MAX));
+        assert_eq!(parse_range("..5"), Ok(0..5));
+        assert_eq!(parse_range(".."), Ok(0..usize::MAX));
+        assert!(parse_range("5").is_err());
+        assert!(parse_range("a..5").is_err());
+        assert!(parse_range("-1..5").is_err());
+    }
+
+    #[test]
+    fn call_occurrences() {
+        assert_eq!(
+            parse_until_call("ScFutex:3"),
+            Ok(("ScFutex".to_string(), 3))
+        );
+        assert!(parse_until_call("ScFutex").is_err());
+        assert!(parse_until_call("ScFutex:0").is_err());
+        assert!(parse_until_call("ScFutex:x").is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Dead store elimination on replay operations
+//!
+//! The stores of a [ReplayOpProp] are applied in order, as one batch, right
+//! after its call (or sync point) is replayed. Within a batch, a store is dead
+//! if either:
+//! * Every byte it writes is overwritten by later stores of the batch
+//! * Memory already holds every byte it writes, from earlier stores of the
+//!   batch
+//!
+//! ### Design Notes
+//! Program writes between batches are not part of the replay operations, so
+//! memory contents are only known within a batch; stores are never
+//! eliminated across calls or sync points
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use replay::structs::{ReplayMemStore, ReplayOp, ReplayOpProp};
+
+/// Outcome of [eliminate_dead_stores]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct StoreReduction {
+    /// Stores before elimination
+    pub stores: usize,
+    /// Stores overwritten later in their batch
+    pub overwritten: usize,
+    /// Stores of bytes memory already holds
+    pub redundant: usize,
+}
+impl StoreReduction {
+    /// Number of stores eliminated
+    pub fn removed(&self) -> usize {
+        self.overwritten + self.redundant
+    }
+}
+impl fmt::Display for StoreReduction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Removed {} of {} stores ({} overwritten, {} redundant)",
+            self.removed(),
+            self.stores,
+            self.overwritten,
+            self.redundant
+        )
+    }
+}
+
+/// Addresses of the bytes written by `store`
+fn store_addrs(store: &ReplayMemStore) -> impl Iterator<Item = u64> {
+    let addr = store.addr as u32 as u64;
+    addr..addr + store.size as u64
+}
+
+/// Bytes written by `store` as `(address, value)` pairs (little-endian), if
+/// its value holds all of them
+fn store_bytes(store: &ReplayMemStore) -> Option<Vec<(u64, u8)>> {
+    if store.size as usize > size_of::<i64>() {
+        return None;
+    }
+    let value = store.value.to_le_bytes();
+    Some(store_addrs(store).zip(value).collect())
+}
+
+/// Drop stores of `prop` that write bytes already written by earlier stores
+fn drop_redundant(prop: &mut ReplayOpProp) -> usize {
+    let mut memory: HashMap<u64, u8> = HashMap::new();
+    let before = prop.stores.len();
+    prop.stores.retain(|store| match store_bytes(store) {
+        Some(bytes)
+            if bytes
+                .iter()
+                .all(|(addr, val)| memory.get(addr) == Some(val)) =>
+        {
+            false
+        }
+        Some(bytes) => {
+            memory.extend(bytes);
+            true
+        }
+        // Contents are unknown, so forget the bytes it writes
+        None => {
+            store_addrs(store).for_each(|addr| {
+                memory.remove(&addr);
+            });
+            true
+        }
+    });
+    before - prop.stores.len()
+}
+
+/// Drop stores of `prop` whose bytes are all overwritten by later stores
+fn drop_overwritten(prop: &mut ReplayOpProp) -> usize {
+    let mut written: HashSet<u64> = HashSet::new();
+    let mut live: Vec<bool> = prop
+        .stores
+        .iter()
+        .rev()
+        .map(|store| {
+            let addrs: Vec<u64> = store_addrs(store).collect();
+            let dead = addrs.iter().all(|addr| written.contains(addr));
+            written.extend(addrs);
+            !dead
+        })
+        .collect();
+    let before = prop.stores.len();
+    prop.stores.retain(|_| live.pop().unwrap());
+    before - prop.stores.len()
+}
+
+/// Eliminate dead stores from all `replay_ops`, preserving the memory
+/// contents after every batch
+pub fn eliminate_dead_stores(replay_ops: &mut BTreeMap<u32, ReplayOp>) -> StoreReduction {
+    let mut reduction = StoreReduction::default();
+    for prop in replay_ops.values_mut().flat_map(|op| op.props.iter_mut()) {
+        reduction.stores += prop.stores.len();
+        reduction.redundant += drop_redundant(prop);
+        reduction.overwritten += drop_overwritten(prop);
+    }
+    reduction
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::trace::CallID;
+
+    fn store(addr: i32, size: u32, value: i64) -> ReplayMemStore {
+        ReplayMemStore { addr, size, value }
+    }
+
+    /// Plan of a single call storing `stores`
+    fn ops(stores: Vec<ReplayMemStore>) -> BTreeMap<u32, ReplayOp> {
+        let prop = ReplayOpProp {
+            tid: 0,
+            return_val: 0,
+            call_id: CallID::ScGeneric,
+            stores,
+            sync_id: 0,
+        };
+        let op = ReplayOp {
+            access_idx: 1,
+            func_idx: 0,
+            implicit_sync: false,
+            props: vec![prop],
+            max_tid: 0,
+        };
+        BTreeMap::from([(1, op)])
+    }
+
+    /// Stores left after eliminating dead stores from `stores`
+    fn eliminate(stores: Vec<ReplayMemStore>) -> (Vec<ReplayMemStore>, StoreReduction) {
+        let mut ops = ops(stores);
+        let reduction = eliminate_dead_stores(&mut ops);
+        (ops[&1].props[0].stores.clone(), reduction)
+    }
+
+    /// Memory after applying `stores` of at most 8 bytes
+    fn memory(stores: &[ReplayMemStore]) -> BTreeMap<u64, u8> {
+        let mut memory = BTreeMap::new();
+        for store in stores {
+            memory.extend(store_bytes(store).unwrap());
+        }
+        memory
+    }
+
+    #[test]
+    fn overwritten() {
+        let stores = vec![
+            store(0x100, 4, 0x11111111),
+            store(0x200, 8, 1),
+            store(0x100, 4, 0x22222222),
+            // Overwritten by the two stores after it
+            store(0x200, 2, 0x3333),
+            store(0x200, 1, 0x44),
+            store(0x201, 1, 0x55),
+        ];
+        let (left, reduction) = eliminate(stores.clone());
+        assert_eq!(
+            left,
+            [
+                store(0x200, 8, 1),
+                store(0x100, 4, 0x22222222),
+                store(0x200, 1, 0x44),
+                store(0x201, 1, 0x55),
+            ]
+        );
+        assert_eq!(
+            reduction,
+            StoreReduction {
+                stores: 6,
+                overwritten: 2,
+                redundant: 0,
+            }
+        );
+        assert_eq!(memory(&left), memory(&stores));
+    }
+
+    #[test]
+    fn partially_overwritten() {
+        let stores = vec![store(0x100, 4, 0x11111111), store(0x102, 4, 0x22222222)];
+        let (left, reduction) = eliminate(stores.clone());
+        assert_eq!(left, stores);
+        assert_eq!(reduction.removed(), 0);
+    }
+
+    #[test]
+    fn redundant_after_partial_overlap() {
+        let stores = vec![
+            store(0x100, 4, 0x44332211),
+            store(0x102, 4, 0x66554433),
+            // Bytes 0x100..0x104 are 11 22 33 44, as written above
+            store(0x100, 2, 0x2211),
+            store(0x101, 2, 0x3322),
+            // Byte 0x102 now differs (0x33, not 0x11)
+            store(0x102, 1, 0x11),
+        ];
+        let (left, reduction) = eliminate(stores.clone());
+        assert_eq!(
+            left,
+            [
+                store(0x100, 4, 0x44332211),
+                store(0x102, 4, 0x66554433),
+                store(0x102, 1, 0x11),
+            ]
+        );
+        assert_eq!(
+            reduction,
+            StoreReduction {
+                stores: 5,
+                overwritten: 0,
+                redundant: 2,
+            }
+        );
+        assert_eq!(memory(&left), memory(&stores));
+    }
+
+    #[test]
+    fn large_stores() {
+        // A 16-byte store's contents are unknown, so it never makes later
+        // stores redundant
+        let stores = vec![store(0x100, 4, 7), store(0x100, 16, 0), store(0x100, 4, 7)];
+        let (left, reduction) = eliminate(stores.clone());
+        assert_eq!(left, &stores[1..]);
+        assert_eq!(
+            reduction,
+            StoreReduction {
+                stores: 3,
+                overwritten: 1,
+                redundant: 0,
+            }
+        );
+
+        // ...but is overwritten by stores covering all of its bytes
+        let stores = vec![store(0x100, 16, 0), store(0x100, 8, 1), store(0x108, 8, 2)];
+        let (left, reduction) = eliminate(stores.clone());
+        assert_eq!(left, &stores[1..]);
+        assert_eq!(reduction.overwritten, 1);
+
+        let stores = vec![store(0x100, 16, 0), store(0x100, 8, 1)];
+        let (left, _) = eliminate(stores.clone());
+        assert_eq!(left, stores);
+    }
+
+    #[test]
+    fn zero_size_stores() {
+        let stores = vec![store(0x100, 0, 1), store(0x100, 4, 2), store(0x104, 0, 3)];
+        let (left, reduction) = eliminate(stores);
+        assert_eq!(left, [store(0x100, 4, 2)]);
+        assert_eq!(reduction.removed(), 2);
+    }
+
+    #[test]
+    fn batches_are_independent() {
+        let mut ops = ops(vec![store(0x100, 4, 1)]);
+        let mut prop = ops[&1].props[0].clone();
+        prop.sync_id = 1;
+        ops.get_mut(&1).unwrap().props.push(prop);
+        let reduction = eliminate_dead_stores(&mut ops);
+        assert_eq!(reduction.removed(), 0);
+        assert_eq!(ops[&1].total_stores(), 2);
+    }
+}
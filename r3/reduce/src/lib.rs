@@ -0,0 +1,12 @@
+//! Library crate for reducing replay plans between recording and replay
+//! generation (the middle R of Record-Reduce-Replay).
+//!
+//! Passes transform the [ReplayOp](replay::structs::ReplayOp)s of a
+//! [ReplayPlan](replay::plan::ReplayPlan) in place, without changing the
+//! replayed behaviour. The `reduce` binary applies them to a trace or plan
+//! and writes the reduced plan for `replay --plan`.
+//!
+//! [Minimization](minimize) instead shrinks a plan as far as a predicate on the
+//! replay's behaviour (e.g. a crash) allows
+pub mod dead_stores;
+pub mod minimize;
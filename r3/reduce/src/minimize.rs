@@ -0,0 +1,548 @@
+//! Delta-debugging minimization of a replay plan, preserving a predicate on
+//! the replay's behaviour (e.g. that it still crashes)
+//!
+//! Minimization drops whole threads, then [ReplayOpProp]s, then individual
+//! stores, regenerating and re-running the replay for each candidate. A
+//! candidate is kept only if the predicate still holds; candidates that fail
+//! to generate or run, or time out, count as the predicate not holding
+//!
+//! Candidates are generated without the recorded termination (see
+//! [`replay::termination`]), so the predicate is checked against how the
+//! replay itself ends rather than the replayed ending
+//!
+//! ### Design Notes
+//! Each granularity is minimized with `ddmin` over complements: the units are
+//! split into chunks, and the first chunk whose removal preserves the
+//! predicate is dropped. Granularities are revisited until none shrinks, since
+//! e.g. dropping stores can make a thread removable
+use log::{debug, info, warn};
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use std::cmp::{max, min};
+use std::collections::BTreeSet;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::trace::Termination;
+use common::R3Error;
+use replay::generator::{generate_replay_module, Backend, ReplayMetadata};
+use replay::parser::reorder_replay_ops;
+use replay::plan::ReplayPlan;
+use replay::structs::ReplayOpProp;
+use runner::{run, RunOptions};
+
+/// Environment variable holding the candidate replay module's path for
+/// [Predicate::Command]
+pub const REPLAY_MODULE_VAR: &str = "R3_REPLAY_MODULE";
+
+/// Behaviour of a replay preserved by minimization
+#[derive(Debug, Clone)]
+pub enum Predicate {
+    /// Replay exits with the given status
+    Exit(i32),
+    /// Replay traps with a message containing the given text
+    Trap(String),
+    /// Shell command succeeds, with the candidate replay module's path in
+    /// [REPLAY_MODULE_VAR]
+    Command(String),
+}
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Predicate::Exit(code) => write!(f, "Exit with code {}", code),
+            Predicate::Trap(message) => write!(f, "Trap with \"{}\"", message),
+            Predicate::Command(cmd) => write!(f, "`{}` succeeds", cmd),
+        }
+    }
+}
+
+/// Granularity at which parts of a plan are dropped
+#[derive(Debug, Clone, Copy)]
+enum Level {
+    Threads,
+    Props,
+    Stores,
+}
+
+/// A droppable part of a plan: `(tid, 0)` for threads, `(sync_id, 0)` for
+/// props and `(sync_id, store_idx)` for stores
+type Unit = (u64, usize);
+
+/// Props of `plan`, across all replay ops
+fn props(plan: &ReplayPlan) -> impl Iterator<Item = &ReplayOpProp> {
+    plan.ops.values().flat_map(|op| op.props.iter())
+}
+
+/// All units of `plan` at `level`
+fn units(plan: &ReplayPlan, level: Level) -> Vec<Unit> {
+    let units: BTreeSet<Unit> = match level {
+        Level::Threads => props(plan).map(|prop| (prop.tid, 0)).collect(),
+        Level::Props => props(plan).map(|prop| (prop.sync_id, 0)).collect(),
+        Level::Stores => props(plan)
+            .flat_map(|prop| (0..prop.stores.len()).map(|idx| (prop.sync_id, idx)))
+            .collect(),
+    };
+    units.into_iter().collect()
+}
+
+/// `plan` without the `removed` units at `level`
+///
+/// Replay ops left without props are dropped entirely
+fn without(plan: &ReplayPlan, level: Level, removed: &BTreeSet<Unit>) -> ReplayPlan {
+    let mut candidate = plan.clone();
+    for op in candidate.ops.values_mut() {
+        match level {
+            Level::Threads => op.props.retain(|prop| !removed.contains(&(prop.tid, 0))),
+            Level::Props => op
+                .props
+                .retain(|prop| !removed.contains(&(prop.sync_id, 0))),
+            Level::Stores => {
+                for prop in op.props.iter_mut() {
+                    let mut idx = 0;
+                    prop.stores.retain(|_| {
+                        idx += 1;
+                        !removed.contains(&(prop.sync_id, idx - 1))
+                    });
+                }
+            }
+        }
+    }
+    candidate.ops.retain(|_, op| !op.props.is_empty());
+    candidate
+}
+
+/// Size of a plan, in the units minimization drops
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PlanSize {
+    pub threads: usize,
+    pub props: usize,
+    pub stores: usize,
+}
+impl PlanSize {
+    pub fn of(plan: &ReplayPlan) -> Self {
+        PlanSize {
+            threads: units(plan, Level::Threads).len(),
+            props: units(plan, Level::Props).len(),
+            stores: plan.total_stores(),
+        }
+    }
+}
+impl fmt::Display for PlanSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} threads, {} props, {} stores",
+            self.threads, self.props, self.stores
+        )
+    }
+}
+
+/// Options of minimization; see `reduce -h` for their CLI counterparts
+#[derive(Debug, Clone, Default)]
+pub struct MinimizeOptions {
+    /// Backend generating candidate replay modules; [`Backend::for_ops`] if
+    /// `None`
+    pub backend: Option<Backend>,
+    /// Enable debug calls within candidate replay modules
+    pub debug: bool,
+    /// Fold contiguous stores into data segments
+    pub fold_stores: bool,
+    /// Kill candidate replays (or predicate commands) running longer than
+    /// this; the predicate then does not hold
+    pub timeout: Option<Duration>,
+}
+
+/// Generate the replay module of `wasmbin` for `plan` as configured by
+/// `options`, embedding `metadata` (usually `plan.metadata`)
+pub fn generate(
+    wasmbin: &[u8],
+    plan: &ReplayPlan,
+    metadata: &ReplayMetadata,
+    options: &MinimizeOptions,
+) -> Result<Vec<u8>, R3Error> {
+    let mut ops = plan.ops.clone();
+    reorder_replay_ops(&mut ops);
+    generate_replay_module(
+        &ops,
+        wasmbin,
+        options.debug,
+        options.fold_stores,
+        metadata,
+        options.backend,
+    )
+}
+
+/// Interval at which a predicate command with a timeout is polled
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Temporary file holding the candidate module for [Predicate::Command],
+/// removed when dropped
+struct CandidateFile(PathBuf);
+impl Drop for CandidateFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Whether shell command `cmd` succeeds with the candidate module at
+/// `candidate`, killing it (and its children) once `timeout` elapses
+fn command_holds(cmd: &str, candidate: &Path, timeout: Option<Duration>) -> Result<bool, R3Error> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env(REPLAY_MODULE_VAR, candidate)
+        .process_group(0)
+        .spawn()?;
+    let Some(timeout) = timeout else {
+        return Ok(child.wait()?.success());
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status.success());
+        }
+        if Instant::now() >= deadline {
+            warn!("`{}` timed out after {:?}; killing it", cmd, timeout);
+            // The command runs in its own process group, so this also kills
+            // e.g. a `runner` it started
+            killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL).map_err(io::Error::from)?;
+            child.wait()?;
+            return Ok(false);
+        }
+        thread::sleep(TIMEOUT_POLL_INTERVAL);
+    }
+}
+
+/// Minimize `plan` at `level` with `ddmin`, keeping candidates for which
+/// `holds`
+fn ddmin<F>(mut plan: ReplayPlan, level: Level, holds: &mut F) -> Result<ReplayPlan, R3Error>
+where
+    F: FnMut(&ReplayPlan) -> Result<bool, R3Error>,
+{
+    let mut all = units(&plan, level);
+    let mut chunks = 2;
+    while !all.is_empty() {
+        let chunk_len = all.len().div_ceil(chunks);
+        let mut reduced = None;
+        for chunk in all.chunks(chunk_len) {
+            let candidate = without(&plan, level, &chunk.iter().copied().collect());
+            if holds(&candidate)? {
+                reduced = Some(candidate);
+                break;
+            }
+        }
+        match reduced {
+            Some(candidate) => {
+                plan = candidate;
+                all = units(&plan, level);
+                chunks = max(chunks - 1, 2);
+            }
+            None if chunk_len == 1 => break,
+            None => chunks = min(chunks * 2, all.len()),
+        }
+    }
+    Ok(plan)
+}
+
+/// Minimize `plan` at every level with [ddmin] until none shrinks, keeping
+/// candidates for which `holds`
+fn minimize_plan<F>(mut plan: ReplayPlan, mut holds: F) -> Result<ReplayPlan, R3Error>
+where
+    F: FnMut(&ReplayPlan) -> Result<bool, R3Error>,
+{
+    loop {
+        let size = PlanSize::of(&plan);
+        for level in [Level::Threads, Level::Props, Level::Stores] {
+            plan = ddmin(plan, level, &mut holds)?;
+            info!("Minimized {:?} | {}", level, PlanSize::of(&plan));
+        }
+        if PlanSize::of(&plan) == size {
+            return Ok(plan);
+        }
+    }
+}
+
+/// Minimizes replay plans of a module, checking candidates against a
+/// [Predicate]
+pub struct Minimizer<'a> {
+    wasmbin: &'a [u8],
+    /// Argv (program name + arguments) to run replays with
+    argv: Vec<String>,
+    predicate: Predicate,
+    options: MinimizeOptions,
+    /// Where candidate modules are written for [Predicate::Command]
+    candidate: CandidateFile,
+    /// Number of candidates tested so far
+    tests: usize,
+}
+
+impl<'a> Minimizer<'a> {
+    /// Minimizer for replays of `wasmbin` run with `argv`, generated as
+    /// configured by `options`
+    pub fn new(
+        wasmbin: &'a [u8],
+        argv: Vec<String>,
+        predicate: Predicate,
+        options: MinimizeOptions,
+    ) -> Self {
+        Minimizer {
+            wasmbin,
+            argv,
+            predicate,
+            options,
+            candidate: CandidateFile(
+                env::temp_dir().join(format!("r3-reduce-{}.wasm", process::id())),
+            ),
+            tests: 0,
+        }
+    }
+
+    /// Number of candidates tested so far
+    pub fn tests(&self) -> usize {
+        self.tests
+    }
+
+    /// Whether the predicate holds for the replay of `plan`
+    pub fn holds(&mut self, plan: &ReplayPlan) -> Result<bool, R3Error> {
+        self.tests += 1;
+        let metadata = ReplayMetadata {
+            termination: None,
+            ..plan.metadata.clone()
+        };
+        let module = match generate(self.wasmbin, plan, &metadata, &self.options) {
+            Ok(module) => module,
+            Err(e) => {
+                debug!("Candidate {} could not be generated: {}", self.tests, e);
+                return Ok(false);
+            }
+        };
+        let holds = match &self.predicate {
+            Predicate::Command(cmd) => {
+                fs::write(&self.candidate.0, &module)?;
+                command_holds(cmd, &self.candidate.0, self.options.timeout)?
+            }
+            predicate => {
+                let options = RunOptions {
+                    timeout: self.options.timeout,
+                    ..Default::default()
+                };
+                let termination = match run(&module, &self.argv, &options) {
+                    Ok(result) => result.termination,
+                    Err(e) => {
+                        debug!("Candidate {} could not be run: {}", self.tests, e);
+                        return Ok(false);
+                    }
+                };
+                match (predicate, termination) {
+                    (Predicate::Exit(expected), Some(Termination::Exit { code })) => {
+                        code == *expected
+                    }
+                    (Predicate::Trap(expected), Some(Termination::Trap { message, .. })) => {
+                        message.contains(expected.as_str())
+                    }
+                    _ => false,
+                }
+            }
+        };
+        debug!("Candidate {}: predicate holds: {}", self.tests, holds);
+        Ok(holds)
+    }
+
+    /// Minimize `plan` while the predicate holds
+    ///
+    /// Fails if the predicate does not hold for `plan` itself
+    pub fn minimize(&mut self, plan: ReplayPlan) -> Result<ReplayPlan, R3Error> {
+        if !self.holds(&plan)? {
+            return Err(R3Error::Reduce(format!(
+                "predicate ({}) does not hold for the unreduced replay",
+                self.predicate
+            )));
+        }
+        minimize_plan(plan, |candidate| self.holds(candidate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::trace::CallID;
+    use replay::structs::{ReplayMemStore, ReplayOp};
+    use std::collections::BTreeMap;
+
+    fn store(addr: i32) -> ReplayMemStore {
+        ReplayMemStore {
+            addr,
+            size: 4,
+            value: addr as i64,
+        }
+    }
+
+    fn prop(tid: u64, sync_id: u64, stores: Vec<ReplayMemStore>) -> ReplayOpProp {
+        ReplayOpProp {
+            tid,
+            return_val: 0,
+            call_id: CallID::ScGeneric,
+            stores,
+            sync_id,
+        }
+    }
+
+    /// Plan of 3 threads calling at 2 sites, with 8 props of 2 stores each
+    fn plan() -> ReplayPlan {
+        let mut ops = BTreeMap::new();
+        for access_idx in [1, 2] {
+            let props = (0..4)
+                .map(|idx| {
+                    let sync_id = (access_idx as u64 - 1) * 4 + idx;
+                    let addr = sync_id as i32 * 16;
+                    prop(idx % 3, sync_id, vec![store(addr), store(addr + 4)])
+                })
+                .collect();
+            ops.insert(
+                access_idx,
+                ReplayOp {
+                    access_idx,
+                    func_idx: 0,
+                    implicit_sync: false,
+                    props,
+                    max_tid: 2,
+                },
+            );
+        }
+        ReplayPlan {
+            sha256: String::from("0123abcd"),
+            ops,
+            metadata: ReplayMetadata::default(),
+        }
+    }
+
+    /// Whether `plan` has a prop with `sync_id` storing to `addr`
+    fn stores_to(plan: &ReplayPlan, sync_id: u64, addr: i32) -> bool {
+        props(plan).any(|prop| {
+            prop.sync_id == sync_id && prop.stores.iter().any(|store| store.addr == addr)
+        })
+    }
+
+    #[test]
+    fn without_threads() {
+        let reduced = without(&plan(), Level::Threads, &BTreeSet::from([(0, 0), (2, 0)]));
+        assert!(props(&reduced).all(|prop| prop.tid == 1));
+        assert_eq!(
+            PlanSize::of(&reduced),
+            PlanSize {
+                threads: 1,
+                props: 2,
+                stores: 4,
+            }
+        );
+    }
+
+    #[test]
+    fn without_props() {
+        // Dropping all props of a site drops its op
+        let removed = (4..8).map(|sync_id| (sync_id, 0)).collect();
+        let reduced = without(&plan(), Level::Props, &removed);
+        assert_eq!(reduced.ops.keys().collect::<Vec<_>>(), [&1]);
+        assert_eq!(reduced.ops[&1], plan().ops[&1]);
+    }
+
+    #[test]
+    fn without_stores() {
+        let reduced = without(&plan(), Level::Stores, &BTreeSet::from([(3, 0), (5, 1)]));
+        assert_eq!(reduced.total_stores(), 14);
+        assert!(!stores_to(&reduced, 3, 48) && stores_to(&reduced, 3, 52));
+        assert!(stores_to(&reduced, 5, 80) && !stores_to(&reduced, 5, 84));
+        assert_eq!(PlanSize::of(&reduced).props, 8);
+    }
+
+    #[test]
+    fn minimizes_to_store() {
+        let mut tests = 0;
+        let reduced = minimize_plan(plan(), |candidate| {
+            tests += 1;
+            Ok(stores_to(candidate, 6, 100))
+        })
+        .unwrap();
+        assert_eq!(
+            PlanSize::of(&reduced),
+            PlanSize {
+                threads: 1,
+                props: 1,
+                stores: 1,
+            }
+        );
+        assert!(stores_to(&reduced, 6, 100));
+        // ddmin needs far fewer tests than the 2^16 subsets of stores
+        assert!(tests < 64, "{} tests", tests);
+    }
+
+    #[test]
+    fn minimizes_to_threads() {
+        // Holds while threads 0 and 2 are both present
+        let reduced = minimize_plan(plan(), |candidate| {
+            let tids: BTreeSet<u64> = props(candidate).map(|prop| prop.tid).collect();
+            Ok(tids.contains(&0) && tids.contains(&2))
+        })
+        .unwrap();
+        assert_eq!(
+            PlanSize::of(&reduced),
+            PlanSize {
+                threads: 2,
+                props: 2,
+                stores: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn keeps_plan_if_nothing_removable() {
+        let plan = plan();
+        let full = PlanSize::of(&plan);
+        let reduced = minimize_plan(
+            plan.clone(),
+            |candidate| Ok(PlanSize::of(candidate) == full),
+        )
+        .unwrap();
+        assert_eq!(reduced, plan);
+    }
+
+    #[test]
+    fn predicate_errors_abort() {
+        let err = ddmin(plan(), Level::Props, &mut |_: &ReplayPlan| {
+            Err(R3Error::Reduce(String::from("broken predicate")))
+        });
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn candidate_file_is_removed() {
+        let path = env::temp_dir().join(format!("r3-reduce-test-{}.wasm", process::id()));
+        {
+            let candidate = CandidateFile(path.clone());
+            fs::write(&candidate.0, b"\0asm").unwrap();
+            assert!(path.exists());
+        }
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn command_timeout() {
+        let path = Path::new("/nonexistent");
+        assert!(command_holds("true", path, None).unwrap());
+        assert!(!command_holds("false", path, None).unwrap());
+        assert!(command_holds("test \"$R3_REPLAY_MODULE\" = /nonexistent", path, None).unwrap());
+
+        let start = Instant::now();
+        let timeout = Some(Duration::from_millis(100));
+        assert!(!command_holds("sleep 10; true", path, timeout).unwrap());
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(command_holds("true", path, timeout).unwrap());
+    }
+}
@@ -1,3 +1,199 @@
-fn main() {
-    println!("Hello, world!");
+//! Binary crate for reducing the replay plan of a Trace before replay
+//! generation.
+use clap::error::ErrorKind;
+use clap::{ArgGroup, CommandFactory, Parser};
+use log::{info, warn};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::time::Duration;
+
+use common::trace::TraceReader;
+use replay::generator::Backend;
+use replay::plan::ReplayPlan;
+
+use reduce::dead_stores::eliminate_dead_stores;
+use reduce::minimize::{generate, MinimizeOptions, Minimizer, PlanSize, Predicate};
+
+/// Command-Line Arguments
+#[derive(Parser, Debug)]
+#[command(version, about, long_about=None)]
+#[command(group(ArgGroup::new("predicate").args(["expect_exit", "expect_trap", "expect_cmd"]).requires("wasmfile")))]
+struct CLI {
+    /// Trace output file generated by `record`
+    #[arg(short, long, default_value_t = String::from("trace.r3"))]
+    tracefile: String,
+
+    /// Replay plan to reduce further, used instead of the trace
+    #[arg(short, long, conflicts_with = "tracefile")]
+    plan: Option<String>,
+
+    /// Output reduced replay plan, for `replay --plan`
+    #[arg(short, long, default_value_t = String::from("replay.plan"))]
+    outfile: String,
+
+    /// Minimize the replay while it exits with this status
+    #[arg(long)]
+    expect_exit: Option<i32>,
+
+    /// Minimize the replay while it traps (with a message containing the
+    /// given text, if any)
+    #[arg(long, num_args = 0..=1, default_missing_value = "")]
+    expect_trap: Option<String>,
+
+    /// Minimize the replay while this shell command succeeds; the candidate
+    /// replay module's path is in `$R3_REPLAY_MODULE`
+    #[arg(long)]
+    expect_cmd: Option<String>,
+
+    /// Count a candidate replay (or `--expect-cmd` command) running longer
+    /// than this many seconds as the predicate not holding
+    #[arg(long, requires = "predicate")]
+    timeout: Option<u64>,
+
+    /// Fold contiguous stores into data segments in generated replay
+    /// modules (`rust` backend only), reporting
+    /// the module size saved
+    #[arg(long, requires = "wasmfile")]
+    fold_stores: bool,
+
+    /// Original (unmodified) Wasm file, to generate replays during
+    /// minimization or store folding
+    #[arg(short, long)]
+    wasmfile: Option<String>,
+
+    /// Output minimized (or folded) replay module
+    #[arg(short, long, default_value_t = String::from("reduced.wasm"))]
+    replayfile: String,
+
+    /// Backend generating replay modules [default: rust, or cpp for traces
+    /// with calls from several threads]
+    #[arg(short, long, value_enum)]
+    backend: Option<Backend>,
+
+    /// Enable debug calls within generated replay modules
+    #[arg(short, long)]
+    debug: bool,
+
+    /// Arguments to run replay modules with during minimization
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+impl CLI {
+    /// Predicate to minimize with, if any
+    fn predicate(&self) -> Option<Predicate> {
+        if let Some(code) = self.expect_exit {
+            Some(Predicate::Exit(code))
+        } else if let Some(ref message) = self.expect_trap {
+            Some(Predicate::Trap(message.clone()))
+        } else {
+            self.expect_cmd.clone().map(Predicate::Command)
+        }
+    }
+}
+
+impl CLI {
+    /// Print the CLI configuration
+    fn print(&self) {
+        info!("Tracefile: {:?}", self.tracefile);
+        info!("Plan [optional]: {:?}", self.plan);
+        info!("Outfile: {:?}", self.outfile);
+        info!("Predicate [optional]: {:?}", self.predicate());
+        info!("Timeout [optional]: {:?}", self.timeout);
+        info!("Wasmfile [optional]: {:?}", self.wasmfile);
+        info!("Replayfile: {:?}", self.replayfile);
+        info!("Backend [optional]: {:?}", self.backend);
+        info!("Generate Debug: {:?}", self.debug);
+        info!("Fold Stores: {:?}", self.fold_stores);
+        info!("Replay Args: {:?}", self.args);
+    }
+}
+
+/// Entrypoint for `reduce`
+fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::builder().format_timestamp_millis().init();
+    let cli = CLI::parse();
+    if cli.fold_stores && cli.backend == Some(Backend::Cpp) {
+        CLI::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--fold-stores requires the rust backend (`-b rust`)",
+            )
+            .exit();
+    }
+    cli.print();
+
+    let mut plan = match cli.plan {
+        Some(ref planfile) => ReplayPlan::deserialize(&fs::read(planfile)?, None)?,
+        None => {
+            let file = File::open(cli.tracefile.as_str())?;
+            let mut reader = TraceReader::new(BufReader::new(file))?;
+            let header = reader.header().clone();
+            let plan = ReplayPlan::from_trace(&header, reader.by_ref())?;
+            if reader.is_truncated() {
+                warn!("Trace is truncated; replay will diverge after the last recorded op");
+            }
+            plan
+        }
+    };
+    let size_before = plan.serialize().len();
+
+    let reduction = eliminate_dead_stores(&mut plan.ops);
+    println!("Dead stores | {}", reduction);
+
+    let wasmbin = cli.wasmfile.as_ref().map(fs::read).transpose()?;
+    let options = MinimizeOptions {
+        backend: cli.backend,
+        debug: cli.debug,
+        fold_stores: cli.fold_stores,
+        timeout: cli.timeout.map(Duration::from_secs),
+    };
+    let predicate = cli.predicate();
+    if let Some(ref predicate) = predicate {
+        let wasmbin = wasmbin.as_ref().unwrap();
+        let argv = std::iter::once(cli.replayfile.clone())
+            .chain(cli.args.iter().cloned())
+            .collect();
+        let size_before = PlanSize::of(&plan);
+        let mut minimizer = Minimizer::new(wasmbin, argv, predicate.clone(), options.clone());
+        plan = minimizer.minimize(plan)?;
+        println!(
+            "Minimized | {} -> {} ({} replays tested)",
+            size_before,
+            PlanSize::of(&plan),
+            minimizer.tests()
+        );
+    }
+    if predicate.is_some() || cli.fold_stores {
+        let wasmbin = wasmbin.as_ref().unwrap();
+        let replay_module = generate(wasmbin, &plan, &plan.metadata, &options)?;
+        if cli.fold_stores {
+            let unfolded = MinimizeOptions {
+                fold_stores: false,
+                ..options
+            };
+            let unfolded_len = generate(wasmbin, &plan, &plan.metadata, &unfolded)?.len();
+            println!(
+                "Folded stores | replay module {} -> {} bytes ({:.1}% smaller)",
+                unfolded_len,
+                replay_module.len(),
+                100.0 * (unfolded_len as f64 - replay_module.len() as f64) / unfolded_len as f64
+            );
+        }
+        fs::write(&cli.replayfile, replay_module)?;
+        info!("Wrote reduced replay module to {}", cli.replayfile);
+    }
+
+    let ser = plan.serialize();
+    println!(
+        "Plan size | {} -> {} bytes ({:.1}% smaller)",
+        size_before,
+        ser.len(),
+        100.0 * (size_before - ser.len()) as f64 / size_before as f64
+    );
+    fs::write(&cli.outfile, ser)?;
+    info!("Wrote reduced plan to {}", cli.outfile);
+
+    Ok(())
 }
@@ -1,14 +1,17 @@
-//! Utilities for generating replay instrumentation (over FFI to C++ library)
+//! Utilities for generating replay instrumentation (over FFI to C++ library,
+//! or natively with the `rust-generator` feature)
+use clap::ValueEnum;
 use libc::c_void;
 use log::{debug, info};
-use std::error::Error;
-use std::fs::File;
-use std::io::Write;
-use std::mem::ManuallyDrop;
+use serde::{Deserialize, Serialize};
 
 use crate::structs::*;
+use crate::termination::replay_termination;
 
-use common::instrument::{destroy_instrument_module, instrument_module, InstrumentArgs};
+use common::instrument::{InstrumentArgs, InstrumentedModule};
+use common::sections::{append_custom_section, OUTPUT_SECTION, TERMINATION_SECTION};
+use common::trace::{Termination, TraceError};
+use common::R3Error;
 
 use std::collections::BTreeMap;
 
@@ -18,30 +21,28 @@ use std::collections::BTreeMap;
 /// Rust will drop all data once they are out of local scope so raw FFI pointers
 /// will not stay after allocation if the instrumentation is called in disjoint
 /// scope.
-/// This struct allows us to manually control when the data is dropped (which is
-/// done after instrumentation is complete)
-struct FFIManualDropData {
+/// This struct owns the props the FFI ops point into, and acts as a guard:
+/// it must be held until instrumentation is complete, and frees the props when
+/// dropped on any path (including errors). Moving it does not move the inner
+/// buffers, so the pointers stay valid
+struct FFIPropsGuard {
     ffi_props_all: Vec<Vec<ReplayOpPropCFFI>>,
 }
 
-/// To generate this C-like FFI struct, we need to have manually
-/// dropped data that the user is required to drop after use
-fn generate_ffi_ops(
-    replay_ops: &BTreeMap<u32, ReplayOp>,
-) -> (Vec<ReplayOpCFFI>, ManuallyDrop<FFIManualDropData>) {
+/// To generate this C-like FFI struct, we need to keep the returned guard
+/// alive while the ops are in use
+fn generate_ffi_ops(replay_ops: &BTreeMap<u32, ReplayOp>) -> (Vec<ReplayOpCFFI>, FFIPropsGuard) {
     let mut ffi_ops: Vec<ReplayOpCFFI> = Vec::new();
-    let mut ffi_manual_drop = ManuallyDrop::new(FFIManualDropData {
+    let mut guard = FFIPropsGuard {
         ffi_props_all: Vec::new(),
-    });
-    for (_access_idx, op) in replay_ops {
-        // let mut ffi_props: Vec<ReplayOpPropCFFI> = Vec::new();
-        ffi_manual_drop.ffi_props_all.push(Vec::new());
-        {
-            let ffi_props: &mut Vec<ReplayOpPropCFFI> =
-                ffi_manual_drop.ffi_props_all.last_mut().unwrap();
-            for prop in &op.props {
+    };
+    for op in replay_ops.values() {
+        let ffi_props: Vec<ReplayOpPropCFFI> = op
+            .props
+            .iter()
+            .map(|prop| {
                 let (ffi_call_id, ffi_call_args) = prop.call_id.to_parts();
-                ffi_props.push(ReplayOpPropCFFI {
+                ReplayOpPropCFFI {
                     tid: prop.tid,
                     return_val: prop.return_val,
                     call_id: ffi_call_id,
@@ -49,36 +50,94 @@ fn generate_ffi_ops(
                     stores: prop.stores.as_ptr(),
                     num_stores: prop.stores.len() as u32,
                     sync_id: prop.sync_id,
-                });
-            }
-            // Push the actual Op data
-            ffi_ops.push(ReplayOpCFFI {
-                access_idx: op.access_idx,
-                func_idx: op.func_idx,
-                implicit_sync: op.implicit_sync as u32,
-                props: ffi_props.as_ptr(),
-                num_props: ffi_props.len() as u32,
-                max_tid: op.max_tid,
-            });
+                }
+            })
+            .collect();
+        // Push the actual Op data
+        ffi_ops.push(ReplayOpCFFI {
+            access_idx: op.access_idx,
+            func_idx: op.func_idx,
+            implicit_sync: op.implicit_sync as u32,
+            props: ffi_props.as_ptr(),
+            num_props: ffi_props.len() as u32,
+            max_tid: op.max_tid,
+        });
+        guard.ffi_props_all.push(ffi_props);
+    }
+    (ffi_ops, guard)
+}
+
+/// Module name of the replay interface registered by `runner`
+pub(crate) const REPLAY_MODULE: &str = "r3-replay";
+
+/// Recorded behaviour embedded into a replay module as custom sections, for the
+/// runner to verify the replay against
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayMetadata {
+    /// Embedded in a [`TERMINATION_SECTION`]
+    pub termination: Option<Termination>,
+    /// Concatenated output per fd; embedded in an [`OUTPUT_SECTION`]
+    pub output: Option<BTreeMap<i32, Vec<u8>>>,
+}
+impl ReplayMetadata {
+    /// Append custom sections for all present metadata to `module`
+    fn embed(&self, module: &mut Vec<u8>) -> Result<(), R3Error> {
+        if let Some(ref termination) = self.termination {
+            append_custom_section(
+                module,
+                TERMINATION_SECTION,
+                &postcard::to_stdvec(termination).map_err(TraceError::from)?,
+            );
+        }
+        if let Some(ref output) = self.output {
+            let output = postcard::to_stdvec(output).map_err(TraceError::from)?;
+            append_custom_section(module, OUTPUT_SECTION, &output);
         }
+        Ok(())
     }
-    (ffi_ops, ffi_manual_drop)
 }
 
-/// Generate a replay file by instrumenting the original wasm binary with replay
-/// operations
-pub fn generate_replay_file(
+/// Backend generating the replay module
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// `r3-replay-generator` routine of the C++ instrumentation library
+    Cpp,
+    /// Native Rust generator (see [`rust_generator`](crate::rust_generator))
+    #[cfg(feature = "rust-generator")]
+    Rust,
+}
+impl Backend {
+    /// Backend for `replay_ops` when none is chosen: [`Rust`](Backend::Rust),
+    /// which replays runs of calls as single cases, unless calls come from
+    /// more than one thread, whose ordering only [`Cpp`](Backend::Cpp)
+    /// replays
+    pub fn for_ops(replay_ops: &BTreeMap<u32, ReplayOp>) -> Backend {
+        let mut tids = replay_ops
+            .values()
+            .flat_map(|op| &op.props)
+            .map(|prop| prop.tid);
+        let first = tids.next();
+        match tids.any(|tid| Some(tid) != first) {
+            #[cfg(feature = "rust-generator")]
+            false => Backend::Rust,
+            _ => Backend::Cpp,
+        }
+    }
+}
+
+/// Generate a replay module with the C++ instrumentation library
+fn generate_replay_module_cpp(
     replay_ops: &BTreeMap<u32, ReplayOp>,
-    wasmbin: &Vec<u8>,
-    outfile: &str,
+    wasmbin: &[u8],
     debug: bool,
-) -> Result<(), Box<dyn Error>> {
-    let (ffi_ops, mut ffi_manual_drop) = generate_ffi_ops(replay_ops);
+) -> Result<Vec<u8>, R3Error> {
+    // Frees the props the ops point into once out of scope, even on errors
+    let (ffi_ops, _ffi_props) = generate_ffi_ops(replay_ops);
     for op in &ffi_ops {
         debug!("{}", op);
     }
     info!("Generating replay file from input wasm binary");
-    let replay_module: &[u8] = instrument_module(
+    let replay_module = InstrumentedModule::new(
         wasmbin,
         "r3-replay-generator",
         InstrumentArgs::AnonArr(
@@ -87,16 +146,87 @@ pub fn generate_replay_file(
             debug as i64,
         ),
     )?;
-    // Drop the manually managed C FFI replay-op data
-    unsafe {
-        ManuallyDrop::drop(&mut ffi_manual_drop);
+    Ok(replay_module.to_vec())
+}
+
+/// Generate a replay module by instrumenting the original wasm binary with
+/// replay operations using `backend` (or [`Backend::for_ops`]), embedding
+/// `metadata` for verification
+///
+/// A recorded exit or trap in `metadata` is also replayed once the entry
+/// function returns (see [`termination`](crate::termination))
+///
+/// `fold_stores` folds contiguous stores into data segments, which only the
+/// [`Rust`](Backend::Rust) backend supports
+pub fn generate_replay_module(
+    replay_ops: &BTreeMap<u32, ReplayOp>,
+    wasmbin: &[u8],
+    debug: bool,
+    fold_stores: bool,
+    metadata: &ReplayMetadata,
+    backend: Option<Backend>,
+) -> Result<Vec<u8>, R3Error> {
+    let backend = backend.unwrap_or_else(|| Backend::for_ops(replay_ops));
+    info!("Generating replay module with the {:?} backend", backend);
+    let mut replay_module_buf = match backend {
+        Backend::Cpp if fold_stores => {
+            return Err(R3Error::Instrument(String::from(
+                "Store folding is not supported by the C++ backend",
+            )))
+        }
+        Backend::Cpp => generate_replay_module_cpp(replay_ops, wasmbin, debug)?,
+        #[cfg(feature = "rust-generator")]
+        Backend::Rust => {
+            crate::rust_generator::generate_replay_module(replay_ops, wasmbin, debug, fold_stores)?
+        }
+    };
+    if let Some(ref termination) = metadata.termination {
+        replay_module_buf = replay_termination(&replay_module_buf, termination)?;
+    }
+    metadata.embed(&mut replay_module_buf)?;
+    Ok(replay_module_buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::trace::CallID;
+
+    /// Replay op at `access_idx` with a prop for each of `tids`
+    fn op(access_idx: u32, tids: &[u64]) -> (u32, ReplayOp) {
+        let props = tids
+            .iter()
+            .enumerate()
+            .map(|(sync_id, &tid)| ReplayOpProp {
+                tid,
+                return_val: 0,
+                call_id: CallID::ScGeneric,
+                stores: vec![],
+                sync_id: sync_id as u64,
+            })
+            .collect();
+        let op = ReplayOp {
+            access_idx,
+            func_idx: 0,
+            implicit_sync: false,
+            props,
+            max_tid: tids.iter().copied().max().unwrap_or(0),
+        };
+        (access_idx, op)
     }
 
-    // Write the instrumented module to file
-    let mut file = File::create(outfile)?;
-    file.write_all(replay_module)?;
-    info!("Wrote replay file to {}", outfile);
+    /// Backend for plans of a single thread
+    #[cfg(feature = "rust-generator")]
+    const SINGLE_THREAD: Backend = Backend::Rust;
+    #[cfg(not(feature = "rust-generator"))]
+    const SINGLE_THREAD: Backend = Backend::Cpp;
 
-    destroy_instrument_module(replay_module);
-    Ok(())
+    #[test]
+    fn backend_for_ops() {
+        let backend = |ops: Vec<(u32, ReplayOp)>| Backend::for_ops(&ops.into_iter().collect());
+        assert_eq!(backend(vec![]), SINGLE_THREAD);
+        assert_eq!(backend(vec![op(0, &[3, 3]), op(1, &[3])]), SINGLE_THREAD);
+        assert_eq!(backend(vec![op(0, &[3]), op(1, &[4])]), Backend::Cpp);
+        assert_eq!(backend(vec![op(0, &[3, 4])]), Backend::Cpp);
+    }
 }
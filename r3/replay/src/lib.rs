@@ -0,0 +1,211 @@
+//! Library crate for generating replay Wasm modules given a Trace and the
+//! original module that was recorded, for embedding replay generation in
+//! other tools.
+//!
+//! The `replay` binary is a thin CLI over [generate_replay] and
+//! [generate_replay_from_plan]
+
+#![feature(binary_heap_into_iter_sorted)]
+
+use log::{info, warn};
+use sha256::digest;
+
+use common::trace::{TraceData, TraceError, TraceHeader, TraceOp};
+use common::R3Error;
+
+pub mod parser;
+use parser::{dump_replay_ops, reorder_replay_ops};
+
+pub mod generator;
+use generator::{generate_replay_module, Backend};
+
+pub mod plan;
+use plan::ReplayPlan;
+
+#[cfg(feature = "rust-generator")]
+pub mod rust_generator;
+pub mod structs;
+pub mod termination;
+
+/// Options of replay generation; see `replay -h` for their CLI counterparts
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Backend generating the replay module; [`Backend::for_ops`] if `None`
+    pub backend: Option<Backend>,
+    /// Enable debug calls within the replay module
+    pub debug: bool,
+    /// Fold contiguous stores into data segments
+    pub fold_stores: bool,
+    /// Instrumentation scheme the trace is expected to be recorded with
+    pub scheme: String,
+    /// Transformed replay operations output file
+    pub opsfile: Option<String>,
+}
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            backend: None,
+            debug: false,
+            fold_stores: false,
+            scheme: String::from("r3-record"),
+            opsfile: None,
+        }
+    }
+}
+
+/// Recording schemes numbering access sites identically, so that traces of
+/// either one replay alike
+const EQUIVALENT_SCHEMES: [&str; 2] = ["r3-record", common::instrument::r3_record::SCHEME];
+
+/// Whether a trace recorded with scheme `recorded` replays soundly when
+/// `expected` was asked for
+fn schemes_match(recorded: &str, expected: &str) -> bool {
+    recorded == expected
+        || (EQUIVALENT_SCHEMES.contains(&recorded) && EQUIVALENT_SCHEMES.contains(&expected))
+}
+
+/// Warning for replaying a trace with `header` when scheme `expected` was
+/// asked for, if its recording scheme does not match or is unknown
+fn scheme_warning(header: &TraceHeader, expected: &str) -> Option<String> {
+    match &header.provenance {
+        Some(provenance) if !schemes_match(&provenance.scheme, expected) => Some(format!(
+            "Trace was recorded with scheme \"{}\" (expected \"{}\"); replay may be unsound",
+            provenance.scheme, expected
+        )),
+        Some(_) => None,
+        None => Some(String::from(
+            "Trace has no provenance; cannot verify its recording scheme",
+        )),
+    }
+}
+
+/// Generate a replay module for `wasmbin` from the ops of a trace with
+/// `header`, returning the module
+///
+/// The trace is consumed op-by-op (e.g. from a
+/// [`TraceReader`](common::trace::TraceReader)); fails if it was recorded
+/// from a different module
+pub fn generate_replay<I>(
+    wasmbin: &[u8],
+    header: &TraceHeader,
+    trace: I,
+    options: &ReplayOptions,
+) -> Result<Vec<u8>, R3Error>
+where
+    I: IntoIterator<Item = Result<TraceOp, TraceError>>,
+{
+    header.check_sha256(Some(digest(wasmbin).as_str()))?;
+    match (scheme_warning(header, &options.scheme), &header.provenance) {
+        (Some(warning), _) => warn!("{}", warning),
+        (None, Some(provenance)) => info!(
+            "Trace recorded by {} on {}",
+            provenance.tool_version, provenance.host
+        ),
+        (None, None) => {}
+    }
+    generate_replay_from_plan(wasmbin, ReplayPlan::from_trace(header, trace)?, options)
+}
+
+/// Generate a replay module for `wasmbin` from `plan`, e.g. as reduced by
+/// `reduce`; fails if it was planned for a different module
+pub fn generate_replay_from_plan(
+    wasmbin: &[u8],
+    mut plan: ReplayPlan,
+    options: &ReplayOptions,
+) -> Result<Vec<u8>, R3Error> {
+    let sha256 = digest(wasmbin);
+    if plan.sha256 != sha256 {
+        return Err(R3Error::Sha256Mismatch {
+            expected: sha256,
+            found: plan.sha256,
+        });
+    }
+    // Dump ops before reordering since it's already ordered by sync_ids
+    if let Some(ref opsfile) = options.opsfile {
+        dump_replay_ops(&plan.ops, opsfile.as_str())?;
+    }
+    // Reorder replay ops to order by tids first and then sync_ids
+    reorder_replay_ops(&mut plan.ops);
+
+    match plan.metadata.termination {
+        Some(ref kind) => info!("Recorded termination: {}", kind),
+        None => warn!("Trace has no termination record; runner cannot verify the replay's ending"),
+    }
+    if let Some(ref output) = plan.metadata.output {
+        for (fd, data) in output {
+            info!("Recorded output: {} bytes on fd {}", data.len(), fd);
+        }
+    }
+
+    generate_replay_module(
+        &plan.ops,
+        wasmbin,
+        options.debug,
+        options.fold_stores,
+        &plan.metadata,
+        options.backend,
+    )
+}
+
+/// Generate a replay module for `wasmbin` from an in-memory `trace`, e.g. as
+/// returned by
+/// [`Recorder::record`](../record/struct.Recorder.html#method.record)
+pub fn generate_replay_from_trace(
+    wasmbin: &[u8],
+    trace: &TraceData,
+    options: &ReplayOptions,
+) -> Result<Vec<u8>, R3Error> {
+    if trace.truncated {
+        warn!("Trace is truncated; replay will diverge after the last recorded op");
+    }
+    generate_replay(
+        wasmbin,
+        &trace.header,
+        trace.trace.iter().cloned().map(Ok),
+        options,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::trace::Provenance;
+
+    #[test]
+    fn equivalent_schemes() {
+        assert!(schemes_match("r3-record", "r3-record"));
+        assert!(schemes_match("r3-record-rs", "r3-record"));
+        assert!(schemes_match("r3-record", "r3-record-rs"));
+        assert!(schemes_match("custom", "custom"));
+        assert!(!schemes_match("custom", "r3-record"));
+        assert!(!schemes_match("r3-record-rs", "custom"));
+    }
+
+    #[test]
+    fn scheme_warnings() {
+        let recorded = |scheme: &str| {
+            let mut header = TraceHeader::new("0123abcd");
+            header.provenance = Some(Provenance {
+                argv: vec![],
+                env: vec![],
+                scheme: scheme.to_string(),
+                instargs: vec![],
+                log_level: 0,
+                tool_version: String::new(),
+                host: String::new(),
+                target: String::new(),
+                started_ms: 0,
+                finished_ms: 0,
+            });
+            header
+        };
+        assert_eq!(scheme_warning(&recorded("r3-record"), "r3-record"), None);
+        assert_eq!(scheme_warning(&recorded("r3-record-rs"), "r3-record"), None);
+        assert_eq!(
+            scheme_warning(&recorded("custom"), "r3-record").unwrap(),
+            "Trace was recorded with scheme \"custom\" (expected \"r3-record\"); replay may be unsound"
+        );
+        let unknown = scheme_warning(&TraceHeader::new("0123abcd"), "r3-record");
+        assert!(unknown.unwrap().starts_with("Trace has no provenance"));
+    }
+}
@@ -6,6 +6,7 @@ use std::fs::File;
 use std::io::{self, Write};
 
 use common::trace::*;
+use common::R3Error;
 
 use crate::structs::*;
 
@@ -92,14 +93,24 @@ pub fn reorder_replay_ops(replay_ops: &mut BTreeMap<u32, ReplayOp>) {
 ///  trace[n] → trace[m]  ⇒ replay_idx[n] < replay_idx[m]
 /// ```
 /// where ⇒ denotes "happened before" relation
-pub fn construct_replay_ops(trace: &Vec<TraceOp>) -> BTreeMap<u32, ReplayOp> {
+///
+/// The trace is consumed op-by-op (e.g. from a [`TraceReader`]), so only the
+/// resulting replay operations are held in memory
+///
+/// Fails if the trace is malformed, or if a differing access precedes every
+/// call it could be attributed to (e.g. in a sliced trace)
+pub fn construct_replay_ops<I>(trace: I) -> Result<BTreeMap<u32, ReplayOp>, R3Error>
+where
+    I: IntoIterator<Item = Result<TraceOp, TraceError>>,
+{
     let mut replay: BTreeMap<u32, ReplayOp> = BTreeMap::new();
 
     let mut queued_seq_ops: VecDeque<ReplayOpSingle> = VecDeque::new();
 
     let mut sync_id_global = 0;
     for trace_op in trace {
-        match trace_op {
+        let trace_op = trace_op?;
+        match &trace_op {
             TraceOp::Call {
                 tid,
                 access_idx,
@@ -162,19 +173,20 @@ pub fn construct_replay_ops(trace: &Vec<TraceOp>) -> BTreeMap<u32, ReplayOp> {
                 // We currently map all differing accesses to the last call
                 // i.e, the front of queued calls
                 if *differ {
-                    if let Some(ref mut target_call) = queued_seq_ops.front_mut() {
-                        target_call.prop.stores.push(ReplayMemStore {
-                            addr: *addr,
-                            size: *size,
-                            value: *load_value,
-                        });
-                    } else {
-                        panic!("No previous call to map access to in trace");
-                    }
+                    let target_call =
+                        queued_seq_ops.front_mut().ok_or(R3Error::UnmappedAccess {
+                            tid: *tid,
+                            access_idx: *access_idx,
+                        })?;
+                    target_call.prop.stores.push(ReplayMemStore {
+                        addr: *addr,
+                        size: *size,
+                        value: *load_value,
+                    });
                 }
                 // Synchronized accesses are treated as ops for ordering
                 // We don't flush to map since it's not a call
-                if let TraceOp::SyncAccess { .. } = trace_op {
+                if let TraceOp::SyncAccess { .. } = &trace_op {
                     trace!(
                         "New sync access --> {:?}; Flushing queue {:?}",
                         *opcode,
@@ -197,11 +209,87 @@ pub fn construct_replay_ops(trace: &Vec<TraceOp>) -> BTreeMap<u32, ReplayOp> {
                     });
                 }
             }
+            // Output and termination are embedded into the replay module
+            // separately
+            TraceOp::Output { .. } | TraceOp::Terminate { .. } => {}
         }
     }
 
     // Flush any remaining queued calls
     append_vecd_to_map(&mut replay, &mut queued_seq_ops);
 
-    return replay;
+    Ok(replay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(tid: u64, access_idx: u32, call_id: CallID) -> TraceOp {
+        TraceOp::Call {
+            tid,
+            access_idx,
+            opcode: 0x10,
+            func_idx: 0,
+            return_val: 0,
+            call_id,
+        }
+    }
+
+    fn store(tid: u64, access_idx: u32, addr: i32) -> TraceOp {
+        TraceOp::Access {
+            tid,
+            access_idx,
+            opcode: 0x28,
+            addr,
+            size: 4,
+            load_value: 1,
+            expected_value: 0,
+            differ: true,
+        }
+    }
+
+    #[test]
+    fn stores_map_to_calls() {
+        let trace = [
+            call(1, 10, CallID::ScGeneric),
+            store(1, 2, 0x100),
+            call(1, 11, CallID::ScGeneric),
+            store(1, 2, 0x200),
+        ];
+        let ops = construct_replay_ops(trace.into_iter().map(Ok)).unwrap();
+        let stores = |access_idx: u32| -> Vec<i32> {
+            ops[&access_idx].props[0]
+                .stores
+                .iter()
+                .map(|store| store.addr)
+                .collect()
+        };
+        assert_eq!(stores(10), [0x100]);
+        assert_eq!(stores(11), [0x200]);
+    }
+
+    #[test]
+    fn unmapped_access() {
+        let trace = [store(3, 5, 0x100), call(3, 10, CallID::ScGeneric)];
+        assert!(matches!(
+            construct_replay_ops(trace.into_iter().map(Ok)),
+            Err(R3Error::UnmappedAccess {
+                tid: 3,
+                access_idx: 5
+            })
+        ));
+    }
+
+    #[test]
+    fn trace_errors() {
+        let trace = [
+            Ok(call(1, 10, CallID::ScGeneric)),
+            Err(TraceError::Truncated),
+        ];
+        assert!(matches!(
+            construct_replay_ops(trace),
+            Err(R3Error::Trace(TraceError::Truncated))
+        ));
+    }
 }
@@ -0,0 +1,109 @@
+//! Replay plans: replay operations constructed from a trace, saved for
+//! transformation (e.g. by `reduce`) before replay generation
+//!
+//! ### Format (version 1)
+//! ```text
+//!  | PLAN_MAGIC (4B) | version (u32 LE) | postcard-encoded ReplayPlan |
+//! ```
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use common::trace::{TraceError, TraceHeader, TraceOp};
+use common::R3Error;
+
+use crate::generator::ReplayMetadata;
+use crate::parser::construct_replay_ops;
+use crate::structs::ReplayOp;
+
+/// Magic bytes at the start of every replay plan
+pub const PLAN_MAGIC: [u8; 4] = [0x00, b'R', b'3', b'P'];
+
+/// Current replay plan format version
+pub const PLAN_VERSION: u32 = 1;
+
+/// Replay operations of a trace, with the recorded behaviour to embed into the
+/// replay module
+///
+/// Operations are kept in trace-observed order; they are only reordered for
+/// replay generation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayPlan {
+    /// Digest of the recorded module
+    pub sha256: String,
+    pub ops: BTreeMap<u32, ReplayOp>,
+    pub metadata: ReplayMetadata,
+}
+
+impl ReplayPlan {
+    /// Construct the plan for the ops of a trace with `header`
+    ///
+    /// The trace is consumed op-by-op, like [construct_replay_ops]
+    pub fn from_trace<I>(header: &TraceHeader, trace: I) -> Result<Self, R3Error>
+    where
+        I: IntoIterator<Item = Result<TraceOp, TraceError>>,
+    {
+        let mut metadata = ReplayMetadata::default();
+        let ops = construct_replay_ops(trace.into_iter().inspect(|op| {
+            match op {
+                Ok(TraceOp::Terminate { kind }) => metadata.termination = Some(kind.clone()),
+                Ok(TraceOp::Output { fd, data, .. }) => metadata
+                    .output
+                    .get_or_insert_with(BTreeMap::new)
+                    .entry(*fd)
+                    .or_default()
+                    .extend_from_slice(data),
+                _ => {}
+            }
+        }))?;
+        Ok(ReplayPlan {
+            sha256: header.sha256.clone(),
+            ops,
+            metadata,
+        })
+    }
+
+    /// Serialize the plan into the current format
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut ser = PLAN_MAGIC.to_vec();
+        ser.extend_from_slice(&PLAN_VERSION.to_le_bytes());
+        postcard::to_io(self, &mut ser).unwrap();
+        ser
+    }
+
+    /// Deserialize a plan from buffer `ser`
+    ///
+    /// Optionally provide a SHA256 digest of the module to verify it was
+    /// planned for
+    pub fn deserialize(ser: &[u8], sha256: Option<&str>) -> Result<Self, R3Error> {
+        let body = ser
+            .strip_prefix(&PLAN_MAGIC[..])
+            .ok_or_else(|| R3Error::Plan(String::from("missing magic bytes")))?;
+        let (version, body) = body
+            .split_first_chunk::<4>()
+            .ok_or_else(|| R3Error::Plan(String::from("missing version")))?;
+        let version = u32::from_le_bytes(*version);
+        if version != PLAN_VERSION {
+            return Err(R3Error::Plan(format!(
+                "unsupported version {} (supported: {})",
+                version, PLAN_VERSION
+            )));
+        }
+        let plan: ReplayPlan =
+            postcard::from_bytes(body).map_err(|e| R3Error::Plan(e.to_string()))?;
+        if let Some(sha256) = sha256 {
+            if plan.sha256 != sha256 {
+                return Err(R3Error::Sha256Mismatch {
+                    expected: sha256.to_string(),
+                    found: plan.sha256,
+                });
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Total number of memory stores across all operations
+    pub fn total_stores(&self) -> usize {
+        self.ops.values().map(ReplayOp::total_stores).sum()
+    }
+}
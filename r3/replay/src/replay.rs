@@ -1,23 +1,17 @@
 //! Binary crate for generating replay Wasm modules given a Trace and the
 //! original module that was recorded.
-
-#![feature(binary_heap_into_iter_sorted)]
-
-use clap::Parser;
-use log::info;
-use sha256::digest;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use log::{info, warn};
 use std::error::Error;
-use std::fs;
-
-use common::trace::*;
+use std::fs::{self, File};
+use std::io::BufReader;
 
-pub mod parser;
-use parser::{construct_replay_ops, dump_replay_ops, reorder_replay_ops};
+use common::trace::TraceReader;
 
-pub mod generator;
-use generator::generate_replay_file;
-
-pub mod structs;
+use replay::generator::Backend;
+use replay::plan::ReplayPlan;
+use replay::{generate_replay, generate_replay_from_plan, ReplayOptions};
 
 /// Command-Line Arguments
 #[derive(Parser, Debug)]
@@ -27,6 +21,10 @@ struct CLI {
     #[arg(short, long, default_value_t = String::from("trace.r3"))]
     tracefile: String,
 
+    /// Replay plan generated by `reduce`, used instead of the trace
+    #[arg(short, long, conflicts_with = "tracefile")]
+    plan: Option<String>,
+
     /// Output (modified) Wasm replay file
     #[arg(short, long, default_value_t = String::from("replay.wasm"))]
     outfile: String,
@@ -35,10 +33,26 @@ struct CLI {
     #[arg(short, long)]
     debug: bool,
 
+    /// Fold contiguous stores into data segments (`rust` backend only)
+    #[arg(long)]
+    fold_stores: bool,
+
     /// Transformed replay operations output file
     #[arg(short = 'f', long)]
     opsfile: Option<String>,
 
+    /// Backend generating the replay module [default: rust, or cpp for traces
+    /// with calls from several threads]. `rust` replays runs of calls
+    /// returning identical or progressing values as a single case; `cpp`
+    /// modules grow with call count, but order calls across threads
+    #[arg(short, long, value_enum)]
+    backend: Option<Backend>,
+
+    /// Instrumentation scheme the trace is expected to be recorded with
+    /// (`r3-record` and `r3-record-rs` are interchangeable)
+    #[arg(long, default_value_t = String::from("r3-record"))]
+    scheme: String,
+
     /// Original (unmodified) Wasm file
     #[arg(short, long)]
     wasmfile: String,
@@ -49,8 +63,12 @@ impl CLI {
     fn print(&self) {
         info!("Wasmfile: {:?}", self.wasmfile);
         info!("Tracefile: {:?}", self.tracefile);
+        info!("Plan [optional]: {:?}", self.plan);
         info!("Generate Debug: {:?}", self.debug);
+        info!("Fold Stores: {:?}", self.fold_stores);
         info!("Opsfile: {:?}", self.opsfile);
+        info!("Backend [optional]: {:?}", self.backend);
+        info!("Expected Scheme: {:?}", self.scheme);
         info!("Outfile: {:?}", self.outfile);
     }
 }
@@ -59,25 +77,45 @@ impl CLI {
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::builder().format_timestamp_millis().init();
     let cli = CLI::parse();
+    if cli.fold_stores && cli.backend == Some(Backend::Cpp) {
+        CLI::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--fold-stores requires the rust backend (`-b rust`)",
+            )
+            .exit();
+    }
     cli.print();
 
-    // Read wasm file, compute its digest
     let wasmbin = fs::read(cli.wasmfile.as_str())?;
-    let sha256_wasm = digest(&wasmbin);
-
-    // Read trace file and deserialize
-    let tracebin = fs::read(cli.tracefile.as_str())?;
-    let deserialized = TraceData::deserialize(&tracebin, Some(sha256_wasm.as_str()));
-
-    let mut replay_ops = construct_replay_ops(&deserialized.trace);
-    // Dump ops before reordering since it's already ordered by sync_ids
-    if let Some(opsfile) = cli.opsfile {
-        dump_replay_ops(&replay_ops, opsfile.as_str()).unwrap();
-    }
-    // Reorder replay ops to order by tids first and then sync_ids
-    reorder_replay_ops(&mut replay_ops);
 
-    generate_replay_file(&replay_ops, &wasmbin, &cli.outfile, cli.debug)?;
+    let options = ReplayOptions {
+        backend: cli.backend,
+        debug: cli.debug,
+        fold_stores: cli.fold_stores,
+        scheme: cli.scheme,
+        opsfile: cli.opsfile,
+    };
+    let replay_module = match cli.plan {
+        Some(planfile) => {
+            let plan = ReplayPlan::deserialize(&fs::read(planfile)?, None)?;
+            generate_replay_from_plan(&wasmbin, plan, &options)?
+        }
+        None => {
+            // Stream trace file and generate the replay module
+            let file = File::open(cli.tracefile.as_str())?;
+            let mut reader = TraceReader::new(BufReader::new(file))?;
+            let header = reader.header().clone();
+            let replay_module = generate_replay(&wasmbin, &header, reader.by_ref(), &options)?;
+            if reader.is_truncated() {
+                warn!("Trace is truncated; replay will diverge after the last recorded op");
+            }
+            replay_module
+        }
+    };
+
+    fs::write(&cli.outfile, replay_module)?;
+    info!("Wrote replay file to {}", cli.outfile);
 
     Ok(())
 }
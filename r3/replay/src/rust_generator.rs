@@ -0,0 +1,1448 @@
+//! Native Rust replay module generator (feature `rust-generator`)
+//!
+//! Rewrites the original module directly from [`ReplayOp`]s, without going
+//! through the C++ `r3-replay-generator` routine:
+//! * Every function import is replaced by a trapping stub, and the
+//!   [`r3-replay`](SC_IMPORTS) interface is imported instead
+//! * Every recorded call site of an import (identified by its access index)
+//!   calls a generated *site function* instead, which replays the recorded
+//!   stores and return value of the next recorded call of the current thread
+//!
+//! Access indices are assigned as during recording (see [common::rewrite])
+//!
+//! ### Design Notes
+//! A site function dispatches on the thread ID (from `SC_gettid`) and then on
+//! a per-site, per-thread case counter (a mutable global) with `br_table`s.
+//! Props of a thread are replayed in `sync_id` order. Ordering **across**
+//! threads is not enforced, so plans with calls from more than one thread are
+//! rejected (the C++ backend replays them), and implicit synchronization ops,
+//! ordered by the program within a single thread, are not instrumented
+//!
+//! Each case replays a single [`ReplayOpProp`], except for runs of at least
+//! [`RUN_MIN_PROPS`] consecutive calls that only return a value (no stores or
+//! host calls), with identical or (for integers) arithmetic-progression
+//! return values. A run is replayed by a single case that computes the return
+//! value from the call's position in the run, kept in a second per-thread
+//! global. Generated code is thus proportional to the number of distinct
+//! behaviours rather than the number of calls (without `debug`, which logs
+//! every call). Progressions wrap at the width of the import's result, like
+//! the recorded values. Runs are only found here: the plan's
+//! [`ReplayOp::props`] still hold every call
+//!
+//! With store folding, the stores of a call that form a contiguous run of at
+//! least [`FOLD_MIN_BYTES`] bytes (e.g. a buffer filled by `read`) are emitted
+//! as a passive data segment, copied in with `memory.init` and then dropped,
+//! instead of one store instruction per recorded [`ReplayMemStore`]. This
+//! requires bulk memory support in the engine running the replay
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, BTreeSet};
+use std::convert::Infallible;
+
+use wasm_encoder::reencode::{utils, Error as ReencodeError, Reencode};
+use wasm_encoder::{
+    BlockType, CodeSection, ConstExpr, DataCountSection, DataSection, EntityType, Function,
+    FunctionSection, GlobalSection, GlobalType, ImportSection, Instruction, MemArg, SectionId,
+    TypeSection, ValType,
+};
+use wasmparser::{
+    CompositeInnerType, FuncType, KnownCustom, NameSectionReader, Operator, Parser, Payload,
+    TypeRef,
+};
+
+use common::trace::CallID;
+use common::{rewrite, R3Error};
+
+use crate::generator::REPLAY_MODULE;
+use crate::structs::*;
+
+/// Replay interface functions imported by every replay module, at function
+/// indices `0..SC_IMPORTS.len()`
+const SC_IMPORTS: [(&str, &[ValType], &[ValType]); 6] = [
+    ("SC_proc_exit", &[ValType::I32], &[]),
+    ("SC_thread_exit", &[ValType::I32], &[]),
+    ("SC_writev", &[ValType::I32; 3], &[ValType::I64]),
+    ("SC_futex_log", &[ValType::I32; 3], &[]),
+    ("SC_gettid", &[], &[ValType::I32]),
+    (
+        "SC_log_call",
+        &[
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I32,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+            ValType::I64,
+        ],
+        &[],
+    ),
+];
+const SC_PROC_EXIT: u32 = 0;
+const SC_THREAD_EXIT: u32 = 1;
+const SC_WRITEV: u32 = 2;
+const SC_FUTEX_LOG: u32 = 3;
+const SC_GETTID: u32 = 4;
+const SC_LOG_CALL: u32 = 5;
+const NUM_SC_IMPORTS: u32 = SC_IMPORTS.len() as u32;
+
+/// Minimum number of consecutive calls replayed by a single case
+const RUN_MIN_PROPS: usize = 4;
+
+/// Minimum length of a contiguous run of stored bytes that is folded into a
+/// data segment; shorter runs cost less as individual stores
+const FOLD_MIN_BYTES: usize = 16;
+
+/// Function import of the original module
+struct FuncImport {
+    module: String,
+    name: String,
+    ty: u32,
+}
+
+/// Index spaces of the original module that are extended by the rewrite
+#[derive(Default)]
+struct ModuleLayout {
+    /// Function types by type index (`None` for non-function types)
+    types: Vec<Option<FuncType>>,
+    func_imports: Vec<FuncImport>,
+    num_defined_funcs: u32,
+    /// Imported and defined globals
+    num_globals: u32,
+    /// Data segments of the original module
+    num_data: u32,
+    has_data_count: bool,
+}
+impl ModuleLayout {
+    /// Whether `op`, encoded at the start of `code`, takes an access index
+    fn is_access_site(&self, code: &[u8], op: &Operator) -> bool {
+        rewrite::is_access_site(code, op, self.func_imports.len() as u32)
+    }
+
+    /// Type of the imported function `func_idx`
+    fn import_type(&self, func_idx: u32) -> &FuncType {
+        let ty = self.func_imports[func_idx as usize].ty;
+        self.types[ty as usize].as_ref().unwrap()
+    }
+}
+
+/// Stores of a recorded call to replay
+#[derive(Default)]
+struct PropStores<'a> {
+    /// Folded runs, as start address and index into
+    /// [`ReplayGenerator::segments`]
+    folded: Vec<(u64, usize)>,
+    /// Stores replayed individually, in recorded order
+    stores: Vec<&'a ReplayMemStore>,
+}
+impl<'a> PropStores<'a> {
+    /// Replay all of `stores` individually
+    fn unfolded(stores: &'a [ReplayMemStore]) -> Self {
+        PropStores {
+            folded: vec![],
+            stores: stores.iter().collect(),
+        }
+    }
+
+    /// Fold the contiguous runs of at least [`FOLD_MIN_BYTES`] in `stores`
+    /// into new `segments`
+    ///
+    /// A folded run holds the final value of each byte, which is equivalent
+    /// since all stores of a call are replayed at once. Every store lies
+    /// within a single run, so stores are either folded or kept whole
+    fn fold(stores: &'a [ReplayMemStore], segments: &mut Vec<Vec<u8>>) -> Self {
+        let mut bytes: BTreeMap<u64, u8> = BTreeMap::new();
+        for store in stores {
+            let value = store.value.to_le_bytes();
+            for (offset, byte) in value[..store.size as usize].iter().enumerate() {
+                bytes.insert(store.addr as u32 as u64 + offset as u64, *byte);
+            }
+        }
+        let mut runs: Vec<(u64, Vec<u8>)> = Vec::new();
+        for (addr, byte) in bytes {
+            match runs.last_mut() {
+                Some((start, run)) if *start + run.len() as u64 == addr => run.push(byte),
+                _ => runs.push((addr, vec![byte])),
+            }
+        }
+
+        let mut prop_stores = PropStores::default();
+        // End address of each folded run, by start address
+        let mut folded_ends: BTreeMap<u64, u64> = BTreeMap::new();
+        for (start, run) in runs {
+            if run.len() >= FOLD_MIN_BYTES {
+                folded_ends.insert(start, start + run.len() as u64);
+                prop_stores.folded.push((start, segments.len()));
+                segments.push(run);
+            }
+        }
+        prop_stores.stores = stores
+            .iter()
+            .filter(|store| {
+                let addr = store.addr as u32 as u64;
+                !folded_ends
+                    .range(..=addr)
+                    .next_back()
+                    .is_some_and(|(_, end)| addr < *end)
+            })
+            .collect();
+        prop_stores
+    }
+}
+
+/// Consecutive recorded calls of a thread, replayed by a single case
+struct PropRun {
+    /// Index of the first call in [`ThreadProps::props`]
+    start: usize,
+    len: usize,
+    /// Return value of the first call
+    base: i64,
+    /// Difference between the return values of consecutive calls
+    step: i64,
+}
+
+/// How the return values of a run may progress
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Progression {
+    /// Identical return values only
+    None,
+    /// Arithmetic progression wrapping at 32 bits (recorded sign-extended)
+    I32,
+    /// Arithmetic progression wrapping at 64 bits
+    I64,
+}
+impl Progression {
+    /// Truncate `value` to the width of the progression, sign-extended
+    fn wrap(self, value: i64) -> i64 {
+        match self {
+            Progression::I32 => value as i32 as i64,
+            _ => value,
+        }
+    }
+}
+
+/// Recorded calls of a single thread at a site
+struct ThreadProps {
+    tid: u64,
+    /// Global counting the cases replayed so far
+    counter: u32,
+    /// Global counting the calls replayed so far in the current run, if the
+    /// thread has runs of multiple calls
+    position: Option<u32>,
+    /// Indices into the site's [`ReplayOp::props`], in `sync_id` order
+    props: Vec<usize>,
+    /// Cases, in replay order
+    runs: Vec<PropRun>,
+}
+impl ThreadProps {
+    /// Split the calls `props` of `op` into runs, whose return values progress
+    /// as `progression` allows
+    fn runs(op: &ReplayOp, props: &[usize], debug: bool, progression: Progression) -> Vec<PropRun> {
+        let returns_only = |idx: usize| {
+            let prop = &op.props[props[idx]];
+            !debug
+                && prop.stores.is_empty()
+                && !matches!(
+                    prop.call_id,
+                    CallID::ScProcExit { .. } | CallID::ScThreadExit { .. }
+                )
+                && !matches!(prop.call_id, CallID::ScMmap { grow } if grow > 0)
+        };
+        let return_val = |idx: usize| op.props[props[idx]].return_val;
+
+        let mut runs: Vec<PropRun> = Vec::new();
+        let mut start = 0;
+        while start < props.len() {
+            let base = return_val(start);
+            let step = match start + 1 < props.len() && progression != Progression::None {
+                true => progression.wrap(return_val(start + 1).wrapping_sub(base)),
+                false => 0,
+            };
+            let mut len = 1;
+            if returns_only(start) {
+                while start + len < props.len()
+                    && returns_only(start + len)
+                    && return_val(start + len)
+                        == progression.wrap(base.wrapping_add(step.wrapping_mul(len as i64)))
+                {
+                    len += 1;
+                }
+            }
+            if len < RUN_MIN_PROPS {
+                len = 1;
+            }
+            runs.push(PropRun {
+                start,
+                len,
+                base,
+                step,
+            });
+            start += len;
+        }
+        runs
+    }
+}
+
+/// Call site of an import that is replaced by a generated site function
+struct ReplaySite<'a> {
+    op: &'a ReplayOp,
+    /// Type of the called import (and the site function)
+    ty: u32,
+    threads: Vec<ThreadProps>,
+    /// Stores to replay for each of [`ReplayOp::props`]
+    stores: Vec<PropStores<'a>>,
+}
+
+/// [`Reencode`]r rewriting the original module into a replay module
+struct ReplayGenerator<'a> {
+    wasm: &'a [u8],
+    layout: ModuleLayout,
+    sites: Vec<ReplaySite<'a>>,
+    /// Function index of the site function for each replaced access index
+    site_funcs: BTreeMap<u32, u32>,
+    /// Passive data segments of folded stores, following the original
+    /// module's segments
+    segments: Vec<Vec<u8>>,
+    /// Case counter and run position globals of all sites
+    num_counters: u32,
+    debug: bool,
+    num_bodies: u32,
+    next_access_idx: u32,
+    imports_done: bool,
+    globals_done: bool,
+    data_count_done: bool,
+    data_done: bool,
+}
+
+impl<'a> ReplayGenerator<'a> {
+    /// Scan the original module and match `replay_ops` to its call sites,
+    /// folding stores if `fold_stores`
+    fn new(
+        replay_ops: &'a BTreeMap<u32, ReplayOp>,
+        wasm: &'a [u8],
+        debug: bool,
+        fold_stores: bool,
+    ) -> Result<Self, R3Error> {
+        let parse_err = |e: wasmparser::BinaryReaderError| R3Error::Instrument(e.to_string());
+
+        // Callee of every import call site, by access index
+        let mut call_sites: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut layout = ModuleLayout::default();
+        let mut access_idx = 0;
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.map_err(parse_err)? {
+                Payload::TypeSection(reader) => {
+                    for rec_group in reader {
+                        for sub_type in rec_group.map_err(parse_err)?.into_types() {
+                            layout.types.push(match sub_type.composite_type.inner {
+                                CompositeInnerType::Func(ty) => Some(ty),
+                                _ => None,
+                            });
+                        }
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.map_err(parse_err)?;
+                        match import.ty {
+                            TypeRef::Func(ty) => layout.func_imports.push(FuncImport {
+                                module: import.module.to_string(),
+                                name: import.name.to_string(),
+                                ty,
+                            }),
+                            TypeRef::Global(_) => layout.num_globals += 1,
+                            _ => {}
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => layout.num_defined_funcs = reader.count(),
+                Payload::GlobalSection(reader) => layout.num_globals += reader.count(),
+                Payload::DataCountSection { .. } => layout.has_data_count = true,
+                Payload::DataSection(reader) => layout.num_data = reader.count(),
+                Payload::CodeSectionEntry(body) => {
+                    let mut reader = body.get_operators_reader().map_err(parse_err)?;
+                    while !reader.eof() {
+                        let pos = reader.original_position();
+                        let op = reader.read().map_err(parse_err)?;
+                        if layout.is_access_site(&wasm[pos..], &op) {
+                            if let Operator::Call { function_index } = op {
+                                call_sites.insert(access_idx, function_index);
+                            }
+                            access_idx += 1;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        info!(
+            "Module has {} access sites ({} import call sites)",
+            access_idx,
+            call_sites.len()
+        );
+        if layout.num_defined_funcs == 0 {
+            return Err(R3Error::Instrument(String::from(
+                "Module has no functions to replay",
+            )));
+        }
+
+        let tids: BTreeSet<u64> = replay_ops
+            .values()
+            .flat_map(|op| &op.props)
+            .map(|prop| prop.tid)
+            .collect();
+        if tids.len() > 1 {
+            return Err(R3Error::Instrument(format!(
+                "Trace has calls from {} threads, but ordering across threads is not replayed by this backend",
+                tids.len()
+            )));
+        }
+
+        let mut sites: Vec<ReplaySite> = Vec::new();
+        let mut site_funcs: BTreeMap<u32, u32> = BTreeMap::new();
+        let mut segments: Vec<Vec<u8>> = Vec::new();
+        let mut num_counters = 0;
+        for op in replay_ops.values() {
+            if op.implicit_sync {
+                continue;
+            }
+            let func_idx = *call_sites.get(&op.access_idx).ok_or_else(|| {
+                R3Error::Instrument(format!(
+                    "Replay op at access index {} is not an import call site in the module",
+                    op.access_idx
+                ))
+            })?;
+            if func_idx != op.func_idx {
+                return Err(R3Error::Instrument(format!(
+                    "Access index {} calls function {} in the module, but {} in the trace",
+                    op.access_idx, func_idx, op.func_idx
+                )));
+            }
+            let ty = layout.func_imports[func_idx as usize].ty;
+            let progression = match layout.import_type(func_idx).results().first() {
+                Some(wasmparser::ValType::I32) => Progression::I32,
+                Some(wasmparser::ValType::I64) => Progression::I64,
+                _ => Progression::None,
+            };
+            if layout.import_type(func_idx).results().len() > 1 {
+                return Err(R3Error::Instrument(format!(
+                    "Import {} returns multiple values",
+                    func_idx
+                )));
+            }
+            if let Some(store) = op
+                .props
+                .iter()
+                .flat_map(|prop| &prop.stores)
+                .find(|store| ![1, 2, 4, 8].contains(&store.size))
+            {
+                return Err(R3Error::Instrument(format!(
+                    "Unsupported store of size {} at access index {}",
+                    store.size, op.access_idx
+                )));
+            }
+
+            let mut by_tid: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+            for (prop_idx, prop) in op.props.iter().enumerate() {
+                by_tid.entry(prop.tid).or_default().push(prop_idx);
+            }
+            let threads = by_tid
+                .into_iter()
+                .map(|(tid, mut props)| {
+                    props.sort_by_key(|prop_idx| op.props[*prop_idx].sync_id);
+                    let runs = ThreadProps::runs(op, &props, debug, progression);
+                    num_counters += 1;
+                    let counter = layout.num_globals + num_counters - 1;
+                    let position = runs.iter().any(|run| run.len > 1).then(|| {
+                        num_counters += 1;
+                        layout.num_globals + num_counters - 1
+                    });
+                    ThreadProps {
+                        tid,
+                        counter,
+                        position,
+                        props,
+                        runs,
+                    }
+                })
+                .collect();
+            let stores = op
+                .props
+                .iter()
+                .map(|prop| match fold_stores {
+                    true => PropStores::fold(&prop.stores, &mut segments),
+                    false => PropStores::unfolded(&prop.stores),
+                })
+                .collect();
+
+            site_funcs.insert(
+                op.access_idx,
+                NUM_SC_IMPORTS
+                    + layout.num_defined_funcs
+                    + layout.func_imports.len() as u32
+                    + sites.len() as u32,
+            );
+            sites.push(ReplaySite {
+                op,
+                ty,
+                threads,
+                stores,
+            });
+        }
+        for import in &layout.func_imports {
+            debug!("Stubbing import {}::{}", import.module, import.name);
+        }
+        info!(
+            "Replacing {} imports and {} call sites",
+            layout.func_imports.len(),
+            sites.len()
+        );
+        let runs: Vec<&PropRun> = sites
+            .iter()
+            .flat_map(|site| &site.threads)
+            .flat_map(|thread| &thread.runs)
+            .filter(|run| run.len > 1)
+            .collect();
+        if !runs.is_empty() {
+            info!(
+                "Collapsed {} calls into {} runs",
+                runs.iter().map(|run| run.len).sum::<usize>(),
+                runs.len()
+            );
+        }
+        if fold_stores {
+            let total_stores: usize = sites.iter().map(|site| site.op.total_stores()).sum();
+            let unfolded: usize = sites
+                .iter()
+                .flat_map(|site| &site.stores)
+                .map(|stores| stores.stores.len())
+                .sum();
+            info!(
+                "Folded {} of {} stores into {} data segments ({} bytes)",
+                total_stores - unfolded,
+                total_stores,
+                segments.len(),
+                segments.iter().map(Vec::len).sum::<usize>()
+            );
+        }
+
+        Ok(ReplayGenerator {
+            wasm,
+            layout,
+            sites,
+            site_funcs,
+            segments,
+            num_counters,
+            debug,
+            num_bodies: 0,
+            next_access_idx: 0,
+            imports_done: false,
+            globals_done: false,
+            data_count_done: false,
+            data_done: false,
+        })
+    }
+
+    /// Add the replay interface imports
+    fn push_replay_imports(&mut self, imports: &mut ImportSection) {
+        let num_types = self.layout.types.len() as u32;
+        for (i, (name, _, _)) in SC_IMPORTS.iter().enumerate() {
+            imports.import(
+                REPLAY_MODULE,
+                name,
+                EntityType::Function(num_types + i as u32),
+            );
+        }
+        self.imports_done = true;
+    }
+
+    /// Add the per-thread case counters and run positions of all sites
+    fn push_counters(&mut self, globals: &mut GlobalSection) {
+        for _ in 0..self.num_counters {
+            globals.global(
+                GlobalType {
+                    val_type: ValType::I32,
+                    mutable: true,
+                    shared: false,
+                },
+                &ConstExpr::i32_const(0),
+            );
+        }
+        self.globals_done = true;
+    }
+
+    /// Add the data segments of folded stores
+    fn push_segments(&mut self, data: &mut DataSection) {
+        for segment in &self.segments {
+            data.passive(segment.iter().copied());
+        }
+        self.data_done = true;
+    }
+
+    /// Stub replacing an import: any call not seen during recording traps
+    fn import_stub() -> Function {
+        let mut f = Function::new([]);
+        f.instruction(&Instruction::Unreachable);
+        f.instruction(&Instruction::End);
+        f
+    }
+
+    /// Site function replaying the recorded calls at `site`
+    fn site_function(&self, site: &ReplaySite) -> Function {
+        let num_params = self.layout.types[site.ty as usize]
+            .as_ref()
+            .unwrap()
+            .params()
+            .len() as u32;
+        let tid_local = num_params;
+        let counter_local = num_params + 1;
+        let position_local = num_params + 2;
+        let mut f = Function::new([(3, ValType::I32)]);
+
+        f.instruction(&Instruction::Call(SC_GETTID));
+        f.instruction(&Instruction::LocalSet(tid_local));
+        let mut tid_targets = vec![site.threads.len() as u32; site.op.max_tid as usize + 1];
+        for (case, thread) in site.threads.iter().enumerate() {
+            tid_targets[thread.tid as usize] = case as u32;
+        }
+        Self::dispatch(
+            &mut f,
+            tid_local,
+            &tid_targets,
+            site.threads.len(),
+            |f, case| {
+                let thread = &site.threads[case];
+                f.instruction(&Instruction::GlobalGet(thread.counter));
+                f.instruction(&Instruction::LocalTee(counter_local));
+                f.instruction(&Instruction::I32Const(1));
+                f.instruction(&Instruction::I32Add);
+                f.instruction(&Instruction::GlobalSet(thread.counter));
+                let run_targets: Vec<u32> = (0..thread.runs.len() as u32).collect();
+                Self::dispatch(
+                    f,
+                    counter_local,
+                    &run_targets,
+                    thread.runs.len(),
+                    |f, case| {
+                        let run = &thread.runs[case];
+                        match run.len {
+                            1 => self.replay_prop(f, site, thread.props[run.start]),
+                            _ => self.replay_run(f, site, thread, case, position_local),
+                        }
+                    },
+                );
+            },
+        );
+        f.instruction(&Instruction::End);
+        f
+    }
+
+    /// Emit a `br_table` on `local` to `case_body(case)` for each of
+    /// `num_cases` cases, where `targets` maps values of `local` to cases.
+    /// Values that are out of range of `targets`, or map to `num_cases`, trap
+    ///
+    /// Case bodies must not fall through
+    fn dispatch<F>(
+        f: &mut Function,
+        local: u32,
+        targets: &[u32],
+        num_cases: usize,
+        mut case_body: F,
+    ) where
+        F: FnMut(&mut Function, usize),
+    {
+        for _ in 0..=num_cases {
+            f.instruction(&Instruction::Block(BlockType::Empty));
+        }
+        f.instruction(&Instruction::LocalGet(local));
+        f.instruction(&Instruction::BrTable(targets.into(), num_cases as u32));
+        for case in 0..num_cases {
+            f.instruction(&Instruction::End);
+            case_body(f, case);
+        }
+        f.instruction(&Instruction::End);
+        f.instruction(&Instruction::Unreachable);
+    }
+
+    /// Replay the recorded stores and return value of `op.props[prop_idx]`
+    fn replay_prop(&self, f: &mut Function, site: &ReplaySite, prop_idx: usize) {
+        let op = site.op;
+        let prop = &op.props[prop_idx];
+        let (call_id, args) = prop.call_id.to_parts();
+        if self.debug {
+            for value in [
+                op.access_idx,
+                op.func_idx,
+                prop.tid as u32,
+                prop_idx as u32,
+                call_id,
+            ] {
+                f.instruction(&Instruction::I32Const(value as i32));
+            }
+            for value in [
+                prop.return_val,
+                args[0],
+                args[1],
+                args[2],
+                prop.sync_id as i64,
+            ] {
+                f.instruction(&Instruction::I64Const(value));
+            }
+            f.instruction(&Instruction::Call(SC_LOG_CALL));
+        }
+        if let CallID::ScMmap { grow } = prop.call_id {
+            if grow > 0 {
+                f.instruction(&Instruction::I32Const(grow as i32));
+                f.instruction(&Instruction::MemoryGrow(0));
+                f.instruction(&Instruction::Drop);
+            }
+        }
+        for (addr, segment) in &site.stores[prop_idx].folded {
+            let data_index = self.layout.num_data + *segment as u32;
+            f.instruction(&Instruction::I32Const(*addr as i32));
+            f.instruction(&Instruction::I32Const(0));
+            f.instruction(&Instruction::I32Const(self.segments[*segment].len() as i32));
+            f.instruction(&Instruction::MemoryInit { mem: 0, data_index });
+            f.instruction(&Instruction::DataDrop(data_index));
+        }
+        for store in &site.stores[prop_idx].stores {
+            let memarg = MemArg {
+                offset: 0,
+                align: store.size.trailing_zeros(),
+                memory_index: 0,
+            };
+            f.instruction(&Instruction::I32Const(store.addr));
+            f.instruction(&Instruction::I64Const(store.value));
+            f.instruction(&match store.size {
+                1 => Instruction::I64Store8(memarg),
+                2 => Instruction::I64Store16(memarg),
+                4 => Instruction::I64Store32(memarg),
+                _ => Instruction::I64Store(memarg),
+            });
+        }
+        let host_call = match prop.call_id {
+            CallID::ScProcExit { status } => Some((SC_PROC_EXIT, vec![status])),
+            CallID::ScThreadExit { status } => Some((SC_THREAD_EXIT, vec![status])),
+            CallID::ScWritev { fd, iov, iovcnt } if self.debug => {
+                Some((SC_WRITEV, vec![fd, iov, iovcnt as i32]))
+            }
+            CallID::ScFutex { addr, val, .. } if self.debug => {
+                Some((SC_FUTEX_LOG, vec![addr, args[1] as i32, val as i32]))
+            }
+            _ => None,
+        };
+        if let Some((func, call_args)) = host_call {
+            for arg in call_args {
+                f.instruction(&Instruction::I32Const(arg));
+            }
+            f.instruction(&Instruction::Call(func));
+            if func == SC_WRITEV {
+                f.instruction(&Instruction::Drop);
+            }
+        }
+        self.replay_return(f, site, prop.return_val);
+    }
+
+    /// Replay the `case`th run of `thread`, which is at least two calls long
+    ///
+    /// The case counter was already advanced past the run, so it is reset to
+    /// the run until its last call
+    fn replay_run(
+        &self,
+        f: &mut Function,
+        site: &ReplaySite,
+        thread: &ThreadProps,
+        case: usize,
+        position_local: u32,
+    ) {
+        let run = &thread.runs[case];
+        let position = thread.position.unwrap();
+        f.instruction(&Instruction::GlobalGet(position));
+        f.instruction(&Instruction::LocalTee(position_local));
+        f.instruction(&Instruction::I32Const(run.len as i32 - 1));
+        f.instruction(&Instruction::I32LtU);
+        f.instruction(&Instruction::If(BlockType::Empty));
+        f.instruction(&Instruction::LocalGet(position_local));
+        f.instruction(&Instruction::I32Const(1));
+        f.instruction(&Instruction::I32Add);
+        f.instruction(&Instruction::GlobalSet(position));
+        f.instruction(&Instruction::I32Const(case as i32));
+        f.instruction(&Instruction::GlobalSet(thread.counter));
+        f.instruction(&Instruction::Else);
+        f.instruction(&Instruction::I32Const(0));
+        f.instruction(&Instruction::GlobalSet(position));
+        f.instruction(&Instruction::End);
+
+        if run.step == 0 {
+            self.replay_return(f, site, run.base);
+            return;
+        }
+        // `base + position * step`, wrapping like the recorded values
+        match self.site_results(site).first() {
+            Some(wasmparser::ValType::I32) => {
+                f.instruction(&Instruction::I32Const(run.base as i32));
+                f.instruction(&Instruction::LocalGet(position_local));
+                f.instruction(&Instruction::I32Const(run.step as i32));
+                f.instruction(&Instruction::I32Mul);
+                f.instruction(&Instruction::I32Add);
+            }
+            _ => {
+                f.instruction(&Instruction::I64Const(run.base));
+                f.instruction(&Instruction::LocalGet(position_local));
+                f.instruction(&Instruction::I64ExtendI32U);
+                f.instruction(&Instruction::I64Const(run.step));
+                f.instruction(&Instruction::I64Mul);
+                f.instruction(&Instruction::I64Add);
+            }
+        }
+        f.instruction(&Instruction::Return);
+    }
+
+    /// Result types of the import called at `site`
+    fn site_results(&self, site: &ReplaySite) -> &[wasmparser::ValType] {
+        self.layout.types[site.ty as usize]
+            .as_ref()
+            .unwrap()
+            .results()
+    }
+
+    /// Return `return_val` as the result of the import called at `site`
+    fn replay_return(&self, f: &mut Function, site: &ReplaySite, return_val: i64) {
+        match self.site_results(site).first() {
+            Some(wasmparser::ValType::I32) => {
+                f.instruction(&Instruction::I32Const(return_val as i32));
+            }
+            Some(wasmparser::ValType::I64) => {
+                f.instruction(&Instruction::I64Const(return_val));
+            }
+            Some(wasmparser::ValType::F32) => {
+                f.instruction(&Instruction::F32Const(f32::from_bits(return_val as u32)));
+            }
+            Some(wasmparser::ValType::F64) => {
+                f.instruction(&Instruction::F64Const(f64::from_bits(return_val as u64)));
+            }
+            Some(ty) => {
+                warn!(
+                    "Cannot replay return value of type {:?} at access index {}",
+                    ty, site.op.access_idx
+                );
+                f.instruction(&Instruction::Unreachable);
+            }
+            None => {}
+        }
+        f.instruction(&Instruction::Return);
+    }
+
+    /// Remap function names to the rewritten module, naming generated
+    /// functions. Other name subsections are dropped
+    fn parse_name_section(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        reader: NameSectionReader,
+    ) -> Result<(), ReencodeError<Infallible>> {
+        let mut extra: BTreeMap<u32, String> = BTreeMap::new();
+        for (i, (name, _, _)) in SC_IMPORTS.iter().enumerate() {
+            extra.insert(i as u32, name.to_string());
+        }
+        for (access_idx, func_idx) in &self.site_funcs {
+            extra.insert(*func_idx, format!("r3_replay_site_{}", access_idx));
+        }
+        let names = rewrite::remap_function_names(reader, |idx| self.function_index(idx), extra)?;
+        module.section(&names);
+        Ok(())
+    }
+}
+
+impl Reencode for ReplayGenerator<'_> {
+    type Error = Infallible;
+
+    /// Defined functions follow the replay interface imports, followed by
+    /// import stubs and then site functions
+    fn function_index(&mut self, func: u32) -> u32 {
+        let num_imports = self.layout.func_imports.len() as u32;
+        if func < num_imports {
+            NUM_SC_IMPORTS + self.layout.num_defined_funcs + func
+        } else {
+            NUM_SC_IMPORTS + func - num_imports
+        }
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_type_section(self, types, section)?;
+        for (_, params, results) in SC_IMPORTS {
+            types
+                .ty()
+                .function(params.iter().copied(), results.iter().copied());
+        }
+        Ok(())
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        self.push_replay_imports(imports);
+        for import in section {
+            let import = import?;
+            if !matches!(import.ty, TypeRef::Func(_)) {
+                utils::parse_import(self, imports, import)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_function_section(self, functions, section)?;
+        for import in &self.layout.func_imports {
+            functions.function(import.ty);
+        }
+        for site in &self.sites {
+            functions.function(site.ty);
+        }
+        Ok(())
+    }
+
+    fn parse_global_section(
+        &mut self,
+        globals: &mut GlobalSection,
+        section: wasmparser::GlobalSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_global_section(self, globals, section)?;
+        self.push_counters(globals);
+        Ok(())
+    }
+
+    fn data_count(&mut self, count: u32) -> u32 {
+        self.data_count_done = true;
+        count + self.segments.len() as u32
+    }
+
+    fn parse_data_section(
+        &mut self,
+        data: &mut DataSection,
+        section: wasmparser::DataSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_data_section(self, data, section)?;
+        self.push_segments(data);
+        Ok(())
+    }
+
+    fn parse_function_body(
+        &mut self,
+        code: &mut CodeSection,
+        func: wasmparser::FunctionBody<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        let mut f = self.new_function_with_parsed_locals(&func)?;
+        let mut reader = func.get_operators_reader()?;
+        while !reader.eof() {
+            let pos = reader.original_position();
+            let op = reader.read()?;
+            if self.layout.is_access_site(&self.wasm[pos..], &op) {
+                let access_idx = self.next_access_idx;
+                self.next_access_idx += 1;
+                if let Some(site_func) = self.site_funcs.get(&access_idx) {
+                    f.instruction(&Instruction::Call(*site_func));
+                    continue;
+                }
+            }
+            f.instruction(&self.instruction(op)?);
+        }
+        code.function(&f);
+
+        self.num_bodies += 1;
+        if self.num_bodies == self.layout.num_defined_funcs {
+            for _ in &self.layout.func_imports {
+                code.function(&Self::import_stub());
+            }
+            for site in &self.sites {
+                code.function(&self.site_function(site));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_custom_section(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        section: wasmparser::CustomSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        match section.as_known() {
+            KnownCustom::Name(reader) => self.parse_name_section(module, reader),
+            _ => utils::parse_custom_section(self, module, section),
+        }
+    }
+
+    /// Insert the import, global, data count and data sections if the
+    /// original module has none
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        if !self.imports_done && !matches!(before, Some(SectionId::Type | SectionId::Import)) {
+            let mut imports = ImportSection::new();
+            self.push_replay_imports(&mut imports);
+            module.section(&imports);
+        }
+        if !self.globals_done
+            && matches!(
+                before,
+                None | Some(
+                    SectionId::Export
+                        | SectionId::Start
+                        | SectionId::Element
+                        | SectionId::DataCount
+                        | SectionId::Code
+                        | SectionId::Data
+                )
+            )
+        {
+            let mut globals = GlobalSection::new();
+            self.push_counters(&mut globals);
+            module.section(&globals);
+        }
+        if self.segments.is_empty() {
+            return Ok(());
+        }
+        // `memory.init` requires a data count section
+        if !self.layout.has_data_count
+            && !self.data_count_done
+            && matches!(before, None | Some(SectionId::Code | SectionId::Data))
+        {
+            module.section(&DataCountSection {
+                count: self.layout.num_data + self.segments.len() as u32,
+            });
+            self.data_count_done = true;
+        }
+        if !self.data_done && before.is_none() {
+            let mut data = DataSection::new();
+            self.push_segments(&mut data);
+            module.section(&data);
+        }
+        Ok(())
+    }
+}
+
+/// Generate a replay module from the original wasm binary and its replay
+/// operations (ordered by
+/// [`reorder_replay_ops`](crate::parser::reorder_replay_ops))
+///
+/// With `debug`, replayed calls are logged and `writev`s/`futex`es are
+/// forwarded to the replay interface. With `fold_stores`, contiguous stores
+/// are folded into data segments
+pub fn generate_replay_module(
+    replay_ops: &BTreeMap<u32, ReplayOp>,
+    wasmbin: &[u8],
+    debug: bool,
+    fold_stores: bool,
+) -> Result<Vec<u8>, R3Error> {
+    let mut generator = ReplayGenerator::new(replay_ops, wasmbin, debug, fold_stores)?;
+    let mut module = wasm_encoder::Module::new();
+    generator
+        .parse_core_module(&mut module, Parser::new(0), wasmbin)
+        .map_err(|e| R3Error::Instrument(e.to_string()))?;
+    let module = module.finish();
+    info!(
+        "Generate | Insize: {}, Outsize: {}",
+        wasmbin.len(),
+        module.len()
+    );
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{
+        DataCountSection, ExportKind, ExportSection, FunctionSection, MemorySection, MemoryType,
+        Module, TypeSection,
+    };
+    use wasmparser::Validator;
+
+    /// Module importing `env::f: () -> i32`, whose export `main(n)` returns
+    /// the sum of `n` (at least 1) calls of `f`, at access index 0, and
+    /// stores the result of the call made with `n` left at `LOG + 4 * n`.
+    /// With `data`, it has an active and a passive data segment, and a data
+    /// count
+    fn module(data: bool) -> Vec<u8> {
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        types.ty().function([], [ValType::I32]);
+        types.ty().function([ValType::I32], [ValType::I32]);
+        module.section(&types);
+        let mut imports = ImportSection::new();
+        imports.import("env", "f", EntityType::Function(0));
+        module.section(&imports);
+        let mut functions = FunctionSection::new();
+        functions.function(1);
+        module.section(&functions);
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+        module.section(&memories);
+        let mut exports = ExportSection::new();
+        exports.export("main", ExportKind::Func, 1);
+        exports.export("memory", ExportKind::Memory, 0);
+        module.section(&exports);
+        if data {
+            module.section(&DataCountSection { count: 2 });
+        }
+        let mut code = CodeSection::new();
+        let mut f = Function::new([(2, ValType::I32)]);
+        for instruction in [
+            Instruction::Loop(BlockType::Empty),
+            Instruction::LocalGet(0),
+            Instruction::I32Const(2),
+            Instruction::I32Shl,
+            Instruction::Call(0),
+            Instruction::LocalTee(2),
+            Instruction::I32Store(MemArg {
+                offset: LOG as u64,
+                align: 2,
+                memory_index: 0,
+            }),
+            Instruction::LocalGet(2),
+            Instruction::LocalGet(1),
+            Instruction::I32Add,
+            Instruction::LocalSet(1),
+            Instruction::LocalGet(0),
+            Instruction::I32Const(1),
+            Instruction::I32Sub,
+            Instruction::LocalTee(0),
+            Instruction::BrIf(0),
+            Instruction::End,
+            Instruction::LocalGet(1),
+            Instruction::End,
+        ] {
+            f.instruction(&instruction);
+        }
+        code.function(&f);
+        module.section(&code);
+        if data {
+            let mut segments = DataSection::new();
+            segments.active(0, &ConstExpr::i32_const(1000), b"orig".iter().copied());
+            segments.passive(b"p".iter().copied());
+            module.section(&segments);
+        }
+        module.finish()
+    }
+
+    /// Address of the call results logged by [`module`]
+    const LOG: usize = 0x800;
+
+    fn store(addr: i32, size: u32, value: i64) -> ReplayMemStore {
+        ReplayMemStore { addr, size, value }
+    }
+
+    /// Replay op of the call at access index 0, made by thread 0
+    fn ops(props: Vec<(i64, Vec<ReplayMemStore>)>) -> BTreeMap<u32, ReplayOp> {
+        let props = props
+            .into_iter()
+            .enumerate()
+            .map(|(sync_id, (return_val, stores))| ReplayOpProp {
+                tid: 0,
+                return_val,
+                call_id: CallID::ScGeneric,
+                stores,
+                sync_id: sync_id as u64,
+            })
+            .collect();
+        let op = ReplayOp {
+            access_idx: 0,
+            func_idx: 0,
+            implicit_sync: false,
+            props,
+            max_tid: 0,
+        };
+        BTreeMap::from([(0, op)])
+    }
+
+    /// Generate and validate the replay module of `ops` for `wasm`
+    fn generate(
+        ops: &BTreeMap<u32, ReplayOp>,
+        wasm: &[u8],
+        debug: bool,
+        fold_stores: bool,
+    ) -> Vec<u8> {
+        let replay = generate_replay_module(ops, wasm, debug, fold_stores).unwrap();
+        Validator::new().validate_all(&replay).unwrap();
+        replay
+    }
+
+    /// Result of `main(n)` in replay module `wasm`, and memory afterwards
+    fn run(wasm: &[u8], n: i32) -> (i32, Vec<u8>) {
+        use wasmi::{Engine, Linker, Store};
+        let engine = Engine::default();
+        let module = wasmi::Module::new(&engine, wasm).unwrap();
+        let mut store = Store::new(&engine, ());
+        let mut linker = <Linker<()>>::new(&engine);
+        linker
+            .func_wrap(REPLAY_MODULE, "SC_proc_exit", |_: i32| {})
+            .unwrap()
+            .func_wrap(REPLAY_MODULE, "SC_thread_exit", |_: i32| {})
+            .unwrap()
+            .func_wrap(REPLAY_MODULE, "SC_writev", |_: i32, _: i32, _: i32| 0i64)
+            .unwrap()
+            .func_wrap(REPLAY_MODULE, "SC_futex_log", |_: i32, _: i32, _: i32| {})
+            .unwrap()
+            .func_wrap(REPLAY_MODULE, "SC_gettid", || 0i32)
+            .unwrap()
+            .func_wrap(
+                REPLAY_MODULE,
+                "SC_log_call",
+                |_: i32, _: i32, _: i32, _: i32, _: i32, _: i64, _: i64, _: i64, _: i64, _: i64| {},
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let main = instance.get_typed_func::<i32, i32>(&store, "main").unwrap();
+        let result = main.call(&mut store, n).unwrap();
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        (result, memory.data(&store).to_vec())
+    }
+
+    /// Data count and number of data segments of `wasm`
+    fn data_layout(wasm: &[u8]) -> (Option<u32>, u32) {
+        let mut layout = (None, 0);
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.unwrap() {
+                Payload::DataCountSection { count, .. } => layout.0 = Some(count),
+                Payload::DataSection(reader) => layout.1 = reader.count(),
+                _ => {}
+            }
+        }
+        layout
+    }
+
+    #[test]
+    fn fold_overlapping_stores() {
+        let stores = [
+            store(0x100, 8, 0x1111111111111111),
+            store(0x108, 8, 0x2222222222222222),
+            // Overlaps the first store; its bytes win
+            store(0x104, 2, 0xBBAA),
+            store(0x200, 4, 1),
+        ];
+        let mut segments = Vec::new();
+        let folded = PropStores::fold(&stores, &mut segments);
+        assert_eq!(folded.folded, [(0x100, 0)]);
+        assert_eq!(folded.stores, [&stores[3]]);
+        let mut expected = [0x11; 8].to_vec();
+        expected[4..6].copy_from_slice(&[0xAA, 0xBB]);
+        expected.extend([0x22; 8]);
+        assert_eq!(segments, [expected]);
+    }
+
+    #[test]
+    fn fold_min_bytes() {
+        // Runs of 15 (8 + 4 + 2 + 1) and 16 (8 + 8) bytes
+        let stores = [
+            store(0x100, 8, -1),
+            store(0x108, 4, -1),
+            store(0x10C, 2, -1),
+            store(0x10E, 1, -1),
+            store(0x200, 8, 1),
+            store(0x208, 8, 2),
+        ];
+        let mut segments = vec![vec![0]];
+        let folded = PropStores::fold(&stores, &mut segments);
+        assert_eq!(folded.folded, [(0x200, 1)]);
+        assert_eq!(folded.stores, stores[..4].iter().collect::<Vec<_>>());
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[1].len(), FOLD_MIN_BYTES);
+
+        let mut segments = Vec::new();
+        let folded = PropStores::fold(&stores[..4], &mut segments);
+        assert!(folded.folded.is_empty() && segments.is_empty());
+        assert_eq!(folded.stores.len(), 4);
+    }
+
+    #[test]
+    fn fold_data_segments() {
+        let mut stores: Vec<ReplayMemStore> = (0..8)
+            .map(|idx| store(0x100 + idx * 8, 8, 0x0102030405060708 * (idx as i64 + 1)))
+            .collect();
+        stores.push(store(0x104, 2, 0x7777));
+        stores.push(store(0x300, 1, 9));
+        let ops = ops(vec![(5, stores)]);
+
+        for data in [false, true] {
+            let wasm = module(data);
+            let unfolded = generate(&ops, &wasm, false, false);
+            let folded = generate(&ops, &wasm, false, true);
+            let num_data = if data { 2 } else { 0 };
+            match data {
+                true => assert_eq!(data_layout(&unfolded), (Some(num_data), num_data)),
+                false => assert_eq!(data_layout(&unfolded), (None, 0)),
+            }
+            // The data count is inserted (or updated) for `memory.init`
+            assert_eq!(data_layout(&folded), (Some(num_data + 1), num_data + 1));
+
+            let (result, memory) = run(&unfolded, 1);
+            assert_eq!(result, 5);
+            assert_eq!(run(&folded, 1), (result, memory.clone()));
+            assert_eq!(&memory[0x104..0x106], &[0x77, 0x77]);
+            assert_eq!(memory[0x300], 9);
+            if data {
+                assert_eq!(&memory[1000..1004], b"orig");
+            }
+        }
+    }
+
+    /// Runs of the calls returning `return_vals`, as (start, len, base, step)
+    /// with the step of single calls zeroed
+    fn runs(return_vals: &[i64], progression: Progression) -> Vec<(usize, usize, i64, i64)> {
+        let ops = ops(return_vals.iter().map(|val| (*val, vec![])).collect());
+        let props: Vec<usize> = (0..return_vals.len()).collect();
+        ThreadProps::runs(&ops[&0], &props, false, progression)
+            .into_iter()
+            .map(|run| {
+                (
+                    run.start,
+                    run.len,
+                    run.base,
+                    if run.len > 1 { run.step } else { 0 },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn runs_min_props() {
+        let short = [(0, 1, 7, 0), (1, 1, 7, 0), (2, 1, 7, 0)];
+        assert_eq!(runs(&[7; 3], Progression::I64), short);
+        assert_eq!(runs(&[7; RUN_MIN_PROPS], Progression::I64), [(0, 4, 7, 0)]);
+        assert_eq!(
+            runs(&[1, 3, 5], Progression::I64),
+            [(0, 1, 1, 0), (1, 1, 3, 0), (2, 1, 5, 0)]
+        );
+        assert_eq!(runs(&[], Progression::I64), []);
+    }
+
+    #[test]
+    fn runs_break_partway() {
+        // The progression breaks at 10, leaving a run too short for a case
+        assert_eq!(
+            runs(&[0, 1, 2, 3, 4, 10, 11, 12], Progression::I64),
+            [(0, 5, 0, 1), (5, 1, 10, 0), (6, 1, 11, 0), (7, 1, 12, 0)]
+        );
+        assert_eq!(
+            runs(&[9, 6, 3, 0, -3, 5, 5, 5, 5, 5], Progression::I32),
+            [(0, 5, 9, -3), (5, 5, 5, 0)]
+        );
+        // Identical values only, without a progression
+        assert_eq!(
+            runs(&[0, 1, 2, 3, 3, 3, 3], Progression::None),
+            [(0, 1, 0, 0), (1, 1, 1, 0), (2, 1, 2, 0), (3, 4, 3, 0)]
+        );
+    }
+
+    #[test]
+    fn runs_wrap() {
+        // i32 results are recorded sign-extended
+        let max = i32::MAX as i64;
+        let min = i32::MIN as i64;
+        assert_eq!(
+            runs(&[max - 1, max, min, min + 1], Progression::I32),
+            [(0, 4, max - 1, 1)]
+        );
+        assert_eq!(
+            runs(&[max - 1, max, min, min + 1], Progression::I64).len(),
+            4
+        );
+        assert_eq!(
+            runs(
+                &[i64::MAX - 1, i64::MAX, i64::MIN, i64::MIN + 1],
+                Progression::I64
+            ),
+            [(0, 4, i64::MAX - 1, 1)]
+        );
+        // A step that only fits in 64 bits does not progress at 32 bits
+        assert_eq!(
+            runs(&[0, 1 << 32, 2 << 32, 3 << 32], Progression::I32).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn runs_exclude_stores_and_debug() {
+        let mut ops = ops(vec![(0, vec![]); 9]);
+        ops.get_mut(&0).unwrap().props[4]
+            .stores
+            .push(store(0x100, 4, 1));
+        let op = &ops[&0];
+        let props: Vec<usize> = (0..9).collect();
+        let runs = ThreadProps::runs(op, &props, false, Progression::I64);
+        let runs: Vec<_> = runs.iter().map(|run| (run.start, run.len)).collect();
+        assert_eq!(runs, [(0, 4), (4, 1), (5, 4)]);
+        assert!(ThreadProps::runs(op, &props, true, Progression::I64)
+            .iter()
+            .all(|run| run.len == 1));
+    }
+
+    #[test]
+    fn runs_replay_across_boundaries() {
+        let max = i32::MAX as i64;
+        let return_vals: Vec<i64> = [
+            // Progression, single calls, identical run, then a wrapping one
+            vec![10, 11, 12, 13, 14, 15, 16, 17],
+            vec![5, 5, 5],
+            vec![7; 4],
+            vec![max - 1, max, i32::MIN as i64, i32::MIN as i64 + 1],
+            vec![100, 98, 96, 94, 92],
+            vec![1],
+        ]
+        .concat();
+        let ops = ops(return_vals.iter().map(|val| (*val, vec![])).collect());
+        let n = return_vals.len();
+        let wasm = module(false);
+
+        let replay = generate(&ops, &wasm, false, false);
+        let logged = generate(&ops, &wasm, true, false);
+        assert!(replay.len() < logged.len());
+        for replay in [replay, logged] {
+            let (sum, memory) = run(&replay, n as i32);
+            let results: Vec<i64> = (0..n)
+                .map(|call| {
+                    let addr = LOG + 4 * (n - call);
+                    i32::from_le_bytes(memory[addr..addr + 4].try_into().unwrap()) as i64
+                })
+                .collect();
+            assert_eq!(results, return_vals);
+            let expected = return_vals
+                .iter()
+                .fold(0i32, |sum, val| sum.wrapping_add(*val as i32));
+            assert_eq!(sum, expected);
+        }
+    }
+
+    #[test]
+    fn multiple_threads() {
+        let mut replay_ops = ops(vec![(1, vec![]), (2, vec![])]);
+        replay_ops.get_mut(&0).unwrap().props[1].tid = 1;
+        assert!(matches!(
+            generate_replay_module(&replay_ops, &module(false), false, false),
+            Err(R3Error::Instrument(msg)) if msg.contains("2 threads")
+        ));
+    }
+}
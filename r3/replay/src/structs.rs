@@ -1,11 +1,12 @@
 //! Datatypes used to represent replay operations and their properties
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 use common::trace::CallID;
 
 /// Represents a memory store operation to replay
 #[repr(C)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ReplayMemStore {
     pub addr: i32,
     pub size: u32,
@@ -13,7 +14,7 @@ pub struct ReplayMemStore {
 }
 
 /// Dynamic properties of a **single** dynamic replay operation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ReplayOpProp {
     pub tid: u64,
     pub return_val: i64,
@@ -51,7 +52,7 @@ pub struct ReplayOpSingle {
 /// ### Design Notes
 /// `access_idx` specifies the static code location. This is the most format to
 /// enable static instrumentation for replay generation
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ReplayOp {
     pub access_idx: u32,
     pub func_idx: u32,
@@ -0,0 +1,453 @@
+//! Rewriting replay modules to end the way the recording did
+//!
+//! The entry export ([`ENTRY_EXPORT`]) of a replay module is redirected to a
+//! generated wrapper that calls the original entry function and then:
+//! * replays `SC_proc_exit(code)` for a recorded [`Termination::Exit`]
+//! * executes `unreachable` for a recorded [`Termination::Trap`]
+//!
+//! Recorded signals are not replayed
+//!
+//! ### Design Notes
+//! The wrapper only runs once the replay returns from its entry function, so
+//! replays that already exit (through a replayed `proc_exit`) or trap end as
+//! they would without it. It is applied to the output of either backend: the
+//! `SC_proc_exit` replay interface import is reused if present, and added
+//! otherwise
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+
+use common::rewrite::{self, wasm_encoder, wasmparser};
+use common::trace::Termination;
+use common::R3Error;
+use wasm_encoder::reencode::{utils, Error as ReencodeError, Reencode};
+use wasm_encoder::{
+    CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection, ImportSection,
+    Instruction, SectionId, TypeSection, ValType,
+};
+use wasmparser::{CompositeInnerType, KnownCustom, Parser, Payload, TypeRef};
+
+use crate::generator::REPLAY_MODULE;
+
+/// Export invoked by the engine to run a module
+pub const ENTRY_EXPORT: &str = "_start";
+
+/// Replay interface function exiting the process
+const PROC_EXIT_IMPORT: &str = "SC_proc_exit";
+
+/// [`Reencode`]r redirecting the entry export to a terminating wrapper
+struct TerminationRewriter<'a> {
+    termination: &'a Termination,
+    num_types: u32,
+    num_func_imports: u32,
+    num_defined_funcs: u32,
+    /// Function index of an existing `SC_proc_exit` import
+    proc_exit: Option<u32>,
+    /// Original entry function and its type
+    entry: u32,
+    entry_ty: u32,
+    /// Number of params and results of the entry function
+    entry_arity: (u32, u32),
+    imports_done: bool,
+}
+
+impl<'a> TerminationRewriter<'a> {
+    /// Scan `wasm` for its entry function; `None` if it has no entry export
+    fn new(wasm: &[u8], termination: &'a Termination) -> Result<Option<Self>, R3Error> {
+        let parse_err = |e: wasmparser::BinaryReaderError| R3Error::Instrument(e.to_string());
+
+        let mut func_types: Vec<Option<(u32, u32)>> = Vec::new();
+        let mut funcs: Vec<u32> = Vec::new();
+        let mut num_func_imports = 0;
+        let mut proc_exit = None;
+        let mut entry = None;
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.map_err(parse_err)? {
+                Payload::TypeSection(reader) => {
+                    for rec_group in reader {
+                        for sub_type in rec_group.map_err(parse_err)?.into_types() {
+                            func_types.push(match sub_type.composite_type.inner {
+                                CompositeInnerType::Func(ty) => {
+                                    Some((ty.params().len() as u32, ty.results().len() as u32))
+                                }
+                                _ => None,
+                            });
+                        }
+                    }
+                }
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.map_err(parse_err)?;
+                        if let TypeRef::Func(ty) = import.ty {
+                            if import.module == REPLAY_MODULE && import.name == PROC_EXIT_IMPORT {
+                                proc_exit = Some(num_func_imports);
+                            }
+                            funcs.push(ty);
+                            num_func_imports += 1;
+                        }
+                    }
+                }
+                Payload::FunctionSection(reader) => {
+                    for ty in reader {
+                        funcs.push(ty.map_err(parse_err)?);
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export.map_err(parse_err)?;
+                        if export.name == ENTRY_EXPORT
+                            && export.kind == wasmparser::ExternalKind::Func
+                        {
+                            entry = Some(export.index);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        let Some(entry) = entry else {
+            warn!(
+                "Module has no `{}` export; the recorded termination is not replayed",
+                ENTRY_EXPORT
+            );
+            return Ok(None);
+        };
+        let entry_ty = *funcs.get(entry as usize).ok_or_else(|| {
+            R3Error::Instrument(format!("Entry function {} does not exist", entry))
+        })?;
+        let entry_arity = func_types
+            .get(entry_ty as usize)
+            .copied()
+            .flatten()
+            .ok_or_else(|| {
+                R3Error::Instrument(format!("Entry type {} is not a function", entry_ty))
+            })?;
+        Ok(Some(TerminationRewriter {
+            termination,
+            num_types: func_types.len() as u32,
+            num_func_imports,
+            num_defined_funcs: funcs.len() as u32 - num_func_imports,
+            proc_exit,
+            entry,
+            entry_ty,
+            entry_arity,
+            imports_done: false,
+        }))
+    }
+
+    /// Whether `SC_proc_exit` must be imported by the rewrite
+    fn adds_import(&self) -> bool {
+        self.proc_exit.is_none()
+    }
+
+    /// Function index of `SC_proc_exit` in the rewritten module
+    fn proc_exit_func(&self) -> u32 {
+        self.proc_exit.unwrap_or(self.num_func_imports)
+    }
+
+    /// Function index of the wrapper in the rewritten module
+    fn wrapper_func(&self) -> u32 {
+        self.num_func_imports + self.adds_import() as u32 + self.num_defined_funcs
+    }
+
+    /// Add the `SC_proc_exit` import, if missing
+    fn push_import(&mut self, imports: &mut ImportSection) {
+        if self.adds_import() {
+            imports.import(
+                REPLAY_MODULE,
+                PROC_EXIT_IMPORT,
+                EntityType::Function(self.num_types),
+            );
+        }
+        self.imports_done = true;
+    }
+
+    /// Wrapper calling the entry function, then ending like the recording
+    fn wrapper(&mut self) -> Function {
+        let (num_params, num_results) = self.entry_arity;
+        let mut f = Function::new([]);
+        for param in 0..num_params {
+            f.instruction(&Instruction::LocalGet(param));
+        }
+        let entry = self.function_index(self.entry);
+        f.instruction(&Instruction::Call(entry));
+        for _ in 0..num_results {
+            f.instruction(&Instruction::Drop);
+        }
+        match self.termination {
+            Termination::Exit { code } => {
+                f.instruction(&Instruction::I32Const(*code));
+                f.instruction(&Instruction::Call(self.proc_exit_func()));
+            }
+            Termination::Trap { .. } => {}
+            Termination::Signal { .. } => unreachable!("signals are not replayed"),
+        }
+        // `SC_proc_exit` does not return either
+        f.instruction(&Instruction::Unreachable);
+        f.instruction(&Instruction::End);
+        f
+    }
+}
+
+impl Reencode for TerminationRewriter<'_> {
+    type Error = Infallible;
+
+    /// An added `SC_proc_exit` import follows the original function imports
+    fn function_index(&mut self, func: u32) -> u32 {
+        if self.adds_import() && func >= self.num_func_imports {
+            func + 1
+        } else {
+            func
+        }
+    }
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut TypeSection,
+        section: wasmparser::TypeSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_type_section(self, types, section)?;
+        if self.adds_import() {
+            types.ty().function([ValType::I32], []);
+        }
+        Ok(())
+    }
+
+    fn parse_import_section(
+        &mut self,
+        imports: &mut ImportSection,
+        section: wasmparser::ImportSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_import_section(self, imports, section)?;
+        self.push_import(imports);
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut FunctionSection,
+        section: wasmparser::FunctionSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_function_section(self, functions, section)?;
+        functions.function(self.entry_ty);
+        Ok(())
+    }
+
+    fn parse_export_section(
+        &mut self,
+        exports: &mut ExportSection,
+        section: wasmparser::ExportSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        for export in section {
+            let export = export?;
+            if export.name == ENTRY_EXPORT && export.kind == wasmparser::ExternalKind::Func {
+                exports.export(export.name, ExportKind::Func, self.wrapper_func());
+            } else {
+                utils::parse_export(self, exports, export);
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_code_section(
+        &mut self,
+        code: &mut CodeSection,
+        section: wasmparser::CodeSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        utils::parse_code_section(self, code, section)?;
+        code.function(&self.wrapper());
+        Ok(())
+    }
+
+    /// Remap function names, naming the wrapper (and any added import)
+    fn parse_custom_section(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        section: wasmparser::CustomSectionReader<'_>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        match section.as_known() {
+            KnownCustom::Name(reader) => {
+                let mut extra: BTreeMap<u32, String> = BTreeMap::new();
+                extra.insert(self.wrapper_func(), String::from("r3_replay_entry"));
+                if self.adds_import() {
+                    extra.insert(self.proc_exit_func(), PROC_EXIT_IMPORT.to_string());
+                }
+                let names =
+                    rewrite::remap_function_names(reader, |idx| self.function_index(idx), extra)?;
+                module.section(&names);
+                Ok(())
+            }
+            _ => utils::parse_custom_section(self, module, section),
+        }
+    }
+
+    /// Insert the import section if the original module has none
+    fn intersperse_section_hook(
+        &mut self,
+        module: &mut wasm_encoder::Module,
+        _after: Option<SectionId>,
+        before: Option<SectionId>,
+    ) -> Result<(), ReencodeError<Self::Error>> {
+        if !self.imports_done && !matches!(before, Some(SectionId::Type | SectionId::Import)) {
+            let mut imports = ImportSection::new();
+            self.push_import(&mut imports);
+            module.section(&imports);
+        }
+        Ok(())
+    }
+}
+
+/// Rewrite replay module `wasm` to end with the recorded `termination` once
+/// its entry function returns
+///
+/// Modules without an entry export, and recorded signals, are returned
+/// unchanged
+pub fn replay_termination(wasm: &[u8], termination: &Termination) -> Result<Vec<u8>, R3Error> {
+    if let Termination::Signal { signo } = termination {
+        warn!(
+            "Recording was killed by signal {}, which is not replayed",
+            signo
+        );
+        return Ok(wasm.to_vec());
+    }
+    let Some(mut rewriter) = TerminationRewriter::new(wasm, termination)? else {
+        return Ok(wasm.to_vec());
+    };
+    debug!(
+        "Replaying termination \"{}\" after entry function {}",
+        termination, rewriter.entry
+    );
+    let mut module = wasm_encoder::Module::new();
+    rewriter
+        .parse_core_module(&mut module, Parser::new(0), wasm)
+        .map_err(|e| R3Error::Instrument(e.to_string()))?;
+    Ok(module.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wasm_encoder::{Module, TypeSection};
+    use wasmparser::{ExternalKind, Operator, Validator};
+
+    /// Module with a `(func)` entry export, optionally importing
+    /// `SC_proc_exit` first
+    fn module(import_proc_exit: bool) -> Vec<u8> {
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        types.ty().function([], []);
+        types.ty().function([ValType::I32], []);
+        module.section(&types);
+        if import_proc_exit {
+            let mut imports = ImportSection::new();
+            imports.import(REPLAY_MODULE, PROC_EXIT_IMPORT, EntityType::Function(1));
+            module.section(&imports);
+        }
+        let mut functions = FunctionSection::new();
+        functions.function(0);
+        module.section(&functions);
+        let mut exports = ExportSection::new();
+        exports.export(ENTRY_EXPORT, ExportKind::Func, import_proc_exit as u32);
+        module.section(&exports);
+        let mut code = CodeSection::new();
+        let mut f = Function::new([]);
+        f.instruction(&Instruction::End);
+        code.function(&f);
+        module.section(&code);
+        module.finish()
+    }
+
+    /// Function imports, entry export and wrapper body of a valid `wasm`
+    fn inspect(wasm: &[u8]) -> (Vec<String>, u32, Vec<String>) {
+        Validator::new().validate_all(wasm).unwrap();
+        let mut imports = Vec::new();
+        let mut entry = None;
+        let mut bodies = Vec::new();
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.unwrap() {
+                Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.unwrap();
+                        imports.push(format!("{}::{}", import.module, import.name));
+                    }
+                }
+                Payload::ExportSection(reader) => {
+                    for export in reader {
+                        let export = export.unwrap();
+                        if export.name == ENTRY_EXPORT && export.kind == ExternalKind::Func {
+                            entry = Some(export.index);
+                        }
+                    }
+                }
+                Payload::CodeSectionEntry(body) => {
+                    let ops = body.get_operators_reader().unwrap();
+                    bodies.push(
+                        ops.into_iter()
+                            .map(|op| match op.unwrap() {
+                                Operator::Call { function_index } => {
+                                    format!("call {}", function_index)
+                                }
+                                Operator::I32Const { value } => format!("i32.const {}", value),
+                                op => format!("{:?}", op),
+                            })
+                            .collect(),
+                    );
+                }
+                _ => {}
+            }
+        }
+        (imports, entry.unwrap(), bodies.pop().unwrap())
+    }
+
+    #[test]
+    fn exit_adds_import() {
+        let wasm = replay_termination(&module(false), &Termination::Exit { code: 3 }).unwrap();
+        let (imports, entry, wrapper) = inspect(&wasm);
+        assert_eq!(imports, ["r3-replay::SC_proc_exit"]);
+        assert_eq!(entry, 2);
+        assert_eq!(
+            wrapper,
+            ["call 1", "i32.const 3", "call 0", "Unreachable", "End"]
+        );
+    }
+
+    #[test]
+    fn exit_reuses_import() {
+        let wasm = replay_termination(&module(true), &Termination::Exit { code: 0 }).unwrap();
+        let (imports, entry, wrapper) = inspect(&wasm);
+        assert_eq!(imports, ["r3-replay::SC_proc_exit"]);
+        assert_eq!(entry, 2);
+        assert_eq!(
+            wrapper,
+            ["call 1", "i32.const 0", "call 0", "Unreachable", "End"]
+        );
+    }
+
+    #[test]
+    fn trap() {
+        let termination = Termination::Trap {
+            tid: 0,
+            message: String::from("unreachable"),
+            access_idx: None,
+        };
+        let wasm = replay_termination(&module(true), &termination).unwrap();
+        let (_, entry, wrapper) = inspect(&wasm);
+        assert_eq!(entry, 2);
+        assert_eq!(wrapper, ["call 1", "Unreachable", "End"]);
+    }
+
+    #[test]
+    fn unchanged() {
+        let wasm = module(false);
+        let signal = Termination::Signal { signo: 9 };
+        assert_eq!(replay_termination(&wasm, &signal).unwrap(), wasm);
+
+        let mut module = Module::new();
+        let mut types = TypeSection::new();
+        types.ty().function([], []);
+        module.section(&types);
+        let wasm = module.finish();
+        let exit = Termination::Exit { code: 1 };
+        assert_eq!(replay_termination(&wasm, &exit).unwrap(), wasm);
+    }
+}
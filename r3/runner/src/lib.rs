@@ -0,0 +1,263 @@
+//! Library crate for running replay Wasm modules generated by
+//! [`replay`](../replay/index.html), for embedding replay in other tools.
+//!
+//! The `runner` binary is a thin CLI over [run]
+use libc::c_void;
+use log::{error, info, warn};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{fork, ForkResult, Pid};
+use std::collections::BTreeMap;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use wamr_rust_sdk::{instance::Instance, module::Module, runtime::Runtime};
+
+use wamr_rust_sdk::{log_level_t, LOG_LEVEL_WARNING};
+
+use common::sections::{find_custom_section, OUTPUT_SECTION, TERMINATION_SECTION};
+use common::trace::{Termination, TraceError};
+use common::R3Error;
+
+mod oracle;
+use oracle::{recv_engine_msg, send_engine_msg, set_engine_channel, EngineMsg, OutputOracle};
+
+mod runner_interface;
+use runner_interface::{
+    wasm_r3_replay_futex_log, wasm_r3_replay_gettid, wasm_r3_replay_log_call,
+    wasm_r3_replay_proc_exit, wasm_r3_replay_thread_exit, wasm_r3_replay_writev,
+};
+
+/// Options of a replay run; see `runner -h` for their CLI counterparts
+#[derive(Debug, Clone)]
+pub struct RunOptions {
+    /// Log-level within the Wasm engine
+    pub log_level: log_level_t,
+    /// Kill the replay if it runs longer than this
+    pub timeout: Option<Duration>,
+}
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            log_level: LOG_LEVEL_WARNING,
+            timeout: None,
+        }
+    }
+}
+
+/// Interval at which a replay with a timeout is polled for termination
+const TIMEOUT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Outcome of a replay run, alongside the recorded behaviour embedded in the
+/// replay module by [`replay`](../replay/index.html)
+#[derive(Debug)]
+pub struct RunResult {
+    /// How the replay terminated, if known
+    pub termination: Option<Termination>,
+    /// How the recording terminated, if embedded
+    pub expected_termination: Option<Termination>,
+    /// Verification of the replayed output against the recorded output, if
+    /// embedded; fails with a description of the first divergence
+    pub output_check: Option<Result<(), String>>,
+    /// The replay was killed after running longer than
+    /// [`RunOptions::timeout`]; its termination is then unknown
+    pub timed_out: bool,
+}
+impl RunResult {
+    /// Whether the replay terminated like the recording, if the recorded
+    /// termination is embedded
+    pub fn termination_matches(&self) -> Option<bool> {
+        self.expected_termination.as_ref().map(|expected| {
+            self.termination
+                .as_ref()
+                .is_some_and(|actual| expected.same_outcome(actual))
+        })
+    }
+
+    /// Whether the replay matched all embedded recorded behaviour
+    pub fn is_faithful(&self) -> bool {
+        !self.timed_out
+            && self.termination_matches() != Some(false)
+            && !matches!(self.output_check, Some(Err(_)))
+    }
+}
+
+/// Wait for engine process `child` to terminate, killing it once `timeout`
+/// elapses; `None` if it was killed
+fn wait_engine(child: Pid, timeout: Option<Duration>) -> io::Result<Option<WaitStatus>> {
+    let Some(timeout) = timeout else {
+        return Ok(Some(waitpid(child, None)?));
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match waitpid(child, Some(WaitPidFlag::WNOHANG))? {
+            WaitStatus::StillAlive if Instant::now() >= deadline => {
+                warn!(
+                    "Wasm engine (PID: {}) timed out after {:?}; killing it",
+                    child, timeout
+                );
+                kill(child, Signal::SIGKILL)?;
+                waitpid(child, None)?;
+                return Ok(None);
+            }
+            WaitStatus::StillAlive => thread::sleep(TIMEOUT_POLL_INTERVAL),
+            status => return Ok(Some(status)),
+        }
+    }
+}
+
+/// Run replay module `wasm_module` with `argv` (program name + arguments)
+///
+/// A replay that diverges from the recording is a successful run, reported in
+/// the [RunResult]; only failures to run are errors
+///
+/// ### Design Notes
+/// The engine runs in a forked process, so guest `proc_exit`, traps and
+/// crashes cannot take down the caller, and WAMR's process-wide state stays
+/// isolated from any runtime the caller hosts. As with any `fork` of a
+/// multithreaded process, only the calling thread is duplicated
+pub fn run(
+    wasm_module: &[u8],
+    argv: &[String],
+    options: &RunOptions,
+) -> Result<RunResult, R3Error> {
+    if argv.is_empty() {
+        return Err(R3Error::Engine(String::from("argv has no program name")));
+    }
+    // Termination observed during recording, if embedded by `replay`
+    let expected_termination: Option<Termination> =
+        find_custom_section(wasm_module, TERMINATION_SECTION)
+            .map(postcard::from_bytes)
+            .transpose()
+            .map_err(TraceError::from)?;
+
+    // Output recorded per fd, if embedded by `replay`
+    let expected_output: Option<BTreeMap<i32, Vec<u8>>> =
+        find_custom_section(wasm_module, OUTPUT_SECTION)
+            .map(postcard::from_bytes)
+            .transpose()
+            .map_err(TraceError::from)?;
+
+    // Channel for the engine to report its behaviour back to the parent
+    let (mut engine_rx, engine_tx) = UnixStream::pair()?;
+    match unsafe { fork() }.map_err(io::Error::from)? {
+        ForkResult::Child => {
+            info!("Wasm engine executing with PID: {}", process::id());
+            drop(engine_rx);
+            set_engine_channel(engine_tx, expected_output.is_some());
+            engine_process(wasm_module, argv, options)
+        }
+        ForkResult::Parent { child } => {
+            drop(engine_tx);
+            // Consume reports while the engine runs, so it never blocks on a
+            // full socket
+            let reports = thread::spawn(move || -> io::Result<_> {
+                let mut oracle = expected_output.map(OutputOracle::new);
+                let mut trap_msg: Option<String> = None;
+                let mut failure: Option<String> = None;
+                while let Some(msg) = recv_engine_msg(&mut engine_rx)? {
+                    match msg {
+                        EngineMsg::Output { fd, data } => {
+                            if let Some(ref mut oracle) = oracle {
+                                oracle.feed(fd, &data);
+                            }
+                        }
+                        EngineMsg::Trap(msg) => trap_msg = Some(msg),
+                        EngineMsg::Failed(msg) => failure = Some(msg),
+                    }
+                }
+                Ok((trap_msg, failure, oracle.map(OutputOracle::finish)))
+            });
+            let status = wait_engine(child, options.timeout)?;
+            let (trap_msg, failure, output_check) = reports.join().unwrap()?;
+            if let Some(msg) = failure {
+                return Err(R3Error::Engine(msg));
+            }
+            let termination = match status {
+                None => None,
+                Some(WaitStatus::Exited(pid, code)) => {
+                    info!("Wasm engine (PID: {}) exited with status: {}", pid, code);
+                    match trap_msg {
+                        None => Some(Termination::Exit { code }),
+                        Some(message) => Some(Termination::Trap {
+                            tid: 0,
+                            message,
+                            access_idx: None,
+                        }),
+                    }
+                }
+                Some(WaitStatus::Signaled(pid, signal, _)) => {
+                    warn!("Wasm engine (PID: {}) killed by signal: {:?}", pid, signal);
+                    Some(Termination::Signal {
+                        signo: signal as i32,
+                    })
+                }
+                status => {
+                    warn!("Wasm engine exited with bad status: {:?}", status);
+                    None
+                }
+            };
+            Ok(RunResult {
+                termination,
+                expected_termination,
+                output_check,
+                timed_out: status.is_none(),
+            })
+        }
+    }
+}
+
+/// Body of the forked engine process, reporting over the engine channel
+///
+/// Never returns to the caller of [run]
+fn engine_process(wasm_module: &[u8], argv: &[String], options: &RunOptions) -> ! {
+    let exit_code =
+        match panic::catch_unwind(AssertUnwindSafe(|| execute(wasm_module, argv, options))) {
+            Ok(Ok(())) => {
+                info!("Wasm module safely exited from child process");
+                0
+            }
+            Ok(Err(msg)) => {
+                send_engine_msg(&msg);
+                1
+            }
+            Err(_) => {
+                error!("Wasm engine panicked");
+                send_engine_msg(&EngineMsg::Failed(String::from("engine panicked")));
+                1
+            }
+        };
+    process::exit(exit_code);
+}
+
+/// Instantiate and run `wasm_module` with `argv` in WAMR, registering any
+/// replay interface methods
+fn execute(wasm_module: &[u8], argv: &[String], options: &RunOptions) -> Result<(), EngineMsg> {
+    let runtime = Runtime::builder()
+        .use_system_allocator()
+        .set_host_function_module_name("r3-replay")
+        .register_host_function("SC_proc_exit", wasm_r3_replay_proc_exit as *mut c_void)
+        .register_host_function("SC_thread_exit", wasm_r3_replay_thread_exit as *mut c_void)
+        .register_host_function("SC_writev", wasm_r3_replay_writev as *mut c_void)
+        .register_host_function("SC_futex_log", wasm_r3_replay_futex_log as *mut c_void)
+        .register_host_function("SC_gettid", wasm_r3_replay_gettid as *mut c_void)
+        .register_host_function("SC_log_call", wasm_r3_replay_log_call as *mut c_void)
+        .set_max_thread_num(100)
+        .build()
+        .map_err(|e| EngineMsg::Failed(e.to_string()))?;
+    runtime.set_log_level(options.log_level);
+    let module = Module::from_buf(&runtime, wasm_module, &argv[0])
+        .map_err(|e| EngineMsg::Failed(e.to_string()))?;
+    let instance = Instance::new(&runtime, &module, 1024 * 256)
+        .map_err(|e| EngineMsg::Failed(e.to_string()))?;
+
+    if let Err(e) = instance.execute_main(&argv.to_vec()) {
+        warn!("Wasm module terminated with error: {}", e);
+        return Err(EngineMsg::Trap(e.to_string()));
+    }
+    Ok(())
+}
@@ -0,0 +1,272 @@
+//! Verification of replay behaviour against the recording
+//!
+//! The forked Wasm engine reports observable behaviour ([`EngineMsg`]s) to the
+//! runner over a socket, where it is checked against the metadata embedded in
+//! the replay module by [`replay`](../replay/index.html)
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Number of bytes of context shown around an output divergence
+const DIFF_CONTEXT: usize = 32;
+
+/// Behaviour reported by the engine process to the runner
+#[derive(Debug, Serialize, Deserialize)]
+pub enum EngineMsg {
+    /// Bytes passed to a replayed `writev` on `fd`
+    Output { fd: i32, data: Vec<u8> },
+    /// Module trapped with the given message
+    Trap(String),
+    /// Engine could not instantiate the module
+    Failed(String),
+}
+
+/// Engine-side end of the report channel
+static ENGINE_CHANNEL: OnceLock<Mutex<UnixStream>> = OnceLock::new();
+
+/// Whether replayed output is reported over [ENGINE_CHANNEL]
+static REPORT_OUTPUT: AtomicBool = AtomicBool::new(false);
+
+/// Register the engine-side end of the report channel, optionally reporting
+/// replayed output over it
+pub fn set_engine_channel(stream: UnixStream, report_output: bool) {
+    let _ = ENGINE_CHANNEL.set(Mutex::new(stream));
+    REPORT_OUTPUT.store(report_output, Ordering::Relaxed);
+}
+
+/// Whether replayed output should be reported to the runner
+pub fn reporting_output() -> bool {
+    REPORT_OUTPUT.load(Ordering::Relaxed)
+}
+
+/// Send a length-prefixed [`EngineMsg`] over the engine channel, if registered
+pub fn send_engine_msg(msg: &EngineMsg) {
+    if let Some(channel) = ENGINE_CHANNEL.get() {
+        let ser = postcard::to_stdvec(msg).unwrap();
+        let stream = &mut *channel.lock().unwrap();
+        if let Err(e) = stream
+            .write_all(&(ser.len() as u32).to_le_bytes())
+            .and_then(|_| stream.write_all(&ser))
+        {
+            warn!("Failed to report {:?} to runner: {}", msg, e);
+        }
+    }
+}
+
+/// Receive the next [`EngineMsg`], or `None` once the engine has exited
+pub fn recv_engine_msg(stream: &mut UnixStream) -> io::Result<Option<EngineMsg>> {
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut ser = vec![0u8; u32::from_le_bytes(len) as usize];
+    stream.read_exact(&mut ser)?;
+    postcard::from_bytes(&ser)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Incremental byte-for-byte comparison of replayed output against the
+/// recorded output, per fd and in order
+pub struct OutputOracle {
+    expected: BTreeMap<i32, Vec<u8>>,
+    replayed: BTreeMap<i32, usize>,
+    divergence: Option<String>,
+}
+impl OutputOracle {
+    pub fn new(expected: BTreeMap<i32, Vec<u8>>) -> Self {
+        OutputOracle {
+            expected,
+            replayed: BTreeMap::new(),
+            divergence: None,
+        }
+    }
+
+    /// Check the next replayed `data` written to `fd`
+    pub fn feed(&mut self, fd: i32, data: &[u8]) {
+        if self.divergence.is_some() {
+            return;
+        }
+        let offset = self.replayed.entry(fd).or_insert(0);
+        let expected = self.expected.get(&fd).map_or(&[][..], |v| &v[..]);
+        let expected_rest = &expected[(*offset).min(expected.len())..];
+        if let Some(idx) = (0..data.len()).find(|&i| expected_rest.get(i) != Some(&data[i])) {
+            self.divergence = Some(Self::diff(fd, *offset + idx, expected, *offset, data));
+        }
+        *offset += data.len();
+    }
+
+    /// Format the divergence on `fd` at byte `at`, where `data` was replayed
+    /// starting at byte `start`
+    fn diff(fd: i32, at: usize, expected: &[u8], start: usize, data: &[u8]) -> String {
+        let from = at.saturating_sub(DIFF_CONTEXT);
+        let window = |bytes: &[u8], base: usize| -> String {
+            let lo = from.saturating_sub(base).min(bytes.len());
+            let hi = (at + DIFF_CONTEXT).saturating_sub(base).min(bytes.len());
+            bytes[lo..hi].escape_ascii().to_string()
+        };
+        format!(
+            "Output diverged on fd {} at byte {}\n  Recorded: \"{}\"\n  Replayed: \"{}\"",
+            fd,
+            at,
+            window(expected, 0),
+            window(data, start)
+        )
+    }
+
+    /// Conclude verification once the engine has exited, reporting the first
+    /// divergence (including missing output)
+    pub fn finish(mut self) -> Result<(), String> {
+        if self.divergence.is_none() {
+            for (fd, expected) in self.expected.iter() {
+                let replayed = self.replayed.get(fd).copied().unwrap_or(0);
+                if replayed < expected.len() {
+                    self.divergence = Some(format!(
+                        "Output diverged on fd {} at byte {}\n  Recorded: \"{}\"\n  Replayed: <end of output>",
+                        fd,
+                        replayed,
+                        expected[replayed..(replayed + DIFF_CONTEXT).min(expected.len())]
+                            .escape_ascii()
+                    ));
+                    break;
+                }
+            }
+        }
+        match self.divergence {
+            Some(diff) => Err(diff),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expecting(expected: &[(i32, &[u8])]) -> OutputOracle {
+        OutputOracle::new(
+            expected
+                .iter()
+                .map(|(fd, data)| (*fd, data.to_vec()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn matching_output() {
+        let mut oracle = expecting(&[(1, b"hello world\n"), (2, b"warning")]);
+        oracle.feed(1, b"hello");
+        oracle.feed(2, b"warning");
+        oracle.feed(1, b"");
+        oracle.feed(1, b" world\n");
+        assert_eq!(oracle.finish(), Ok(()));
+        assert_eq!(expecting(&[]).finish(), Ok(()));
+    }
+
+    #[test]
+    fn diverged_output() {
+        let mut oracle = expecting(&[(1, b"hello world\n")]);
+        oracle.feed(1, b"hello");
+        oracle.feed(1, b" wOrld\n");
+        // Only the first divergence is reported
+        oracle.feed(1, b"more");
+        let diff = oracle.finish().unwrap_err();
+        assert!(
+            diff.starts_with("Output diverged on fd 1 at byte 7\n"),
+            "{}",
+            diff
+        );
+        assert!(diff.contains("Recorded: \"hello world\\n\""), "{}", diff);
+        assert!(diff.contains("Replayed: \" wOrld\\n\""), "{}", diff);
+    }
+
+    #[test]
+    fn extra_output() {
+        let mut oracle = expecting(&[(1, b"ok\n")]);
+        oracle.feed(1, b"ok\nextra");
+        let diff = oracle.finish().unwrap_err();
+        assert!(
+            diff.starts_with("Output diverged on fd 1 at byte 3\n"),
+            "{}",
+            diff
+        );
+
+        // Output on an fd without recorded output
+        let mut oracle = expecting(&[(1, b"ok\n")]);
+        oracle.feed(1, b"ok\n");
+        oracle.feed(2, b"oops");
+        let diff = oracle.finish().unwrap_err();
+        assert!(
+            diff.starts_with("Output diverged on fd 2 at byte 0\n"),
+            "{}",
+            diff
+        );
+    }
+
+    #[test]
+    fn missing_output() {
+        let mut oracle = expecting(&[(1, b"hello world\n"), (2, b"")]);
+        oracle.feed(1, b"hello");
+        assert_eq!(
+            oracle.finish().unwrap_err(),
+            "Output diverged on fd 1 at byte 5\n  Recorded: \" world\\n\"\n  Replayed: <end of output>"
+        );
+    }
+
+    #[test]
+    fn diff_context() {
+        let expected = [b'a'; 100];
+        let mut replayed = expected;
+        replayed[60] = b'b';
+        let mut oracle = expecting(&[(1, &expected)]);
+        oracle.feed(1, &replayed[..50]);
+        oracle.feed(1, &replayed[50..]);
+        let diff = oracle.finish().unwrap_err();
+        // Context of DIFF_CONTEXT bytes either side of byte 60
+        let recorded = "a".repeat(2 * DIFF_CONTEXT);
+        assert!(
+            diff.contains(&format!("Recorded: \"{}\"", recorded)),
+            "{}",
+            diff
+        );
+        // The replayed context is clipped to the chunk fed from byte 50
+        let replayed = format!("{}b{}", "a".repeat(10), "a".repeat(DIFF_CONTEXT - 1));
+        assert!(
+            diff.contains(&format!("Replayed: \"{}\"", replayed)),
+            "{}",
+            diff
+        );
+    }
+
+    #[test]
+    fn engine_msgs() {
+        let (mut engine, mut runner) = UnixStream::pair().unwrap();
+        for msg in [
+            EngineMsg::Output {
+                fd: 1,
+                data: b"hi".to_vec(),
+            },
+            EngineMsg::Trap("unreachable".into()),
+        ] {
+            let ser = postcard::to_stdvec(&msg).unwrap();
+            engine.write_all(&(ser.len() as u32).to_le_bytes()).unwrap();
+            engine.write_all(&ser).unwrap();
+        }
+        drop(engine);
+        assert!(matches!(
+            recv_engine_msg(&mut runner).unwrap(),
+            Some(EngineMsg::Output { fd: 1, data }) if data == b"hi"
+        ));
+        assert!(matches!(
+            recv_engine_msg(&mut runner).unwrap(),
+            Some(EngineMsg::Trap(message)) if message == "unreachable"
+        ));
+        assert!(recv_engine_msg(&mut runner).unwrap().is_none());
+    }
+}
@@ -1,23 +1,14 @@
 //! Binary crate for running replay Wasm modules generated by
 //! [`replay`](../replay/index.html).
 use clap::Parser;
-use libc::c_void;
-use log::{info, warn};
-use nix::sys::wait::{waitpid, WaitStatus};
-use nix::unistd::{fork, ForkResult};
+use log::{error, info};
 use std::error::Error;
 use std::fs;
-use std::process;
-
-use wamr_rust_sdk::{instance::Instance, module::Module, runtime::Runtime};
+use std::time::Duration;
 
 use wamr_rust_sdk::{log_level_t, LOG_LEVEL_WARNING};
 
-mod runner_interface;
-use runner_interface::{
-    wasm_r3_replay_futex_log, wasm_r3_replay_gettid, wasm_r3_replay_log_call,
-    wasm_r3_replay_proc_exit, wasm_r3_replay_thread_exit, wasm_r3_replay_writev,
-};
+use runner::{run, RunOptions};
 
 /// Command-Line Arguments
 #[derive(Parser, Debug)]
@@ -27,6 +18,10 @@ struct CLI {
     #[arg(short, long, default_value_t = LOG_LEVEL_WARNING)]
     verbose: log_level_t,
 
+    /// Kill the replay after running this many seconds
+    #[arg(short, long)]
+    timeout: Option<u64>,
+
     /// Input Command (Wasm program path + Argv)
     #[arg(num_args = 1..)]
     input_command: Vec<String>,
@@ -35,6 +30,7 @@ struct CLI {
 impl CLI {
     /// Print the CLI configuration
     fn print(&self) {
+        info!("Timeout [optional]: {:?}", self.timeout);
         info!("Input Command: {:?}", self.input_command);
     }
 }
@@ -46,41 +42,51 @@ fn main() -> Result<(), Box<dyn Error>> {
     let cli = CLI::parse();
     cli.print();
 
-    // Read wasm file, compute its digest
-    let infile = cli.input_command[0].as_str();
-    let wasm_module = fs::read(infile)?;
+    // Read wasm file and run it
+    let wasm_module = fs::read(cli.input_command[0].as_str())?;
+    let result = run(
+        &wasm_module,
+        &cli.input_command,
+        &RunOptions {
+            log_level: cli.verbose,
+            timeout: cli.timeout.map(Duration::from_secs),
+        },
+    )?;
+    if result.timed_out {
+        return Err("Replay timed out".into());
+    }
 
-    match unsafe { fork() }? {
-        ForkResult::Child => {
-            info!("Wasm engine executing with PID: {}", process::id());
-            // WAMR Instantiate and run, registering any replay interface methods
-            let runtime = Runtime::builder()
-                .use_system_allocator()
-                .set_host_function_module_name("r3-replay")
-                .register_host_function("SC_proc_exit", wasm_r3_replay_proc_exit as *mut c_void)
-                .register_host_function("SC_thread_exit", wasm_r3_replay_thread_exit as *mut c_void)
-                .register_host_function("SC_writev", wasm_r3_replay_writev as *mut c_void)
-                .register_host_function("SC_futex_log", wasm_r3_replay_futex_log as *mut c_void)
-                .register_host_function("SC_gettid", wasm_r3_replay_gettid as *mut c_void)
-                .register_host_function("SC_log_call", wasm_r3_replay_log_call as *mut c_void)
-                .set_max_thread_num(100)
-                .build()?;
-            runtime.set_log_level(cli.verbose);
-            let module = Module::from_buf(&runtime, &wasm_module[..], infile)?;
-            let instance = Instance::new(&runtime, &module, 1024 * 256)?;
+    // Verify the replay wrote the same output as the recording
+    match result.output_check {
+        Some(Ok(())) => info!("Replay output matches recording"),
+        Some(Err(ref diff)) => {
+            error!("Replay output mismatch | {}", diff);
+            return Err("Replay output differs from the recording".into());
+        }
+        None => info!("No recorded output embedded in module; skipping verification"),
+    }
 
-            let _ = instance.execute_main(&cli.input_command)?;
-            info!("Wasm module safely exited from child process");
-            process::exit(0);
+    // Verify the replay ended the same way as the recording
+    match (result.termination_matches(), result.expected_termination) {
+        (Some(true), _) => {
+            info!(
+                "Replay termination matches recording: {}",
+                result.termination.unwrap()
+            );
+        }
+        (Some(false), Some(expected)) => {
+            error!(
+                "Replay termination mismatch | Recorded: {}, Replayed: {}",
+                expected,
+                result
+                    .termination
+                    .map_or(String::from("Unknown"), |t| t.to_string())
+            );
+            return Err("Replay did not terminate like the recording".into());
+        }
+        _ => {
+            info!("No recorded termination embedded in module; skipping verification");
         }
-        ForkResult::Parent { child } => match waitpid(child, None)? {
-            WaitStatus::Exited(pid, status) => {
-                info!("Wasm engine (PID: {}) exited with status: {}", pid, status);
-            }
-            status => {
-                warn!("Wasm engine exited with bad status: {:?}", status);
-            }
-        },
     }
 
     return Ok(());
@@ -2,9 +2,10 @@
 //! modules -- This is the **replay interface** for any new Wasm engine to run
 //! modules.
 use libc;
-use log::{debug, trace, warn};
+use log::{debug, error, trace, warn};
 use std::process;
 
+use crate::oracle::{reporting_output, send_engine_msg, EngineMsg};
 use common::trace::{CallID, ReplayPropLogInfo};
 use common::wasm2native::*;
 use wamr_rust_sdk::{wasm_cluster_cancel_thread, wasm_exec_env_t};
@@ -33,6 +34,12 @@ pub fn wasm_r3_replay_writev(
     iovcnt: i32,
 ) -> i64 {
     debug!("Writev | fd: {}, iovs: {}, iovcnt: {} ", fd, iovs, iovcnt);
+    if reporting_output() {
+        match unsafe { read_wali_iovec_bytes(exec_env, iovs, iovcnt, usize::MAX) } {
+            Some(data) => send_engine_msg(&EngineMsg::Output { fd, data }),
+            None => warn!("Writev | iovec at {:#X} is out of bounds", iovs),
+        }
+    }
     let native_iovs = unsafe { get_native_iovec_from_wali(exec_env, iovs, iovcnt) };
     unsafe {
         if fd != 1 {
@@ -77,7 +84,10 @@ pub fn wasm_r3_replay_log_call(
     a3: i64,
     sync_id: u64,
 ) {
-    let call_id = CallID::from_parts(call_id, [a1, a2, a3]).unwrap();
+    let call_id = CallID::from_parts(call_id, [a1, a2, a3]).unwrap_or_else(|e| {
+        error!("Replay log: {}", e);
+        CallID::ScUnknown
+    });
     debug!(
         "{}",
         ReplayPropLogInfo {